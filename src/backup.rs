@@ -0,0 +1,412 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Portable wallet backup archives.
+//!
+//! Archive layout:
+//! - magic bytes `CTDLBKUP` and archive format version (1 byte);
+//! - flags (1 byte), with bit 0 set for encrypted archives;
+//! - for encrypted archives: scrypt parameters, salt and nonce;
+//! - payload, which is a sequence of sections, each prefixed with one-byte
+//!   section type and 8-byte little-endian section length. For encrypted
+//!   archives the payload is encrypted with XChaCha20-Poly1305 using all
+//!   preceding bytes as associated data;
+//! - SHA256 checksum of all preceding archive data.
+//!
+//! Wallet data are stored in the versioned strict-encoded form, so archives
+//! made by older versions are upgraded with the storage migrations on
+//! restore.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use amplify::IoError;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::Address;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use microservices::FileFormat;
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::hd::UnhardenedIndex;
+use zeroize::Zeroize;
+
+use crate::model::{Citadel, ContractId};
+use crate::storage::encrypted::{
+    KdfParams, KDF_PARAMS_LEN, NONCE_LEN, SALT_LEN,
+};
+use crate::storage::migrations::SCHEMA;
+
+const MAGIC: [u8; 8] = *b"CTDLBKUP";
+const VERSION: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 0x01;
+const CHECKSUM_LEN: usize = 32;
+
+const SECTION_CITADEL: u8 = 1;
+const SECTION_ADDRESSES: u8 = 2;
+const SECTION_STASH_FILE: u8 = 3;
+const SECTION_CHANGE_ADDRESSES: u8 = 4;
+
+#[derive(Clone, Debug, Display, From, Error)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// I/O error with backup data: {0}
+    #[from]
+    #[from(std::io::Error)]
+    Io(IoError),
+
+    /// the file is not a citadel backup archive
+    NotArchive,
+
+    /// backup archive version {0} is not supported
+    UnsupportedVersion(u8),
+
+    /// backup archive checksum does not match; the archive is damaged
+    ChecksumMismatch,
+
+    /// backup archive is encrypted, passphrase is required to restore it
+    PassphraseRequired,
+
+    /// wrong passphrase for the backup archive
+    WrongPassphrase,
+
+    /// backup archive encryption error: {0}
+    Encryption(String),
+
+    /// backup archive contains broken data: {0}
+    Broken(String),
+
+    /// backup contains contracts already present in the wallet ({0}); use
+    /// merge or replace restore mode
+    ContractConflicts(String),
+}
+
+impl From<strict_encoding::Error> for Error {
+    fn from(err: strict_encoding::Error) -> Self {
+        Error::Broken(err.to_string())
+    }
+}
+
+/// Wallet data contained in the backup archive
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Backup {
+    /// Contracts with their data, identities, signers and assets
+    pub citadel: Citadel,
+
+    /// Address derivations reserved or used by each of the contracts
+    pub address_derivations:
+        BTreeMap<ContractId, BTreeMap<Address, UnhardenedIndex>>,
+
+    /// Change branch derivations reserved for the change outputs by each of
+    /// the contracts. Archives made by older versions have no such data.
+    pub change_derivations:
+        BTreeMap<ContractId, BTreeMap<Address, UnhardenedIndex>>,
+
+    /// RGB stash files, indexed by their path relative to the data directory
+    pub stash: BTreeMap<String, Vec<u8>>,
+}
+
+impl Backup {
+    /// Serializes backup into an archive, encrypting it if the passphrase is
+    /// provided
+    pub fn to_archive(
+        &self,
+        passphrase: Option<&str>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![];
+        let citadel = SCHEMA
+            .store(&self.citadel, FileFormat::StrictEncode)
+            .map_err(|err| Error::Broken(err.to_string()))?;
+        write_section(&mut payload, SECTION_CITADEL, &citadel);
+        write_section(
+            &mut payload,
+            SECTION_ADDRESSES,
+            &self.address_derivations.strict_serialize()?,
+        );
+        write_section(
+            &mut payload,
+            SECTION_CHANGE_ADDRESSES,
+            &self.change_derivations.strict_serialize()?,
+        );
+        for (path, data) in &self.stash {
+            let mut section = (path.len() as u16).to_le_bytes().to_vec();
+            section.extend(path.as_bytes());
+            section.extend(data);
+            write_section(&mut payload, SECTION_STASH_FILE, &section);
+        }
+
+        let mut archive = MAGIC.to_vec();
+        archive.push(VERSION);
+        match passphrase {
+            None => {
+                archive.push(0);
+                archive.extend(payload);
+            }
+            Some(passphrase) => {
+                archive.push(FLAG_ENCRYPTED);
+                let kdf = KdfParams::default();
+                let mut salt = [0u8; SALT_LEN];
+                let mut nonce = [0u8; NONCE_LEN];
+                thread_rng().fill_bytes(&mut salt);
+                thread_rng().fill_bytes(&mut nonce);
+                archive.extend_from_slice(&kdf.to_bytes());
+                archive.extend_from_slice(&salt);
+                archive.extend_from_slice(&nonce);
+
+                let mut key = kdf
+                    .derive_key(passphrase, &salt)
+                    .map_err(|err| Error::Encryption(err.to_string()))?;
+                let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+                key.zeroize();
+                let ciphertext = cipher
+                    .encrypt(
+                        XNonce::from_slice(&nonce),
+                        Payload {
+                            msg: &payload,
+                            aad: &archive,
+                        },
+                    )
+                    .map_err(|_| Error::Encryption(s!("encryption failure")));
+                payload.zeroize();
+                archive.extend(ciphertext?);
+            }
+        }
+        let checksum = sha256::Hash::hash(&archive);
+        archive.extend_from_slice(&checksum[..]);
+        Ok(archive)
+    }
+
+    /// Validates and parses backup archive
+    pub fn from_archive(
+        archive: &[u8],
+        passphrase: Option<&str>,
+    ) -> Result<Backup, Error> {
+        let header_len = MAGIC.len() + 2;
+        if archive.len() < header_len + CHECKSUM_LEN
+            || archive[..MAGIC.len()] != MAGIC
+        {
+            return Err(Error::NotArchive);
+        }
+        let (archive, checksum) =
+            archive.split_at(archive.len() - CHECKSUM_LEN);
+        if sha256::Hash::hash(archive)[..] != checksum[..] {
+            return Err(Error::ChecksumMismatch);
+        }
+        let version = archive[MAGIC.len()];
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = archive[MAGIC.len() + 1];
+
+        let mut payload = if flags & FLAG_ENCRYPTED == 0 {
+            archive[header_len..].to_vec()
+        } else {
+            let passphrase = passphrase.ok_or(Error::PassphraseRequired)?;
+            let crypto_len = KDF_PARAMS_LEN + SALT_LEN + NONCE_LEN;
+            if archive.len() < header_len + crypto_len {
+                return Err(Error::Broken(s!("truncated encryption header")));
+            }
+            let (header, ciphertext) =
+                archive.split_at(header_len + crypto_len);
+            let kdf_start = header_len;
+            let salt_start = kdf_start + KDF_PARAMS_LEN;
+            let nonce_start = salt_start + SALT_LEN;
+            let kdf = KdfParams::from_bytes(&header[kdf_start..salt_start]);
+            let mut key = kdf
+                .derive_key(passphrase, &header[salt_start..nonce_start])
+                .map_err(|err| Error::Encryption(err.to_string()))?;
+            let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+            key.zeroize();
+            cipher
+                .decrypt(
+                    XNonce::from_slice(&header[nonce_start..]),
+                    Payload {
+                        msg: ciphertext,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| Error::WrongPassphrase)?
+        };
+
+        let backup = Self::parse_payload(&payload);
+        payload.zeroize();
+        backup
+    }
+
+    fn parse_payload(mut payload: &[u8]) -> Result<Backup, Error> {
+        let mut citadel = None;
+        let mut backup = Backup::default();
+        while !payload.is_empty() {
+            if payload.len() < 9 {
+                return Err(Error::Broken(s!("truncated section header")));
+            }
+            let section_type = payload[0];
+            let len = u64::from_le_bytes(
+                payload[1..9].try_into().expect("fixed-size slice"),
+            ) as usize;
+            payload = &payload[9..];
+            if payload.len() < len {
+                return Err(Error::Broken(s!("truncated section data")));
+            }
+            let (data, rest) = payload.split_at(len);
+            payload = rest;
+
+            match section_type {
+                SECTION_CITADEL => {
+                    let loaded = SCHEMA
+                        .load::<Citadel>(data, FileFormat::StrictEncode, 0)
+                        .map_err(|err| Error::Broken(err.to_string()))?;
+                    if let Some(version) = loaded.migrated_from {
+                        SCHEMA.report(version, &loaded.changes, None);
+                    }
+                    citadel = Some(loaded.data);
+                }
+                SECTION_ADDRESSES => {
+                    backup.address_derivations =
+                        StrictDecode::strict_deserialize(data)?;
+                }
+                SECTION_CHANGE_ADDRESSES => {
+                    backup.change_derivations =
+                        StrictDecode::strict_deserialize(data)?;
+                }
+                SECTION_STASH_FILE => {
+                    if data.len() < 2 {
+                        return Err(Error::Broken(s!("broken stash file")));
+                    }
+                    let path_len = u16::from_le_bytes([data[0], data[1]]);
+                    let (path, content) = data[2..]
+                        .split_at((path_len as usize).min(data.len() - 2));
+                    let path =
+                        String::from_utf8(path.to_vec()).map_err(|_| {
+                            Error::Broken(s!("non-UTF8 stash file name"))
+                        })?;
+                    backup.stash.insert(path, content.to_vec());
+                }
+                unknown => {
+                    // Sections added by the future versions of the archive
+                    // format are skipped
+                    warn!("Skipping unknown backup section type {}", unknown)
+                }
+            }
+        }
+        backup.citadel = citadel
+            .ok_or_else(|| Error::Broken(s!("archive has no wallet data")))?;
+        Ok(backup)
+    }
+}
+
+fn write_section(payload: &mut Vec<u8>, section_type: u8, data: &[u8]) {
+    payload.push(section_type);
+    payload.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    payload.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod test {
+    use commit_verify::CommitVerify;
+
+    use super::*;
+    use crate::test_utils::address;
+
+    fn backup() -> Backup {
+        let id = ContractId::commit(b"contract");
+        Backup {
+            citadel: none!(),
+            address_derivations: bmap! {
+                id => bmap! { address(1) => UnhardenedIndex::zero() }
+            },
+            change_derivations: bmap! {
+                id => bmap! { address(2) => UnhardenedIndex::from(3u8) }
+            },
+            stash: bmap! { s!("stash/fungibled.dat") => b"stash".to_vec() },
+        }
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        let backup = backup();
+        let archive = backup.to_archive(None).unwrap();
+        assert_eq!(Backup::from_archive(&archive, None).unwrap(), backup);
+        // Passphrase is ignored for the archives which are not encrypted
+        assert_eq!(
+            Backup::from_archive(&archive, Some("pass")).unwrap(),
+            backup
+        );
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let backup = backup();
+        let archive = backup.to_archive(Some("pass")).unwrap();
+        assert!(!archive.windows(5).any(|window| window == b"stash"));
+        assert!(matches!(
+            Backup::from_archive(&archive, None),
+            Err(Error::PassphraseRequired)
+        ));
+        assert!(matches!(
+            Backup::from_archive(&archive, Some("wrong")),
+            Err(Error::WrongPassphrase)
+        ));
+        assert_eq!(
+            Backup::from_archive(&archive, Some("pass")).unwrap(),
+            backup
+        );
+    }
+
+    #[test]
+    fn damaged_archive() {
+        let archive = backup().to_archive(None).unwrap();
+        let mut damaged = archive.clone();
+        damaged[MAGIC.len() + 10] ^= 0x01;
+        assert!(matches!(
+            Backup::from_archive(&damaged, None),
+            Err(Error::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            Backup::from_archive(&archive[..archive.len() - 1], None),
+            Err(Error::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            Backup::from_archive(&archive[MAGIC.len()..], None),
+            Err(Error::NotArchive)
+        ));
+    }
+
+    #[test]
+    fn unknown_sections() {
+        // Archive made by a future version with an extra section and
+        // without the change derivations known to older versions only
+        let backup = backup();
+        let mut archive = MAGIC.to_vec();
+        archive.push(VERSION);
+        archive.push(0);
+        let citadel = SCHEMA
+            .store(&backup.citadel, FileFormat::StrictEncode)
+            .unwrap();
+        write_section(&mut archive, SECTION_CITADEL, &citadel);
+        write_section(&mut archive, 0xFF, b"future data");
+        write_section(
+            &mut archive,
+            SECTION_ADDRESSES,
+            &backup.address_derivations.strict_serialize().unwrap(),
+        );
+        let checksum = sha256::Hash::hash(&archive);
+        archive.extend_from_slice(&checksum[..]);
+
+        let restored = Backup::from_archive(&archive, None).unwrap();
+        assert_eq!(restored.address_derivations, backup.address_derivations);
+        assert!(restored.change_derivations.is_empty());
+        assert!(restored.stash.is_empty());
+    }
+}
@@ -13,11 +13,11 @@
 
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 
-use bitcoin::{Address, OutPoint, Txid};
+use bitcoin::{Address, BlockHash, OutPoint, Transaction, Txid};
 use wallet::hd::UnhardenedIndex;
 
 use super::Error;
-use crate::model::{Allocations, ContractId, Utxo};
+use crate::model::{Allocations, Branch, ContractId, Utxo};
 
 pub trait Driver {
     fn blockpos_to_txid(&self, height: u32, offset: u16) -> Option<Txid>;
@@ -30,12 +30,51 @@ pub trait Driver {
     fn unspent_bitcoin_only(
         &self,
         contract_id: ContractId,
-    ) -> Result<HashSet<Utxo>, Error>;
+    ) -> Result<HashSet<Utxo>, Error> {
+        let unspent = self.unspent(contract_id)?;
+        let outpoints = self
+            .allocations(contract_id)?
+            .into_iter()
+            .filter_map(|(outpoint, mut assets)| {
+                // Removing bitcoins from accounting
+                assets.remove(&rgb::ContractId::default());
+                if assets.values().sum::<u64>() == 0 {
+                    Some(outpoint)
+                } else {
+                    None
+                }
+            })
+            .collect::<BTreeSet<_>>();
+        Ok(unspent
+            .get(&rgb::ContractId::default())
+            .map(|utxo_set| {
+                utxo_set
+                    .into_iter()
+                    .filter(|utxo| outpoints.contains(&utxo.outpoint()))
+                    .map(Utxo::clone)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
 
     fn allocations(
         &self,
         contract_id: ContractId,
-    ) -> Result<Allocations, Error>;
+    ) -> Result<Allocations, Error> {
+        Ok(self.unspent(contract_id)?.iter().fold(
+            Allocations::new(),
+            |mut allocations, (asset_id, utxos)| {
+                for utxo in utxos {
+                    *allocations
+                        .entry(utxo.outpoint())
+                        .or_insert(default!())
+                        .entry(*asset_id)
+                        .or_insert(0) += utxo.value;
+                }
+                allocations
+            },
+        ))
+    }
 
     fn utxo(
         &self,
@@ -51,11 +90,20 @@ pub trait Driver {
         unspent: BTreeMap<rgb::ContractId, Vec<Utxo>>,
     ) -> Result<(), Error>;
 
+    /// Returns addresses from the receive branch which were given to payers
     fn used_address_derivations(
         &self,
         contract_id: ContractId,
     ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error>;
 
+    /// Returns addresses from the change branch which were reserved for the
+    /// change outputs
+    fn used_change_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error>;
+
+    /// Returns addresses used in any of the derivation branches
     fn used_addresses(
         &self,
         contract_id: ContractId,
@@ -64,16 +112,19 @@ pub trait Driver {
     fn used_derivations(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Result<HashSet<UnhardenedIndex>, Error>;
 
     fn next_unused_derivation(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Result<UnhardenedIndex, Error>;
 
     fn use_address_derivation(
         &mut self,
         contract_id: ContractId,
+        branch: Branch,
         address: Address,
         path: UnhardenedIndex,
     ) -> Result<bool, Error>;
@@ -81,17 +132,79 @@ pub trait Driver {
     fn last_used_derivation(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Option<UnhardenedIndex>;
 
+    /// Removes used address from any of the derivation branches
     fn forget_address(
         &mut self,
         contract_id: ContractId,
         address: &Address,
     ) -> Result<bool, Error>;
 
+    /// Returns derivation branch and index of the used address
     fn address_derivation(
         &self,
         contract_id: ContractId,
         address: &Address,
+    ) -> Option<(Branch, UnhardenedIndex)>;
+
+    /// Returns cached transaction, if it is known
+    fn transaction(&self, txid: &Txid) -> Option<Transaction>;
+
+    fn cache_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<(), Error>;
+
+    /// Removes cached transactions which have no outputs belonging to any
+    /// of the wallet contracts, returning the number of removed transactions
+    fn evict_transactions(&mut self) -> Result<usize, Error>;
+
+    /// Returns hashes of the blocks mining known transactions, indexed by
+    /// the block height
+    fn block_hashes(&self) -> BTreeMap<u32, BlockHash>;
+
+    fn record_block_hashes(
+        &mut self,
+        block_hashes: BTreeMap<u32, BlockHash>,
+    ) -> Result<(), Error>;
+
+    /// Removes hashes of the blocks below `height`, which are too deep to be
+    /// checked for chain reorganizations
+    fn prune_block_hashes(&mut self, height: u32) -> Result<(), Error>;
+
+    /// Removes data about the blocks starting from `height`, which were
+    /// orphaned by a chain reorganization, together with the transaction
+    /// mining information and UTXOs from these blocks. Returns ids of the
+    /// transactions which were mined in the removed blocks.
+    fn rollback(&mut self, height: u32) -> Result<BTreeSet<Txid>, Error>;
+
+    /// Returns chain height at the last synchronization of the contract
+    fn updated_height(&self, contract_id: ContractId) -> Option<u32>;
+
+    /// Returns gap limit for the address lookup stored for the contract
+    fn gap_limit(&self, contract_id: ContractId) -> Option<u8>;
+
+    fn set_gap_limit(
+        &mut self,
+        contract_id: ContractId,
+        gap_limit: u8,
+    ) -> Result<(), Error>;
+
+    /// Returns highest derivation index of the branch where the
+    /// synchronization has found funds
+    fn highest_index(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
     ) -> Option<UnhardenedIndex>;
+
+    /// Records derivation indexes where the synchronization has found
+    /// funds; indexes below the already known highest ones are ignored
+    fn record_highest_indexes(
+        &mut self,
+        contract_id: ContractId,
+        indexes: BTreeMap<Branch, UnhardenedIndex>,
+    ) -> Result<(), Error>;
 }
@@ -11,6 +11,8 @@
 // along with this software.
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+use crate::migration;
+
 #[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
 #[display(doc_comments)]
 #[non_exhaustive]
@@ -28,6 +30,10 @@ pub enum Error {
     #[from]
     StrictEncoding(strict_encoding::Error),
 
+    /// {0}
+    #[from]
+    Migration(migration::Error),
+
     /// error in YAML data encoding: {0}
     YamlEncoding(String),
 
@@ -39,6 +45,10 @@ pub enum Error {
     #[from(toml::de::Error)]
     #[from(toml::ser::Error)]
     TomlEncoding,
+
+    /// SQLite database error: {0}
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
 }
 
 impl From<serde_yaml::Error> for Error {
@@ -46,3 +56,10 @@ impl From<serde_yaml::Error> for Error {
         Error::YamlEncoding(err.to_string())
     }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err.to_string())
+    }
+}
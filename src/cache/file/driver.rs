@@ -13,81 +13,30 @@
 
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 
-use bitcoin::{Address, OutPoint, Txid};
-use wallet::hd::{ChildIndex, UnhardenedIndex};
+use bitcoin::{Address, BlockHash, OutPoint, Transaction, Txid};
+use wallet::hd::UnhardenedIndex;
 
 use super::FileDriver;
 use crate::cache::{Driver, Error};
-use crate::model::{Allocations, ContractId, Utxo};
+use crate::model::{Branch, ContractId, Utxo};
 
 impl Driver for FileDriver {
     fn blockpos_to_txid(&self, height: u32, offset: u16) -> Option<Txid> {
-        self.cache.mine_info.get(&(height, offset)).copied()
+        self.memory.blockpos_to_txid(height, offset)
     }
 
     fn unspent(
         &self,
         contract_id: ContractId,
     ) -> Result<BTreeMap<rgb::ContractId, HashSet<Utxo>>, Error> {
-        self.map_contract_or_default(contract_id, |c| c.unspent.clone())
-    }
-
-    fn unspent_bitcoin_only(
-        &self,
-        contract_id: ContractId,
-    ) -> Result<HashSet<Utxo>, Error> {
-        let unspent = self.unspent(contract_id)?;
-        let outpoints = self
-            .allocations(contract_id)?
-            .into_iter()
-            .filter_map(|(outpoint, mut assets)| {
-                // Removing bitcoins from accounting
-                assets.remove(&rgb::ContractId::default());
-                if assets.values().sum::<u64>() == 0 {
-                    Some(outpoint)
-                } else {
-                    None
-                }
-            })
-            .collect::<BTreeSet<_>>();
-        Ok(unspent
-            .get(&rgb::ContractId::default())
-            .map(|utxo_set| {
-                utxo_set
-                    .into_iter()
-                    .filter(|utxo| outpoints.contains(&utxo.outpoint()))
-                    .map(Utxo::clone)
-                    .collect()
-            })
-            .unwrap_or_default())
-    }
-
-    fn allocations(
-        &self,
-        contract_id: ContractId,
-    ) -> Result<Allocations, Error> {
-        self.map_contract_or_default(contract_id, |cache| {
-            cache.unspent.iter().fold(
-                Allocations::new(),
-                |mut allocations, (asset_id, utxos)| {
-                    for utxo in utxos {
-                        *allocations
-                            .entry(utxo.outpoint())
-                            .or_insert(default!())
-                            .entry(*asset_id)
-                            .or_insert(0) += utxo.value;
-                    }
-                    allocations
-                },
-            )
-        })
+        self.memory.unspent(contract_id)
     }
 
     fn utxo(
         &self,
         contract_id: ContractId,
     ) -> Result<BTreeSet<OutPoint>, Error> {
-        self.map_contract_or_default(contract_id, |cache| cache.utxo.clone())
+        self.memory.utxo(contract_id)
     }
 
     fn update(
@@ -98,26 +47,13 @@ impl Driver for FileDriver {
         utxo: BTreeSet<OutPoint>,
         unspent: BTreeMap<rgb::ContractId, Vec<Utxo>>,
     ) -> Result<(), Error> {
-        self.cache.mine_info.extend(mine_info);
-        let cache = self
-            .cache
-            .descriptors
-            .entry(contract_id)
-            .or_insert(default!());
-        cache.unspent = unspent
-            .into_iter()
-            .map(|(asset_id, utxos)| {
-                (
-                    asset_id,
-                    utxos.into_iter().filter(|utxo| utxo.value > 0).collect(),
-                )
-            })
-            .collect();
-        cache.utxo = utxo;
-        if let Some(height) = updated_height {
-            self.cache.known_height = height;
-            cache.updated_height = height;
-        }
+        self.memory.update(
+            contract_id,
+            mine_info,
+            updated_height,
+            utxo,
+            unspent,
+        )?;
         self.store()
     }
 
@@ -125,90 +61,62 @@ impl Driver for FileDriver {
         &self,
         contract_id: ContractId,
     ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
-        self.map_contract_or_default(contract_id, |cache| {
-            cache.used_address_derivations.clone()
-        })
+        self.memory.used_address_derivations(contract_id)
+    }
+
+    fn used_change_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
+        self.memory.used_change_derivations(contract_id)
     }
 
     fn used_addresses(
         &self,
         contract_id: ContractId,
     ) -> Result<HashSet<Address>, Error> {
-        self.map_contract_or_default(contract_id, |cache| {
-            cache
-                .used_address_derivations
-                .iter()
-                .map(|(address, _)| address)
-                .cloned()
-                .collect()
-        })
+        self.memory.used_addresses(contract_id)
     }
 
     fn used_derivations(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Result<HashSet<UnhardenedIndex>, Error> {
-        self.map_contract_or_default(contract_id, |cache| {
-            cache
-                .used_address_derivations
-                .iter()
-                .map(|(_, derivation)| derivation)
-                .copied()
-                .collect()
-        })
+        self.memory.used_derivations(contract_id, branch)
     }
 
     fn next_unused_derivation(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Result<UnhardenedIndex, Error> {
-        self.map_contract_or_default(contract_id, |cache| {
-            cache
-                .used_address_derivations
-                .values()
-                .max()
-                .copied()
-                .and_then(UnhardenedIndex::checked_inc)
-                .unwrap_or_default()
-        })
+        self.memory.next_unused_derivation(contract_id, branch)
     }
 
     fn use_address_derivation(
         &mut self,
         contract_id: ContractId,
+        branch: Branch,
         address: Address,
         path: UnhardenedIndex,
     ) -> Result<bool, Error> {
-        self.with_contract(contract_id, |cache| {
-            if cache
-                .used_address_derivations
-                .get(&address)
-                .map(|p| p != &path)
-                .unwrap_or(false)
-            {
-                Err(Error::WrongDerivation)
-            } else {
-                Ok(cache
-                    .used_address_derivations
-                    .insert(address, path)
-                    .is_none())
-            }
-        })
+        let used = self.memory.use_address_derivation(
+            contract_id,
+            branch,
+            address,
+            path,
+        )?;
+        self.store()?;
+        Ok(used)
     }
 
     fn last_used_derivation(
         &self,
         contract_id: ContractId,
+        branch: Branch,
     ) -> Option<UnhardenedIndex> {
-        let cache = self.cache.descriptors.get(&contract_id)?;
-        Some(
-            cache
-                .used_address_derivations
-                .values()
-                .copied()
-                .max_by_key(|index| index.clone())
-                .unwrap_or_default(),
-        )
+        self.memory.last_used_derivation(contract_id, branch)
     }
 
     fn forget_address(
@@ -216,18 +124,105 @@ impl Driver for FileDriver {
         contract_id: ContractId,
         address: &Address,
     ) -> Result<bool, Error> {
-        self.with_contract(contract_id, |cache| {
-            Ok(cache.used_address_derivations.remove(&address).is_some())
-        })
+        let forgotten = self.memory.forget_address(contract_id, address)?;
+        self.store()?;
+        Ok(forgotten)
     }
 
     fn address_derivation(
         &self,
         contract_id: ContractId,
         address: &Address,
+    ) -> Option<(Branch, UnhardenedIndex)> {
+        self.memory.address_derivation(contract_id, address)
+    }
+
+    fn transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.memory.transaction(txid)
+    }
+
+    fn cache_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        if transactions.is_empty() {
+            return Ok(());
+        }
+        self.memory.cache_transactions(transactions)?;
+        self.store()
+    }
+
+    fn evict_transactions(&mut self) -> Result<usize, Error> {
+        let evicted = self.memory.evict_transactions()?;
+        if evicted > 0 {
+            self.store()?;
+        }
+        Ok(evicted)
+    }
+
+    fn block_hashes(&self) -> BTreeMap<u32, BlockHash> {
+        self.memory.block_hashes()
+    }
+
+    fn record_block_hashes(
+        &mut self,
+        block_hashes: BTreeMap<u32, BlockHash>,
+    ) -> Result<(), Error> {
+        if block_hashes.is_empty() {
+            return Ok(());
+        }
+        self.memory.record_block_hashes(block_hashes)?;
+        self.store()
+    }
+
+    fn prune_block_hashes(&mut self, height: u32) -> Result<(), Error> {
+        if self.memory.block_hashes().range(..height).next().is_none() {
+            return Ok(());
+        }
+        self.memory.prune_block_hashes(height)?;
+        self.store()
+    }
+
+    fn rollback(&mut self, height: u32) -> Result<BTreeSet<Txid>, Error> {
+        let orphaned = self.memory.rollback(height)?;
+        self.store()?;
+        Ok(orphaned)
+    }
+
+    fn updated_height(&self, contract_id: ContractId) -> Option<u32> {
+        self.memory.updated_height(contract_id)
+    }
+
+    fn gap_limit(&self, contract_id: ContractId) -> Option<u8> {
+        self.memory.gap_limit(contract_id)
+    }
+
+    fn set_gap_limit(
+        &mut self,
+        contract_id: ContractId,
+        gap_limit: u8,
+    ) -> Result<(), Error> {
+        self.memory.set_gap_limit(contract_id, gap_limit)?;
+        self.store()
+    }
+
+    fn highest_index(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
     ) -> Option<UnhardenedIndex> {
-        self.cache.descriptors.get(&contract_id).and_then(|cache| {
-            cache.used_address_derivations.get(address).cloned()
-        })
+        self.memory.highest_index(contract_id, branch)
+    }
+
+    fn record_highest_indexes(
+        &mut self,
+        contract_id: ContractId,
+        indexes: BTreeMap<Branch, UnhardenedIndex>,
+    ) -> Result<(), Error> {
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        self.memory.record_highest_indexes(contract_id, indexes)?;
+        self.store()
     }
 }
@@ -11,16 +11,14 @@
 // along with this software.
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
-use std::io::{Read, Seek, Write};
+use std::fs;
 use std::path::PathBuf;
-use std::{fs, io};
 
 use microservices::FileFormat;
-use strict_encoding::{StrictDecode, StrictEncode};
 
-use super::{Cache, ContractCache};
-use crate::cache::Error;
-use crate::model::ContractId;
+use crate::cache::migrations::SCHEMA;
+use crate::cache::{Error, MemoryDriver};
+use crate::journal::JournaledFile;
 
 const CACHE_FILENAME: &'static str = "cache";
 
@@ -53,9 +51,9 @@ impl FileConfig {
 
 #[derive(Debug)]
 pub struct FileDriver {
-    fd: fs::File,
+    file: JournaledFile,
     config: FileConfig,
-    pub(super) cache: Cache,
+    pub(super) memory: MemoryDriver,
 }
 
 impl FileDriver {
@@ -64,16 +62,12 @@ impl FileDriver {
         fs::create_dir_all(&config.location)?;
 
         let filename = config.filename();
-        let exists = filename.exists();
-        let fd = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(!exists)
-            .open(&filename)?;
+        let file = JournaledFile::with(filename.clone())?;
+        let exists = file.exists();
         let mut me = Self {
-            fd,
+            file,
             config: config.clone(),
-            cache: none!(),
+            memory: none!(),
         };
         if !exists {
             warn!(
@@ -89,19 +83,15 @@ impl FileDriver {
 
     pub(super) fn load(&mut self) -> Result<(), Error> {
         debug!("Loading cache from `{:?}`", self.config.filename());
-        self.fd.seek(io::SeekFrom::Start(0))?;
+        let data = self.file.read()?;
         trace!("Parsing cache (expected format {})", self.config.format);
-        self.cache = match self.config.format {
-            FileFormat::StrictEncode => Cache::strict_decode(&mut self.fd)?,
-            FileFormat::Yaml => serde_yaml::from_reader(&mut self.fd)?,
-            FileFormat::Toml => {
-                let mut data: Vec<u8> = vec![];
-                self.fd.read_to_end(&mut data)?;
-                toml::from_slice(&data)?
-            }
-            FileFormat::Json => serde_json::from_reader(&mut self.fd)?,
-            _ => unimplemented!(),
-        };
+        let loaded = SCHEMA.load(&data, self.config.format, 0)?;
+        self.memory.cache = loaded.data;
+        if let Some(version) = loaded.migrated_from {
+            let backup = SCHEMA.backup(self.file.path(), version)?;
+            self.store()?;
+            SCHEMA.report(version, &loaded.changes, Some(&backup));
+        }
         trace!("Cache loaded from storage");
         Ok(())
     }
@@ -112,61 +102,9 @@ impl FileDriver {
             self.config.filename(),
             self.config.format
         );
-        self.fd.seek(io::SeekFrom::Start(0))?;
-        self.fd.set_len(0)?;
-        match self.config.format {
-            FileFormat::StrictEncode => {
-                self.cache.strict_encode(&mut self.fd)?;
-            }
-            FileFormat::Yaml => {
-                serde_yaml::to_writer(&mut self.fd, &self.cache)?;
-            }
-            FileFormat::Toml => {
-                let data = toml::to_vec(&self.cache)?;
-                self.fd.write_all(&data)?;
-            }
-            FileFormat::Json => {
-                serde_json::to_writer(&mut self.fd, &self.cache)?;
-            }
-            _ => unimplemented!(),
-        };
+        let data = SCHEMA.store(&self.memory.cache, self.config.format)?;
+        self.file.write(&data)?;
         trace!("Cache stored");
         Ok(())
     }
-
-    pub(super) fn map_contract_or_default<R>(
-        &self,
-        contract_id: ContractId,
-        predicate: impl FnOnce(&ContractCache) -> R,
-    ) -> Result<R, Error>
-    where
-        R: Sized + Default,
-    {
-        Ok(self
-            .cache
-            .descriptors
-            .get(&contract_id)
-            .map(predicate)
-            .unwrap_or_default())
-    }
-
-    pub(super) fn with_contract<R>(
-        &mut self,
-        contract_id: ContractId,
-        predicate: impl FnOnce(&mut ContractCache) -> Result<R, Error>,
-    ) -> Result<R, Error>
-    where
-        R: Sized,
-    {
-        predicate(
-            self.cache
-                .descriptors
-                .entry(contract_id)
-                .or_insert(default!()),
-        )
-        .and_then(|r| {
-            self.store()?;
-            Ok(r)
-        })
-    }
 }
@@ -15,7 +15,6 @@
 
 mod driver;
 mod internal;
-mod model;
 
+pub(self) use super::model::{Cache, ContractCache};
 pub use internal::{FileConfig, FileDriver};
-pub(self) use model::{Cache, ContractCache};
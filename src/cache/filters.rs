@@ -0,0 +1,494 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Compact block filter data used by the BIP157 source of blockchain data.
+//! Unlike the rest of the cache these data are not specific to a wallet, so
+//! they are kept in files shared by all wallets. Block and filter headers,
+//! which grow with the chain, are appended to files of fixed-size records;
+//! the matched transactions are kept in a strict-encoded file.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::{deserialize, serialize, Decodable, Encodable};
+use bitcoin::hashes::{sha256, sha256d, Hash};
+use bitcoin::{BlockHeader, Script, Transaction, Txid};
+use microservices::FileFormat;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use super::Error;
+use crate::migration::{Document, Migration, Schema};
+
+/// Compact filter data format versions:
+/// - 0: initial version;
+/// - 1: block and filter headers are moved to separate append-only files.
+static SCHEMA: Schema = Schema {
+    name: "compact filters",
+    version: 1,
+    migrations: &[Migration {
+        version: 1,
+        description: "block and filter headers are removed; they are \
+                      downloaded again into separate files",
+        upgrade: v1_remove_headers,
+    }],
+};
+
+/// Length of the file prefix made of the magic bytes and the base height
+const PREFIX_LEN: usize = 8;
+
+/// Consensus-encoded data of a fixed size kept for each block in
+/// [`HeaderFile`]
+pub trait Record: Copy + Encodable + Decodable {
+    /// Length of the encoded record
+    const LEN: usize;
+
+    /// Magic bytes starting the file with the records
+    const MAGIC: [u8; 4];
+}
+
+impl Record for BlockHeader {
+    const LEN: usize = 80;
+    const MAGIC: [u8; 4] = *b"CTDH";
+}
+
+impl Record for sha256d::Hash {
+    const LEN: usize = 32;
+    const MAGIC: [u8; 4] = *b"CTDF";
+}
+
+/// Records for the consecutive blocks starting from the `base` height,
+/// which are kept in memory and appended to a file. The file starts with the
+/// magic bytes and the base height; a record torn by an interrupted write is
+/// dropped when the file gets loaded.
+pub struct HeaderFile<T: Record> {
+    path: PathBuf,
+    file: fs::File,
+    base: u32,
+    records: Vec<T>,
+}
+
+impl<T: Record> HeaderFile<T> {
+    fn open(path: PathBuf) -> Result<Self, Error> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        let mut headers = HeaderFile {
+            path,
+            file,
+            base: 0,
+            records: vec![],
+        };
+        if data.len() < PREFIX_LEN || data[..4] != T::MAGIC {
+            if !data.is_empty() {
+                warn!("Discarding unrecognized data in {:?}", headers.path);
+            }
+            headers.reset(0)?;
+            return Ok(headers);
+        }
+
+        let mut base = [0u8; 4];
+        base.copy_from_slice(&data[4..PREFIX_LEN]);
+        headers.base = u32::from_le_bytes(base);
+        let mut chunks = data[PREFIX_LEN..].chunks_exact(T::LEN);
+        for chunk in &mut chunks {
+            headers.records.push(deserialize(chunk).map_err(|err| {
+                strict_encoding::Error::DataIntegrityError(err.to_string())
+            })?);
+        }
+        if !chunks.remainder().is_empty() {
+            warn!("Dropping incomplete record from {:?}", headers.path);
+            headers
+                .file
+                .set_len(headers.len_at(headers.records.len()))?;
+        }
+        Ok(headers)
+    }
+
+    fn len_at(&self, count: usize) -> u64 {
+        (PREFIX_LEN + count * T::LEN) as u64
+    }
+
+    /// Height of the first record
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Height of the last record
+    pub fn tip(&self) -> Option<u32> {
+        match self.records.len() {
+            0 => None,
+            len => Some(self.base + len as u32 - 1),
+        }
+    }
+
+    pub fn get(&self, height: u32) -> Option<T> {
+        let index = height.checked_sub(self.base)? as usize;
+        self.records.get(index).copied()
+    }
+
+    pub fn records(&self) -> &[T] {
+        &self.records
+    }
+
+    /// Removes all records, so the next one is for the block at `base`
+    /// height
+    pub fn reset(&mut self, base: u32) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.write_all(&T::MAGIC)?;
+        self.file.write_all(&base.to_le_bytes())?;
+        self.file.sync_data()?;
+        self.base = base;
+        self.records.clear();
+        Ok(())
+    }
+
+    /// Appends records for the blocks following the last one
+    pub fn extend(&mut self, records: &[T]) -> Result<(), Error> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let data = records.iter().flat_map(serialize).collect::<Vec<_>>();
+        self.file.write_all(&data)?;
+        self.file.sync_data()?;
+        self.records.extend_from_slice(records);
+        Ok(())
+    }
+
+    /// Removes records starting from the given height
+    pub fn truncate(&mut self, height: u32) -> Result<(), Error> {
+        let count = height.saturating_sub(self.base) as usize;
+        if count < self.records.len() {
+            self.file.set_len(self.len_at(count))?;
+            self.records.truncate(count);
+        }
+        Ok(())
+    }
+}
+
+/// Transaction from a block matching compact filters
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Serialize,
+    Deserialize,
+    StrictEncode,
+    StrictDecode,
+)]
+pub struct MatchedTx {
+    /// Height of the block mining the transaction
+    pub height: u32,
+
+    /// Position of the transaction within the block
+    pub position: u16,
+
+    pub tx: Transaction,
+}
+
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Default,
+    Serialize,
+    Deserialize,
+    StrictEncode,
+    StrictDecode,
+)]
+pub struct FilterCache {
+    /// Height up to which block filters were matched against the scripts
+    pub scanned_height: u32,
+
+    /// Scripts matched against the block filters
+    pub scripts: BTreeSet<Script>,
+
+    /// Transactions from the matched blocks which pay to the scripts or
+    /// spend their outputs
+    pub transactions: BTreeMap<Txid, MatchedTx>,
+}
+
+impl FilterCache {
+    /// Removes data for the blocks starting from the given height, which
+    /// were replaced by a chain reorganization
+    pub fn rollback(&mut self, height: u32) {
+        self.scanned_height = self.scanned_height.min(height.saturating_sub(1));
+        self.transactions
+            .retain(|_, matched| matched.height < height);
+    }
+}
+
+/// Compact filter data persisted in the strict-encoded file and two files of
+/// headers next to it
+pub struct FilterStore {
+    filename: PathBuf,
+
+    /// Headers of the best known chain starting from a checkpoint
+    pub headers: HeaderFile<BlockHeader>,
+
+    /// BIP157 headers of the basic block filters starting from the same
+    /// checkpoint as the block headers
+    pub filter_headers: HeaderFile<sha256d::Hash>,
+
+    pub data: FilterCache,
+
+    /// Hash of the last stored data, so unchanged data are not written again
+    stored: Option<sha256::Hash>,
+}
+
+impl FilterStore {
+    pub fn with(filename: PathBuf) -> Result<Self, Error> {
+        info!("Initializing compact filter cache in {:?}", filename);
+        let (data, migrated_from, changes) = if filename.exists() {
+            let loaded = SCHEMA.load(
+                &fs::read(&filename)?,
+                FileFormat::StrictEncode,
+                0,
+            )?;
+            (loaded.data, loaded.migrated_from, loaded.changes)
+        } else {
+            (FilterCache::default(), None, vec![])
+        };
+        let mut store = FilterStore {
+            headers: HeaderFile::open(sibling(&filename, "headers"))?,
+            filter_headers: HeaderFile::open(sibling(&filename, "cfheaders"))?,
+            filename,
+            data,
+            stored: None,
+        };
+        store.check_headers()?;
+        if let Some(version) = migrated_from {
+            let backup = SCHEMA.backup(&store.filename, version)?;
+            store.store()?;
+            SCHEMA.report(version, &changes, Some(&backup));
+        }
+        Ok(store)
+    }
+
+    /// Drops headers which do not build a chain and filter headers which do
+    /// not correspond to the known block headers
+    fn check_headers(&mut self) -> Result<(), Error> {
+        let broken =
+            self.headers.records().windows(2).position(|pair| {
+                pair[1].prev_blockhash != pair[0].block_hash()
+            });
+        if let Some(index) = broken {
+            let height = self.headers.base() + index as u32 + 1;
+            warn!("Cached block headers are broken at height {}", height);
+            self.headers.truncate(height)?;
+        }
+        if self.filter_headers.base() != self.headers.base() {
+            self.filter_headers.reset(self.headers.base())?;
+        }
+        match self.headers.tip() {
+            Some(tip) => self.filter_headers.truncate(tip + 1),
+            None => self.filter_headers.reset(self.headers.base()),
+        }
+    }
+
+    /// Removes all headers, so the chain is synchronized starting from the
+    /// block at `base` height
+    pub fn reset(&mut self, base: u32) -> Result<(), Error> {
+        self.headers.reset(base)?;
+        self.filter_headers.reset(base)
+    }
+
+    /// Removes data for the blocks starting from the given height, which
+    /// were replaced by a chain reorganization
+    pub fn rollback(&mut self, height: u32) -> Result<(), Error> {
+        self.headers.truncate(height)?;
+        self.filter_headers.truncate(height)?;
+        self.data.rollback(height);
+        Ok(())
+    }
+
+    /// Writes the strict-encoded data, unless they have not changed since
+    /// the last write; headers are written as they get added
+    pub fn store(&mut self) -> Result<(), Error> {
+        let data = SCHEMA.store(&self.data, FileFormat::StrictEncode)?;
+        let hash = sha256::Hash::hash(&data);
+        if self.stored == Some(hash) {
+            return Ok(());
+        }
+        trace!("Storing compact filter cache to {:?}", self.filename);
+        let tmp = sibling(&self.filename, "tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.filename)?;
+        self.stored = Some(hash);
+        Ok(())
+    }
+}
+
+fn sibling(filename: &Path, ext: &str) -> PathBuf {
+    let mut path = filename.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+/// Block header, strict-encoded with bitcoin consensus encoding, as it was
+/// kept before format version 1; the header itself is not needed anymore
+struct ChainHeaderV0;
+
+impl StrictDecode for ChainHeaderV0 {
+    fn strict_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        BlockHeader::consensus_decode(d)
+            .map(|_| ChainHeaderV0)
+            .map_err(|err| {
+                strict_encoding::Error::DataIntegrityError(err.to_string())
+            })
+    }
+}
+
+/// Layout of `FilterCache` data before format version 1
+#[derive(StrictDecode)]
+struct FilterCacheV0 {
+    _headers: Vec<ChainHeaderV0>,
+    _filter_headers: Vec<sha256d::Hash>,
+    scanned_height: u32,
+    scripts: BTreeSet<Script>,
+    transactions: BTreeMap<Txid, MatchedTx>,
+}
+
+fn v1_remove_headers(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = FilterCacheV0::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let cache = FilterCache {
+                scanned_height: old.scanned_height,
+                scripts: old.scripts,
+                transactions: old.transactions,
+            };
+            cache
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        Document::Structured(mut value) => {
+            if let Some(map) = value.as_mapping_mut() {
+                map.remove(&"headers".into());
+                map.remove(&"filter_headers".into());
+            }
+            Ok(Document::Structured(value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn filename(name: &str) -> PathBuf {
+        temp_dir(&format!("filter-store-{}", name)).join("filters.dat")
+    }
+
+    fn chain(count: u32) -> Vec<BlockHeader> {
+        let mut headers = vec![genesis_block(Network::Regtest).header];
+        for nonce in 1..count {
+            let prev = headers[headers.len() - 1];
+            headers.push(BlockHeader {
+                prev_blockhash: prev.block_hash(),
+                time: prev.time + 600,
+                nonce,
+                ..prev
+            });
+        }
+        headers
+    }
+
+    #[test]
+    fn headers_append_and_truncate() {
+        let filename = filename("append");
+        let headers = chain(5);
+        let mut store = FilterStore::with(filename.clone()).unwrap();
+        store.reset(100).unwrap();
+        store.headers.extend(&headers[..3]).unwrap();
+        store.headers.extend(&headers[3..]).unwrap();
+        store
+            .filter_headers
+            .extend(&[sha256d::Hash::hash(b"filter"); 5])
+            .unwrap();
+        store.rollback(103).unwrap();
+
+        let store = FilterStore::with(filename.clone()).unwrap();
+        assert_eq!(store.headers.base(), 100);
+        assert_eq!(store.headers.tip(), Some(102));
+        assert_eq!(store.headers.records(), &headers[..3]);
+        assert_eq!(store.headers.get(101), Some(headers[1]));
+        assert_eq!(store.headers.get(99), None);
+        assert_eq!(store.filter_headers.tip(), Some(102));
+
+        // Record torn by an interrupted write is dropped
+        let path = sibling(&filename, "headers");
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&serialize(&headers[3])[..40]).unwrap();
+        let store = FilterStore::with(filename.clone()).unwrap();
+        assert_eq!(store.headers.records(), &headers[..3]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (PREFIX_LEN + 3 * 80) as u64
+        );
+    }
+
+    #[test]
+    fn migrates_headers_out() {
+        let filename = filename("migrate");
+        let transactions = bmap! {
+            Txid::default() => MatchedTx {
+                height: 2,
+                position: 0,
+                tx: genesis_block(Network::Regtest).txdata[0].clone(),
+            }
+        };
+        let mut data = b"CTDV\x00\x00".to_vec();
+        let headers = chain(3);
+        (headers.len() as u16).strict_encode(&mut data).unwrap();
+        for header in &headers {
+            header.consensus_encode(&mut data).unwrap();
+        }
+        vec![sha256d::Hash::hash(b"filter")]
+            .strict_encode(&mut data)
+            .unwrap();
+        2u32.strict_encode(&mut data).unwrap();
+        BTreeSet::<Script>::new().strict_encode(&mut data).unwrap();
+        transactions.strict_encode(&mut data).unwrap();
+        fs::write(&filename, data).unwrap();
+
+        let store = FilterStore::with(filename.clone()).unwrap();
+        assert_eq!(store.data.scanned_height, 2);
+        assert_eq!(store.data.transactions, transactions);
+        assert!(store.headers.is_empty());
+        assert!(sibling(&filename, "v0.backup").exists());
+        drop(store);
+        let store = FilterStore::with(filename).unwrap();
+        assert_eq!(store.data.transactions, transactions);
+    }
+}
@@ -0,0 +1,327 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! In-memory cache driver.
+//!
+//! Cached data are kept only for the lifetime of the driver. The driver also
+//! implements cache logic for the file driver, which persists the data after
+//! each change.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use bitcoin::{Address, BlockHash, OutPoint, Transaction, Txid};
+use wallet::hd::{ChildIndex, UnhardenedIndex};
+
+use super::model::{Cache, ContractCache};
+use super::{Driver, Error};
+use crate::model::{Branch, ContractId, Utxo};
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MemoryDriver {
+    pub(super) cache: Cache,
+}
+
+impl MemoryDriver {
+    pub fn new() -> Self {
+        info!("Initializing in-memory cache driver");
+        MemoryDriver::default()
+    }
+
+    fn map_contract_or_default<R>(
+        &self,
+        contract_id: ContractId,
+        predicate: impl FnOnce(&ContractCache) -> R,
+    ) -> Result<R, Error>
+    where
+        R: Sized + Default,
+    {
+        Ok(self
+            .cache
+            .descriptors
+            .get(&contract_id)
+            .map(predicate)
+            .unwrap_or_default())
+    }
+
+    fn with_contract<R>(
+        &mut self,
+        contract_id: ContractId,
+        predicate: impl FnOnce(&mut ContractCache) -> Result<R, Error>,
+    ) -> Result<R, Error>
+    where
+        R: Sized,
+    {
+        predicate(
+            self.cache
+                .descriptors
+                .entry(contract_id)
+                .or_insert(default!()),
+        )
+    }
+}
+
+impl Driver for MemoryDriver {
+    fn blockpos_to_txid(&self, height: u32, offset: u16) -> Option<Txid> {
+        self.cache.mine_info.get(&(height, offset)).copied()
+    }
+
+    fn unspent(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<rgb::ContractId, HashSet<Utxo>>, Error> {
+        self.map_contract_or_default(contract_id, |c| c.unspent.clone())
+    }
+
+    fn utxo(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeSet<OutPoint>, Error> {
+        self.map_contract_or_default(contract_id, |cache| cache.utxo.clone())
+    }
+
+    fn update(
+        &mut self,
+        contract_id: ContractId,
+        mine_info: BTreeMap<(u32, u16), Txid>,
+        updated_height: Option<u32>,
+        utxo: BTreeSet<OutPoint>,
+        unspent: BTreeMap<rgb::ContractId, Vec<Utxo>>,
+    ) -> Result<(), Error> {
+        self.cache.mine_info.extend(mine_info);
+        let cache = self
+            .cache
+            .descriptors
+            .entry(contract_id)
+            .or_insert(default!());
+        cache.unspent = unspent
+            .into_iter()
+            .map(|(asset_id, utxos)| {
+                (
+                    asset_id,
+                    utxos.into_iter().filter(|utxo| utxo.value > 0).collect(),
+                )
+            })
+            .collect();
+        cache.utxo = utxo;
+        if let Some(height) = updated_height {
+            self.cache.known_height = height;
+            cache.updated_height = height;
+        }
+        Ok(())
+    }
+
+    fn used_address_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
+        self.map_contract_or_default(contract_id, |cache| {
+            cache.used_address_derivations.clone()
+        })
+    }
+
+    fn used_change_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
+        self.map_contract_or_default(contract_id, |cache| {
+            cache.used_change_derivations.clone()
+        })
+    }
+
+    fn used_addresses(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<HashSet<Address>, Error> {
+        self.map_contract_or_default(contract_id, |cache| {
+            cache.used_addresses()
+        })
+    }
+
+    fn used_derivations(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Result<HashSet<UnhardenedIndex>, Error> {
+        self.map_contract_or_default(contract_id, |cache| {
+            cache
+                .derivations(branch)
+                .iter()
+                .map(|(_, derivation)| derivation)
+                .copied()
+                .collect()
+        })
+    }
+
+    fn next_unused_derivation(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Result<UnhardenedIndex, Error> {
+        self.map_contract_or_default(contract_id, |cache| {
+            cache
+                .derivations(branch)
+                .values()
+                .max()
+                .copied()
+                .and_then(UnhardenedIndex::checked_inc)
+                .unwrap_or_default()
+        })
+    }
+
+    fn use_address_derivation(
+        &mut self,
+        contract_id: ContractId,
+        branch: Branch,
+        address: Address,
+        path: UnhardenedIndex,
+    ) -> Result<bool, Error> {
+        self.with_contract(contract_id, |cache| {
+            let derivations = cache.derivations_mut(branch);
+            if derivations
+                .get(&address)
+                .map(|p| p != &path)
+                .unwrap_or(false)
+            {
+                Err(Error::WrongDerivation)
+            } else {
+                Ok(derivations.insert(address, path).is_none())
+            }
+        })
+    }
+
+    fn last_used_derivation(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Option<UnhardenedIndex> {
+        let cache = self.cache.descriptors.get(&contract_id)?;
+        Some(
+            cache
+                .derivations(branch)
+                .values()
+                .copied()
+                .max_by_key(|index| index.clone())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn forget_address(
+        &mut self,
+        contract_id: ContractId,
+        address: &Address,
+    ) -> Result<bool, Error> {
+        self.with_contract(contract_id, |cache| {
+            Ok(cache.forget_address(address))
+        })
+    }
+
+    fn address_derivation(
+        &self,
+        contract_id: ContractId,
+        address: &Address,
+    ) -> Option<(Branch, UnhardenedIndex)> {
+        self.cache
+            .descriptors
+            .get(&contract_id)
+            .and_then(|cache| cache.address_derivation(address))
+    }
+
+    fn transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.cache.tx_cache.get(txid).cloned()
+    }
+
+    fn cache_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        self.cache
+            .tx_cache
+            .extend(transactions.into_iter().map(|tx| (tx.txid(), tx)));
+        Ok(())
+    }
+
+    fn evict_transactions(&mut self) -> Result<usize, Error> {
+        let evicted = self.cache.unowned_transactions();
+        for txid in &evicted {
+            self.cache.tx_cache.remove(txid);
+        }
+        Ok(evicted.len())
+    }
+
+    fn block_hashes(&self) -> BTreeMap<u32, BlockHash> {
+        self.cache.block_hashes.clone()
+    }
+
+    fn record_block_hashes(
+        &mut self,
+        block_hashes: BTreeMap<u32, BlockHash>,
+    ) -> Result<(), Error> {
+        self.cache.block_hashes.extend(block_hashes);
+        Ok(())
+    }
+
+    fn prune_block_hashes(&mut self, height: u32) -> Result<(), Error> {
+        self.cache.block_hashes = self.cache.block_hashes.split_off(&height);
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: u32) -> Result<BTreeSet<Txid>, Error> {
+        Ok(self.cache.rollback(height))
+    }
+
+    fn updated_height(&self, contract_id: ContractId) -> Option<u32> {
+        self.cache
+            .descriptors
+            .get(&contract_id)
+            .map(|cache| cache.updated_height)
+    }
+
+    fn gap_limit(&self, contract_id: ContractId) -> Option<u8> {
+        self.cache.descriptors.get(&contract_id)?.gap_limit
+    }
+
+    fn set_gap_limit(
+        &mut self,
+        contract_id: ContractId,
+        gap_limit: u8,
+    ) -> Result<(), Error> {
+        self.with_contract(contract_id, |cache| {
+            cache.gap_limit = Some(gap_limit);
+            Ok(())
+        })
+    }
+
+    fn highest_index(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Option<UnhardenedIndex> {
+        self.cache
+            .descriptors
+            .get(&contract_id)?
+            .highest_indexes
+            .get(&branch)
+            .copied()
+    }
+
+    fn record_highest_indexes(
+        &mut self,
+        contract_id: ContractId,
+        indexes: BTreeMap<Branch, UnhardenedIndex>,
+    ) -> Result<(), Error> {
+        self.with_contract(contract_id, |cache| {
+            cache.record_highest_indexes(indexes);
+            Ok(())
+        })
+    }
+}
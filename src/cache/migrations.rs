@@ -0,0 +1,338 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Format versions of the cache data ([`super::model::Cache`]) and
+//! migrations between them.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io;
+
+use amplify::Slice32;
+use bitcoin::{Address, BlockHash, OutPoint, PublicKey, Transaction, Txid};
+use chrono::NaiveDateTime;
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::address::AddressCompat;
+use wallet::hd::UnhardenedIndex;
+
+use crate::migration::{Document, Migration, Schema};
+use crate::model::{Branch, ContractId};
+
+/// Cache format versions:
+/// - 0: unversioned data;
+/// - 1: format version is added, data layout is unchanged;
+/// - 2: hashes of the blocks mining cached transactions are added;
+/// - 3: derivation branches are added to UTXOs and addresses;
+/// - 4: gap limit and highest used derivation indexes are added to the
+///   contract data;
+/// - 5: unused block information is removed, since the blocks are tracked
+///   with their hashes.
+pub(crate) static SCHEMA: Schema = Schema {
+    name: "cache",
+    version: 5,
+    migrations: &[
+        Migration {
+            version: 1,
+            description: "format version is added to the cache data",
+            upgrade: v1_add_version,
+        },
+        Migration {
+            version: 2,
+            description: "empty set of block hashes is added",
+            upgrade: v2_add_block_hashes,
+        },
+        Migration {
+            version: 3,
+            description: "UTXOs are assigned to the receive derivation \
+                          branch; empty set of change addresses is added",
+            upgrade: v3_add_branches,
+        },
+        Migration {
+            version: 4,
+            description: "empty gap limit and set of highest derivation \
+                          indexes are added to each contract",
+            upgrade: v4_add_gap_limit,
+        },
+        Migration {
+            version: 5,
+            description: "unused block information is removed",
+            upgrade: v5_remove_block_info,
+        },
+    ],
+};
+
+/// Layout of `Utxo` data before format version 3
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, StrictDecode)]
+struct UtxoV2 {
+    value: u64,
+    height: u32,
+    offset: u16,
+    txid: Txid,
+    vout: u16,
+    derivation_index: UnhardenedIndex,
+    tweak: Option<(Slice32, PublicKey)>,
+    address: Option<AddressCompat>,
+}
+
+/// Layout of `Utxo` data in format version 3
+#[derive(
+    Clone, PartialEq, Eq, PartialOrd, Ord, Hash, StrictEncode, StrictDecode,
+)]
+struct UtxoV3 {
+    value: u64,
+    height: u32,
+    offset: u16,
+    txid: Txid,
+    vout: u16,
+    branch: Branch,
+    derivation_index: UnhardenedIndex,
+    tweak: Option<(Slice32, PublicKey)>,
+    address: Option<AddressCompat>,
+}
+
+/// Layout of `ContractCache` data before format version 3
+#[derive(StrictDecode)]
+struct ContractCacheV2 {
+    updated_height: u32,
+    used_address_derivations: BTreeMap<Address, UnhardenedIndex>,
+    utxo: BTreeSet<OutPoint>,
+    unspent: BTreeMap<rgb::ContractId, HashSet<UtxoV2>>,
+}
+
+/// Layout of `ContractCache` data in format version 3
+#[derive(StrictEncode, StrictDecode)]
+struct ContractCacheV3 {
+    updated_height: u32,
+    used_address_derivations: BTreeMap<Address, UnhardenedIndex>,
+    utxo: BTreeSet<OutPoint>,
+    unspent: BTreeMap<rgb::ContractId, HashSet<UtxoV3>>,
+    used_change_derivations: BTreeMap<Address, UnhardenedIndex>,
+}
+
+/// Layout of `Cache` data in format version 2
+#[derive(StrictDecode)]
+struct CacheV2 {
+    known_height: u32,
+    descriptors: BTreeMap<ContractId, ContractCacheV2>,
+    block_info: Vec<(BlockHash, NaiveDateTime)>,
+    mine_info: BTreeMap<(u32, u16), Txid>,
+    tx_cache: BTreeMap<Txid, Transaction>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+/// Layout of `Cache` data in format version 3
+#[derive(StrictEncode, StrictDecode)]
+struct CacheV3 {
+    known_height: u32,
+    descriptors: BTreeMap<ContractId, ContractCacheV3>,
+    block_info: Vec<(BlockHash, NaiveDateTime)>,
+    mine_info: BTreeMap<(u32, u16), Txid>,
+    tx_cache: BTreeMap<Txid, Transaction>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+/// `ContractCache` data of format version 3 upgraded to version 4. Gap limit
+/// and highest derivation indexes are the last fields of the contract cache,
+/// so the upgrade appends them to the encoded data.
+struct ContractCacheV4(ContractCacheV3);
+
+impl StrictEncode for ContractCacheV4 {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        let gap_limit = Option::<u8>::None;
+        let highest_indexes = BTreeMap::<Branch, UnhardenedIndex>::new();
+        Ok(self.0.strict_encode(&mut e)?
+            + gap_limit.strict_encode(&mut e)?
+            + highest_indexes.strict_encode(&mut e)?)
+    }
+}
+
+/// `Cache` data upgraded to format version 4
+#[derive(StrictEncode)]
+struct CacheV4 {
+    known_height: u32,
+    descriptors: BTreeMap<ContractId, ContractCacheV4>,
+    block_info: Vec<(BlockHash, NaiveDateTime)>,
+    mine_info: BTreeMap<(u32, u16), Txid>,
+    tx_cache: BTreeMap<Txid, Transaction>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+/// Layout of `ContractCache` data since format version 4
+#[derive(StrictEncode, StrictDecode)]
+struct ContractCacheV5 {
+    updated_height: u32,
+    used_address_derivations: BTreeMap<Address, UnhardenedIndex>,
+    utxo: BTreeSet<OutPoint>,
+    unspent: BTreeMap<rgb::ContractId, HashSet<UtxoV3>>,
+    used_change_derivations: BTreeMap<Address, UnhardenedIndex>,
+    gap_limit: Option<u8>,
+    highest_indexes: BTreeMap<Branch, UnhardenedIndex>,
+}
+
+/// Layout of `Cache` data in format version 4, as it is read back
+#[derive(StrictDecode)]
+struct CacheV4Data {
+    known_height: u32,
+    descriptors: BTreeMap<ContractId, ContractCacheV5>,
+    block_info: Vec<(BlockHash, NaiveDateTime)>,
+    mine_info: BTreeMap<(u32, u16), Txid>,
+    tx_cache: BTreeMap<Txid, Transaction>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+/// Layout of `Cache` data in format version 5
+#[derive(StrictEncode)]
+struct CacheV5 {
+    known_height: u32,
+    descriptors: BTreeMap<ContractId, ContractCacheV5>,
+    mine_info: BTreeMap<(u32, u16), Txid>,
+    tx_cache: BTreeMap<Txid, Transaction>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+fn v1_add_version(document: Document) -> Result<Document, String> {
+    Ok(document)
+}
+
+fn v2_add_block_hashes(document: Document) -> Result<Document, String> {
+    match document {
+        // Block hashes are the last field of the cache data, so they are
+        // appended to the encoded data
+        Document::Strict(mut data) => {
+            BTreeMap::<u32, BlockHash>::new()
+                .strict_encode(&mut data)
+                .map_err(|err| err.to_string())?;
+            Ok(Document::Strict(data))
+        }
+        // Missing block hashes are defaulted on deserialization
+        document @ Document::Structured(_) => Ok(document),
+    }
+}
+
+fn v3_add_branches(document: Document) -> Result<Document, String> {
+    match document {
+        // Before the branches were introduced all contracts were using a
+        // single index space, which is the receive branch
+        Document::Strict(data) => {
+            let old = CacheV2::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let descriptors = old
+                .descriptors
+                .into_iter()
+                .map(|(id, cache)| {
+                    let unspent = cache
+                        .unspent
+                        .into_iter()
+                        .map(|(asset_id, utxos)| {
+                            let utxos = utxos
+                                .into_iter()
+                                .map(|utxo| UtxoV3 {
+                                    value: utxo.value,
+                                    height: utxo.height,
+                                    offset: utxo.offset,
+                                    txid: utxo.txid,
+                                    vout: utxo.vout,
+                                    branch: Branch::External,
+                                    derivation_index: utxo.derivation_index,
+                                    tweak: utxo.tweak,
+                                    address: utxo.address,
+                                })
+                                .collect();
+                            (asset_id, utxos)
+                        })
+                        .collect();
+                    let cache = ContractCacheV3 {
+                        updated_height: cache.updated_height,
+                        used_address_derivations: cache
+                            .used_address_derivations,
+                        utxo: cache.utxo,
+                        unspent,
+                        used_change_derivations: none!(),
+                    };
+                    (id, cache)
+                })
+                .collect();
+            let cache = CacheV3 {
+                known_height: old.known_height,
+                descriptors,
+                block_info: old.block_info,
+                mine_info: old.mine_info,
+                tx_cache: old.tx_cache,
+                block_hashes: old.block_hashes,
+            };
+            cache
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        // Missing branches are defaulted on deserialization
+        document @ Document::Structured(_) => Ok(document),
+    }
+}
+
+fn v4_add_gap_limit(document: Document) -> Result<Document, String> {
+    match document {
+        // Highest derivation indexes are left empty, so the first
+        // synchronization after the upgrade scans addresses from the start
+        Document::Strict(data) => {
+            let old = CacheV3::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let cache = CacheV4 {
+                known_height: old.known_height,
+                descriptors: old
+                    .descriptors
+                    .into_iter()
+                    .map(|(id, cache)| (id, ContractCacheV4(cache)))
+                    .collect(),
+                block_info: old.block_info,
+                mine_info: old.mine_info,
+                tx_cache: old.tx_cache,
+                block_hashes: old.block_hashes,
+            };
+            cache
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        // Missing gap limit and indexes are defaulted on deserialization
+        document @ Document::Structured(_) => Ok(document),
+    }
+}
+
+fn v5_remove_block_info(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = CacheV4Data::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let cache = CacheV5 {
+                known_height: old.known_height,
+                descriptors: old.descriptors,
+                mine_info: old.mine_info,
+                tx_cache: old.tx_cache,
+                block_hashes: old.block_hashes,
+            };
+            cache
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        Document::Structured(mut value) => {
+            if let Some(map) = value.as_mapping_mut() {
+                map.remove(&"block_info".into());
+            }
+            Ok(Document::Structured(value))
+        }
+    }
+}
@@ -15,9 +15,290 @@
 
 mod driver;
 mod error;
+#[cfg(feature = "compact_filters")]
+mod filters;
+mod migrations;
+pub(crate) mod model;
 
 pub use driver::Driver;
 pub use error::Error;
+#[cfg(feature = "compact_filters")]
+pub use filters::{FilterCache, FilterStore, HeaderFile, MatchedTx};
 
 mod file;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
 pub use file::{FileConfig, FileDriver};
+pub use memory::MemoryDriver;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteDriver;
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, OutPoint, Txid};
+    use commit_verify::CommitVerify;
+    use microservices::FileFormat;
+    use strict_encoding::{StrictDecode, StrictEncode};
+    use wallet::hd::UnhardenedIndex;
+
+    use super::*;
+    use crate::model::{Branch, ContractId};
+    use crate::test_utils::{address, temp_dir, transaction, utxo};
+
+    fn address_derivations(cache: &mut dyn Driver) {
+        let id = ContractId::commit(b"contract");
+        let zero = UnhardenedIndex::zero();
+        let one = UnhardenedIndex::from(1u8);
+        assert_eq!(cache.last_used_derivation(id, Branch::External), None);
+        assert_eq!(
+            cache.next_unused_derivation(id, Branch::External),
+            Ok(zero)
+        );
+
+        assert_eq!(
+            cache.use_address_derivation(
+                id,
+                Branch::External,
+                address(1),
+                zero
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            cache.use_address_derivation(
+                id,
+                Branch::External,
+                address(1),
+                zero
+            ),
+            Ok(false)
+        );
+        assert_eq!(
+            cache.use_address_derivation(id, Branch::External, address(1), one),
+            Err(Error::WrongDerivation)
+        );
+        cache
+            .use_address_derivation(id, Branch::Internal, address(2), zero)
+            .unwrap();
+
+        assert_eq!(
+            cache.used_address_derivations(id).unwrap(),
+            bmap! { address(1) => zero }
+        );
+        assert_eq!(
+            cache.used_change_derivations(id).unwrap(),
+            bmap! { address(2) => zero }
+        );
+        assert_eq!(
+            cache.used_addresses(id).unwrap(),
+            set![address(1), address(2)]
+        );
+        assert_eq!(
+            cache.used_derivations(id, Branch::Internal).unwrap(),
+            set![zero]
+        );
+        assert_eq!(cache.next_unused_derivation(id, Branch::External), Ok(one));
+        assert_eq!(cache.next_unused_derivation(id, Branch::Internal), Ok(one));
+        assert_eq!(
+            cache.address_derivation(id, &address(2)),
+            Some((Branch::Internal, zero))
+        );
+
+        assert_eq!(cache.forget_address(id, &address(2)), Ok(true));
+        assert_eq!(cache.forget_address(id, &address(2)), Ok(false));
+        assert_eq!(cache.address_derivation(id, &address(2)), None);
+        assert_eq!(
+            cache.next_unused_derivation(id, Branch::Internal),
+            Ok(zero)
+        );
+        assert_eq!(
+            cache.last_used_derivation(id, Branch::External),
+            Some(zero)
+        );
+    }
+
+    fn gap_limit_and_highest_indexes(cache: &mut dyn Driver) {
+        let id = ContractId::commit(b"contract");
+        assert_eq!(cache.gap_limit(id), None);
+        cache.set_gap_limit(id, 7).unwrap();
+        assert_eq!(cache.gap_limit(id), Some(7));
+
+        let five = UnhardenedIndex::from(5u8);
+        let three = UnhardenedIndex::from(3u8);
+        cache
+            .record_highest_indexes(id, bmap! { Branch::External => five })
+            .unwrap();
+        cache
+            .record_highest_indexes(
+                id,
+                bmap! { Branch::External => three, Branch::Internal => three },
+            )
+            .unwrap();
+        assert_eq!(cache.highest_index(id, Branch::External), Some(five));
+        assert_eq!(cache.highest_index(id, Branch::Internal), Some(three));
+    }
+
+    fn bitcoin_only_unspent(cache: &mut dyn Driver) {
+        let id = ContractId::commit(b"contract");
+        let asset_id = rgb::ContractId::strict_deserialize(&[1u8; 32])
+            .expect("any 32 bytes are contract id");
+        let plain = utxo(Txid::hash(b"plain"), 1000, 10);
+        let colored = utxo(Txid::hash(b"colored"), 2000, 10);
+        let empty = utxo(Txid::hash(b"empty"), 0, 10);
+        cache
+            .update(
+                id,
+                bmap! {},
+                Some(10),
+                bset![plain.outpoint(), colored.outpoint()],
+                bmap! {
+                    rgb::ContractId::default() => vec![
+                        plain.clone(),
+                        colored.clone(),
+                        empty,
+                    ],
+                    asset_id => vec![utxo(colored.txid, 100, 10)]
+                },
+            )
+            .unwrap();
+
+        let unspent = cache.unspent(id).unwrap();
+        assert_eq!(
+            unspent[&rgb::ContractId::default()],
+            set![plain.clone(), colored]
+        );
+        assert_eq!(cache.unspent_bitcoin_only(id).unwrap(), set![plain]);
+        assert_eq!(cache.updated_height(id), Some(10));
+    }
+
+    fn rollback_and_eviction(cache: &mut dyn Driver) {
+        let id = ContractId::commit(b"contract");
+        let (old, orphaned) = (transaction(1), transaction(2));
+        let (old_utxo, orphaned_utxo) =
+            (utxo(old.txid(), 1000, 10), utxo(orphaned.txid(), 1000, 12));
+        cache
+            .cache_transactions(vec![old.clone(), orphaned.clone()])
+            .unwrap();
+        cache
+            .update(
+                id,
+                bmap! { (10, 1) => old.txid(), (12, 1) => orphaned.txid() },
+                Some(12),
+                bset![old_utxo.outpoint(), orphaned_utxo.outpoint()],
+                bmap! {
+                    rgb::ContractId::default() => vec![
+                        old_utxo.clone(),
+                        orphaned_utxo,
+                    ]
+                },
+            )
+            .unwrap();
+        cache
+            .record_block_hashes(bmap! {
+                10 => BlockHash::hash(b"10"),
+                12 => BlockHash::hash(b"12")
+            })
+            .unwrap();
+        assert_eq!(cache.blockpos_to_txid(12, 1), Some(orphaned.txid()));
+
+        assert_eq!(cache.rollback(11), Ok(bset![orphaned.txid()]));
+        assert_eq!(cache.blockpos_to_txid(12, 1), None);
+        assert_eq!(cache.blockpos_to_txid(10, 1), Some(old.txid()));
+        assert_eq!(
+            cache.block_hashes(),
+            bmap! { 10 => BlockHash::hash(b"10") }
+        );
+        assert_eq!(cache.updated_height(id), Some(10));
+        assert_eq!(cache.utxo(id), Ok(bset![OutPoint::new(old.txid(), 0)]));
+        assert_eq!(
+            cache.unspent(id).unwrap()[&rgb::ContractId::default()],
+            set![old_utxo]
+        );
+
+        assert_eq!(cache.evict_transactions(), Ok(1));
+        assert_eq!(cache.transaction(&orphaned.txid()), None);
+        assert_eq!(cache.transaction(&old.txid()), Some(old));
+    }
+
+    fn block_hash_pruning(cache: &mut dyn Driver) {
+        cache
+            .record_block_hashes(bmap! {
+                10 => BlockHash::hash(b"10"),
+                12 => BlockHash::hash(b"12")
+            })
+            .unwrap();
+        assert_eq!(cache.prune_block_hashes(11), Ok(()));
+        assert_eq!(
+            cache.block_hashes(),
+            bmap! { 12 => BlockHash::hash(b"12") }
+        );
+    }
+
+    /// Runs the same driver contract checks against each cache driver; each
+    /// check uses its own empty cache
+    fn check_driver(cache: impl Fn() -> Box<dyn Driver>) {
+        address_derivations(cache().as_mut());
+        gap_limit_and_highest_indexes(cache().as_mut());
+        bitcoin_only_unspent(cache().as_mut());
+        rollback_and_eviction(cache().as_mut());
+        block_hash_pruning(cache().as_mut());
+    }
+
+    #[test]
+    fn unspent_branches_are_kept() {
+        let mut change = utxo(Txid::hash(b"change"), 1000, 10);
+        change.branch = Branch::Internal;
+        let cache = model::ContractCache {
+            unspent: bmap! { rgb::ContractId::default() => set![change] },
+            ..default!()
+        };
+        let data = cache.strict_serialize().unwrap();
+        assert_eq!(
+            model::ContractCache::strict_deserialize(data).unwrap(),
+            cache
+        );
+    }
+
+    #[test]
+    fn memory_driver() {
+        check_driver(|| Box::new(MemoryDriver::new()));
+    }
+
+    #[test]
+    fn file_driver() {
+        let dir = temp_dir("cache-file");
+        let no = std::cell::Cell::new(0);
+        check_driver(|| {
+            no.set(no.get() + 1);
+            Box::new(
+                FileDriver::with(FileConfig {
+                    location: dir
+                        .join(no.get().to_string())
+                        .to_string_lossy()
+                        .to_string(),
+                    format: FileFormat::StrictEncode,
+                })
+                .unwrap(),
+            )
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_driver() {
+        let dir = temp_dir("cache-sqlite");
+        let no = std::cell::Cell::new(0);
+        check_driver(|| {
+            no.set(no.get() + 1);
+            Box::new(
+                SqliteDriver::with(dir.join(format!("cache{}.db", no.get())))
+                    .unwrap(),
+            )
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
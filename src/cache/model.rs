@@ -0,0 +1,300 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use serde_with::DisplayFromStr;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io;
+
+use amplify::Slice32;
+use bitcoin::{Address, BlockHash, OutPoint, PublicKey, Transaction, Txid};
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::address::AddressCompat;
+use wallet::hd::UnhardenedIndex;
+
+use crate::model::{Branch, ContractId, Utxo};
+
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Getters,
+    Clone,
+    PartialEq,
+    Debug,
+    Default,
+    StrictEncode,
+    StrictDecode,
+)]
+pub(crate) struct Cache {
+    pub known_height: u32,
+
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    pub descriptors: BTreeMap<ContractId, ContractCache>,
+
+    /// Mapping transaction id to the block height and block offset
+    #[serde_as(as = "BTreeMap<(_, _), DisplayFromStr>")]
+    pub mine_info: BTreeMap<(u32, u16), Txid>,
+
+    /// Transactions with outputs belonging to the wallet contracts
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    pub tx_cache: BTreeMap<Txid, Transaction>,
+
+    /// Hashes of the blocks mining transactions from `mine_info`, used to
+    /// detect chain reorganizations
+    #[serde(default)]
+    #[serde_as(as = "BTreeMap<_, DisplayFromStr>")]
+    pub block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+impl Cache {
+    /// Returns ids of cached transactions which have no outputs in the UTXO
+    /// set of any of the contracts
+    pub fn unowned_transactions(&self) -> Vec<Txid> {
+        let owned = self
+            .descriptors
+            .values()
+            .flat_map(|cache| cache.utxo.iter().map(|outpoint| outpoint.txid))
+            .collect::<BTreeSet<_>>();
+        self.tx_cache
+            .keys()
+            .filter(|txid| !owned.contains(txid))
+            .copied()
+            .collect()
+    }
+
+    /// Removes information about the blocks starting from the given height
+    /// and about transactions and UTXOs mined in them, returning ids of the
+    /// transactions which are not mined anymore
+    pub fn rollback(&mut self, height: u32) -> BTreeSet<Txid> {
+        let orphaned = self
+            .mine_info
+            .split_off(&(height, 0))
+            .into_iter()
+            .map(|(_, txid)| txid)
+            .collect::<BTreeSet<_>>();
+        self.block_hashes.split_off(&height);
+        let last_valid = height.saturating_sub(1);
+        for cache in self.descriptors.values_mut() {
+            cache
+                .utxo
+                .retain(|outpoint| !orphaned.contains(&outpoint.txid));
+            for utxos in cache.unspent.values_mut() {
+                utxos.retain(|utxo| {
+                    utxo.height < height && !orphaned.contains(&utxo.txid)
+                });
+            }
+            cache.updated_height = cache.updated_height.min(last_valid);
+        }
+        self.known_height = self.known_height.min(last_valid);
+        orphaned
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub(crate) struct ContractCache {
+    /// Chain height at the last synchronization of the contract
+    pub updated_height: u32,
+
+    /// Addresses from the receive branch given to payers
+    pub used_address_derivations: BTreeMap<Address, UnhardenedIndex>,
+
+    #[serde_as(as = "BTreeSet<DisplayFromStr>")]
+    pub utxo: BTreeSet<OutPoint>,
+
+    #[serde_as(as = "BTreeMap<DisplayFromStr, HashSet<_>>")]
+    pub unspent: BTreeMap<rgb::ContractId, HashSet<Utxo>>,
+
+    /// Addresses from the change branch used in the change outputs
+    #[serde(default)]
+    pub used_change_derivations: BTreeMap<Address, UnhardenedIndex>,
+
+    /// Gap limit for the address lookup during synchronization
+    #[serde(default)]
+    pub gap_limit: Option<u8>,
+
+    /// Highest derivation index of each branch where the synchronization
+    /// has found funds
+    #[serde(default)]
+    pub highest_indexes: BTreeMap<Branch, UnhardenedIndex>,
+}
+
+impl ContractCache {
+    pub fn derivations(
+        &self,
+        branch: Branch,
+    ) -> &BTreeMap<Address, UnhardenedIndex> {
+        match branch {
+            Branch::External => &self.used_address_derivations,
+            Branch::Internal => &self.used_change_derivations,
+        }
+    }
+
+    pub fn derivations_mut(
+        &mut self,
+        branch: Branch,
+    ) -> &mut BTreeMap<Address, UnhardenedIndex> {
+        match branch {
+            Branch::External => &mut self.used_address_derivations,
+            Branch::Internal => &mut self.used_change_derivations,
+        }
+    }
+
+    /// Returns addresses used in all derivation branches
+    pub fn used_addresses(&self) -> HashSet<Address> {
+        self.used_address_derivations
+            .keys()
+            .chain(self.used_change_derivations.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns derivation branch and index of the used address, searching
+    /// all derivation branches
+    pub fn address_derivation(
+        &self,
+        address: &Address,
+    ) -> Option<(Branch, UnhardenedIndex)> {
+        [Branch::External, Branch::Internal]
+            .iter()
+            .find_map(|branch| {
+                self.derivations(*branch)
+                    .get(address)
+                    .map(|index| (*branch, *index))
+            })
+    }
+
+    /// Removes the address from any of the derivation branches, returning
+    /// whether it was known
+    pub fn forget_address(&mut self, address: &Address) -> bool {
+        let external = self.used_address_derivations.remove(address);
+        let internal = self.used_change_derivations.remove(address);
+        external.is_some() || internal.is_some()
+    }
+
+    /// Records derivation indexes where funds were found, keeping the
+    /// highest index for each branch
+    pub fn record_highest_indexes(
+        &mut self,
+        indexes: BTreeMap<Branch, UnhardenedIndex>,
+    ) {
+        for (branch, index) in indexes {
+            let highest = self.highest_indexes.entry(branch).or_insert(index);
+            if *highest < index {
+                *highest = index;
+            }
+        }
+    }
+}
+
+/// Cache layout of [`Utxo`] data, which unlike the strict encoding of `Utxo`
+/// used by RPC keeps the derivation branch of the output
+#[derive(
+    Clone, PartialEq, Eq, PartialOrd, Ord, Hash, StrictEncode, StrictDecode,
+)]
+pub(crate) struct UtxoRecord {
+    value: u64,
+    height: u32,
+    offset: u16,
+    txid: Txid,
+    vout: u16,
+    branch: Branch,
+    derivation_index: UnhardenedIndex,
+    tweak: Option<(Slice32, PublicKey)>,
+    address: Option<AddressCompat>,
+}
+
+impl From<Utxo> for UtxoRecord {
+    fn from(utxo: Utxo) -> Self {
+        UtxoRecord {
+            value: utxo.value,
+            height: utxo.height,
+            offset: utxo.offset,
+            txid: utxo.txid,
+            vout: utxo.vout,
+            branch: utxo.branch,
+            derivation_index: utxo.derivation_index,
+            tweak: utxo.tweak,
+            address: utxo.address,
+        }
+    }
+}
+
+impl From<UtxoRecord> for Utxo {
+    fn from(record: UtxoRecord) -> Self {
+        Utxo {
+            value: record.value,
+            height: record.height,
+            offset: record.offset,
+            txid: record.txid,
+            vout: record.vout,
+            branch: record.branch,
+            derivation_index: record.derivation_index,
+            tweak: record.tweak,
+            address: record.address,
+        }
+    }
+}
+
+impl StrictEncode for ContractCache {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        let unspent = self
+            .unspent
+            .iter()
+            .map(|(asset_id, utxos)| {
+                let records = utxos
+                    .iter()
+                    .copied()
+                    .map(UtxoRecord::from)
+                    .collect::<HashSet<_>>();
+                (*asset_id, records)
+            })
+            .collect::<BTreeMap<_, _>>();
+        Ok(self.updated_height.strict_encode(&mut e)?
+            + self.used_address_derivations.strict_encode(&mut e)?
+            + self.utxo.strict_encode(&mut e)?
+            + unspent.strict_encode(&mut e)?
+            + self.used_change_derivations.strict_encode(&mut e)?
+            + self.gap_limit.strict_encode(&mut e)?
+            + self.highest_indexes.strict_encode(&mut e)?)
+    }
+}
+
+impl StrictDecode for ContractCache {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        let updated_height = StrictDecode::strict_decode(&mut d)?;
+        let used_address_derivations = StrictDecode::strict_decode(&mut d)?;
+        let utxo = StrictDecode::strict_decode(&mut d)?;
+        let unspent: BTreeMap<rgb::ContractId, HashSet<UtxoRecord>> =
+            StrictDecode::strict_decode(&mut d)?;
+        Ok(ContractCache {
+            updated_height,
+            used_address_derivations,
+            utxo,
+            unspent: unspent
+                .into_iter()
+                .map(|(asset_id, records)| {
+                    (asset_id, records.into_iter().map(Utxo::from).collect())
+                })
+                .collect(),
+            used_change_derivations: StrictDecode::strict_decode(&mut d)?,
+            gap_limit: StrictDecode::strict_decode(&mut d)?,
+            highest_indexes: StrictDecode::strict_decode(&mut d)?,
+        })
+    }
+}
@@ -0,0 +1,941 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! SQLite cache driver.
+//!
+//! Address derivations, UTXOs, mined transaction positions with their block
+//! hashes and cached transactions are kept in separate tables and updated row
+//! by row. Cached data are mirrored in memory on load, so read operations do
+//! not touch the database.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bitcoin::{Address, BlockHash, OutPoint, Transaction, Txid};
+use rusqlite::{params, Connection};
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::hd::{ChildIndex, UnhardenedIndex};
+
+use super::model::{Cache, ContractCache, UtxoRecord};
+use super::{Driver, Error};
+use crate::migration::{SqlMigration, SqlSchema};
+use crate::model::{Branch, ContractId, Utxo};
+
+const SCHEMA_V1: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY NOT NULL,
+    value INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS mine_info (
+    height INTEGER NOT NULL,
+    offset INTEGER NOT NULL,
+    txid TEXT NOT NULL,
+    PRIMARY KEY (height, offset)
+);
+
+CREATE TABLE IF NOT EXISTS contract_cache (
+    contract_id TEXT PRIMARY KEY NOT NULL,
+    updated_height INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS address_derivations (
+    contract_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    derivation BLOB NOT NULL,
+    PRIMARY KEY (contract_id, address)
+);
+
+CREATE TABLE IF NOT EXISTS utxo (
+    contract_id TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    PRIMARY KEY (contract_id, txid, vout)
+);
+
+CREATE TABLE IF NOT EXISTS unspent (
+    contract_id TEXT NOT NULL,
+    asset_id TEXT NOT NULL,
+    utxo BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS unspent_by_contract ON unspent (contract_id);
+";
+
+const SCHEMA_V2: &str = "
+CREATE TABLE IF NOT EXISTS tx_cache (
+    txid TEXT PRIMARY KEY NOT NULL,
+    tx BLOB NOT NULL
+);
+";
+
+const SCHEMA_V3: &str = "
+CREATE TABLE IF NOT EXISTS block_hashes (
+    height INTEGER PRIMARY KEY NOT NULL,
+    hash TEXT NOT NULL
+);
+";
+
+const SCHEMA_V4: &str = "
+ALTER TABLE address_derivations ADD COLUMN branch INTEGER NOT NULL DEFAULT 0;
+DELETE FROM utxo;
+DELETE FROM unspent;
+";
+
+const SCHEMA_V5: &str = "
+ALTER TABLE contract_cache ADD COLUMN gap_limit INTEGER;
+
+CREATE TABLE IF NOT EXISTS highest_indexes (
+    contract_id TEXT NOT NULL,
+    branch INTEGER NOT NULL,
+    derivation BLOB NOT NULL,
+    PRIMARY KEY (contract_id, branch)
+);
+";
+
+static SCHEMA: SqlSchema = SqlSchema {
+    name: "cache",
+    migrations: &[
+        SqlMigration {
+            description:
+                "tables for mined transactions, address derivations and UTXOs",
+            sql: SCHEMA_V1,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "table for cached transactions",
+            sql: SCHEMA_V2,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "table for hashes of the blocks mining transactions",
+            sql: SCHEMA_V3,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "derivation branches are added to the addresses; \
+                          cached UTXOs are removed and will be restored with \
+                          their branches by the next synchronization",
+            sql: SCHEMA_V4,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "gap limit of the contracts and table for highest \
+                          derivation indexes with funds",
+            sql: SCHEMA_V5,
+            upgrade: None,
+        },
+    ],
+};
+
+const KNOWN_HEIGHT_KEY: &str = "known_height";
+
+pub struct SqliteDriver {
+    db: Connection,
+    filename: PathBuf,
+    cache: Cache,
+}
+
+impl SqliteDriver {
+    pub fn with(filename: PathBuf) -> Result<Self, Error> {
+        info!("Initializing SQLite driver for cache in {:?}", filename);
+        if let Some(dir) = filename.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut db = Connection::open(&filename)?;
+        SCHEMA.apply(&mut db, &filename)?;
+        let mut me = SqliteDriver {
+            db,
+            filename,
+            cache: none!(),
+        };
+        me.load()?;
+        Ok(me)
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        debug!("Loading cache from `{:?}`", self.filename);
+        let mut cache = Cache::default();
+
+        let mut stmt = self.db.prepare("SELECT key, value FROM meta")?;
+        let meta = stmt
+            .query_map(params![], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, value) in meta {
+            if key == KNOWN_HEIGHT_KEY {
+                cache.known_height = value;
+            }
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT height, offset, txid FROM mine_info")?;
+        let mine_info = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, u16>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (height, offset, txid) in mine_info {
+            cache.mine_info.insert((height, offset), parse_txid(&txid)?);
+        }
+
+        let mut stmt = self.db.prepare(
+            "SELECT contract_id, updated_height, gap_limit \
+             FROM contract_cache",
+        )?;
+        let contracts = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, Option<u8>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (contract_id, updated_height, gap_limit) in contracts {
+            let contract_cache = cache
+                .descriptors
+                .entry(parse_id(&contract_id)?)
+                .or_insert(default!());
+            contract_cache.updated_height = updated_height;
+            contract_cache.gap_limit = gap_limit;
+        }
+
+        let mut stmt = self.db.prepare(
+            "SELECT contract_id, branch, derivation FROM highest_indexes",
+        )?;
+        let indexes = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u8>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (contract_id, branch, derivation) in indexes {
+            cache
+                .descriptors
+                .entry(parse_id(&contract_id)?)
+                .or_insert(default!())
+                .highest_indexes
+                .insert(
+                    parse_branch(branch)?,
+                    UnhardenedIndex::strict_deserialize(derivation)?,
+                );
+        }
+
+        let mut stmt = self.db.prepare(
+            "SELECT contract_id, address, derivation, branch \
+             FROM address_derivations",
+        )?;
+        let derivations = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    row.get::<_, u8>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (contract_id, address, derivation, branch) in derivations {
+            let address = Address::from_str(&address).map_err(|err| {
+                Error::Sqlite(format!("broken address {}: {}", address, err))
+            })?;
+            cache
+                .descriptors
+                .entry(parse_id(&contract_id)?)
+                .or_insert(default!())
+                .derivations_mut(parse_branch(branch)?)
+                .insert(
+                    address,
+                    UnhardenedIndex::strict_deserialize(derivation)?,
+                );
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT contract_id, txid, vout FROM utxo")?;
+        let utxo = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u32>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (contract_id, txid, vout) in utxo {
+            cache
+                .descriptors
+                .entry(parse_id(&contract_id)?)
+                .or_insert(default!())
+                .utxo
+                .insert(OutPoint::new(parse_txid(&txid)?, vout));
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT contract_id, asset_id, utxo FROM unspent")?;
+        let unspent = stmt
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (contract_id, asset_id, utxo) in unspent {
+            let asset_id =
+                rgb::ContractId::from_str(&asset_id).map_err(|err| {
+                    Error::Sqlite(format!(
+                        "broken asset id {}: {}",
+                        asset_id, err
+                    ))
+                })?;
+            cache
+                .descriptors
+                .entry(parse_id(&contract_id)?)
+                .or_insert(default!())
+                .unspent
+                .entry(asset_id)
+                .or_insert(default!())
+                .insert(UtxoRecord::strict_deserialize(utxo)?.into());
+        }
+
+        let mut stmt = self.db.prepare("SELECT tx FROM tx_cache")?;
+        let transactions = stmt
+            .query_map(params![], |row| row.get::<_, Vec<u8>>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for tx in transactions {
+            let tx = Transaction::strict_deserialize(tx)?;
+            cache.tx_cache.insert(tx.txid(), tx);
+        }
+
+        let mut stmt =
+            self.db.prepare("SELECT height, hash FROM block_hashes")?;
+        let block_hashes = stmt
+            .query_map(params![], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (height, hash) in block_hashes {
+            let hash = BlockHash::from_str(&hash).map_err(|err| {
+                Error::Sqlite(format!("broken block hash {}: {}", hash, err))
+            })?;
+            cache.block_hashes.insert(height, hash);
+        }
+
+        self.cache = cache;
+        trace!("Cache loaded from SQLite database");
+        Ok(())
+    }
+
+    fn contract_cache(
+        &self,
+        contract_id: ContractId,
+    ) -> Option<&ContractCache> {
+        self.cache.descriptors.get(&contract_id)
+    }
+}
+
+fn parse_id(contract_id: &str) -> Result<ContractId, Error> {
+    ContractId::from_str(contract_id).map_err(|err| {
+        Error::Sqlite(format!("broken contract id {}: {}", contract_id, err))
+    })
+}
+
+fn parse_branch(branch: u8) -> Result<Branch, Error> {
+    match branch {
+        0 => Ok(Branch::External),
+        1 => Ok(Branch::Internal),
+        unknown => Err(Error::Sqlite(format!(
+            "unknown derivation branch {}",
+            unknown
+        ))),
+    }
+}
+
+fn branch_code(branch: Branch) -> u8 {
+    match branch {
+        Branch::External => 0,
+        Branch::Internal => 1,
+    }
+}
+
+fn parse_txid(txid: &str) -> Result<Txid, Error> {
+    Txid::from_str(txid).map_err(|err| {
+        Error::Sqlite(format!("broken transaction id {}: {}", txid, err))
+    })
+}
+
+impl Driver for SqliteDriver {
+    fn blockpos_to_txid(&self, height: u32, offset: u16) -> Option<Txid> {
+        self.cache.mine_info.get(&(height, offset)).copied()
+    }
+
+    fn unspent(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<rgb::ContractId, HashSet<Utxo>>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(|cache| cache.unspent.clone())
+            .unwrap_or_default())
+    }
+
+    fn utxo(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeSet<OutPoint>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(|cache| cache.utxo.clone())
+            .unwrap_or_default())
+    }
+
+    fn update(
+        &mut self,
+        contract_id: ContractId,
+        mine_info: BTreeMap<(u32, u16), Txid>,
+        updated_height: Option<u32>,
+        utxo: BTreeSet<OutPoint>,
+        unspent: BTreeMap<rgb::ContractId, Vec<Utxo>>,
+    ) -> Result<(), Error> {
+        let unspent = unspent
+            .into_iter()
+            .map(|(asset_id, utxos)| {
+                (
+                    asset_id,
+                    utxos
+                        .into_iter()
+                        .filter(|utxo| utxo.value > 0)
+                        .collect::<HashSet<_>>(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        let id = contract_id.to_string();
+        let contract_height = updated_height.unwrap_or_else(|| {
+            self.contract_cache(contract_id)
+                .map(|cache| cache.updated_height)
+                .unwrap_or_default()
+        });
+
+        let tx = self.db.transaction()?;
+        for ((height, offset), txid) in &mine_info {
+            tx.execute(
+                "INSERT OR REPLACE INTO mine_info (height, offset, txid) \
+                 VALUES (?1, ?2, ?3)",
+                params![height, offset, txid.to_string()],
+            )?;
+        }
+        tx.execute("DELETE FROM utxo WHERE contract_id = ?1", params![id])?;
+        for outpoint in &utxo {
+            tx.execute(
+                "INSERT INTO utxo (contract_id, txid, vout) \
+                 VALUES (?1, ?2, ?3)",
+                params![id, outpoint.txid.to_string(), outpoint.vout],
+            )?;
+        }
+        tx.execute("DELETE FROM unspent WHERE contract_id = ?1", params![id])?;
+        for (asset_id, utxos) in &unspent {
+            for utxo in utxos {
+                tx.execute(
+                    "INSERT INTO unspent (contract_id, asset_id, utxo) \
+                     VALUES (?1, ?2, ?3)",
+                    params![
+                        id,
+                        asset_id.to_string(),
+                        UtxoRecord::from(*utxo).strict_serialize()?
+                    ],
+                )?;
+            }
+        }
+        if let Some(height) = updated_height {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+                params![KNOWN_HEIGHT_KEY, height],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO contract_cache (contract_id, updated_height) \
+             VALUES (?1, ?2) ON CONFLICT (contract_id) \
+             DO UPDATE SET updated_height = excluded.updated_height",
+            params![id, contract_height],
+        )?;
+        tx.commit()?;
+
+        self.cache.mine_info.extend(mine_info);
+        let cache = self
+            .cache
+            .descriptors
+            .entry(contract_id)
+            .or_insert(default!());
+        cache.unspent = unspent;
+        cache.utxo = utxo;
+        if let Some(height) = updated_height {
+            self.cache.known_height = height;
+            cache.updated_height = height;
+        }
+        Ok(())
+    }
+
+    fn used_address_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(|cache| cache.used_address_derivations.clone())
+            .unwrap_or_default())
+    }
+
+    fn used_change_derivations(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeMap<Address, UnhardenedIndex>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(|cache| cache.used_change_derivations.clone())
+            .unwrap_or_default())
+    }
+
+    fn used_addresses(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<HashSet<Address>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(ContractCache::used_addresses)
+            .unwrap_or_default())
+    }
+
+    fn used_derivations(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Result<HashSet<UnhardenedIndex>, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .map(|cache| cache.derivations(branch).values().copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn next_unused_derivation(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Result<UnhardenedIndex, Error> {
+        Ok(self
+            .contract_cache(contract_id)
+            .and_then(|cache| cache.derivations(branch).values().max().copied())
+            .and_then(UnhardenedIndex::checked_inc)
+            .unwrap_or_default())
+    }
+
+    fn use_address_derivation(
+        &mut self,
+        contract_id: ContractId,
+        branch: Branch,
+        address: Address,
+        path: UnhardenedIndex,
+    ) -> Result<bool, Error> {
+        match self
+            .contract_cache(contract_id)
+            .and_then(|cache| cache.derivations(branch).get(&address))
+        {
+            Some(p) if p != &path => return Err(Error::WrongDerivation),
+            Some(_) => return Ok(false),
+            None => {}
+        }
+        self.db.execute(
+            "INSERT INTO address_derivations \
+             (contract_id, address, derivation, branch) \
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                contract_id.to_string(),
+                address.to_string(),
+                path.strict_serialize()?,
+                branch_code(branch)
+            ],
+        )?;
+        self.cache
+            .descriptors
+            .entry(contract_id)
+            .or_insert(default!())
+            .derivations_mut(branch)
+            .insert(address, path);
+        Ok(true)
+    }
+
+    fn last_used_derivation(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Option<UnhardenedIndex> {
+        let cache = self.contract_cache(contract_id)?;
+        Some(
+            cache
+                .derivations(branch)
+                .values()
+                .copied()
+                .max()
+                .unwrap_or_default(),
+        )
+    }
+
+    fn forget_address(
+        &mut self,
+        contract_id: ContractId,
+        address: &Address,
+    ) -> Result<bool, Error> {
+        self.db.execute(
+            "DELETE FROM address_derivations \
+             WHERE contract_id = ?1 AND address = ?2",
+            params![contract_id.to_string(), address.to_string()],
+        )?;
+        Ok(self
+            .cache
+            .descriptors
+            .get_mut(&contract_id)
+            .map(|cache| cache.forget_address(address))
+            .unwrap_or_default())
+    }
+
+    fn address_derivation(
+        &self,
+        contract_id: ContractId,
+        address: &Address,
+    ) -> Option<(Branch, UnhardenedIndex)> {
+        self.contract_cache(contract_id)?
+            .address_derivation(address)
+    }
+
+    fn transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.cache.tx_cache.get(txid).cloned()
+    }
+
+    fn cache_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        let tx = self.db.transaction()?;
+        for transaction in &transactions {
+            tx.execute(
+                "INSERT OR REPLACE INTO tx_cache (txid, tx) VALUES (?1, ?2)",
+                params![
+                    transaction.txid().to_string(),
+                    transaction.strict_serialize()?
+                ],
+            )?;
+        }
+        tx.commit()?;
+        self.cache
+            .tx_cache
+            .extend(transactions.into_iter().map(|tx| (tx.txid(), tx)));
+        Ok(())
+    }
+
+    fn evict_transactions(&mut self) -> Result<usize, Error> {
+        let evicted = self.cache.unowned_transactions();
+        let tx = self.db.transaction()?;
+        for txid in &evicted {
+            tx.execute(
+                "DELETE FROM tx_cache WHERE txid = ?1",
+                params![txid.to_string()],
+            )?;
+        }
+        tx.commit()?;
+        for txid in &evicted {
+            self.cache.tx_cache.remove(txid);
+        }
+        Ok(evicted.len())
+    }
+
+    fn block_hashes(&self) -> BTreeMap<u32, BlockHash> {
+        self.cache.block_hashes.clone()
+    }
+
+    fn record_block_hashes(
+        &mut self,
+        block_hashes: BTreeMap<u32, BlockHash>,
+    ) -> Result<(), Error> {
+        let tx = self.db.transaction()?;
+        for (height, hash) in &block_hashes {
+            tx.execute(
+                "INSERT OR REPLACE INTO block_hashes (height, hash) \
+                 VALUES (?1, ?2)",
+                params![height, hash.to_string()],
+            )?;
+        }
+        tx.commit()?;
+        self.cache.block_hashes.extend(block_hashes);
+        Ok(())
+    }
+
+    fn prune_block_hashes(&mut self, height: u32) -> Result<(), Error> {
+        self.db.execute(
+            "DELETE FROM block_hashes WHERE height < ?1",
+            params![height],
+        )?;
+        self.cache.block_hashes = self.cache.block_hashes.split_off(&height);
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: u32) -> Result<BTreeSet<Txid>, Error> {
+        let mut cache = self.cache.clone();
+        let orphaned = cache.rollback(height);
+
+        let tx = self.db.transaction()?;
+        tx.execute(
+            "DELETE FROM mine_info WHERE height >= ?1",
+            params![height],
+        )?;
+        tx.execute(
+            "DELETE FROM block_hashes WHERE height >= ?1",
+            params![height],
+        )?;
+        for txid in &orphaned {
+            tx.execute(
+                "DELETE FROM utxo WHERE txid = ?1",
+                params![txid.to_string()],
+            )?;
+        }
+        for (contract_id, contract_cache) in &cache.descriptors {
+            let id = contract_id.to_string();
+            tx.execute(
+                "DELETE FROM unspent WHERE contract_id = ?1",
+                params![id],
+            )?;
+            for (asset_id, utxos) in &contract_cache.unspent {
+                for utxo in utxos {
+                    tx.execute(
+                        "INSERT INTO unspent (contract_id, asset_id, utxo) \
+                         VALUES (?1, ?2, ?3)",
+                        params![
+                            id,
+                            asset_id.to_string(),
+                            UtxoRecord::from(*utxo).strict_serialize()?
+                        ],
+                    )?;
+                }
+            }
+            tx.execute(
+                "UPDATE contract_cache SET updated_height = ?2 \
+                 WHERE contract_id = ?1",
+                params![id, contract_cache.updated_height],
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![KNOWN_HEIGHT_KEY, cache.known_height],
+        )?;
+        tx.commit()?;
+
+        self.cache = cache;
+        Ok(orphaned)
+    }
+
+    fn updated_height(&self, contract_id: ContractId) -> Option<u32> {
+        self.contract_cache(contract_id)
+            .map(|cache| cache.updated_height)
+    }
+
+    fn gap_limit(&self, contract_id: ContractId) -> Option<u8> {
+        self.contract_cache(contract_id)?.gap_limit
+    }
+
+    fn set_gap_limit(
+        &mut self,
+        contract_id: ContractId,
+        gap_limit: u8,
+    ) -> Result<(), Error> {
+        self.db.execute(
+            "INSERT INTO contract_cache \
+             (contract_id, updated_height, gap_limit) VALUES (?1, 0, ?2) \
+             ON CONFLICT (contract_id) \
+             DO UPDATE SET gap_limit = excluded.gap_limit",
+            params![contract_id.to_string(), gap_limit],
+        )?;
+        self.cache
+            .descriptors
+            .entry(contract_id)
+            .or_insert(default!())
+            .gap_limit = Some(gap_limit);
+        Ok(())
+    }
+
+    fn highest_index(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> Option<UnhardenedIndex> {
+        self.contract_cache(contract_id)?
+            .highest_indexes
+            .get(&branch)
+            .copied()
+    }
+
+    fn record_highest_indexes(
+        &mut self,
+        contract_id: ContractId,
+        indexes: BTreeMap<Branch, UnhardenedIndex>,
+    ) -> Result<(), Error> {
+        let cache = self
+            .cache
+            .descriptors
+            .entry(contract_id)
+            .or_insert(default!());
+        cache.record_highest_indexes(indexes);
+        let id = contract_id.to_string();
+        let tx = self.db.transaction()?;
+        for (branch, index) in &cache.highest_indexes {
+            tx.execute(
+                "INSERT OR REPLACE INTO highest_indexes \
+                 (contract_id, branch, derivation) VALUES (?1, ?2, ?3)",
+                params![id, branch_code(*branch), index.strict_serialize()?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use commit_verify::CommitVerify;
+
+    use super::*;
+    use crate::test_utils::{address, temp_dir, transaction, utxo};
+
+    fn db_path(name: &str) -> PathBuf {
+        temp_dir(&format!("cache-sqlite-{}", name)).join("cache.db")
+    }
+
+    #[test]
+    fn round_trip_and_rollback() {
+        let path = db_path("round-trip");
+        let mut cache = SqliteDriver::with(path.clone()).unwrap();
+        let id = ContractId::commit(b"contract");
+        let zero = UnhardenedIndex::zero();
+        let (old, orphaned) = (transaction(1), transaction(2));
+        let (old_utxo, orphaned_utxo) =
+            (utxo(old.txid(), 1000, 10), utxo(orphaned.txid(), 1000, 12));
+        cache
+            .use_address_derivation(id, Branch::External, address(1), zero)
+            .unwrap();
+        cache
+            .use_address_derivation(id, Branch::Internal, address(2), zero)
+            .unwrap();
+        cache.set_gap_limit(id, 7).unwrap();
+        cache
+            .record_highest_indexes(id, bmap! { Branch::Internal => zero })
+            .unwrap();
+        cache
+            .cache_transactions(vec![old.clone(), orphaned.clone()])
+            .unwrap();
+        cache
+            .update(
+                id,
+                bmap! { (10, 1) => old.txid(), (12, 1) => orphaned.txid() },
+                Some(12),
+                bset![old_utxo.outpoint(), orphaned_utxo.outpoint()],
+                bmap! {
+                    rgb::ContractId::default() => vec![
+                        old_utxo.clone(),
+                        orphaned_utxo,
+                    ]
+                },
+            )
+            .unwrap();
+        cache
+            .record_block_hashes(bmap! {
+                10 => BlockHash::hash(b"10"),
+                12 => BlockHash::hash(b"12")
+            })
+            .unwrap();
+        let data = cache.cache.clone();
+        drop(cache);
+
+        let mut cache = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(cache.cache, data);
+        assert_eq!(
+            cache.used_change_derivations(id).unwrap(),
+            bmap! { address(2) => zero }
+        );
+        assert_eq!(cache.gap_limit(id), Some(7));
+
+        // Reorganization is persisted as well
+        assert_eq!(cache.rollback(11), Ok(bset![orphaned.txid()]));
+        assert_eq!(cache.evict_transactions(), Ok(1));
+        let data = cache.cache.clone();
+        drop(cache);
+        let cache = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(cache.cache, data);
+        assert_eq!(cache.blockpos_to_txid(12, 1), None);
+        assert_eq!(cache.updated_height(id), Some(10));
+        assert_eq!(cache.utxo(id), Ok(bset![old_utxo.outpoint()]));
+        assert_eq!(cache.transaction(&orphaned.txid()), None);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrates_old_schema() {
+        let path = db_path("migration");
+        let id = ContractId::commit(b"contract");
+        let db = Connection::open(&path).unwrap();
+        db.execute_batch(SCHEMA_V1).unwrap();
+        db.execute_batch("PRAGMA user_version = 1").unwrap();
+        db.execute(
+            "INSERT INTO address_derivations \
+             (contract_id, address, derivation) VALUES (?1, ?2, ?3)",
+            params![
+                id.to_string(),
+                address(1).to_string(),
+                UnhardenedIndex::zero().strict_serialize().unwrap()
+            ],
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO utxo (contract_id, txid, vout) VALUES (?1, ?2, 0)",
+            params![id.to_string(), Txid::hash(b"tx").to_string()],
+        )
+        .unwrap();
+        drop(db);
+
+        let mut cache = SqliteDriver::with(path.clone()).unwrap();
+        assert!(path.with_file_name("cache.db.v1.backup").exists());
+        // Derivations without branch are external ones, while outputs are
+        // restored with their branches by the next synchronization
+        assert_eq!(
+            cache.address_derivation(id, &address(1)),
+            Some((Branch::External, UnhardenedIndex::zero()))
+        );
+        assert_eq!(cache.utxo(id), Ok(bset![]));
+        cache.set_gap_limit(id, 5).unwrap();
+        drop(cache);
+        let cache = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(cache.gap_limit(id), Some(5));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,855 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Blockchain data source using Bitcoin Core JSON-RPC API. Unspent outputs
+//! are found by scanning the UTXO set with output descriptors, while the
+//! history of the scripts is provided by a watch-only descriptor wallet
+//! which the scripts are imported into on the first request.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Amount, BlockHash, BlockHeader, Script, Transaction, Txid};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use super::proxy::http_agent;
+use super::{
+    check_onion, Driver, Error, HeaderInfo, ProxyConfig, ScriptHistory,
+    ScriptUnspent,
+};
+
+/// Timeout for a single RPC request; UTXO set scans may take minutes
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Name of the watch-only bitcoind wallet tracking contract scripts
+const WATCH_WALLET: &str = "citadel";
+
+/// Bitcoin Core fee estimations are given in BTC per kilo-vbyte
+const BTC_PER_KVB_TO_SAT_PER_VB: f64 = 100_000.0;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("{server}")]
+pub struct BitcoindConfig {
+    /// URL of the bitcoind JSON-RPC API, like `http://127.0.0.1:8332`
+    pub server: String,
+
+    /// RPC credentials in `user:password` form, or path to the bitcoind
+    /// cookie file
+    pub auth: Option<String>,
+
+    /// Proxy used for the connections to the node
+    pub proxy: Option<ProxyConfig>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct RpcResponse {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct ScanUnspent {
+    txid: Txid,
+    vout: u32,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: String,
+    amount: f64,
+    height: u32,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct ScanResult {
+    unspents: Vec<ScanUnspent>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct WalletTx {
+    txid: Txid,
+    blockheight: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct WalletTxDetails {
+    hex: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct BlockTxids {
+    tx: Vec<Txid>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+struct FeeEstimate {
+    feerate: Option<f64>,
+}
+
+pub struct BitcoindDriver {
+    agent: ureq::Agent,
+    server: String,
+    authorization: Option<String>,
+    request_id: AtomicU64,
+
+    /// Scripts already imported into the watch-only wallet
+    watched: Mutex<HashSet<Script>>,
+
+    /// Height of the last block header reported to the subscriber
+    subscribed_height: Mutex<Option<u32>>,
+}
+
+impl BitcoindDriver {
+    pub fn with(config: &BitcoindConfig) -> Result<Self, Error> {
+        debug!("Connecting bitcoind at {} ...", config.server);
+        check_onion(&config.server, config.proxy.as_ref())?;
+        let authorization = match &config.auth {
+            None => None,
+            Some(auth) if auth.contains(':') => Some(auth.clone()),
+            Some(cookie_file) => Some(
+                fs::read_to_string(cookie_file)
+                    .map_err(|err| {
+                        Error::Bitcoind(format!(
+                            "unable to read cookie file {}: {}",
+                            cookie_file, err
+                        ))
+                    })?
+                    .trim()
+                    .to_owned(),
+            ),
+        }
+        .map(|credentials| {
+            format!("Basic {}", base64::encode(credentials.as_bytes()))
+        });
+        let driver = BitcoindDriver {
+            agent: http_agent(REQUEST_TIMEOUT, config.proxy.as_ref())?,
+            server: config.server.trim_end_matches('/').to_owned(),
+            authorization,
+            request_id: AtomicU64::new(0),
+            watched: none!(),
+            subscribed_height: Mutex::new(None),
+        };
+        let height: u32 = driver.call("getblockcount", json!([]))?;
+        debug!("Bitcoind successfully connected, tip is {}", height);
+        driver.open_wallet()?;
+        Ok(driver)
+    }
+
+    fn request<T>(
+        &self,
+        path: &str,
+        method: &str,
+        params: Value,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        trace!("Calling bitcoind RPC {} {}", method, params);
+        let mut request = self.agent.post(&format!("{}{}", self.server, path));
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        let response = match request.send_json(json!({
+            "jsonrpc": "1.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })) {
+            Ok(response) => response,
+            // Bitcoind reports RPC errors with HTTP error status codes,
+            // providing error details in the response body
+            Err(ureq::Error::Status(_, response))
+                if response.content_type() == "application/json" =>
+            {
+                response
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let response: RpcResponse = response.into_json()?;
+        if let Some(err) = response.error {
+            return Err(Error::Bitcoind(format!(
+                "{} (error code {})",
+                err.message, err.code
+            )));
+        }
+        serde_json::from_value(response.result.unwrap_or(Value::Null))
+            .map_err(|err| Error::Bitcoind(err.to_string()))
+    }
+
+    fn call<T>(&self, method: &str, params: Value) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.request("/", method, params)
+    }
+
+    fn wallet_call<T>(&self, method: &str, params: Value) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.request(&format!("/wallet/{}", WATCH_WALLET), method, params)
+    }
+
+    /// Loads the watch-only wallet, creating it if it does not exist yet, and
+    /// reads the list of the scripts it watches
+    fn open_wallet(&self) -> Result<(), Error> {
+        let wallets: Vec<String> = self.call("listwallets", json!([]))?;
+        if !wallets.iter().any(|wallet| wallet == WATCH_WALLET) {
+            if self
+                .call::<Value>("loadwallet", json!([WATCH_WALLET]))
+                .is_err()
+            {
+                info!("Creating bitcoind watch-only wallet {}", WATCH_WALLET);
+                // Arguments: name, disable_private_keys, blank, passphrase,
+                // avoid_reuse, descriptors
+                self.call::<Value>(
+                    "createwallet",
+                    json!([WATCH_WALLET, true, true, "", false, true]),
+                )?;
+            }
+        }
+
+        // Older bitcoind versions do not list wallet descriptors, so
+        // already watched scripts are imported once again
+        let descriptors = self
+            .wallet_call("listdescriptors", json!([]))
+            .unwrap_or_else(|err| {
+                warn!("Unable to list bitcoind wallet descriptors: {}", err);
+                Value::Null
+            });
+        let mut watched = self.watched.lock().expect("poisoned lock");
+        for desc in descriptors["descriptors"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item["desc"].as_str())
+        {
+            let hex = desc
+                .strip_prefix("raw(")
+                .and_then(|desc| desc.split(')').next());
+            if let Some(script) =
+                hex.and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+            {
+                watched.insert(Script::from(script));
+            }
+        }
+        debug!("Bitcoind wallet watches {} scripts", watched.len());
+        Ok(())
+    }
+
+    /// Imports scripts into the watch-only wallet, rescanning the whole
+    /// chain for their transactions
+    fn watch_scripts(&self, scripts: &[Script]) -> Result<(), Error> {
+        let mut watched = self.watched.lock().expect("poisoned lock");
+        let requests = scripts
+            .iter()
+            .filter(|script| !watched.contains(script))
+            .map(|script| {
+                json!({
+                    "desc": raw_descriptor(script),
+                    "timestamp": 0,
+                })
+            })
+            .collect::<Vec<_>>();
+        if requests.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Importing {} scripts into bitcoind wallet; rescanning the chain",
+            requests.len()
+        );
+        let results: Vec<Value> =
+            self.wallet_call("importdescriptors", json!([requests]))?;
+        if let Some(failure) = results
+            .iter()
+            .find(|result| result["success"].as_bool() != Some(true))
+        {
+            return Err(Error::Bitcoind(format!(
+                "unable to import scripts: {}",
+                failure["error"]
+            )));
+        }
+        watched.extend(scripts.iter().cloned());
+        Ok(())
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.call("getblockhash", json!([height]))
+    }
+
+    fn header_info(&self, height: u32) -> Result<HeaderInfo, Error> {
+        let hash = self.block_hash(height)?;
+        let hex: String = self.call("getblockheader", json!([hash, false]))?;
+        Ok(HeaderInfo {
+            height,
+            header: decode_hex(&hex)?,
+        })
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+        // Without transaction index bitcoind knows only mempool and wallet
+        // transactions, so the wallet is used as a fallback
+        match self.call::<String>("getrawtransaction", json!([txid, false])) {
+            Ok(hex) => decode_hex(&hex),
+            Err(_) => {
+                let details: WalletTxDetails =
+                    self.wallet_call("gettransaction", json!([txid, true]))?;
+                decode_hex(&details.hex)
+            }
+        }
+    }
+}
+
+impl Driver for BitcoindDriver {
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let height = self.call("getblockcount", json!([]))?;
+        let info = self.header_info(height)?;
+        *self.subscribed_height.lock().expect("poisoned lock") =
+            Some(info.height);
+        Ok(info)
+    }
+
+    /// Bitcoind RPC does not push notifications, so the chain tip is polled
+    /// and the headers above the last reported one are returned one by one
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let mut subscribed =
+            self.subscribed_height.lock().expect("poisoned lock");
+        let last = match *subscribed {
+            None => return Ok(None),
+            Some(height) => height,
+        };
+        let tip: u32 = self.call("getblockcount", json!([]))?;
+        if tip <= last {
+            return Ok(None);
+        }
+        let info = self.header_info(last + 1)?;
+        *subscribed = Some(info.height);
+        Ok(Some(info))
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.call("getblockcount", json!([]))
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        heights
+            .iter()
+            .map(|height| self.header_info(*height).map(|info| info.header))
+            .collect()
+    }
+
+    /// Scans the UTXO set for the outputs of the scripts. Since the UTXO set
+    /// does not include mempool, unconfirmed outputs are not reported.
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        if scripts.is_empty() {
+            return Ok(vec![]);
+        }
+        let descriptors = scripts
+            .iter()
+            .map(|script| format!("raw({})", script.as_bytes().to_hex()))
+            .collect::<Vec<_>>();
+        let result: ScanResult =
+            self.call("scantxoutset", json!(["start", descriptors]))?;
+        let mut unspent = BTreeMap::<String, Vec<ScriptUnspent>>::new();
+        for item in result.unspents {
+            let value = Amount::from_btc(item.amount)
+                .map_err(|err| Error::Bitcoind(err.to_string()))?
+                .as_sat();
+            unspent.entry(item.script_pubkey).or_default().push(
+                ScriptUnspent {
+                    txid: item.txid,
+                    vout: item.vout,
+                    height: item.height,
+                    value,
+                },
+            );
+        }
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                unspent
+                    .get(&script.as_bytes().to_hex())
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    /// Returns transactions of the watch-only wallet paying to the scripts
+    /// or spending their outputs
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        if scripts.is_empty() {
+            return Ok(vec![]);
+        }
+        self.watch_scripts(scripts)?;
+
+        // Arguments: label, count, skip, include_watchonly
+        let wallet_txs: Vec<WalletTx> = self.wallet_call(
+            "listtransactions",
+            json!(["*", u32::MAX / 2, 0, true]),
+        )?;
+        let mut heights = BTreeMap::new();
+        for tx in wallet_txs {
+            heights.insert(tx.txid, tx.blockheight.unwrap_or_default());
+        }
+        let transactions = heights
+            .keys()
+            .map(|txid| self.transaction(txid))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                let funding = transactions
+                    .iter()
+                    .flat_map(|tx| {
+                        let txid = tx.txid();
+                        tx.output
+                            .iter()
+                            .enumerate()
+                            .filter(move |(_, out)| {
+                                &out.script_pubkey == script
+                            })
+                            .map(move |(vout, _)| (txid, vout as u32))
+                    })
+                    .collect::<HashSet<_>>();
+                transactions
+                    .iter()
+                    .filter(|tx| {
+                        funding.iter().any(|(txid, _)| *txid == tx.txid())
+                            || tx.input.iter().any(|input| {
+                                funding.contains(&(
+                                    input.previous_output.txid,
+                                    input.previous_output.vout,
+                                ))
+                            })
+                    })
+                    .map(|tx| {
+                        let txid = tx.txid();
+                        ScriptHistory {
+                            txid,
+                            height: heights
+                                .get(&txid)
+                                .copied()
+                                .unwrap_or_default(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        txids.iter().map(|txid| self.transaction(txid)).collect()
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        let hash = self.block_hash(height)?;
+        let block: BlockTxids = self.call("getblock", json!([hash, 1]))?;
+        block
+            .tx
+            .iter()
+            .position(|id| id == txid)
+            .map(|pos| pos as u16)
+            .ok_or_else(|| {
+                Error::Bitcoind(format!(
+                    "transaction {} is not mined at height {}",
+                    txid, height
+                ))
+            })
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        debug!("Publishing transaction to bitcoin network via bitcoind");
+        self.call("sendrawtransaction", json!([serialize(tx).to_hex()]))
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        let estimate: FeeEstimate =
+            self.call("estimatesmartfee", json!([target_blocks]))?;
+        Ok(estimate
+            .feerate
+            .map(|feerate| feerate * BTC_PER_KVB_TO_SAT_PER_VB))
+    }
+}
+
+fn decode_hex<T>(hex: &str) -> Result<T, Error>
+where
+    T: bitcoin::consensus::Decodable,
+{
+    let data = Vec::<u8>::from_hex(hex)
+        .map_err(|err| Error::Bitcoind(err.to_string()))?;
+    deserialize(&data).map_err(|err| Error::Bitcoind(err.to_string()))
+}
+
+/// Output descriptor for a raw script with its checksum, as required by
+/// `importdescriptors`
+fn raw_descriptor(script: &Script) -> String {
+    let desc = format!("raw({})", script.as_bytes().to_hex());
+    format!("{}#{}", desc, descriptor_checksum(&desc))
+}
+
+/// Computes output descriptor checksum, as defined in BIP-380
+fn descriptor_checksum(desc: &str) -> String {
+    const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}\
+                                 IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~\
+                                 ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+
+    fn polymod(c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+        for (bit, generator) in GENERATOR.iter().enumerate() {
+            if c0 & (1 << bit) != 0 {
+                c ^= generator;
+            }
+        }
+        c
+    }
+
+    let mut c = 1u64;
+    let mut cls = 0u64;
+    let mut cls_count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .expect("descriptor contains only checksum input characters")
+            as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    (0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Network, OutPoint, TxIn, TxOut};
+
+    use super::*;
+    use crate::chainapi::stand_in::HttpStandIn;
+
+    /// Expected RPC call: URL path, method and either the result or the
+    /// error code with the message returned by the stand-in
+    type Call = (
+        &'static str,
+        &'static str,
+        Result<Value, (i64, &'static str)>,
+    );
+
+    const WALLET_PATH: &str = "/wallet/citadel";
+
+    /// Bitcoind stand-in answering the calls in the scripted order; calls
+    /// deviating from the script are answered with an RPC error
+    fn stand_in(calls: Vec<Call>) -> HttpStandIn {
+        let calls = Mutex::new(VecDeque::from(calls));
+        HttpStandIn::serve(move |request| {
+            let rpc: Value =
+                serde_json::from_str(&request.body).unwrap_or_default();
+            let expected =
+                calls.lock().expect("poisoned call script").pop_front();
+            let result = match expected {
+                Some((path, method, result))
+                    if path == request.path && rpc["method"] == method =>
+                {
+                    result
+                }
+                _ => Err((-32601, "unexpected call")),
+            };
+            let response = match result {
+                Ok(result) => json!({
+                    "result": result,
+                    "error": null,
+                    "id": rpc["id"],
+                }),
+                Err((code, message)) => json!({
+                    "result": null,
+                    "error": { "code": code, "message": message },
+                    "id": rpc["id"],
+                }),
+            };
+            let status = if response["error"].is_null() {
+                200
+            } else {
+                500
+            };
+            (status, response.to_string())
+        })
+    }
+
+    fn startup() -> Vec<Call> {
+        vec![
+            ("/", "getblockcount", Ok(json!(100))),
+            ("/", "listwallets", Ok(json!([WATCH_WALLET]))),
+            (
+                WALLET_PATH,
+                "listdescriptors",
+                Ok(json!({ "descriptors": [] })),
+            ),
+        ]
+    }
+
+    fn driver(server: &HttpStandIn) -> BitcoindDriver {
+        BitcoindDriver::with(&BitcoindConfig {
+            server: server.url(),
+            auth: Some(s!("user:pass")),
+            proxy: None,
+        })
+        .expect("stand-in node is reachable")
+    }
+
+    /// Methods of the RPC calls served by the stand-in, in their order
+    fn methods(server: &HttpStandIn) -> Vec<String> {
+        server
+            .requests()
+            .into_iter()
+            .map(|request| {
+                let rpc: Value = serde_json::from_str(&request.body).unwrap();
+                rpc["method"].as_str().unwrap_or_default().to_owned()
+            })
+            .collect()
+    }
+
+    fn tx(prev_output: OutPoint, script: &Script, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: prev_output,
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+        }
+    }
+
+    #[test]
+    fn descriptor_checksum_vector() {
+        // Test vector from BIP-380
+        assert_eq!(descriptor_checksum("raw(deadbeef)"), "89f8spxm");
+    }
+
+    #[test]
+    fn creates_watch_wallet() {
+        let script = Script::new_op_return(&[1]);
+        let server = stand_in(vec![
+            ("/", "getblockcount", Ok(json!(100))),
+            ("/", "listwallets", Ok(json!([]))),
+            ("/", "loadwallet", Err((-18, "wallet not found"))),
+            ("/", "createwallet", Ok(json!({ "name": WATCH_WALLET }))),
+            (
+                WALLET_PATH,
+                "listdescriptors",
+                Ok(json!({
+                    "descriptors": [{ "desc": raw_descriptor(&script) }],
+                })),
+            ),
+            (WALLET_PATH, "listtransactions", Ok(json!([]))),
+        ]);
+
+        let driver = driver(&server);
+        // Script listed by the wallet is not imported once again
+        assert_eq!(driver.scripts_history(&[script]).unwrap(), vec![vec![]]);
+        assert_eq!(
+            methods(&server),
+            vec![
+                "getblockcount",
+                "listwallets",
+                "loadwallet",
+                "createwallet",
+                "listdescriptors",
+                "listtransactions"
+            ]
+        );
+        assert!(server.requests().iter().all(|request| {
+            request.authorization.as_deref() == Some("Basic dXNlcjpwYXNz")
+        }));
+    }
+
+    #[test]
+    fn unspent_outputs() {
+        let script = Script::new_op_return(&[1]);
+        let txid = Txid::hash(&[1]);
+        let mut calls = startup();
+        calls.push((
+            "/",
+            "scantxoutset",
+            Ok(json!({
+                "unspents": [{
+                    "txid": txid.to_string(),
+                    "vout": 1,
+                    "scriptPubKey": script.as_bytes().to_hex(),
+                    "amount": 0.0001,
+                    "height": 90,
+                }],
+            })),
+        ));
+        let server = stand_in(calls);
+
+        let unspent = driver(&server)
+            .scripts_unspent(&[script, Script::new_op_return(&[2])])
+            .unwrap();
+        assert_eq!(
+            unspent,
+            vec![
+                vec![ScriptUnspent {
+                    txid,
+                    vout: 1,
+                    height: 90,
+                    value: 10_000,
+                }],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn history_from_wallet() {
+        let script = Script::new_op_return(&[1]);
+        let funding = tx(OutPoint::default(), &script, 5000);
+        let spending = tx(
+            OutPoint::new(funding.txid(), 0),
+            &Script::new_op_return(&[2]),
+            4000,
+        );
+        let mut txs = vec![(funding.clone(), Some(100)), (spending, None)];
+        txs.sort_by_key(|(tx, _)| tx.txid());
+
+        let mut calls = startup();
+        calls.push((
+            WALLET_PATH,
+            "importdescriptors",
+            Ok(json!([{
+                "success": true
+            }])),
+        ));
+        calls.push((
+            WALLET_PATH,
+            "listtransactions",
+            Ok(json!(txs
+                .iter()
+                .map(|(tx, height)| json!({
+                    "txid": tx.txid().to_string(),
+                    "blockheight": height,
+                }))
+                .collect::<Vec<_>>())),
+        ));
+        for (tx, _) in &txs {
+            if tx.txid() == funding.txid() {
+                calls.push((
+                    "/",
+                    "getrawtransaction",
+                    Ok(json!(serialize(tx).to_hex())),
+                ));
+            } else {
+                // Without transaction index the wallet is asked for the
+                // transaction instead
+                calls.push((
+                    "/",
+                    "getrawtransaction",
+                    Err((-5, "no such mempool transaction")),
+                ));
+                calls.push((
+                    WALLET_PATH,
+                    "gettransaction",
+                    Ok(json!({ "hex": serialize(tx).to_hex() })),
+                ));
+            }
+        }
+        let count = calls.len();
+        let server = stand_in(calls);
+
+        let history = driver(&server).scripts_history(&[script]).unwrap();
+        let expected = txs
+            .iter()
+            .map(|(tx, height)| ScriptHistory {
+                txid: tx.txid(),
+                height: height.unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(history, vec![expected]);
+        assert_eq!(methods(&server).len(), count);
+    }
+
+    #[test]
+    fn merkle_position_and_broadcast() {
+        let block = genesis_block(Network::Bitcoin);
+        let tx = block.txdata[0].clone();
+        let mut calls = startup();
+        calls.push(("/", "getblockhash", Ok(json!(block.block_hash()))));
+        calls.push((
+            "/",
+            "getblock",
+            Ok(json!({
+                "tx": [Txid::hash(&[1]).to_string(), tx.txid().to_string()],
+            })),
+        ));
+        calls.push(("/", "sendrawtransaction", Ok(json!(tx.txid()))));
+        let server = stand_in(calls);
+
+        let driver = driver(&server);
+        assert_eq!(driver.merkle_position(&tx.txid(), 0).unwrap(), 1);
+        assert_eq!(driver.broadcast(&tx).unwrap(), tx.txid());
+        let request = server.requests().pop().expect("broadcast request");
+        let rpc: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(rpc["params"], json!([serialize(&tx).to_hex()]));
+    }
+}
@@ -0,0 +1,1524 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Light client source of blockchain data using BIP157/158 compact block
+//! filters. The client downloads block headers and filters from a P2P peer,
+//! matches the filters against the requested scripts and fetches only the
+//! matching blocks, so the scripts are never revealed to the peer.
+//! Unconfirmed transactions are not visible to the client.
+
+use std::collections::{BTreeSet, HashSet};
+use std::convert::TryInto;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::constants::{genesis_block, max_target};
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_filter::{GetCFHeaders, GetCFilters};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::network::stream_reader::StreamReader;
+use bitcoin::network::Address;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid,
+};
+use lnpbp::chain::Chain;
+
+use super::{
+    check_onion, Driver, Error, HeaderInfo, ProxyConfig, ScriptHistory,
+    ScriptUnspent,
+};
+use crate::cache::{FilterCache, FilterStore, HeaderFile, MatchedTx};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Type of the BIP158 basic block filter
+const BASIC_FILTER: u8 = 0;
+
+/// Maximal number of filter headers a peer returns for a single request
+const MAX_CFHEADERS: u32 = 2000;
+
+/// Maximal number of filters a peer returns for a single request
+const MAX_CFILTERS: u32 = 1000;
+
+/// Number of headers returned by a peer in a full response
+const MAX_HEADERS: usize = 2000;
+
+/// Number of blocks between difficulty adjustments
+const RETARGET_INTERVAL: u32 = 2016;
+
+/// Expected time between blocks, in seconds
+const TARGET_SPACING: u32 = 600;
+
+const TARGET_TIMESPAN: u32 = RETARGET_INTERVAL * TARGET_SPACING;
+
+/// Blocks from which the header chain is synchronized, so the headers of the
+/// older blocks are not downloaded. The highest checkpoint below the start
+/// height is used; its header is requested from the peer and checked against
+/// the hash, and all following headers must build on it.
+const CHECKPOINTS: &[(Network, u32, &str)] = &[
+    (
+        Network::Bitcoin,
+        11111,
+        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    ),
+    (
+        Network::Bitcoin,
+        33333,
+        "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+    ),
+    (
+        Network::Bitcoin,
+        74000,
+        "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+    ),
+    (
+        Network::Bitcoin,
+        105000,
+        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+    ),
+    (
+        Network::Bitcoin,
+        134444,
+        "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+    ),
+    (
+        Network::Bitcoin,
+        168000,
+        "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+    ),
+    (
+        Network::Bitcoin,
+        193000,
+        "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+    ),
+    (
+        Network::Bitcoin,
+        210000,
+        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+    ),
+    (
+        Network::Bitcoin,
+        216116,
+        "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+    ),
+    (
+        Network::Bitcoin,
+        225430,
+        "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+    ),
+    (
+        Network::Bitcoin,
+        250000,
+        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+    ),
+    (
+        Network::Bitcoin,
+        279000,
+        "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+    ),
+    (
+        Network::Bitcoin,
+        295000,
+        "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+    ),
+    (
+        Network::Testnet,
+        546,
+        "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+    ),
+];
+
+const USER_AGENT: &str = "/citadel:0.5/";
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("{peer}")]
+pub struct CompactFilterConfig {
+    /// Address of the P2P peer serving compact block filters, in
+    /// `host:port` form
+    pub peer: String,
+
+    pub chain: Chain,
+
+    /// Height of the block from which the filters are matched against the
+    /// scripts; blocks below it are expected to contain no wallet
+    /// transactions
+    pub start_height: u32,
+
+    /// File keeping matched transactions; downloaded headers are kept in
+    /// `.headers` and `.cfheaders` files next to it
+    pub cache: PathBuf,
+
+    /// Proxy used for the connections to the peer
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// Connection to a P2P peer
+struct Peer {
+    writer: TcpStream,
+    reader: StreamReader<TcpStream>,
+    magic: u32,
+}
+
+impl Peer {
+    fn connect(
+        addr: &str,
+        network: Network,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<Peer, Error> {
+        debug!("Connecting P2P peer at {} ...", addr);
+        let stream = match proxy {
+            Some(proxy) => proxy.connect(addr)?,
+            None => {
+                let socket_addr = addr
+                    .to_socket_addrs()
+                    .map_err(protocol_err)?
+                    .next()
+                    .ok_or_else(|| {
+                        Error::Protocol(format!("unknown host {}", addr))
+                    })?;
+                TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)
+                    .map_err(|err| {
+                        Error::Unreachable(format!("{}: {}", addr, err))
+                    })?
+            }
+        };
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(protocol_err)?;
+        // With a proxy these are the addresses of the proxy connection
+        let remote_addr = stream.peer_addr().map_err(protocol_err)?;
+        let local_addr = stream.local_addr().map_err(protocol_err)?;
+        let mut peer = Peer {
+            reader: StreamReader::new(
+                stream.try_clone().map_err(protocol_err)?,
+                None,
+            ),
+            writer: stream,
+            magic: network.magic(),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            now.as_secs() as i64,
+            Address::new(&remote_addr, ServiceFlags::NONE),
+            Address::new(&local_addr, ServiceFlags::NONE),
+            now.as_nanos() as u64,
+            s!(USER_AGENT),
+            0,
+        );
+        // Transaction announcements are not needed by the light client
+        version.relay = false;
+        peer.send(NetworkMessage::Version(version))?;
+        let remote = peer.expect(|msg| match msg {
+            NetworkMessage::Version(version) => Some(version),
+            _ => None,
+        })?;
+        if !remote.services.has(ServiceFlags::COMPACT_FILTERS) {
+            return Err(Error::Protocol(format!(
+                "peer {} does not serve compact block filters",
+                addr
+            )));
+        }
+        peer.send(NetworkMessage::Verack)?;
+        peer.expect(|msg| match msg {
+            NetworkMessage::Verack => Some(()),
+            _ => None,
+        })?;
+        debug!(
+            "P2P peer {} ({}) successfully connected",
+            addr, remote.user_agent
+        );
+        Ok(peer)
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<(), Error> {
+        trace!("Sending P2P message {}", payload.cmd());
+        let message = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.writer
+            .write_all(&serialize(&message))
+            .map_err(protocol_err)
+    }
+
+    /// Receives next message from the peer, answering pings
+    fn receive(&mut self) -> Result<NetworkMessage, Error> {
+        loop {
+            let message: RawNetworkMessage =
+                self.reader.read_next().map_err(protocol_err)?;
+            if message.magic != self.magic {
+                return Err(Error::Protocol(s!(
+                    "peer uses a different network"
+                )));
+            }
+            match message.payload {
+                NetworkMessage::Ping(nonce) => {
+                    self.send(NetworkMessage::Pong(nonce))?
+                }
+                payload => return Ok(payload),
+            }
+        }
+    }
+
+    /// Receives messages until the one accepted by `filter`, skipping others
+    fn expect<T>(
+        &mut self,
+        mut filter: impl FnMut(NetworkMessage) -> Option<T>,
+    ) -> Result<T, Error> {
+        loop {
+            if let Some(result) = filter(self.receive()?) {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn block(&mut self, hash: BlockHash) -> Result<Block, Error> {
+        self.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+            hash,
+        )]))?;
+        let block = self.expect(|msg| match msg {
+            NetworkMessage::Block(block) if block.block_hash() == hash => {
+                Some(block)
+            }
+            _ => None,
+        })?;
+        if !block.check_merkle_root() {
+            return Err(Error::Protocol(format!(
+                "block {} has invalid merkle root",
+                hash
+            )));
+        }
+        Ok(block)
+    }
+}
+
+struct FilterState {
+    peer: Option<Peer>,
+    store: FilterStore,
+
+    /// Height of the last block header reported to the subscriber
+    subscribed_height: Option<u32>,
+}
+
+pub struct CompactFilterDriver {
+    config: CompactFilterConfig,
+    network: Network,
+
+    /// Height and hash of the block from which headers are synchronized
+    checkpoint: (u32, BlockHash),
+    state: Mutex<FilterState>,
+}
+
+impl CompactFilterDriver {
+    pub fn with(config: &CompactFilterConfig) -> Result<Self, Error> {
+        check_onion(&config.peer, config.proxy.as_ref())?;
+        let network: Network = (&config.chain).try_into().map_err(|_| {
+            Error::Protocol(format!(
+                "compact block filters are not supported for {}",
+                config.chain
+            ))
+        })?;
+        let checkpoint = checkpoint(network, config.start_height);
+        let (height, hash) = checkpoint;
+        let mut store =
+            FilterStore::with(config.cache.clone()).map_err(cache_err)?;
+        let known = store.headers.get(height);
+        if matches!(known, Some(header) if header.block_hash() != hash) {
+            warn!("Compact filter cache belongs to a different chain");
+            store.data = FilterCache::default();
+            store.reset(height).map_err(cache_err)?;
+        } else if store.headers.is_empty() || store.headers.base() > height {
+            store.reset(height).map_err(cache_err)?;
+        }
+        // Header of a checkpoint other than genesis is requested from the
+        // peer once it gets connected
+        if store.headers.is_empty() && height == 0 {
+            store
+                .headers
+                .extend(&[genesis_block(network).header])
+                .map_err(cache_err)?;
+        }
+        Ok(CompactFilterDriver {
+            config: config.clone(),
+            network,
+            checkpoint,
+            state: Mutex::new(FilterState {
+                peer: None,
+                store,
+                subscribed_height: None,
+            }),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<FilterState> {
+        self.state.lock().expect("poisoned compact filter state")
+    }
+
+    /// Runs the operation with the connected peer, synchronizing block and
+    /// filter headers first. The connection is dropped on failure, so it is
+    /// re-established by the next request. Headers are written as they are
+    /// received, while the rest of the cache is persisted in any case.
+    fn with_peer<T>(
+        &self,
+        operation: impl FnOnce(&mut Peer, &mut FilterStore) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        if state.peer.is_none() {
+            state.peer = Some(Peer::connect(
+                &self.config.peer,
+                self.network,
+                self.config.proxy.as_ref(),
+            )?);
+        }
+        let peer = state.peer.as_mut().expect("peer is connected");
+        let store = &mut state.store;
+        let result = sync_checkpoint(peer, store, self.checkpoint)
+            .and_then(|_| {
+                sync_headers(peer, store, self.network, self.checkpoint)
+            })
+            .and_then(|_| sync_filter_headers(peer, store))
+            .and_then(|_| operation(peer, store));
+        if let Err(err) = &result {
+            warn!("P2P peer {} failure: {}", self.config.peer, err);
+            state.peer = None;
+        }
+        state.store.store().map_err(cache_err)?;
+        result
+    }
+
+    /// Matches filters against the scripts, scanning all blocks for the
+    /// scripts which were not watched before and new blocks for all scripts
+    fn scan_scripts(&self, scripts: &[Script]) -> Result<(), Error> {
+        let start_height = self.config.start_height.max(1);
+        self.with_peer(|peer, store| {
+            let new = scripts
+                .iter()
+                .filter(|script| !store.data.scripts.contains(*script))
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            if !new.is_empty() && store.data.scanned_height >= start_height {
+                debug!(
+                    "Matching block filters against {} new scripts",
+                    new.len()
+                );
+                let relevant = store
+                    .data
+                    .scripts
+                    .union(&new)
+                    .cloned()
+                    .collect::<BTreeSet<_>>();
+                let until = store.data.scanned_height;
+                scan(peer, store, start_height, until, &new, &relevant)?;
+            }
+            store.data.scripts.extend(new);
+
+            let tip = tip_height(store);
+            let from = (store.data.scanned_height + 1).max(start_height);
+            if from <= tip {
+                let scripts = store.data.scripts.clone();
+                scan(peer, store, from, tip, &scripts, &scripts)?;
+            }
+            store.data.scanned_height = tip;
+            Ok(())
+        })
+    }
+}
+
+impl Driver for CompactFilterDriver {
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let info = self.with_peer(|_, store| {
+            let height = tip_height(store);
+            Ok(HeaderInfo {
+                height,
+                header: store.headers.get(height).expect("tip is known"),
+            })
+        })?;
+        self.state().subscribed_height = Some(info.height);
+        Ok(info)
+    }
+
+    /// P2P peer announces new blocks only while the connection is active, so
+    /// the headers are requested from the peer and the ones above the last
+    /// reported are returned one by one
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let subscribed = match self.state().subscribed_height {
+            None => return Ok(None),
+            Some(height) => height,
+        };
+        let next = self.with_peer(|_, store| {
+            // After a reorganization the chain may get shorter
+            let next = subscribed.min(tip_height(store)) + 1;
+            Ok(store.headers.get(next).map(|header| HeaderInfo {
+                height: next,
+                header,
+            }))
+        })?;
+        if let Some(info) = next {
+            self.state().subscribed_height = Some(info.height);
+        }
+        Ok(next)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.with_peer(|_, store| Ok(tip_height(store)))
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        let state = self.state();
+        heights
+            .iter()
+            .map(|height| {
+                state
+                    .store
+                    .headers
+                    .get(*height)
+                    .ok_or(Error::UnknownBlock(*height))
+            })
+            .collect()
+    }
+
+    /// Returns confirmed unspent outputs of the scripts; unconfirmed
+    /// transactions are not known to the light client
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        self.scan_scripts(scripts)?;
+        let state = self.state();
+        let transactions = &state.store.data.transactions;
+        let spent = transactions
+            .values()
+            .flat_map(|matched| matched.tx.input.iter())
+            .map(|input| input.previous_output)
+            .collect::<HashSet<_>>();
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                transactions
+                    .iter()
+                    .flat_map(|(txid, matched)| {
+                        matched.tx.output.iter().enumerate().filter_map(
+                            move |(vout, output)| {
+                                if &output.script_pubkey != script {
+                                    return None;
+                                }
+                                Some(ScriptUnspent {
+                                    txid: *txid,
+                                    vout: vout as u32,
+                                    height: matched.height,
+                                    value: output.value,
+                                })
+                            },
+                        )
+                    })
+                    .filter(|unspent| {
+                        !spent.contains(&OutPoint::new(
+                            unspent.txid,
+                            unspent.vout,
+                        ))
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        self.scan_scripts(scripts)?;
+        let state = self.state();
+        let transactions = &state.store.data.transactions;
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                let funding = transactions
+                    .iter()
+                    .flat_map(|(txid, matched)| {
+                        matched
+                            .tx
+                            .output
+                            .iter()
+                            .enumerate()
+                            .filter(move |(_, out)| {
+                                &out.script_pubkey == script
+                            })
+                            .map(move |(vout, _)| {
+                                OutPoint::new(*txid, vout as u32)
+                            })
+                    })
+                    .collect::<HashSet<_>>();
+                transactions
+                    .iter()
+                    .filter(|(txid, matched)| {
+                        funding.iter().any(|outpoint| outpoint.txid == **txid)
+                            || matched.tx.input.iter().any(|input| {
+                                funding.contains(&input.previous_output)
+                            })
+                    })
+                    .map(|(txid, matched)| ScriptHistory {
+                        txid: *txid,
+                        height: matched.height,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Returns transactions from the matched blocks; other transactions can't
+    /// be requested from P2P peers by their ids
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        let state = self.state();
+        txids
+            .iter()
+            .map(|txid| {
+                state
+                    .store
+                    .data
+                    .transactions
+                    .get(txid)
+                    .map(|matched| matched.tx.clone())
+                    .ok_or(Error::UnknownTransaction(*txid))
+            })
+            .collect()
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        if let Some(matched) = self.state().store.data.transactions.get(txid) {
+            if matched.height == height {
+                return Ok(matched.position);
+            }
+        }
+        self.with_peer(|peer, store| {
+            let hash = store
+                .headers
+                .get(height)
+                .ok_or(Error::UnknownBlock(height))?
+                .block_hash();
+            peer.block(hash)?
+                .txdata
+                .iter()
+                .position(|tx| tx.txid() == *txid)
+                .map(|pos| pos as u16)
+                .ok_or(Error::UnknownTransaction(*txid))
+        })
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        debug!("Publishing transaction to bitcoin network via P2P peer");
+        self.with_peer(|peer, _| {
+            peer.send(NetworkMessage::Tx(tx.clone()))?;
+            Ok(tx.txid())
+        })
+    }
+
+    /// Fee estimation is not available via P2P protocol
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        Ok(None)
+    }
+}
+
+/// Returns the height and hash of the highest checkpoint below the start
+/// height, or of the genesis block if there is none
+fn checkpoint(network: Network, start_height: u32) -> (u32, BlockHash) {
+    CHECKPOINTS
+        .iter()
+        .filter(|(net, height, _)| *net == network && *height < start_height)
+        .last()
+        .map(|(_, height, hash)| {
+            (
+                *height,
+                BlockHash::from_hex(hash).expect("valid checkpoint hash"),
+            )
+        })
+        .unwrap_or_else(|| (0, genesis_block(network).block_hash()))
+}
+
+/// Height of the best known block
+fn tip_height(store: &FilterStore) -> u32 {
+    store
+        .headers
+        .tip()
+        .expect("checkpoint header is always known")
+}
+
+/// Requests the header of the checkpoint block if the chain is not started
+/// yet; a request with no locator returns just the header of the stop hash
+fn sync_checkpoint(
+    peer: &mut Peer,
+    store: &mut FilterStore,
+    (height, hash): (u32, BlockHash),
+) -> Result<(), Error> {
+    if !store.headers.is_empty() {
+        return Ok(());
+    }
+    debug!("Requesting header of checkpoint block {}", hash);
+    peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+        vec![],
+        hash,
+    )))?;
+    let headers = peer.expect(|msg| match msg {
+        NetworkMessage::Headers(headers) => Some(headers),
+        _ => None,
+    })?;
+    match headers.as_slice() {
+        [header] if header.block_hash() == hash => {
+            store.reset(height).map_err(cache_err)?;
+            store.headers.extend(&[*header]).map_err(cache_err)
+        }
+        _ => Err(Error::Protocol(format!(
+            "peer does not provide header of checkpoint block {}",
+            hash
+        ))),
+    }
+}
+
+/// Requests headers following the known chain tip from the peer. A fork of
+/// the known chain replaces it only if it has more work, in which case the
+/// data of the replaced blocks are rolled back.
+fn sync_headers(
+    peer: &mut Peer,
+    store: &mut FilterStore,
+    network: Network,
+    checkpoint: (u32, BlockHash),
+) -> Result<(), Error> {
+    loop {
+        let mut headers =
+            request_headers(peer, locator(store.headers.records()))?;
+        let first = match headers.first() {
+            None => return Ok(()),
+            Some(header) => header,
+        };
+        let fork_height = store
+            .headers
+            .records()
+            .iter()
+            .rposition(|known| known.block_hash() == first.prev_blockhash)
+            .ok_or_else(|| {
+                Error::Protocol(s!("peer headers do not connect to the chain"))
+            })? as u32
+            + store.headers.base();
+        let tip = tip_height(store);
+        let mut full = headers.len() == MAX_HEADERS;
+        // Work of the fork can be compared with the known chain only once
+        // all its headers are received
+        while fork_height < tip && full {
+            let last = headers.last().expect("response is full").block_hash();
+            let more = request_headers(peer, vec![last])?;
+            full = more.len() == MAX_HEADERS;
+            headers.extend(more);
+        }
+
+        let chain = ChainView {
+            known: &store.headers,
+            fork_height,
+            headers: &headers,
+        };
+        let invalid = chain.invalid(network, checkpoint);
+        if fork_height < tip {
+            if let Some(index) = invalid {
+                return Err(invalid_header(&headers[index]));
+            }
+            let known = store.headers.records()
+                [(fork_height + 1 - store.headers.base()) as usize..]
+                .iter();
+            if chain_work(headers.iter()) <= chain_work(known) {
+                warn!(
+                    "Ignoring chain fork starting from block {} which has \
+                     less work than the known chain",
+                    fork_height + 1
+                );
+                return Ok(());
+            }
+            warn!(
+                "Chain reorganization detected starting from block {}",
+                fork_height + 1
+            );
+            store.rollback(fork_height + 1).map_err(cache_err)?;
+        }
+        let valid = invalid.unwrap_or(headers.len());
+        store.headers.extend(&headers[..valid]).map_err(cache_err)?;
+        if let Some(index) = invalid {
+            return Err(invalid_header(&headers[index]));
+        }
+        trace!("Chain tip is at height {}", tip_height(store));
+        if !full {
+            return Ok(());
+        }
+    }
+}
+
+fn request_headers(
+    peer: &mut Peer,
+    locator: Vec<BlockHash>,
+) -> Result<Vec<BlockHeader>, Error> {
+    peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+        locator,
+        BlockHash::default(),
+    )))?;
+    peer.expect(|msg| match msg {
+        NetworkMessage::Headers(headers) => Some(headers),
+        _ => None,
+    })
+}
+
+fn invalid_header(header: &BlockHeader) -> Error {
+    Error::Protocol(format!("peer sent invalid header {}", header.block_hash()))
+}
+
+/// Total work required to mine the headers
+fn chain_work<'a>(headers: impl Iterator<Item = &'a BlockHeader>) -> Uint256 {
+    headers.fold(Uint256::default(), |work, header| work + header.work())
+}
+
+/// Maximal target of the network proof of work
+fn pow_limit(network: Network) -> Uint256 {
+    match network {
+        Network::Signet => BlockHeader::u256_from_compact_target(0x1e0377ae),
+        network => max_target(network),
+    }
+}
+
+/// Known chain up to the fork height followed by the headers received from
+/// the peer
+struct ChainView<'a> {
+    known: &'a HeaderFile<BlockHeader>,
+    fork_height: u32,
+    headers: &'a [BlockHeader],
+}
+
+impl ChainView<'_> {
+    fn get(&self, height: u32) -> Option<BlockHeader> {
+        if height <= self.fork_height {
+            self.known.get(height)
+        } else {
+            let index = height - self.fork_height - 1;
+            self.headers.get(index as usize).copied()
+        }
+    }
+
+    /// Returns index of the first received header which does not follow the
+    /// previous one, has invalid proof of work or difficulty, or does not
+    /// match the checkpoint
+    fn invalid(
+        &self,
+        network: Network,
+        (checkpoint, hash): (u32, BlockHash),
+    ) -> Option<usize> {
+        self.headers.iter().enumerate().position(|(index, header)| {
+            let height = self.fork_height + 1 + index as u32;
+            let prev = self.get(height - 1).expect("chain is contiguous");
+            header.prev_blockhash != prev.block_hash()
+                || header.validate_pow(&header.target()).is_err()
+                || (height == checkpoint && header.block_hash() != hash)
+                || !self.check_bits(network, height, header, &prev)
+        })
+    }
+
+    /// Checks that the difficulty of the block follows the retargeting rules
+    fn check_bits(
+        &self,
+        network: Network,
+        height: u32,
+        header: &BlockHeader,
+        prev: &BlockHeader,
+    ) -> bool {
+        match self.required_bits(network, height, header, prev) {
+            Some(bits) => header.bits == bits,
+            // Blocks preceding the checkpoint are not known, so only the
+            // limits of a single difficulty adjustment are checked
+            None => {
+                let target = header.target();
+                target <= pow_limit(network)
+                    && target <= prev.target().mul_u32(4)
+            }
+        }
+    }
+
+    /// Computes difficulty of the block in compact form, if the blocks
+    /// it depends on are known
+    fn required_bits(
+        &self,
+        network: Network,
+        height: u32,
+        header: &BlockHeader,
+        prev: &BlockHeader,
+    ) -> Option<u32> {
+        let limit = pow_limit(network);
+        if network == Network::Regtest {
+            return Some(prev.bits);
+        }
+        if height % RETARGET_INTERVAL != 0 {
+            if network != Network::Testnet {
+                return Some(prev.bits);
+            }
+            // Testnet allows blocks of minimal difficulty once no block was
+            // found for 20 minutes; other blocks keep the difficulty of the
+            // last block which is not such one
+            let limit_bits = BlockHeader::compact_target_from_u256(&limit);
+            if header.time > prev.time + 2 * TARGET_SPACING {
+                return Some(limit_bits);
+            }
+            let mut height = height - 1;
+            let mut last = *prev;
+            while height % RETARGET_INTERVAL != 0 && last.bits == limit_bits {
+                height -= 1;
+                last = self.get(height)?;
+            }
+            return Some(last.bits);
+        }
+
+        let first = self.get(height - RETARGET_INTERVAL)?;
+        let timespan = prev
+            .time
+            .saturating_sub(first.time)
+            .max(TARGET_TIMESPAN / 4)
+            .min(TARGET_TIMESPAN * 4);
+        let mut target = prev.target().mul_u32(timespan)
+            / Uint256::from_u64(TARGET_TIMESPAN as u64)
+                .expect("timespan fits 256 bits");
+        if target > limit {
+            target = limit;
+        }
+        Some(BlockHeader::compact_target_from_u256(&target))
+    }
+}
+
+/// Requests filter headers for all known blocks, verifying that they build
+/// a chain with the already known filter headers. Filter header of the
+/// checkpoint block is taken from the peer, unless it is the genesis block,
+/// which has no previous filter header.
+fn sync_filter_headers(
+    peer: &mut Peer,
+    store: &mut FilterStore,
+) -> Result<(), Error> {
+    let tip = tip_height(store);
+    loop {
+        let base = store.filter_headers.base();
+        let (start, known) = match store.filter_headers.tip() {
+            Some(height) if height >= tip => return Ok(()),
+            Some(height) => (height + 1, store.filter_headers.get(height)),
+            None if base == 0 => (0, Some(sha256d::Hash::default())),
+            None => (base + 1, None),
+        };
+        if start > tip {
+            return Ok(());
+        }
+        let stop = (start + MAX_CFHEADERS - 1).min(tip);
+        let stop_hash = store
+            .headers
+            .get(stop)
+            .expect("filter headers follow block headers")
+            .block_hash();
+        peer.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: BASIC_FILTER,
+            start_height: start,
+            stop_hash,
+        }))?;
+        let response = peer.expect(|msg| match msg {
+            NetworkMessage::CFHeaders(response)
+                if response.stop_hash == stop_hash =>
+            {
+                Some(response)
+            }
+            _ => None,
+        })?;
+        let previous =
+            sha256d::Hash::from_inner(response.previous_filter.into_inner());
+        if matches!(known, Some(known) if known != previous)
+            || response.filter_hashes.len() as u32 != stop - start + 1
+        {
+            return Err(Error::Protocol(s!(
+                "peer filter headers do not connect to the known ones"
+            )));
+        }
+        let mut filter_headers = vec![];
+        if known.is_none() {
+            filter_headers.push(previous);
+        }
+        let mut prev = previous;
+        for filter_hash in response.filter_hashes {
+            prev = filter_header(filter_hash.into_inner(), prev);
+            filter_headers.push(prev);
+        }
+        store
+            .filter_headers
+            .extend(&filter_headers)
+            .map_err(cache_err)?;
+    }
+}
+
+/// Matches filters of the blocks in the given height range against the
+/// `query` scripts and collects transactions from the matching blocks which
+/// pay to the `relevant` scripts or spend their outputs
+fn scan(
+    peer: &mut Peer,
+    store: &mut FilterStore,
+    from: u32,
+    to: u32,
+    query: &BTreeSet<Script>,
+    relevant: &BTreeSet<Script>,
+) -> Result<(), Error> {
+    debug!("Matching block filters from {} to {}", from, to);
+    let hash_at = |store: &FilterStore, height: u32| {
+        store
+            .headers
+            .get(height)
+            .map(|header| header.block_hash())
+            .ok_or(Error::UnknownBlock(height))
+    };
+    let mut start = from;
+    while start <= to {
+        let stop = (start + MAX_CFILTERS - 1).min(to);
+        peer.send(NetworkMessage::GetCFilters(GetCFilters {
+            filter_type: BASIC_FILTER,
+            start_height: start,
+            stop_hash: hash_at(store, stop)?,
+        }))?;
+        let mut matched = vec![];
+        for height in start..=stop {
+            let block_hash = hash_at(store, height)?;
+            let filter = peer.expect(|msg| match msg {
+                NetworkMessage::CFilter(filter)
+                    if filter.block_hash == block_hash =>
+                {
+                    Some(filter.filter)
+                }
+                _ => None,
+            })?;
+            let filter_header_at = |height: u32| {
+                store
+                    .filter_headers
+                    .get(height)
+                    .ok_or(Error::UnknownBlock(height))
+            };
+            let prev = filter_header_at(height - 1)?;
+            let filter_hash = sha256d::Hash::hash(&filter).into_inner();
+            if filter_header(filter_hash, prev) != filter_header_at(height)? {
+                return Err(Error::Protocol(format!(
+                    "filter for block {} does not match its header",
+                    block_hash
+                )));
+            }
+            let mut scripts = query.iter().map(|script| script.as_bytes());
+            if BlockFilter::new(&filter)
+                .match_any(&block_hash, &mut scripts)
+                .map_err(|err| Error::Protocol(format!("{:?}", err)))?
+            {
+                matched.push((height, block_hash));
+            }
+        }
+        for (height, block_hash) in matched {
+            trace!("Block {} matches the filter", block_hash);
+            let block = peer.block(block_hash)?;
+            collect_transactions(&mut store.data, height, &block, relevant);
+        }
+        start = stop + 1;
+    }
+    Ok(())
+}
+
+fn collect_transactions(
+    cache: &mut FilterCache,
+    height: u32,
+    block: &Block,
+    scripts: &BTreeSet<Script>,
+) {
+    for (position, tx) in block.txdata.iter().enumerate() {
+        let pays = tx
+            .output
+            .iter()
+            .any(|output| scripts.contains(&output.script_pubkey));
+        let spends = tx.input.iter().any(|input| {
+            cache
+                .transactions
+                .get(&input.previous_output.txid)
+                .and_then(|matched| {
+                    matched.tx.output.get(input.previous_output.vout as usize)
+                })
+                .map(|output| scripts.contains(&output.script_pubkey))
+                .unwrap_or_default()
+        });
+        if pays || spends {
+            cache.transactions.insert(
+                tx.txid(),
+                MatchedTx {
+                    height,
+                    position: position as u16,
+                    tx: tx.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// Computes BIP157 filter header from the filter hash and the previous
+/// filter header
+fn filter_header(filter_hash: [u8; 32], prev: sha256d::Hash) -> sha256d::Hash {
+    let mut data = filter_hash.to_vec();
+    data.extend_from_slice(&prev.into_inner());
+    sha256d::Hash::hash(&data)
+}
+
+/// Block locator with exponentially increasing distance between the blocks
+fn locator(headers: &[BlockHeader]) -> Vec<BlockHash> {
+    let mut locator = vec![];
+    let mut height = headers.len() - 1;
+    let mut step = 1;
+    loop {
+        locator.push(headers[height].block_hash());
+        if height == 0 {
+            break;
+        }
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    locator
+}
+
+fn protocol_err(err: impl ToString) -> Error {
+    Error::Protocol(err.to_string())
+}
+
+fn cache_err(err: crate::cache::Error) -> Error {
+    Error::Protocol(format!("compact filter cache error: {}", err))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::network::message_filter::{CFHeaders, CFilter};
+    use bitcoin::util::bip158;
+    use bitcoin::{TxIn, TxOut};
+
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    /// Blocks served by the P2P stand-in; the test may replace them to
+    /// simulate a chain reorganization
+    #[derive(Clone, Default)]
+    struct ServedChain(Arc<Mutex<Vec<Block>>>);
+
+    impl ServedChain {
+        fn blocks(&self) -> Vec<Block> {
+            self.0.lock().expect("poisoned served chain").clone()
+        }
+
+        fn replace(&self, blocks: Vec<Block>) {
+            *self.0.lock().expect("poisoned served chain") = blocks;
+        }
+    }
+
+    fn script(no: u8) -> Script {
+        Builder::new()
+            .push_int(0)
+            .push_slice(&[no; 20])
+            .into_script()
+    }
+
+    /// Appends blocks paying block subsidy to the script; blocks mined for
+    /// different forks at the same height are distinct
+    fn extend(blocks: &mut Vec<Block>, count: u32, fork: u32, script: &Script) {
+        for _ in 0..count {
+            let prev = blocks.last().expect("genesis is present").header;
+            let coinbase = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Builder::new()
+                        .push_int(blocks.len() as i64)
+                        .push_int(fork as i64)
+                        .into_script(),
+                    sequence: u32::MAX,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 50_0000_0000,
+                    script_pubkey: script.clone(),
+                }],
+            };
+            let mut block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: prev.block_hash(),
+                    merkle_root: Default::default(),
+                    time: prev.time + 600,
+                    bits: prev.bits,
+                    nonce: 0,
+                },
+                txdata: vec![coinbase],
+            };
+            block.header.merkle_root = block.merkle_root();
+            while block.header.validate_pow(&block.header.target()).is_err() {
+                block.header.nonce += 1;
+            }
+            blocks.push(block);
+        }
+    }
+
+    fn filter(block: &Block) -> Vec<u8> {
+        BlockFilter::new_script_filter(block, |outpoint| {
+            Err(bip158::Error::UtxoMissing(*outpoint))
+        })
+        .expect("blocks contain only coinbase transactions")
+        .content
+    }
+
+    /// Local P2P peer serving headers, filters and blocks of the chain
+    fn serve(chain: ServedChain) -> String {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("loopback is available");
+        let addr = listener.local_addr().expect("bound listener");
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let chain = chain.clone();
+                thread::spawn(move || handle(stream, chain));
+            }
+        });
+        addr.to_string()
+    }
+
+    fn send(stream: &mut TcpStream, payload: NetworkMessage) -> Option<()> {
+        let message = RawNetworkMessage {
+            magic: Network::Regtest.magic(),
+            payload,
+        };
+        stream.write_all(&serialize(&message)).ok()
+    }
+
+    fn handle(stream: TcpStream, chain: ServedChain) -> Option<()> {
+        let mut writer = stream.try_clone().ok()?;
+        let mut reader = StreamReader::new(stream, None);
+        loop {
+            let message: RawNetworkMessage = reader.read_next().ok()?;
+            let blocks = chain.blocks();
+            let position = |hash: &BlockHash| {
+                blocks.iter().position(|block| block.block_hash() == *hash)
+            };
+            match message.payload {
+                NetworkMessage::Version(_) => {
+                    let addr = Address::new(
+                        &writer.local_addr().ok()?,
+                        ServiceFlags::NONE,
+                    );
+                    let version = VersionMessage::new(
+                        ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+                        0,
+                        addr.clone(),
+                        addr,
+                        0,
+                        s!("/stand-in/"),
+                        blocks.len() as i32 - 1,
+                    );
+                    send(&mut writer, NetworkMessage::Version(version))?;
+                }
+                NetworkMessage::Verack => {
+                    send(&mut writer, NetworkMessage::Verack)?;
+                }
+                NetworkMessage::GetHeaders(request) => {
+                    let known = request
+                        .locator_hashes
+                        .iter()
+                        .find_map(position)
+                        .unwrap_or_default();
+                    let headers = blocks[known + 1..]
+                        .iter()
+                        .take(MAX_HEADERS)
+                        .map(|block| block.header)
+                        .collect();
+                    send(&mut writer, NetworkMessage::Headers(headers))?;
+                }
+                NetworkMessage::GetCFHeaders(request) => {
+                    let start = request.start_height as usize;
+                    let stop = position(&request.stop_hash)?;
+                    let previous = blocks[..start].iter().fold(
+                        sha256d::Hash::default(),
+                        |prev, block| {
+                            let hash = sha256d::Hash::hash(&filter(block));
+                            filter_header(hash.into_inner(), prev)
+                        },
+                    );
+                    let response = CFHeaders {
+                        filter_type: BASIC_FILTER,
+                        stop_hash: request.stop_hash,
+                        previous_filter: Hash::from_inner(
+                            previous.into_inner(),
+                        ),
+                        filter_hashes: blocks[start..=stop]
+                            .iter()
+                            .map(|block| Hash::hash(&filter(block)))
+                            .collect(),
+                    };
+                    send(&mut writer, NetworkMessage::CFHeaders(response))?;
+                }
+                NetworkMessage::GetCFilters(request) => {
+                    let stop = position(&request.stop_hash)?;
+                    for block in &blocks[request.start_height as usize..=stop] {
+                        let response = CFilter {
+                            filter_type: BASIC_FILTER,
+                            block_hash: block.block_hash(),
+                            filter: filter(block),
+                        };
+                        send(&mut writer, NetworkMessage::CFilter(response))?;
+                    }
+                }
+                NetworkMessage::GetData(inventory) => {
+                    for item in inventory {
+                        if let Inventory::WitnessBlock(hash) = item {
+                            let block = blocks[position(&hash)?].clone();
+                            send(&mut writer, NetworkMessage::Block(block))?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Path to the cache file, with the cache files left by the previous
+    /// runs removed
+    fn cache(name: &str) -> PathBuf {
+        temp_dir(&format!("filters-{}", name)).join("filters.dat")
+    }
+
+    fn driver(peer: String, name: &str) -> CompactFilterDriver {
+        CompactFilterDriver::with(&CompactFilterConfig {
+            peer,
+            chain: Chain::Regtest(genesis_block(Network::Regtest).block_hash()),
+            start_height: 1,
+            cache: cache(name),
+            proxy: None,
+        })
+        .expect("regtest is supported")
+    }
+
+    #[test]
+    fn reorg_rolls_back_matched_transactions() {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        extend(&mut blocks, 1, 0, &script(1));
+        extend(&mut blocks, 1, 0, &script(2));
+        extend(&mut blocks, 1, 0, &script(1));
+        let chain = ServedChain::default();
+        chain.replace(blocks.clone());
+        let driver = driver(serve(chain.clone()), "reorg");
+
+        let unspent = driver.scripts_unspent(&[script(2)]).unwrap();
+        assert_eq!(
+            unspent,
+            vec![vec![ScriptUnspent {
+                txid: blocks[2].txdata[0].txid(),
+                vout: 0,
+                height: 2,
+                value: 50_0000_0000,
+            }]]
+        );
+
+        // Chain forks after the first block, replacing the one which paid to
+        // the script
+        let mut fork = blocks[..2].to_vec();
+        extend(&mut fork, 3, 1, &script(1));
+        chain.replace(fork.clone());
+
+        assert_eq!(driver.scripts_unspent(&[script(2)]).unwrap(), vec![vec![]]);
+        assert_eq!(driver.subscribe_headers().unwrap().height, 4);
+        assert_eq!(
+            driver.block_headers(&[1, 2, 4]).unwrap(),
+            vec![fork[1].header, fork[2].header, fork[4].header]
+        );
+    }
+
+    #[test]
+    fn ignores_fork_with_less_work() {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        extend(&mut blocks, 1, 0, &script(1));
+        extend(&mut blocks, 1, 0, &script(2));
+        extend(&mut blocks, 2, 0, &script(1));
+        let chain = ServedChain::default();
+        chain.replace(blocks.clone());
+        let driver = driver(serve(chain.clone()), "less-work");
+        let unspent = driver.scripts_unspent(&[script(2)]).unwrap();
+        assert_eq!(unspent[0][0].txid, blocks[2].txdata[0].txid());
+
+        // Shorter fork after the first block, which has less work than the
+        // known chain
+        let mut fork = blocks[..2].to_vec();
+        extend(&mut fork, 2, 1, &script(1));
+        chain.replace(fork);
+
+        assert_eq!(driver.subscribe_headers().unwrap().height, 4);
+        assert_eq!(
+            driver.block_headers(&[2, 4]).unwrap(),
+            vec![blocks[2].header, blocks[4].header]
+        );
+        assert_eq!(driver.scripts_unspent(&[script(2)]).unwrap(), unspent);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        extend(&mut blocks, 3, 0, &script(1));
+        // Breaking proof of work of the last header
+        let header = &mut blocks[3].header;
+        while header.validate_pow(&header.target()).is_ok() {
+            header.nonce += 1;
+        }
+        let chain = ServedChain::default();
+        chain.replace(blocks.clone());
+        let driver = driver(serve(chain), "pow");
+
+        assert!(matches!(
+            driver.subscribe_headers(),
+            Err(Error::Protocol(_))
+        ));
+        // Valid headers preceding the broken one are kept
+        assert_eq!(driver.block_headers(&[2]).unwrap(), vec![blocks[2].header]);
+        assert!(matches!(
+            driver.block_headers(&[3]),
+            Err(Error::UnknownBlock(3))
+        ));
+
+        // Header with valid proof of work for a difficulty which differs
+        // from the previous block, while regtest has no retargeting
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        extend(&mut blocks, 3, 0, &script(1));
+        let header = &mut blocks[3].header;
+        header.bits = 0x2000ffff;
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        let chain = ServedChain::default();
+        chain.replace(blocks.clone());
+        let driver = driver(serve(chain), "bits");
+
+        assert!(matches!(
+            driver.subscribe_headers(),
+            Err(Error::Protocol(_))
+        ));
+        assert_eq!(driver.block_headers(&[2]).unwrap(), vec![blocks[2].header]);
+        assert!(matches!(
+            driver.block_headers(&[3]),
+            Err(Error::UnknownBlock(3))
+        ));
+
+        // Header which does not follow the previous one
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        extend(&mut blocks, 2, 0, &script(1));
+        let mut orphan = blocks[..2].to_vec();
+        extend(&mut orphan, 1, 1, &script(1));
+        blocks.push(orphan.pop().expect("block is mined"));
+        let chain = ServedChain::default();
+        chain.replace(blocks);
+        let driver = driver(serve(chain), "link");
+
+        assert!(matches!(
+            driver.subscribe_headers(),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            driver.block_headers(&[3]),
+            Err(Error::UnknownBlock(3))
+        ));
+    }
+
+    #[test]
+    fn retargets_difficulty() {
+        let mut store = FilterStore::with(cache("retarget")).unwrap();
+        store.reset(0).unwrap();
+        // Blocks of the first retarget period are found twice faster than
+        // expected
+        let genesis = BlockHeader {
+            bits: 0x1c7fff80,
+            ..genesis_block(Network::Bitcoin).header
+        };
+        let mut headers = vec![genesis];
+        for height in 1..RETARGET_INTERVAL {
+            headers.push(BlockHeader {
+                time: genesis.time + height * TARGET_SPACING / 2,
+                ..genesis
+            });
+        }
+        let prev = headers.last_mut().expect("headers are present");
+        prev.time = genesis.time + TARGET_TIMESPAN / 2;
+        let prev = *prev;
+        store.headers.extend(&headers).unwrap();
+        let chain = ChainView {
+            known: &store.headers,
+            fork_height: RETARGET_INTERVAL - 1,
+            headers: &[],
+        };
+        let next = BlockHeader {
+            time: prev.time + TARGET_SPACING,
+            ..prev
+        };
+
+        assert_eq!(
+            chain.required_bits(Network::Bitcoin, 100, &next, &prev),
+            Some(0x1c7fff80)
+        );
+        assert_eq!(
+            chain.required_bits(
+                Network::Bitcoin,
+                RETARGET_INTERVAL,
+                &next,
+                &prev
+            ),
+            Some(0x1c3fffc0)
+        );
+        assert!(!chain.check_bits(
+            Network::Bitcoin,
+            RETARGET_INTERVAL,
+            &next,
+            &prev
+        ));
+
+        // Testnet allows blocks of minimal difficulty after 20 minutes
+        assert_eq!(
+            chain.required_bits(Network::Testnet, 100, &next, &prev),
+            Some(0x1c7fff80)
+        );
+        let late = BlockHeader {
+            time: prev.time + 2 * TARGET_SPACING + 1,
+            ..prev
+        };
+        assert_eq!(
+            chain.required_bits(Network::Testnet, 100, &late, &prev),
+            Some(0x1d00ffff)
+        );
+    }
+}
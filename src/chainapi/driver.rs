@@ -13,20 +13,129 @@
 
 //! Module responsible for requesting blockchain data
 
-use bitcoin::{Address, Transaction, Txid};
-use wallet::descriptors::ContractDescriptor;
-use wallet::hd::{PubkeyChain, UnhardenedIndex};
-
-pub trait Driver {
-    fn transactions_by_txid(
-        txid: Vec<Txid>,
-        only_mined: bool,
-    ) -> Vec<Transaction>;
-
-    fn txid_by_address(address: Address) -> Vec<Txid>;
-
-    fn txid_by_derived_descriptor(
-        descriptor: ContractDescriptor<PubkeyChain>,
-        index: UnhardenedIndex,
-    ) -> Vec<Txid>;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::{BlockHeader, Script, Transaction, Txid};
+
+use super::Error;
+
+/// Block header together with the height of the block
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HeaderInfo {
+    pub height: u32,
+    pub header: BlockHeader,
+}
+
+/// Transaction output, locked by a script, which is not spent yet
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ScriptUnspent {
+    pub txid: Txid,
+    pub vout: u32,
+
+    /// Height of the block mining the transaction; zero for unconfirmed
+    /// transactions
+    pub height: u32,
+
+    pub value: u64,
+}
+
+/// Transaction spending or funding a script
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ScriptHistory {
+    pub txid: Txid,
+
+    /// Height of the block mining the transaction; zero for unconfirmed
+    /// transactions
+    pub height: u32,
+}
+
+/// Status of the script transaction history, which changes when the script
+/// gets new transactions or some of its transactions get mined or unmined
+pub type ScriptStatus = sha256::Hash;
+
+/// Source of the blockchain data used by the runtime. The same source is
+/// shared by the runtime and chainwatch service threads.
+pub trait Driver: Send + Sync {
+    /// Returns the header of the current chain tip, subscribing to the
+    /// following block headers
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error>;
+
+    /// Returns the next block header received since the subscription, if
+    /// there is one
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error>;
+
+    /// Returns height of the current chain tip without affecting the headers
+    /// reported by [`Driver::pop_header`]
+    fn tip_height(&self) -> Result<u32, Error>;
+
+    /// Returns headers of the blocks at the given heights, in the same order
+    fn block_headers(&self, heights: &[u32])
+        -> Result<Vec<BlockHeader>, Error>;
+
+    /// Returns unspent outputs for each of the scripts, in the same order
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error>;
+
+    /// Returns transactions spending or funding each of the scripts, in the
+    /// same order
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error>;
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error>;
+
+    /// Returns position of the transaction within the block at the given
+    /// height
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error>;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error>;
+
+    /// Returns fee rate, in satoshis per virtual byte, required for the
+    /// transaction to get mined within the given number of blocks; or
+    /// `None` if the estimation is not available
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error>;
+
+    /// Returns current statuses of the scripts, in the same order; scripts
+    /// without transactions have no status. Sources supporting push
+    /// notifications subscribe to the status changes, while others compute
+    /// the status from the script history on each call.
+    fn scripts_status(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Option<ScriptStatus>>, Error> {
+        Ok(self
+            .scripts_history(scripts)?
+            .iter()
+            .map(|history| script_status(history))
+            .collect())
+    }
+
+    /// Whether the source is notified about script status changes, so
+    /// [`Driver::scripts_status`] does not query the script history and can
+    /// be called frequently
+    fn subscribes_scripts(&self) -> bool {
+        false
+    }
+}
+
+/// Computes script status digest from the script history for the sources of
+/// blockchain data without script subscriptions. The digest does not follow
+/// Electrum protocol rules for ordering of unconfirmed transactions, so it
+/// must be compared only with the digests computed by this function and not
+/// with the statuses reported by Electrum servers.
+pub fn script_status(history: &[ScriptHistory]) -> Option<ScriptStatus> {
+    if history.is_empty() {
+        return None;
+    }
+    let mut history = history.to_vec();
+    // Confirmed transactions go first, ordered by their height; the order of
+    // transactions within the same block is not known to all sources
+    history.sort_by_key(|item| (item.height == 0, item.height, item.txid));
+    let mut engine = ScriptStatus::engine();
+    for item in history {
+        engine.input(format!("{}:{}:", item.txid, item.height).as_bytes());
+    }
+    Some(ScriptStatus::from_engine(engine))
 }
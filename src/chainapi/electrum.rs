@@ -13,39 +13,609 @@
 
 //! Module responsible for requesting blockchain data
 
-use std::net::IpAddr;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-use electrum_client::Client as ElectrumClient;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHeader, Script, Transaction, Txid};
+use electrum_client::{
+    Client as ElectrumClient, ConfigBuilder, ElectrumApi,
+    Error as ElectrumError, GetHistoryRes, GetMerkleRes, HeaderNotification,
+    ListUnspentRes, RawClient,
+};
 
-use super::Driver;
-use bitcoin::{Address, Transaction, Txid};
-use wallet::descriptors::ContractDescriptor;
-use wallet::hd::{PubkeyChain, UnhardenedIndex};
+use super::{
+    check_onion, Driver, Error, HeaderInfo, ProxyConfig, ScriptHistory,
+    ScriptStatus, ScriptUnspent,
+};
 
+/// Electrum fee estimations are given in BTC per kilobyte
+const BTC_PER_KB_TO_SAT_PER_VB: f64 = 100_000.0;
+
+/// Delay before the first reconnection attempt after a connection failure;
+/// it doubles with each following failed attempt
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+
+/// Maximal delay between reconnection attempts
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(300);
+
+/// Connection which was not used for this time is checked with a ping
+/// before the next request
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("{server}")]
 pub struct ElectrumConfig {
-    pub electrum: IpAddr,
+    /// Electrum server connection string
+    pub server: String,
+
+    /// Timeout for the server requests, in seconds; requests wait for the
+    /// response infinitely if no timeout is given
+    pub timeout: Option<u8>,
+
+    /// Proxy used for the connections to the server
+    pub proxy: Option<ProxyConfig>,
 }
 
+/// State of the connection to the Electrum server
+struct Connection {
+    client: Option<Box<dyn Api + Send>>,
+
+    /// Delay before the next reconnection attempt
+    delay: Duration,
+
+    /// Time after which the next reconnection attempt can be made
+    retry_at: Instant,
+
+    /// Time of the last successful request
+    used_at: Instant,
+
+    /// Height of the last block header reported to the subscriber; `None`
+    /// if there is no header subscription
+    header_height: Option<u32>,
+
+    /// New chain tip received on restoring the header subscription after
+    /// reconnection, which is not reported to the subscriber yet
+    missed_header: Option<HeaderInfo>,
+
+    /// Scripts subscribed to over the current connection
+    subscribed: HashSet<Script>,
+
+    /// Last known statuses of the scripts subscribed to
+    statuses: HashMap<Script, Option<ScriptStatus>>,
+}
+
+impl Connection {
+    /// Drops connection after a failure; the next request reconnects to the
+    /// server immediately
+    fn drop_client(&mut self) {
+        self.client = None;
+        self.subscribed.clear();
+        self.retry_at = Instant::now();
+    }
+}
+
+/// Electrum server client keeping a single long-lived connection, which is
+/// checked for health and re-established on failures with exponential
+/// backoff. Header and script subscriptions are restored after
+/// reconnection.
 pub struct ElectrumDriver {
-    electrum: ElectrumClient,
+    config: ElectrumConfig,
+    connection: Mutex<Connection>,
+}
+
+impl ElectrumDriver {
+    /// Creates driver and connects to the server. If the server is not
+    /// reachable, the driver is still created and keeps reconnecting on the
+    /// following requests.
+    pub fn with(config: &ElectrumConfig) -> Result<Self, Error> {
+        check_onion(&config.server, config.proxy.as_ref())?;
+        let now = Instant::now();
+        let driver = ElectrumDriver {
+            config: config.clone(),
+            connection: Mutex::new(Connection {
+                client: None,
+                delay: RECONNECT_DELAY_MIN,
+                retry_at: now,
+                used_at: now,
+                header_height: None,
+                missed_header: None,
+                subscribed: none!(),
+                statuses: none!(),
+            }),
+        };
+        if let Err(err) = driver.ensure_connected(&mut driver.connection()) {
+            warn!("{}; the runtime starts offline", err);
+        }
+        Ok(driver)
+    }
+
+    fn connection(&self) -> MutexGuard<Connection> {
+        self.connection
+            .lock()
+            .expect("poisoned electrum connection")
+    }
+
+    fn connect(&self) -> Result<Box<dyn Api + Send>, ElectrumError> {
+        let proxy = match &self.config.proxy {
+            None => {
+                let config =
+                    ConfigBuilder::new().timeout(self.config.timeout)?.build();
+                return Ok(Box::new(ElectrumClient::from_config(
+                    &self.config.server,
+                    config,
+                )?));
+            }
+            Some(proxy) => proxy,
+        };
+
+        // Electrum client does not support timeouts for the connections via
+        // proxy, so we connect the server over the proxy ourselves and set
+        // the timeouts on the resulting socket
+        let server = self.config.server.as_str();
+        let (ssl, target) = match server.strip_prefix("ssl://") {
+            Some(target) => (true, target),
+            None => (false, server.strip_prefix("tcp://").unwrap_or(server)),
+        };
+        let stream = proxy.connect(target).map_err(|err| {
+            ElectrumError::IOError(io::Error::new(
+                io::ErrorKind::Other,
+                err.to_string(),
+            ))
+        })?;
+        let timeout = self
+            .config
+            .timeout
+            .map(|timeout| Duration::from_secs(timeout as u64));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(if ssl {
+            Box::new(RawClient::new_ssl_from_stream(target, true, stream)?)
+        } else {
+            Box::new(RawClient::from(stream))
+        })
+    }
+
+    /// Checks the existing connection if it was not used for a while and
+    /// reconnects to the server if there is no connection, unless the next
+    /// reconnection attempt is delayed by the backoff
+    fn ensure_connected(&self, conn: &mut Connection) -> Result<(), Error> {
+        if let Some(client) = &conn.client {
+            if conn.used_at.elapsed() < HEALTH_CHECK_INTERVAL {
+                return Ok(());
+            }
+            trace!("Checking electrum server connection health");
+            match client.ping() {
+                Ok(_) => {
+                    conn.used_at = Instant::now();
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(
+                        "Electrum server {} does not respond: {:?}",
+                        self.config.server, err
+                    );
+                    conn.drop_client();
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if now < conn.retry_at {
+            return Err(Error::Unreachable(self.config.server.clone()));
+        }
+        debug!("Connecting electrum server at {} ...", self.config.server);
+        let client = match self.connect() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!(
+                    "Unable to connect electrum server {}: {:?}; next attempt \
+                     in {} seconds",
+                    self.config.server,
+                    err,
+                    conn.delay.as_secs()
+                );
+                conn.retry_at = now + conn.delay;
+                conn.delay = (conn.delay * 2).min(RECONNECT_DELAY_MAX);
+                return Err(Error::Unreachable(self.config.server.clone()));
+            }
+        };
+        debug!("Electrum server successfully connected");
+
+        if let Some(height) = conn.header_height {
+            debug!("Restoring subscription to new blocks");
+            match client.block_headers_subscribe() {
+                Ok(info) if info.height as u32 > height => {
+                    conn.missed_header = Some(HeaderInfo {
+                        height: info.height as u32,
+                        header: info.header,
+                    })
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("Unable to restore block subscription: {:?}", err);
+                    conn.retry_at = now + conn.delay;
+                    conn.delay = (conn.delay * 2).min(RECONNECT_DELAY_MAX);
+                    return Err(Error::Unreachable(self.config.server.clone()));
+                }
+            }
+        }
+        conn.client = Some(client);
+        conn.delay = RECONNECT_DELAY_MIN;
+        conn.used_at = now;
+        Ok(())
+    }
+
+    /// Performs request over the connection. If the connection turns out to
+    /// be broken, the request is repeated once over a new connection.
+    fn request_with<T>(
+        &self,
+        conn: &mut Connection,
+        request: impl Fn(&dyn Api) -> Result<T, ElectrumError>,
+    ) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            self.ensure_connected(conn)?;
+            let client = conn.client.as_deref().expect("connection is present");
+            match request(client) {
+                Ok(result) => {
+                    conn.used_at = Instant::now();
+                    return Ok(result);
+                }
+                Err(err) if is_connection_failure(&err) => {
+                    warn!(
+                        "Connection to electrum server {} is lost: {:?}",
+                        self.config.server, err
+                    );
+                    conn.drop_client();
+                    if attempt > 0 {
+                        conn.retry_at = Instant::now() + conn.delay;
+                        return Err(Error::Unreachable(
+                            self.config.server.clone(),
+                        ));
+                    }
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn request<T>(
+        &self,
+        request: impl Fn(&dyn Api) -> Result<T, ElectrumError>,
+    ) -> Result<T, Error> {
+        self.request_with(&mut self.connection(), request)
+    }
+}
+
+/// Part of the electrum API used by the driver, which is implemented both by
+/// the clients made by the electrum library and by the clients over the
+/// streams connected via proxy
+trait Api {
+    fn ping(&self) -> Result<(), ElectrumError>;
+
+    fn block_headers_subscribe(
+        &self,
+    ) -> Result<HeaderNotification, ElectrumError>;
+
+    fn block_headers_pop(
+        &self,
+    ) -> Result<Option<HeaderNotification>, ElectrumError>;
+
+    fn batch_block_header(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, ElectrumError>;
+
+    fn batch_script_list_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ListUnspentRes>>, ElectrumError>;
+
+    fn batch_script_get_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<GetHistoryRes>>, ElectrumError>;
+
+    fn batch_transaction_get(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Transaction>, ElectrumError>;
+
+    fn transaction_get_merkle(
+        &self,
+        txid: &Txid,
+        height: usize,
+    ) -> Result<GetMerkleRes, ElectrumError>;
+
+    fn transaction_broadcast(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Txid, ElectrumError>;
+
+    fn estimate_fee(&self, number: usize) -> Result<f64, ElectrumError>;
+
+    fn script_subscribe(
+        &self,
+        script: &Script,
+    ) -> Result<Option<electrum_client::ScriptStatus>, ElectrumError>;
+
+    fn script_pop(
+        &self,
+        script: &Script,
+    ) -> Result<Option<electrum_client::ScriptStatus>, ElectrumError>;
+}
+
+impl<C> Api for C
+where
+    C: ElectrumApi,
+{
+    fn ping(&self) -> Result<(), ElectrumError> {
+        ElectrumApi::ping(self)
+    }
+
+    fn block_headers_subscribe(
+        &self,
+    ) -> Result<HeaderNotification, ElectrumError> {
+        ElectrumApi::block_headers_subscribe(self)
+    }
+
+    fn block_headers_pop(
+        &self,
+    ) -> Result<Option<HeaderNotification>, ElectrumError> {
+        ElectrumApi::block_headers_pop(self)
+    }
+
+    fn batch_block_header(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, ElectrumError> {
+        ElectrumApi::batch_block_header(self, heights.iter().copied())
+    }
+
+    fn batch_script_list_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ListUnspentRes>>, ElectrumError> {
+        ElectrumApi::batch_script_list_unspent(self, scripts)
+    }
+
+    fn batch_script_get_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<GetHistoryRes>>, ElectrumError> {
+        ElectrumApi::batch_script_get_history(self, scripts)
+    }
+
+    fn batch_transaction_get(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Transaction>, ElectrumError> {
+        ElectrumApi::batch_transaction_get(self, txids)
+    }
+
+    fn transaction_get_merkle(
+        &self,
+        txid: &Txid,
+        height: usize,
+    ) -> Result<GetMerkleRes, ElectrumError> {
+        ElectrumApi::transaction_get_merkle(self, txid, height)
+    }
+
+    fn transaction_broadcast(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Txid, ElectrumError> {
+        ElectrumApi::transaction_broadcast(self, tx)
+    }
+
+    fn estimate_fee(&self, number: usize) -> Result<f64, ElectrumError> {
+        ElectrumApi::estimate_fee(self, number)
+    }
+
+    fn script_subscribe(
+        &self,
+        script: &Script,
+    ) -> Result<Option<electrum_client::ScriptStatus>, ElectrumError> {
+        ElectrumApi::script_subscribe(self, script)
+    }
+
+    fn script_pop(
+        &self,
+        script: &Script,
+    ) -> Result<Option<electrum_client::ScriptStatus>, ElectrumError> {
+        ElectrumApi::script_pop(self, script)
+    }
+}
+
+/// Detects errors caused by a broken connection rather than by the server
+/// response
+fn is_connection_failure(err: &ElectrumError) -> bool {
+    matches!(
+        err,
+        ElectrumError::IOError(_)
+            | ElectrumError::SharedIOError(_)
+            | ElectrumError::AllAttemptsErrored(_)
+            | ElectrumError::CouldntLockReader
+            | ElectrumError::Mpsc
+    )
 }
 
 impl Driver for ElectrumDriver {
-    fn transactions_by_txid(
-        txid: Vec<Txid>,
-        only_mined: bool,
-    ) -> Vec<Transaction> {
-        unimplemented!()
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let mut conn = self.connection();
+        let info = self.request_with(&mut conn, |client| {
+            // Notifications received before the subscription are stale
+            while client.block_headers_pop()?.is_some() {}
+            client.block_headers_subscribe()
+        })?;
+        conn.header_height = Some(info.height as u32);
+        conn.missed_header = None;
+        Ok(HeaderInfo {
+            height: info.height as u32,
+            header: info.header,
+        })
+    }
+
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let mut conn = self.connection();
+        let info = self
+            .request_with(&mut conn, |client| client.block_headers_pop())?
+            .map(|info| HeaderInfo {
+                height: info.height as u32,
+                header: info.header,
+            })
+            .or_else(|| conn.missed_header.take());
+        if let Some(info) = info {
+            conn.header_height = Some(info.height);
+        }
+        Ok(info)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        let mut conn = self.connection();
+        let subscribed = conn.header_height.is_some();
+        self.request_with(&mut conn, |client| {
+            let info = client.block_headers_subscribe()?;
+            if !subscribed {
+                // Nobody pops header notifications without the subscriber
+                while client.block_headers_pop()?.is_some() {}
+            }
+            Ok(info.height as u32)
+        })
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        if heights.is_empty() {
+            return Ok(vec![]);
+        }
+        self.request(|client| client.batch_block_header(heights))
+    }
+
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        if scripts.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .request(|client| client.batch_script_list_unspent(scripts))?
+            .into_iter()
+            .map(|unspent| {
+                unspent
+                    .into_iter()
+                    .map(|item| ScriptUnspent {
+                        txid: item.tx_hash,
+                        vout: item.tx_pos as u32,
+                        height: item.height as u32,
+                        value: item.value,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        if scripts.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .request(|client| client.batch_script_get_history(scripts))?
+            .into_iter()
+            .map(|history| {
+                history
+                    .into_iter()
+                    .map(|item| ScriptHistory {
+                        txid: item.tx_hash,
+                        // Electrum uses negative heights for unconfirmed
+                        // transactions with unconfirmed parents
+                        height: item.height.max(0) as u32,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        if txids.is_empty() {
+            return Ok(vec![]);
+        }
+        self.request(|client| client.batch_transaction_get(txids))
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        Ok(self
+            .request(|client| {
+                client.transaction_get_merkle(txid, height as usize)
+            })?
+            .pos as u16)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        debug!("Publishing transaction to bitcoin network via Electrum server");
+        self.request(|client| client.transaction_broadcast(tx))
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        let fee =
+            self.request(|client| client.estimate_fee(target_blocks as usize))?;
+        // Electrum returns -1 when the server has no estimation
+        Ok(if fee < 0.0 {
+            None
+        } else {
+            Some(fee * BTC_PER_KB_TO_SAT_PER_VB)
+        })
     }
 
-    fn txid_by_address(address: Address) -> Vec<Txid> {
-        unimplemented!()
+    /// Subscribes to the scripts not subscribed yet over the current
+    /// connection and applies status change notifications received for the
+    /// rest
+    fn scripts_status(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Option<ScriptStatus>>, Error> {
+        let mut conn = self.connection();
+        // Notifications are read from the connection only together with
+        // request responses
+        self.request_with(&mut conn, |client| client.ping())?;
+        let mut statuses = Vec::with_capacity(scripts.len());
+        for script in scripts {
+            if conn.subscribed.contains(script) {
+                while let Some(update) = self
+                    .request_with(&mut conn, |client| {
+                        client.script_pop(script)
+                    })?
+                {
+                    conn.statuses.insert(
+                        script.clone(),
+                        Some(ScriptStatus::from_inner(update)),
+                    );
+                }
+            } else {
+                let status = self
+                    .request_with(&mut conn, |client| {
+                        client.script_subscribe(script)
+                    })?
+                    .map(ScriptStatus::from_inner);
+                conn.subscribed.insert(script.clone());
+                conn.statuses.insert(script.clone(), status);
+            }
+            statuses.push(conn.statuses.get(script).copied().flatten());
+        }
+        Ok(statuses)
     }
 
-    fn txid_by_derived_descriptor(
-        descriptor: ContractDescriptor<PubkeyChain>,
-        index: UnhardenedIndex,
-    ) -> Vec<Txid> {
-        unimplemented!()
+    fn subscribes_scripts(&self) -> bool {
+        true
     }
 }
@@ -0,0 +1,490 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Set of Electrum servers used as a single source of blockchain data, with
+//! automatic failover between the servers and optional cross-checking of the
+//! data returned by them.
+
+use std::sync::{Mutex, MutexGuard};
+
+use bitcoin::{BlockHeader, Script, Transaction, Txid};
+
+use super::{
+    Driver, ElectrumConfig, ElectrumDriver, Error, HeaderInfo, ScriptHistory,
+    ScriptStatus, ScriptUnspent,
+};
+
+/// Number of attempts to query unspent outputs from a server without its
+/// chain tip moving during the query
+const TIP_QUERY_ATTEMPTS: usize = 3;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub struct ElectrumPoolConfig {
+    /// Servers in the order of their priority: requests are sent to the
+    /// first reachable server
+    pub servers: Vec<ElectrumConfig>,
+
+    /// Whether unspent outputs and transaction positions within blocks must
+    /// be confirmed by two different servers
+    pub paranoid: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+struct PoolState {
+    /// Index of the server which served the last request
+    active: Option<usize>,
+
+    /// Index of the server providing header subscription
+    header_server: Option<usize>,
+
+    /// Height of the last block header reported to the subscriber; `None`
+    /// if there is no header subscription
+    header_height: Option<u32>,
+}
+
+pub struct ElectrumPool {
+    servers: Vec<ElectrumDriver>,
+    paranoid: bool,
+    state: Mutex<PoolState>,
+}
+
+impl ElectrumPool {
+    pub fn with(config: &ElectrumPoolConfig) -> Result<Self, Error> {
+        if config.servers.is_empty() {
+            return Err(Error::InvalidConfig(s!(
+                "no electrum servers are given"
+            )));
+        }
+        if config.paranoid && config.servers.len() < 2 {
+            return Err(Error::InvalidConfig(s!(
+                "paranoid mode requires at least two electrum servers"
+            )));
+        }
+        let servers = config
+            .servers
+            .iter()
+            .map(ElectrumDriver::with)
+            .collect::<Result<_, _>>()?;
+        Ok(ElectrumPool {
+            servers,
+            paranoid: config.paranoid,
+            state: none!(),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<PoolState> {
+        self.state.lock().expect("poisoned electrum pool state")
+    }
+
+    /// Performs the request with the servers in the order of their priority,
+    /// skipping unreachable ones. Returns index of the server which has
+    /// served the request. The pool state is not locked during the request,
+    /// so requests from different threads do not wait for each other.
+    fn failover<T>(
+        &self,
+        skip: Option<usize>,
+        mut request: impl FnMut(usize, &ElectrumDriver) -> Result<T, Error>,
+    ) -> Result<(usize, T), Error> {
+        let mut failure = None;
+        for (index, server) in self.servers.iter().enumerate() {
+            if Some(index) == skip {
+                continue;
+            }
+            match request(index, server) {
+                Err(Error::Unreachable(details)) => {
+                    debug!("Electrum server {} is skipped: {}", index, details);
+                    failure = Some(details);
+                }
+                result => {
+                    if skip.is_none() {
+                        let mut state = self.state();
+                        if state.active != Some(index) {
+                            info!("Using electrum server #{}", index + 1);
+                            state.active = Some(index);
+                        }
+                    }
+                    return result.map(|result| (index, result));
+                }
+            }
+        }
+        Err(Error::Unreachable(failure.unwrap_or_else(|| {
+            s!("no other electrum server is available")
+        })))
+    }
+
+    fn request<T>(
+        &self,
+        request: impl FnMut(usize, &ElectrumDriver) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.failover(None, request).map(|(_, result)| result)
+    }
+
+    /// Performs the request with the first reachable server and, in paranoid
+    /// mode, repeats it with another server. Both results are passed to
+    /// `reconcile`, which returns the verified result or `None` if the
+    /// servers disagree.
+    fn cross_checked<T: std::fmt::Debug>(
+        &self,
+        what: &str,
+        request: impl Fn(&ElectrumDriver) -> Result<T, Error>,
+        reconcile: impl Fn(T, T) -> Option<T>,
+    ) -> Result<T, Error> {
+        let (index, result) =
+            self.failover(None, |_, server| request(server))?;
+        if !self.paranoid {
+            return Ok(result);
+        }
+        let (other, check) = self
+            .failover(Some(index), |_, server| request(server))
+            .map_err(|err| {
+                Error::Unreachable(format!(
+                    "unable to cross-check {}: {}",
+                    what, err
+                ))
+            })?;
+        let details = format!("{:?} vs {:?}", result, check);
+        match reconcile(result, check) {
+            Some(result) => {
+                trace!("Electrum server #{} confirms {}", other + 1, what);
+                Ok(result)
+            }
+            None => {
+                error!(
+                    "Electrum servers #{} and #{} disagree on {}: {}",
+                    index + 1,
+                    other + 1,
+                    what,
+                    details
+                );
+                Err(Error::Mismatch(format!(
+                    "servers #{} and #{} return different {}",
+                    index + 1,
+                    other + 1,
+                    what
+                )))
+            }
+        }
+    }
+}
+
+/// Unspent outputs reported by a server together with its chain tip height
+#[derive(Clone, Debug)]
+struct TipUnspent {
+    tip: u32,
+    unspent: Vec<Vec<ScriptUnspent>>,
+}
+
+/// Queries unspent outputs together with the chain tip they correspond to.
+/// The tip is read before and after the query, which is repeated if a block
+/// arrives in between. A server whose tip keeps moving is reported as
+/// unreachable, so the pool uses the next one.
+fn tip_unspent(
+    tip_height: impl Fn() -> Result<u32, Error>,
+    scripts_unspent: impl Fn() -> Result<Vec<Vec<ScriptUnspent>>, Error>,
+) -> Result<TipUnspent, Error> {
+    let mut tip = tip_height()?;
+    for _ in 0..TIP_QUERY_ATTEMPTS {
+        let unspent = scripts_unspent()?;
+        let after = tip_height()?;
+        if after == tip {
+            return Ok(TipUnspent { tip, unspent });
+        }
+        debug!(
+            "Chain tip has moved from {} to {} during unspent outputs query",
+            tip, after
+        );
+        tip = after;
+    }
+    Err(Error::Unreachable(s!(
+        "chain tip keeps moving during unspent outputs query"
+    )))
+}
+
+/// Checks unspent outputs reported by two servers which may have different
+/// chain tips, returning only the outputs confirmed by both of them.
+///
+/// Only confirmed outputs mined at or below the lower tip can be verified:
+/// unconfirmed outputs differ since servers see different mempools, and
+/// outputs from the newer blocks are not known to the lagging server yet.
+/// Such outputs are dropped from the result as unverified and are picked up
+/// by the next synchronization once both servers know about them.
+///
+/// Servers with the same tip must report the same outputs. The lagging
+/// server may also report outputs spent in the blocks it does not know yet;
+/// these are dropped as well, while an output reported only by the leading
+/// server can't be explained by the tip difference and means disagreement.
+fn reconcile_unspent(
+    first: TipUnspent,
+    second: TipUnspent,
+) -> Option<TipUnspent> {
+    let (leading, lagging) = if first.tip >= second.tip {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let common_tip = lagging.tip;
+    let confirmed = |outputs: &[ScriptUnspent]| {
+        let mut confirmed = outputs
+            .iter()
+            .filter(|output| output.height > 0 && output.height <= common_tip)
+            .copied()
+            .collect::<Vec<_>>();
+        confirmed
+            .sort_by_key(|output| (output.height, output.txid, output.vout));
+        confirmed
+    };
+    if leading.unspent.len() != lagging.unspent.len() {
+        return None;
+    }
+    let mut verified = Vec::with_capacity(leading.unspent.len());
+    for (leading_outputs, lagging_outputs) in
+        leading.unspent.iter().zip(&lagging.unspent)
+    {
+        let leading_outputs = confirmed(leading_outputs);
+        let lagging_outputs = confirmed(lagging_outputs);
+        if leading_outputs
+            .iter()
+            .any(|output| !lagging_outputs.contains(output))
+        {
+            return None;
+        }
+        if lagging_outputs
+            .iter()
+            .any(|output| !leading_outputs.contains(output))
+        {
+            if leading.tip == common_tip {
+                return None;
+            }
+            debug!(
+                "Skipping unspent outputs which may be spent after block {}",
+                common_tip
+            );
+        }
+        verified.push(leading_outputs);
+    }
+    Some(TipUnspent {
+        tip: common_tip,
+        unspent: verified,
+    })
+}
+
+impl Driver for ElectrumPool {
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let (index, info) =
+            self.failover(None, |_, server| server.subscribe_headers())?;
+        let mut state = self.state();
+        state.header_server = Some(index);
+        state.header_height = Some(info.height);
+        Ok(info)
+    }
+
+    /// Pops headers from the server providing the subscription. If it becomes
+    /// unreachable, the subscription is moved to the next server and its
+    /// chain tip is reported.
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let (header_server, last_height) = {
+            let state = self.state();
+            match state.header_height {
+                None => return Ok(None),
+                Some(height) => (state.header_server, height),
+            }
+        };
+        let (index, info) = self.failover(None, |index, server| {
+            if Some(index) == header_server {
+                return server.pop_header();
+            }
+            server.subscribe_headers().map(|info| {
+                Some(info).filter(|info| info.height != last_height)
+            })
+        })?;
+        let mut state = self.state();
+        state.header_server = Some(index);
+        if let Some(info) = info {
+            state.header_height = Some(info.height);
+        }
+        Ok(info)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.request(|_, server| server.tip_height())
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        self.request(|_, server| server.block_headers(heights))
+    }
+
+    /// In paranoid mode only the unspent outputs confirmed by both servers
+    /// are returned (see [`reconcile_unspent`])
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        if !self.paranoid {
+            return self.request(|_, server| server.scripts_unspent(scripts));
+        }
+        self.cross_checked(
+            "unspent outputs",
+            |server| {
+                tip_unspent(
+                    || server.tip_height(),
+                    || server.scripts_unspent(scripts),
+                )
+            },
+            reconcile_unspent,
+        )
+        .map(|result| result.unspent)
+    }
+
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        self.request(|_, server| server.scripts_history(scripts))
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        self.request(|_, server| server.transactions(txids))
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        self.cross_checked(
+            "transaction block position",
+            |server| server.merkle_position(txid, height),
+            |position, check| Some(position).filter(|_| position == check),
+        )
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        self.request(|_, server| server.broadcast(tx))
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        self.request(|_, server| server.estimate_fee(target_blocks))
+    }
+
+    fn scripts_status(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Option<ScriptStatus>>, Error> {
+        self.request(|_, server| server.scripts_status(scripts))
+    }
+
+    fn subscribes_scripts(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    fn output(no: u8, height: u32) -> ScriptUnspent {
+        ScriptUnspent {
+            txid: Txid::hash(&[no]),
+            vout: 0,
+            height,
+            value: 1000 * no as u64,
+        }
+    }
+
+    fn unspent(tip: u32, outputs: Vec<ScriptUnspent>) -> TipUnspent {
+        TipUnspent {
+            tip,
+            unspent: vec![outputs],
+        }
+    }
+
+    #[test]
+    fn same_tip() {
+        let outputs = vec![output(1, 10), output(2, 20), output(3, 0)];
+        let verified = reconcile_unspent(
+            unspent(20, outputs.clone()),
+            unspent(20, vec![output(2, 20), output(1, 10)]),
+        )
+        .unwrap();
+        assert_eq!(verified.unspent, vec![vec![output(1, 10), output(2, 20)]]);
+
+        // Either server may omit or add an output
+        assert!(reconcile_unspent(
+            unspent(20, outputs.clone()),
+            unspent(20, vec![output(1, 10)]),
+        )
+        .is_none());
+        assert!(reconcile_unspent(
+            unspent(20, vec![output(1, 10)]),
+            unspent(20, outputs),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn different_tips() {
+        // Output 2 is spent and output 4 is created in block 25, unknown to
+        // the lagging server
+        let leading = unspent(25, vec![output(1, 10), output(4, 25)]);
+        let lagging = unspent(20, vec![output(1, 10), output(2, 20)]);
+        let verified =
+            reconcile_unspent(lagging.clone(), leading.clone()).unwrap();
+        assert_eq!(verified.tip, 20);
+        assert_eq!(verified.unspent, vec![vec![output(1, 10)]]);
+
+        // Output from the blocks known to both servers is missing
+        let lagging = unspent(20, vec![output(2, 20)]);
+        assert!(reconcile_unspent(leading, lagging).is_none());
+    }
+
+    #[test]
+    fn tip_moves_during_query() {
+        // Block 21 arrives while the first query is processed, so the
+        // outputs are queried again for the new tip
+        let tip = Cell::new(20);
+        let queries = Cell::new(0);
+        let result = tip_unspent(
+            || Ok(tip.get()),
+            || {
+                queries.set(queries.get() + 1);
+                if queries.get() == 1 {
+                    tip.set(21);
+                }
+                Ok(vec![vec![output(1, 10), output(2, 21)]])
+            },
+        )
+        .unwrap();
+        assert_eq!(queries.get(), 2);
+        assert_eq!(result.tip, 21);
+        let verified = reconcile_unspent(
+            result,
+            unspent(21, vec![output(1, 10), output(2, 21)]),
+        )
+        .unwrap();
+        assert_eq!(verified.unspent, vec![vec![output(1, 10), output(2, 21)]]);
+
+        // Server whose tip moves during every query is skipped
+        let result = tip_unspent(
+            || Ok(tip.get()),
+            || {
+                tip.set(tip.get() + 1);
+                Ok(vec![vec![]])
+            },
+        );
+        assert!(matches!(result, Err(Error::Unreachable(_))));
+    }
+}
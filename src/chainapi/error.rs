@@ -0,0 +1,81 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use bitcoin::Txid;
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// electrum server error: {0}
+    Electrum(String),
+
+    /// blockchain data server is unreachable: {0}
+    Unreachable(String),
+
+    /// blockchain data servers disagree: {0}; one of them may be malicious
+    Mismatch(String),
+
+    /// invalid configuration of blockchain data source: {0}
+    InvalidConfig(String),
+
+    /// block at height {0} is not known
+    UnknownBlock(u32),
+
+    /// transaction {0} is not known
+    UnknownTransaction(Txid),
+
+    /// transaction {0} is rejected: {1}
+    Rejected(Txid, String),
+
+    /// P2P protocol error: {0}
+    #[cfg(feature = "compact_filters")]
+    Protocol(String),
+
+    /// HTTP request to the blockchain data server has failed: {0}
+    #[cfg(any(feature = "esplora", feature = "bitcoind"))]
+    Http(String),
+
+    /// Esplora server error: {0}
+    #[cfg(feature = "esplora")]
+    Esplora(String),
+
+    /// bitcoind RPC error: {0}
+    #[cfg(feature = "bitcoind")]
+    Bitcoind(String),
+}
+
+impl From<electrum_client::Error> for Error {
+    fn from(err: electrum_client::Error) -> Self {
+        Error::Electrum(format!("{:?}", err))
+    }
+}
+
+#[cfg(any(feature = "esplora", feature = "bitcoind"))]
+impl From<ureq::Error> for Error {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Transport(transport) => {
+                Error::Unreachable(transport.to_string())
+            }
+            err => Error::Http(err.to_string()),
+        }
+    }
+}
+
+#[cfg(any(feature = "esplora", feature = "bitcoind"))]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Http(err.to_string())
+    }
+}
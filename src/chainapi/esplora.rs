@@ -0,0 +1,498 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Blockchain data source using Esplora (or electrs) HTTP REST API
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{BlockHash, BlockHeader, Script, Transaction, Txid};
+use serde::de::DeserializeOwned;
+
+use super::proxy::http_agent;
+use super::{
+    check_onion, Driver, Error, HeaderInfo, ProxyConfig, ScriptHistory,
+    ScriptUnspent,
+};
+
+/// Timeout for a single HTTP request to the Esplora server
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of confirmed transactions returned by Esplora in a single page of
+/// the script history
+const HISTORY_PAGE_SIZE: usize = 25;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("{server}")]
+pub struct EsploraConfig {
+    /// Base URL of the Esplora API, like `https://blockstream.info/api`
+    pub server: String,
+
+    /// Proxy used for the connections to the server
+    pub proxy: Option<ProxyConfig>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+struct TxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+impl TxStatus {
+    fn height(&self) -> u32 {
+        if self.confirmed {
+            self.block_height.unwrap_or_default()
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct Utxo {
+    txid: Txid,
+    vout: u32,
+    status: TxStatus,
+    value: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct TxInfo {
+    txid: Txid,
+    status: TxStatus,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+struct MerkleProof {
+    block_height: u32,
+    pos: u32,
+}
+
+pub struct EsploraDriver {
+    agent: ureq::Agent,
+    server: String,
+
+    /// Height of the last block header reported to the subscriber
+    subscribed_height: Mutex<Option<u32>>,
+}
+
+impl EsploraDriver {
+    pub fn with(config: &EsploraConfig) -> Result<Self, Error> {
+        debug!("Connecting Esplora server at {} ...", config.server);
+        check_onion(&config.server, config.proxy.as_ref())?;
+        let driver = EsploraDriver {
+            agent: http_agent(REQUEST_TIMEOUT, config.proxy.as_ref())?,
+            server: config.server.trim_end_matches('/').to_owned(),
+            subscribed_height: Mutex::new(None),
+        };
+        let height = driver.tip_height()?;
+        debug!("Esplora server successfully connected, tip is {}", height);
+        Ok(driver)
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response, Error> {
+        let url = format!("{}/{}", self.server, path);
+        trace!("Requesting {}", url);
+        Ok(self.agent.get(&url).call()?)
+    }
+
+    fn get_text(&self, path: &str) -> Result<String, Error> {
+        Ok(self.get(path)?.into_string()?.trim().to_owned())
+    }
+
+    fn get_json<T>(&self, path: &str) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(self.get(path)?.into_json()?)
+    }
+
+    fn get_hex<T>(&self, path: &str) -> Result<T, Error>
+    where
+        T: bitcoin::consensus::Decodable,
+    {
+        let data = Vec::<u8>::from_hex(&self.get_text(path)?)
+            .map_err(|err| Error::Esplora(err.to_string()))?;
+        deserialize(&data).map_err(|err| Error::Esplora(err.to_string()))
+    }
+
+    fn header_info(&self, height: u32) -> Result<HeaderInfo, Error> {
+        let hash: BlockHash = self
+            .get_text(&format!("block-height/{}", height))?
+            .parse()
+            .map_err(|_| Error::Esplora(s!("invalid block hash")))?;
+        Ok(HeaderInfo {
+            height,
+            header: self.get_hex(&format!("block/{}/header", hash))?,
+        })
+    }
+
+    /// Esplora indexes scripts by hex-encoded SHA256 hash of the script
+    fn script_hash(script: &Script) -> String {
+        sha256::Hash::hash(script.as_bytes()).into_inner().to_hex()
+    }
+}
+
+impl Driver for EsploraDriver {
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let info = self.header_info(self.tip_height()?)?;
+        *self.subscribed_height.lock().expect("poisoned lock") =
+            Some(info.height);
+        Ok(info)
+    }
+
+    /// Esplora does not push notifications, so the chain tip is polled and
+    /// the headers above the last reported one are returned one by one
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let mut subscribed =
+            self.subscribed_height.lock().expect("poisoned lock");
+        let last = match *subscribed {
+            None => return Ok(None),
+            Some(height) => height,
+        };
+        if self.tip_height()? <= last {
+            return Ok(None);
+        }
+        let info = self.header_info(last + 1)?;
+        *subscribed = Some(info.height);
+        Ok(Some(info))
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.get_text("blocks/tip/height")?
+            .parse()
+            .map_err(|_| Error::Esplora(s!("invalid chain tip height")))
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        heights
+            .iter()
+            .map(|height| self.header_info(*height).map(|info| info.header))
+            .collect()
+    }
+
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        scripts
+            .iter()
+            .map(|script| {
+                let utxos: Vec<Utxo> = self.get_json(&format!(
+                    "scripthash/{}/utxo",
+                    Self::script_hash(script)
+                ))?;
+                Ok(utxos
+                    .into_iter()
+                    .map(|utxo| ScriptUnspent {
+                        txid: utxo.txid,
+                        vout: utxo.vout,
+                        height: utxo.status.height(),
+                        value: utxo.value,
+                    })
+                    .collect())
+            })
+            .collect()
+    }
+
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        scripts
+            .iter()
+            .map(|script| {
+                let script_hash = Self::script_hash(script);
+                // The first page contains unconfirmed transactions followed
+                // by the most recent confirmed ones; older confirmed
+                // transactions are requested page by page
+                let mut page: Vec<TxInfo> =
+                    self.get_json(&format!("scripthash/{}/txs", script_hash))?;
+                let mut history = vec![];
+                loop {
+                    let confirmed =
+                        page.iter().filter(|tx| tx.status.confirmed).count();
+                    let last = page
+                        .iter()
+                        .rev()
+                        .find(|tx| tx.status.confirmed)
+                        .map(|tx| tx.txid);
+                    history.extend(page.into_iter().map(|tx| ScriptHistory {
+                        txid: tx.txid,
+                        height: tx.status.height(),
+                    }));
+                    match last {
+                        Some(last) if confirmed >= HISTORY_PAGE_SIZE => {
+                            page = self.get_json(&format!(
+                                "scripthash/{}/txs/chain/{}",
+                                script_hash, last
+                            ))?;
+                        }
+                        _ => break,
+                    }
+                }
+                Ok(history)
+            })
+            .collect()
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        txids
+            .iter()
+            .map(|txid| self.get_hex(&format!("tx/{}/hex", txid)))
+            .collect()
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        let proof: MerkleProof =
+            self.get_json(&format!("tx/{}/merkle-proof", txid))?;
+        if proof.block_height != height {
+            return Err(Error::Esplora(format!(
+                "transaction {} is mined at height {} and not {}",
+                txid, proof.block_height, height
+            )));
+        }
+        Ok(proof.pos as u16)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        debug!("Publishing transaction to bitcoin network via Esplora server");
+        let url = format!("{}/tx", self.server);
+        self.agent
+            .post(&url)
+            .send_string(&serialize(tx).to_hex())?
+            .into_string()?
+            .trim()
+            .parse()
+            .map_err(|_| Error::Esplora(s!("invalid transaction id")))
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        let estimates: BTreeMap<String, f64> =
+            self.get_json("fee-estimates")?;
+        // Esplora gives estimations for a fixed set of targets, so the one
+        // for the closest target not exceeding the requested is used
+        Ok(estimates
+            .into_iter()
+            .filter_map(|(target, fee)| {
+                target.parse::<u16>().ok().map(|target| (target, fee))
+            })
+            .filter(|(target, _)| *target <= target_blocks)
+            .max_by_key(|(target, _)| *target)
+            .map(|(_, fee)| fee))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+    use serde_json::json;
+
+    use super::*;
+    use crate::chainapi::stand_in::HttpStandIn;
+
+    const TIP_PATH: &str = "/blocks/tip/height";
+
+    fn txid(no: u8) -> Txid {
+        Txid::hash(&[no])
+    }
+
+    fn tx_info(txid: Txid, height: Option<u32>) -> serde_json::Value {
+        json!({
+            "txid": txid.to_string(),
+            "status": {
+                "confirmed": height.is_some(),
+                "block_height": height,
+            },
+        })
+    }
+
+    fn driver(server: &HttpStandIn) -> EsploraDriver {
+        EsploraDriver::with(&EsploraConfig {
+            server: server.url(),
+            proxy: None,
+        })
+        .expect("stand-in server is reachable")
+    }
+
+    #[test]
+    fn unspent_outputs() {
+        let script = Script::new_op_return(&[1]);
+        let empty_script = Script::new_op_return(&[2]);
+        let utxo_path =
+            format!("/scripthash/{}/utxo", EsploraDriver::script_hash(&script));
+        let server = HttpStandIn::serve(move |request| {
+            if request.path == TIP_PATH {
+                return (200, s!("100"));
+            }
+            if request.path == utxo_path {
+                let utxos = json!([
+                    {
+                        "txid": txid(1).to_string(),
+                        "vout": 0,
+                        "status": { "confirmed": true, "block_height": 90 },
+                        "value": 1000,
+                    },
+                    {
+                        "txid": txid(2).to_string(),
+                        "vout": 1,
+                        "status": { "confirmed": false },
+                        "value": 2000,
+                    },
+                ]);
+                return (200, utxos.to_string());
+            }
+            if request.path.ends_with("/utxo") {
+                return (200, s!("[]"));
+            }
+            (404, String::new())
+        });
+
+        let unspent = driver(&server)
+            .scripts_unspent(&[script, empty_script])
+            .unwrap();
+        assert_eq!(
+            unspent,
+            vec![
+                vec![
+                    ScriptUnspent {
+                        txid: txid(1),
+                        vout: 0,
+                        height: 90,
+                        value: 1000,
+                    },
+                    ScriptUnspent {
+                        txid: txid(2),
+                        vout: 1,
+                        height: 0,
+                        value: 2000,
+                    },
+                ],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn history_pagination() {
+        let script = Script::new_op_return(&[1]);
+        let script_hash = EsploraDriver::script_hash(&script);
+        let first_path = format!("/scripthash/{}/txs", script_hash);
+        let next_path =
+            format!("/scripthash/{}/txs/chain/{}", script_hash, txid(25));
+        let server = HttpStandIn::serve(move |request| {
+            if request.path == TIP_PATH {
+                return (200, s!("200"));
+            }
+            if request.path == first_path {
+                let page =
+                    vec![tx_info(txid(100), None), tx_info(txid(101), None)]
+                        .into_iter()
+                        .chain(
+                            (1..=25).map(|no| {
+                                tx_info(txid(no), Some(150 - no as u32))
+                            }),
+                        )
+                        .collect::<Vec<_>>();
+                return (200, json!(page).to_string());
+            }
+            if request.path == next_path {
+                let page = (26..=28)
+                    .map(|no| tx_info(txid(no), Some(150 - no as u32)))
+                    .collect::<Vec<_>>();
+                return (200, json!(page).to_string());
+            }
+            (404, String::new())
+        });
+
+        let history = driver(&server).scripts_history(&[script]).unwrap();
+        assert_eq!(history.len(), 1);
+        let history = &history[0];
+        assert_eq!(history.len(), 30);
+        assert_eq!(
+            history[0],
+            ScriptHistory {
+                txid: txid(100),
+                height: 0
+            }
+        );
+        assert_eq!(
+            history[29],
+            ScriptHistory {
+                txid: txid(28),
+                height: 122
+            }
+        );
+        // The last page is not full, so there is no request for the next one
+        let paths = server.paths();
+        assert!(paths.iter().any(|path| path.contains("/txs/chain/")));
+        assert!(!paths
+            .iter()
+            .any(|path| path.ends_with(&format!("/txs/chain/{}", txid(28)))));
+    }
+
+    #[test]
+    fn merkle_proof() {
+        let proof_path = format!("/tx/{}/merkle-proof", txid(1));
+        let server = HttpStandIn::serve(move |request| {
+            if request.path == TIP_PATH {
+                return (200, s!("100"));
+            }
+            if request.path == proof_path {
+                let proof = json!({
+                    "block_height": 100,
+                    "merkle": [txid(2).to_string()],
+                    "pos": 7,
+                });
+                return (200, proof.to_string());
+            }
+            (404, String::new())
+        });
+
+        let driver = driver(&server);
+        assert_eq!(driver.merkle_position(&txid(1), 100).unwrap(), 7);
+        assert!(matches!(
+            driver.merkle_position(&txid(1), 99),
+            Err(Error::Esplora(_))
+        ));
+    }
+
+    #[test]
+    fn broadcast() {
+        let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let txid = tx.txid();
+        let server = HttpStandIn::serve(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", TIP_PATH) => (200, s!("100")),
+                ("POST", "/tx") => (200, txid.to_string()),
+                _ => (404, String::new()),
+            }
+        });
+
+        assert_eq!(driver(&server).broadcast(&tx).unwrap(), txid);
+        let request = server
+            .requests()
+            .into_iter()
+            .find(|request| request.method == "POST")
+            .expect("transaction is posted");
+        assert_eq!(request.body, serialize(&tx).to_hex());
+    }
+}
@@ -13,8 +13,35 @@
 
 //! Module responsible for requesting blockchain data
 
+#[cfg(feature = "bitcoind")]
+mod bitcoind;
+#[cfg(feature = "compact_filters")]
+mod compact_filters;
 mod driver;
 mod electrum;
+mod electrum_pool;
+mod error;
+#[cfg(feature = "esplora")]
+mod esplora;
+mod proxy;
+#[cfg(any(test, feature = "simulator"))]
+mod simulator;
+#[cfg(all(test, any(feature = "esplora", feature = "bitcoind")))]
+mod stand_in;
 
-pub use driver::Driver;
+#[cfg(feature = "bitcoind")]
+pub use bitcoind::{BitcoindConfig, BitcoindDriver};
+#[cfg(feature = "compact_filters")]
+pub use compact_filters::{CompactFilterConfig, CompactFilterDriver};
+pub use driver::{
+    script_status, Driver, HeaderInfo, ScriptHistory, ScriptStatus,
+    ScriptUnspent,
+};
 pub use electrum::{ElectrumConfig, ElectrumDriver};
+pub use electrum_pool::{ElectrumPool, ElectrumPoolConfig};
+pub use error::Error;
+#[cfg(feature = "esplora")]
+pub use esplora::{EsploraConfig, EsploraDriver};
+pub use proxy::{check_onion, ProxyConfig};
+#[cfg(any(test, feature = "simulator"))]
+pub use simulator::RegtestSimulator;
@@ -0,0 +1,114 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! SOCKS5 proxy, like Tor, used for the connections to the sources of
+//! blockchain data. Host names are resolved by the proxy, so Tor onion
+//! service addresses can be used for the servers.
+
+use std::net::TcpStream;
+#[cfg(any(feature = "esplora", feature = "bitcoind"))]
+use std::time::Duration;
+
+use socks::Socks5Stream;
+
+use super::Error;
+
+/// Domain of the Tor onion service addresses
+const ONION_DOMAIN: &str = ".onion";
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("socks5://{addr}")]
+pub struct ProxyConfig {
+    /// Proxy address in `host:port` form
+    pub addr: String,
+
+    /// Stream isolation key. Tor sends connections made with different keys
+    /// over different circuits, so the servers can't link them together.
+    pub isolation: Option<String>,
+}
+
+impl ProxyConfig {
+    /// SOCKS5 credentials representing the stream isolation key
+    pub fn credentials(&self) -> Option<(String, String)> {
+        self.isolation
+            .as_ref()
+            .map(|key| (format!("citadel-{}", key), key.clone()))
+    }
+
+    /// Connects to the target `host:port` over the proxy
+    pub fn connect(&self, target: &str) -> Result<TcpStream, Error> {
+        let stream = match self.credentials() {
+            Some((username, password)) => Socks5Stream::connect_with_password(
+                self.addr.as_str(),
+                target,
+                &username,
+                &password,
+            ),
+            None => Socks5Stream::connect(self.addr.as_str(), target),
+        }
+        .map_err(|err| {
+            Error::Unreachable(format!("{} via {}: {}", target, self, err))
+        })?;
+        Ok(stream.into_inner())
+    }
+
+    /// Proxy URL used by HTTP clients
+    #[cfg(any(feature = "esplora", feature = "bitcoind"))]
+    fn url(&self) -> String {
+        match self.credentials() {
+            Some((username, password)) => {
+                format!("socks5://{}:{}@{}", username, password, self.addr)
+            }
+            None => format!("socks5://{}", self.addr),
+        }
+    }
+}
+
+/// Checks that onion service addresses of the servers are used only with a
+/// proxy
+pub fn check_onion(
+    server: &str,
+    proxy: Option<&ProxyConfig>,
+) -> Result<(), Error> {
+    if proxy.is_some() {
+        return Ok(());
+    }
+    let authority = server
+        .splitn(2, "://")
+        .last()
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or(server);
+    let host = authority.rsplitn(2, ':').last().unwrap_or(authority);
+    if host.ends_with(ONION_DOMAIN) {
+        return Err(Error::InvalidConfig(format!(
+            "onion service {} can be reached only via Tor proxy",
+            server
+        )));
+    }
+    Ok(())
+}
+
+/// Creates HTTP client sending requests via the proxy, if one is given
+#[cfg(any(feature = "esplora", feature = "bitcoind"))]
+pub(super) fn http_agent(
+    timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<ureq::Agent, Error> {
+    let mut builder = ureq::AgentBuilder::new().timeout(timeout);
+    if let Some(proxy) = proxy {
+        let proxy = ureq::Proxy::new(proxy.url())
+            .map_err(|err| Error::InvalidConfig(err.to_string()))?;
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build())
+}
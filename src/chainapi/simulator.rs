@@ -0,0 +1,423 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Deterministic in-process simulation of a regtest chain, used as a source
+//! of blockchain data for testing the runtime without network access.
+//!
+//! The simulator is shared between its clones, so a test may keep one of
+//! them for mining blocks, paying to scripts and triggering reorganizations
+//! while the runtime uses the other one as its [`Driver`].
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction,
+    TxIn, TxMerkleNode, TxOut, Txid,
+};
+
+use super::{Driver, Error, HeaderInfo, ScriptHistory, ScriptUnspent};
+
+/// Block subsidy paid by the simulated coinbase transactions
+const BLOCK_SUBSIDY: u64 = 50_0000_0000;
+
+/// Interval between the timestamps of the simulated blocks
+const BLOCK_INTERVAL: u32 = 600;
+
+/// Fee rate reported by the simulator unless set to a different value
+const DEFAULT_FEE_RATE: f64 = 1.0;
+
+struct ChainState {
+    /// Blocks of the active chain, indexed by their height
+    blocks: Vec<Block>,
+
+    /// Unconfirmed transactions, in the order they were added
+    mempool: Vec<Transaction>,
+
+    /// Height of the last block header reported to the subscriber
+    subscribed_height: Option<u32>,
+
+    /// Number of the performed reorganizations, making blocks mined after
+    /// each of them distinct from the replaced ones
+    generation: u32,
+
+    /// Number of the funding transactions created by the simulator
+    funded: u64,
+
+    /// Script receiving block subsidy
+    miner_script: Script,
+
+    fee_rate: Option<f64>,
+}
+
+impl ChainState {
+    fn tip_height(&self) -> u32 {
+        self.blocks.len() as u32 - 1
+    }
+
+    fn header_info(&self, height: u32) -> Result<HeaderInfo, Error> {
+        self.blocks
+            .get(height as usize)
+            .map(|block| HeaderInfo {
+                height,
+                header: block.header,
+            })
+            .ok_or(Error::UnknownBlock(height))
+    }
+
+    /// All known transactions with the height of the block mining them;
+    /// mempool transactions have zero height
+    fn transactions(&self) -> impl Iterator<Item = (u32, &Transaction)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| {
+                block.txdata.iter().map(move |tx| (height as u32, tx))
+            })
+            .chain(self.mempool.iter().map(|tx| (0, tx)))
+    }
+
+    fn unspent(&self) -> BTreeMap<OutPoint, (u32, TxOut)> {
+        let mut unspent = BTreeMap::new();
+        for (height, tx) in self.transactions() {
+            for input in &tx.input {
+                unspent.remove(&input.previous_output);
+            }
+            let txid = tx.txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                unspent.insert(
+                    OutPoint::new(txid, vout as u32),
+                    (height, output.clone()),
+                );
+            }
+        }
+        unspent
+    }
+
+    fn mine_block(&mut self) -> BlockHash {
+        let height = self.blocks.len() as u32;
+        let prev = self.blocks[height as usize - 1].header;
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(self.generation as i64)
+                    .into_script(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: BLOCK_SUBSIDY,
+                script_pubkey: self.miner_script.clone(),
+            }],
+        };
+        let mut txdata = vec![coinbase];
+        txdata.extend(self.mempool.drain(..));
+        // Simulated blocks commit to their transactions with a plain hash
+        // of the transaction ids instead of the merkle tree root
+        let txids = txdata
+            .iter()
+            .flat_map(|tx| tx.txid().into_inner().to_vec())
+            .collect::<Vec<_>>();
+        let header = BlockHeader {
+            version: prev.version,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::hash(&txids),
+            time: prev.time + BLOCK_INTERVAL,
+            bits: prev.bits,
+            nonce: self.generation,
+        };
+        let hash = header.block_hash();
+        self.blocks.push(Block { header, txdata });
+        hash
+    }
+}
+
+/// Simulated regtest chain
+#[derive(Clone)]
+pub struct RegtestSimulator {
+    state: Arc<Mutex<ChainState>>,
+}
+
+impl Default for RegtestSimulator {
+    fn default() -> Self {
+        RegtestSimulator::new()
+    }
+}
+
+impl RegtestSimulator {
+    /// Creates a chain containing only the regtest genesis block
+    pub fn new() -> RegtestSimulator {
+        RegtestSimulator {
+            state: Arc::new(Mutex::new(ChainState {
+                blocks: vec![genesis_block(Network::Regtest)],
+                mempool: vec![],
+                subscribed_height: None,
+                generation: 0,
+                funded: 0,
+                miner_script: Script::new(),
+                fee_rate: Some(DEFAULT_FEE_RATE),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<ChainState> {
+        self.state.lock().expect("poisoned simulator state")
+    }
+
+    /// Height of the chain tip
+    pub fn height(&self) -> u32 {
+        self.state().tip_height()
+    }
+
+    /// Sets the script receiving block subsidy of the blocks mined later
+    pub fn set_miner_script(&self, script: Script) {
+        self.state().miner_script = script;
+    }
+
+    /// Sets the fee rate, in satoshis per virtual byte, reported for all
+    /// confirmation targets; `None` makes fee estimation unavailable
+    pub fn set_fee_rate(&self, fee_rate: Option<f64>) {
+        self.state().fee_rate = fee_rate;
+    }
+
+    /// Mines blocks on top of the chain tip; the first of them includes all
+    /// mempool transactions. Returns hashes of the mined blocks.
+    pub fn mine(&self, count: u32) -> Vec<BlockHash> {
+        let mut state = self.state();
+        (0..count).map(|_| state.mine_block()).collect()
+    }
+
+    /// Adds a transaction paying the given amount to the script into the
+    /// mempool. The transaction spends an output which does not exist in the
+    /// simulated chain.
+    pub fn pay_to(&self, script: Script, value: u64) -> Txid {
+        let mut state = self.state();
+        state.funded += 1;
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(
+                    Txid::hash(&state.funded.to_le_bytes()),
+                    0,
+                ),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        };
+        let txid = tx.txid();
+        state.mempool.push(tx);
+        txid
+    }
+
+    /// Adds a transaction into the mempool without checking its inputs
+    pub fn add_transaction(&self, tx: Transaction) -> Txid {
+        let txid = tx.txid();
+        self.state().mempool.push(tx);
+        txid
+    }
+
+    /// Replaces the given number of blocks at the chain tip with a longer
+    /// chain, mining one block more than it was disconnected. Transactions
+    /// from the disconnected blocks are returned into the mempool and get
+    /// mined again, except the ones listed in `drop`. Returns hashes of the
+    /// new blocks.
+    pub fn reorg(&self, depth: u32, drop: &[Txid]) -> Vec<BlockHash> {
+        let mut state = self.state();
+        let fork_height = (state.tip_height() + 1).saturating_sub(depth).max(1);
+        let disconnected = state.blocks.split_off(fork_height as usize);
+        let mut mempool = disconnected
+            .into_iter()
+            .flat_map(|block| block.txdata.into_iter().skip(1))
+            .filter(|tx| !drop.contains(&tx.txid()))
+            .collect::<Vec<_>>();
+        mempool.extend(state.mempool.drain(..));
+        state.mempool = mempool;
+        state.generation += 1;
+        if let Some(height) = state.subscribed_height {
+            state.subscribed_height = Some(height.min(fork_height - 1));
+        }
+        (0..=depth).map(|_| state.mine_block()).collect()
+    }
+}
+
+impl Driver for RegtestSimulator {
+    fn subscribe_headers(&self) -> Result<HeaderInfo, Error> {
+        let mut state = self.state();
+        let height = state.tip_height();
+        state.subscribed_height = Some(height);
+        state.header_info(height)
+    }
+
+    fn pop_header(&self) -> Result<Option<HeaderInfo>, Error> {
+        let mut state = self.state();
+        let next = match state.subscribed_height {
+            Some(height) if height < state.tip_height() => height + 1,
+            _ => return Ok(None),
+        };
+        state.subscribed_height = Some(next);
+        state.header_info(next).map(Some)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        Ok(self.state().tip_height())
+    }
+
+    fn block_headers(
+        &self,
+        heights: &[u32],
+    ) -> Result<Vec<BlockHeader>, Error> {
+        let state = self.state();
+        heights
+            .iter()
+            .map(|height| state.header_info(*height).map(|info| info.header))
+            .collect()
+    }
+
+    fn scripts_unspent(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptUnspent>>, Error> {
+        let unspent = self.state().unspent();
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                unspent
+                    .iter()
+                    .filter(|(_, (_, output))| &output.script_pubkey == script)
+                    .map(|(outpoint, (height, output))| ScriptUnspent {
+                        txid: outpoint.txid,
+                        vout: outpoint.vout,
+                        height: *height,
+                        value: output.value,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn scripts_history(
+        &self,
+        scripts: &[Script],
+    ) -> Result<Vec<Vec<ScriptHistory>>, Error> {
+        let state = self.state();
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                let mut funded = vec![];
+                let mut history = vec![];
+                for (height, tx) in state.transactions() {
+                    let txid = tx.txid();
+                    let spends = tx
+                        .input
+                        .iter()
+                        .any(|input| funded.contains(&input.previous_output));
+                    let mut pays = false;
+                    for (vout, output) in tx.output.iter().enumerate() {
+                        if &output.script_pubkey == script {
+                            funded.push(OutPoint::new(txid, vout as u32));
+                            pays = true;
+                        }
+                    }
+                    if spends || pays {
+                        history.push(ScriptHistory { txid, height });
+                    }
+                }
+                history
+            })
+            .collect())
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        let state = self.state();
+        txids
+            .iter()
+            .map(|txid| {
+                state
+                    .transactions()
+                    .find(|(_, tx)| tx.txid() == *txid)
+                    .map(|(_, tx)| tx.clone())
+                    .ok_or(Error::UnknownTransaction(*txid))
+            })
+            .collect()
+    }
+
+    fn merkle_position(&self, txid: &Txid, height: u32) -> Result<u16, Error> {
+        let state = self.state();
+        let block = state
+            .blocks
+            .get(height as usize)
+            .ok_or(Error::UnknownBlock(height))?;
+        block
+            .txdata
+            .iter()
+            .position(|tx| tx.txid() == *txid)
+            .map(|pos| pos as u16)
+            .ok_or(Error::UnknownTransaction(*txid))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let mut state = self.state();
+        let txid = tx.txid();
+        if state.transactions().any(|(_, known)| known.txid() == txid) {
+            return Ok(txid);
+        }
+        let unspent = state.unspent();
+        let mut input_value = 0u64;
+        for (no, input) in tx.input.iter().enumerate() {
+            if tx.input[..no]
+                .iter()
+                .any(|prev| prev.previous_output == input.previous_output)
+            {
+                return Err(Error::Rejected(txid, s!("duplicate input")));
+            }
+            match unspent.get(&input.previous_output) {
+                Some((_, output)) => input_value += output.value,
+                None => {
+                    return Err(Error::Rejected(
+                        txid,
+                        format!(
+                            "input {} is missing or spent",
+                            input.previous_output
+                        ),
+                    ))
+                }
+            }
+        }
+        let output_value: u64 = tx.output.iter().map(|out| out.value).sum();
+        if output_value > input_value {
+            return Err(Error::Rejected(
+                txid,
+                s!("outputs exceed value of the inputs"),
+            ));
+        }
+        state.mempool.push(tx.clone());
+        Ok(txid)
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<f64>, Error> {
+        Ok(self.state().fee_rate)
+    }
+}
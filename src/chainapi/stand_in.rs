@@ -0,0 +1,133 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Loopback HTTP server standing in for the blockchain data sources in the
+//! driver tests. Each connection serves a single request, whose response is
+//! produced by the handler given by the test; all served requests are
+//! recorded for the later inspection.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(super) struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+pub(super) struct HttpStandIn {
+    port: u16,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl HttpStandIn {
+    /// Starts the server in a background thread; the handler returns HTTP
+    /// status and JSON or plain text body of the response
+    pub fn serve<F>(handler: F) -> HttpStandIn
+    where
+        F: Fn(&HttpRequest) -> (u16, String) + Send + 'static,
+    {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("loopback is available");
+        let port = listener.local_addr().expect("bound listener").port();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                if let Some(request) = read_request(&stream) {
+                    let (status, body) = handler(&request);
+                    log.lock().expect("poisoned request log").push(request);
+                    write_response(stream, status, &body);
+                }
+            }
+        });
+        HttpStandIn { port, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().expect("poisoned request log").clone()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .map(|request| request.path)
+            .collect()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<HttpRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut split = line.split_whitespace();
+    let method = split.next()?.to_owned();
+    let path = split.next()?.to_owned();
+
+    let mut len = 0usize;
+    let mut authorization = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut split = header.splitn(2, ':');
+        let name = split.next()?.trim().to_lowercase();
+        let value = split.next().unwrap_or_default().trim().to_owned();
+        match name.as_str() {
+            "content-length" => len = value.parse().ok()?,
+            "authorization" => authorization = Some(value),
+            _ => {}
+        }
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).ok()?;
+    Some(HttpRequest {
+        method,
+        path,
+        authorization,
+        body: String::from_utf8(body).ok()?,
+    })
+}
+
+fn write_response(mut stream: TcpStream, status: u16, body: &str) {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Error",
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    let _ = stream.flush();
+}
@@ -12,5 +12,14 @@
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 //! Module responsible for monitoring new chain data. It operates as a
-//! standalone runtime (running as a thread/daemon) listening for incoming push
-//! notifications for Electrum or Bitcoin Core
+//! standalone service running in a background thread, which receives new
+//! block headers and script status changes from the source of blockchain
+//! data. Contracts with changed script statuses are synchronized by sending
+//! requests to the runtime RPC API, so the runtime cache stays up to date
+//! without any client action.
+
+mod service;
+mod watchlist;
+
+pub use service::{spawn, ChainWatch};
+pub use watchlist::{ScriptStatuses, WatchList};
@@ -0,0 +1,221 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use internet2::{
+    session, zmqsocket, CreateUnmarshaller, PlainTranscoder, Session,
+    TypedEnum, Unmarshall, Unmarshaller, ZmqSocketAddr, ZmqType,
+};
+use microservices::node::TryService;
+
+use super::WatchList;
+use crate::chainapi;
+use crate::model::ContractId;
+use crate::rpc::{message, Reply, Request};
+use crate::Error;
+
+/// Interval between the checks for new blocks and script status changes
+/// received via subscriptions
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval between the checks for unconfirmed transactions with the sources
+/// of blockchain data which compute script statuses from the script history.
+/// With such sources script statuses are checked on each new block and with
+/// this interval otherwise.
+const HISTORY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts chainwatch service in a background thread. The service sends
+/// contract synchronization requests to the runtime RPC API at the given
+/// endpoint.
+pub fn spawn(
+    rpc_endpoint: ZmqSocketAddr,
+    watchlist: WatchList,
+) -> Result<thread::JoinHandle<()>, Error> {
+    Ok(thread::Builder::new()
+        .name(s!("chainwatch"))
+        .spawn(move || match ChainWatch::with(&rpc_endpoint, watchlist) {
+            Ok(service) => service.run_or_panic("chainwatch"),
+            Err(err) => error!("Unable to start chainwatch: {}", err),
+        })?)
+}
+
+pub struct ChainWatch {
+    /// Scripts to watch and the source of the blockchain data
+    watchlist: WatchList,
+
+    /// Runtime RPC API endpoint
+    rpc_endpoint: ZmqSocketAddr,
+
+    /// Client session with the runtime RPC API
+    session_rpc: session::Raw<PlainTranscoder, zmqsocket::Connection>,
+
+    /// Unmarshaller instance used for parsing RPC replies
+    unmarshaller: Unmarshaller<Reply>,
+
+    /// Source of the blockchain data which the service is subscribed to for
+    /// the block header notifications
+    subscribed: Option<Arc<dyn chainapi::Driver>>,
+
+    /// Time of the last check of the script statuses
+    checked: Option<Instant>,
+}
+
+impl ChainWatch {
+    pub fn with(
+        rpc_endpoint: &ZmqSocketAddr,
+        watchlist: WatchList,
+    ) -> Result<Self, Error> {
+        debug!("Connecting chainwatch to RPC API socket {}", rpc_endpoint);
+        Ok(ChainWatch {
+            watchlist,
+            rpc_endpoint: rpc_endpoint.clone(),
+            session_rpc: connect(rpc_endpoint)?,
+            unmarshaller: Reply::create_unmarshaller(),
+            subscribed: None,
+            checked: None,
+        })
+    }
+}
+
+fn connect(
+    rpc_endpoint: &ZmqSocketAddr,
+) -> Result<session::Raw<PlainTranscoder, zmqsocket::Connection>, Error> {
+    Ok(session::Raw::with_zmq_unencrypted(
+        ZmqType::Req,
+        rpc_endpoint,
+        None,
+        None,
+    )?)
+}
+
+impl TryService for ChainWatch {
+    type ErrorType = Error;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        info!("Chainwatch service started");
+        loop {
+            // Failures of the blockchain data source are temporary, so the
+            // service keeps running and retries on the next check
+            if let Err(err) = self.check_chain() {
+                warn!("Chainwatch check has failed: {}", err);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl ChainWatch {
+    /// Receives new block headers and synchronizes the contracts with
+    /// script statuses different from the ones at the time of the last
+    /// contract synchronization. The service is the only consumer of the
+    /// block header notifications while it runs, reporting the height of the
+    /// received blocks to the runtime via the watch list.
+    fn check_chain(&mut self) -> Result<(), Error> {
+        let chain = match self.watchlist.chain() {
+            None => return Ok(()),
+            Some(chain) => chain,
+        };
+        let mut new_block = false;
+        let subscribed = self.subscribed.as_ref().map_or(false, |known| {
+            // Comparing data pointers only, since vtable pointers of the same
+            // object may differ
+            Arc::as_ptr(known) as *const () == Arc::as_ptr(&chain) as *const ()
+        });
+        if !subscribed {
+            // Source of the blockchain data changes with the active wallet
+            let info = chain.subscribe_headers()?;
+            debug!("Subscribed to new blocks; chain tip is {}", info.height);
+            new_block = info.height != self.watchlist.height();
+            self.watchlist.set_height(info.height);
+            self.subscribed = Some(chain.clone());
+        }
+        while let Some(info) = chain.pop_header()? {
+            debug!("New block at height {}", info.height);
+            self.watchlist.set_height(info.height);
+            new_block = true;
+        }
+
+        let due = match self.checked {
+            None => true,
+            Some(_) if new_block || chain.subscribes_scripts() => true,
+            Some(checked) => checked.elapsed() >= HISTORY_POLL_INTERVAL,
+        };
+        if !due {
+            return Ok(());
+        }
+        self.checked = Some(Instant::now());
+
+        let watched = self.watchlist.scripts();
+        let scripts = watched
+            .values()
+            .flat_map(|statuses| statuses.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        trace!("Checking statuses of {} watched scripts", scripts.len());
+        let statuses = chain.scripts_status(&scripts)?;
+        let current = scripts
+            .into_iter()
+            .zip(statuses)
+            .collect::<BTreeMap<_, _>>();
+
+        for (contract_id, known) in watched {
+            if known
+                .iter()
+                .any(|(script, status)| current.get(script) != Some(status))
+            {
+                self.sync_contract(contract_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_contract(&mut self, contract_id: ContractId) -> Result<(), Error> {
+        debug!(
+            "Contract {} has new transactions; synchronizing",
+            contract_id
+        );
+        let request =
+            Request::SyncContractGap(message::SyncContractGapRequest {
+                contract_id,
+                gap_limit: None,
+            });
+        let raw = match self
+            .session_rpc
+            .send_raw_message(&request.serialize())
+            .and_then(|_| self.session_rpc.recv_raw_message())
+        {
+            Ok(raw) => raw,
+            Err(err) => {
+                // REQ socket does not accept a new request until it receives
+                // the reply to the previous one, so the session is recreated
+                debug!("Reconnecting chainwatch to RPC API socket");
+                self.session_rpc = connect(&self.rpc_endpoint)?;
+                return Err(err.into());
+            }
+        };
+        match &*self.unmarshaller.unmarshall(&raw)? {
+            Reply::Failure(failure) => warn!(
+                "Synchronization of contract {} has failed: {}",
+                contract_id, failure
+            ),
+            _ => info!("Contract {} is synchronized", contract_id),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::Script;
+
+use crate::chainapi::{self, ScriptStatus};
+use crate::model::ContractId;
+
+/// Statuses of the contract scripts known to the runtime cache
+pub type ScriptStatuses = BTreeMap<Script, Option<ScriptStatus>>;
+
+#[derive(Clone, Default)]
+struct WatchState {
+    /// Source of the blockchain data of the active wallet
+    chain: Option<Arc<dyn chainapi::Driver>>,
+
+    /// Height of the last block header received by the chainwatch service
+    height: u32,
+
+    /// Scripts of the active wallet contracts with their statuses at the
+    /// time of the last contract synchronization
+    scripts: BTreeMap<ContractId, ScriptStatuses>,
+}
+
+/// Contract scripts watched by the chainwatch service, shared between the
+/// runtime and the service threads. The runtime updates the scripts after
+/// each change of the contract data, records script statuses on each
+/// contract synchronization and provides the source of blockchain data of
+/// the active wallet, while the service, being the only consumer of the block
+/// header notifications, reports the height of the last received block.
+///
+/// Scripts which were not checked by a synchronization during the runtime
+/// lifetime are assumed to have no transactions, so the contracts with used
+/// scripts are synchronized by the service once after the runtime start.
+#[derive(Clone, Default)]
+pub struct WatchList(Arc<Mutex<WatchState>>);
+
+impl WatchList {
+    fn state(&self) -> MutexGuard<WatchState> {
+        self.0.lock().expect("poisoned chainwatch list")
+    }
+
+    /// Source of the blockchain data of the active wallet, if provided by
+    /// the runtime
+    pub fn chain(&self) -> Option<Arc<dyn chainapi::Driver>> {
+        self.state().chain.clone()
+    }
+
+    pub fn set_chain(&self, chain: Arc<dyn chainapi::Driver>) {
+        self.state().chain = Some(chain);
+    }
+
+    /// Height of the last block header received by the chainwatch service
+    pub fn height(&self) -> u32 {
+        self.state().height
+    }
+
+    pub fn set_height(&self, height: u32) {
+        self.state().height = height;
+    }
+
+    /// Replaces the set of scripts watched for the contract, keeping known
+    /// statuses of the scripts which remain watched
+    pub fn watch(&self, contract_id: ContractId, scripts: BTreeSet<Script>) {
+        let mut state = self.state();
+        let known = state.scripts.remove(&contract_id).unwrap_or_default();
+        let statuses = scripts
+            .into_iter()
+            .map(|script| {
+                let status = known.get(&script).copied().flatten();
+                (script, status)
+            })
+            .collect();
+        state.scripts.insert(contract_id, statuses);
+    }
+
+    /// Records statuses of the watched contract scripts at the time of the
+    /// contract synchronization
+    pub fn set_statuses(
+        &self,
+        contract_id: ContractId,
+        statuses: &ScriptStatuses,
+    ) {
+        if let Some(known) = self.state().scripts.get_mut(&contract_id) {
+            for (script, status) in known.iter_mut() {
+                if let Some(synced) = statuses.get(script) {
+                    *status = *synced;
+                }
+            }
+        }
+    }
+
+    pub fn unwatch(&self, contract_id: ContractId) {
+        self.state().scripts.remove(&contract_id);
+    }
+
+    /// Stops watching all contracts
+    pub fn clear(&self) {
+        self.state().scripts.clear();
+    }
+
+    pub fn scripts(&self) -> BTreeMap<ContractId, ScriptStatuses> {
+        self.state().scripts.clone()
+    }
+}
@@ -12,6 +12,8 @@
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
 use std::str::FromStr;
 
 use bitcoin::Txid;
@@ -26,12 +28,12 @@ use lnpbp::chain::{AssetId, Chain};
 use microservices::rpc::Failure;
 use rgb::{AtomicValue, Consignment, Genesis};
 use wallet::descriptors::{self, ContentType};
-use wallet::hd::{PubkeyChain, UnhardenedIndex};
+use wallet::hd::UnhardenedIndex;
 use wallet::psbt::Psbt;
 use wallet::scripts::PubkeyScript;
 
 use super::Config;
-use crate::model::ContractId;
+use crate::model::{ContractId, LabelRef};
 use crate::rpc::{message, Reply, Request};
 use crate::Error;
 
@@ -47,6 +49,9 @@ pub struct Client {
     config: Config,
     session_rpc: session::Raw<PlainTranscoder, zmqsocket::Connection>,
     unmarshaller: Unmarshaller<Reply>,
+    /// Wallet which wallet-specific requests of this client are addressed
+    /// to; the wallet active at the runtime is used if `None`
+    wallet: Option<String>,
 }
 
 impl Client {
@@ -63,10 +68,31 @@ impl Client {
             config,
             session_rpc,
             unmarshaller: Reply::create_unmarshaller(),
+            wallet: None,
         })
     }
 
+    /// Wallet which wallet-specific requests of this client are addressed to
+    pub fn wallet(&self) -> Option<&str> {
+        self.wallet.as_deref()
+    }
+
+    /// Addresses further wallet-specific requests of this client to the open
+    /// wallet, or to the wallet active at the runtime if `None`
+    pub fn set_wallet(&mut self, name: Option<impl ToString>) {
+        self.wallet = name.map(|name| name.to_string());
+    }
+
     pub fn request(&mut self, request: Request) -> Result<Reply, Error> {
+        let request = match self.wallet {
+            Some(ref wallet) if !request.is_wallet_independent() => {
+                Request::InWallet(message::WalletRequest {
+                    wallet: wallet.clone(),
+                    request: request.serialize(),
+                })
+            }
+            _ => request,
+        };
         trace!("Sending request to the server: {:?}", request);
         let data = request.serialize();
         trace!("Raw request data ({} bytes): {:02X?}", data.len(), data);
@@ -81,21 +107,157 @@ impl Client {
 }
 
 impl Client {
+    pub fn storage_unlock(
+        &mut self,
+        passphrase: impl ToString,
+    ) -> Result<Reply, Error> {
+        self.request(Request::UnlockStorage(message::Passphrase::from(
+            passphrase.to_string(),
+        )))
+    }
+
+    /// Creates new encrypted storage protected with the passphrase
+    pub fn storage_init(
+        &mut self,
+        passphrase: impl ToString,
+    ) -> Result<Reply, Error> {
+        self.request(Request::InitStorage(message::Passphrase::from(
+            passphrase.to_string(),
+        )))
+    }
+
+    pub fn storage_lock(&mut self) -> Result<Reply, Error> {
+        self.request(Request::LockStorage)
+    }
+
+    pub fn storage_change_passphrase(
+        &mut self,
+        old_passphrase: impl ToString,
+        new_passphrase: impl ToString,
+    ) -> Result<Reply, Error> {
+        self.request(Request::ChangePassphrase(
+            message::ChangePassphraseRequest {
+                old_passphrase: old_passphrase.to_string().into(),
+                new_passphrase: new_passphrase.to_string().into(),
+            },
+        ))
+    }
+
+    /// Exports wallet backup into the archive file at the client side,
+    /// replacing the existing file atomically
+    pub fn backup_export(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<impl ToString>,
+        include_stash: bool,
+    ) -> Result<Reply, Error> {
+        match self.request(Request::ExportBackup(
+            message::ExportBackupRequest {
+                passphrase: passphrase.map(|p| p.to_string().into()),
+                include_stash,
+            },
+        ))? {
+            Reply::Backup(archive) => {
+                let path = path.as_ref();
+                let mut tmp = path.as_os_str().to_owned();
+                tmp.push(".tmp");
+                fs::write(&tmp, archive)?;
+                fs::File::open(&tmp)?.sync_all()?;
+                fs::rename(&tmp, path)?;
+                Ok(Reply::Success)
+            }
+            Reply::Failure(failure) => Err(failure.into()),
+            _ => Err(Error::UnexpectedApi),
+        }
+    }
+
+    /// Imports wallet backup from the archive file at the client side
+    pub fn backup_import(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: Option<impl ToString>,
+        mode: message::RestoreMode,
+        restore_stash: bool,
+    ) -> Result<Reply, Error> {
+        let archive = fs::read(path)?;
+        self.request(Request::ImportBackup(message::ImportBackupRequest {
+            archive,
+            passphrase: passphrase.map(|p| p.to_string().into()),
+            mode,
+            restore_stash,
+        }))
+    }
+
+    pub fn wallet_list(&mut self) -> Result<Reply, Error> {
+        self.request(Request::ListWallets)
+    }
+
+    /// Creates new wallet, making it active at the runtime and addressing
+    /// further wallet-specific requests of this client to it
+    pub fn wallet_create(
+        &mut self,
+        name: impl ToString,
+    ) -> Result<Reply, Error> {
+        let name = name.to_string();
+        let reply = self.request(Request::CreateWallet(name.clone()))?;
+        if let Reply::Success = reply {
+            self.wallet = Some(name);
+        }
+        Ok(reply)
+    }
+
+    /// Opens the wallet, making it active at the runtime and addressing
+    /// further wallet-specific requests of this client to it
+    pub fn wallet_open(&mut self, name: impl ToString) -> Result<Reply, Error> {
+        let name = name.to_string();
+        let reply = self.request(Request::OpenWallet(name.clone()))?;
+        if let Reply::Success = reply {
+            self.wallet = Some(name);
+        }
+        Ok(reply)
+    }
+
+    pub fn wallet_close(
+        &mut self,
+        name: impl ToString,
+    ) -> Result<Reply, Error> {
+        let name = name.to_string();
+        let reply = self.request(Request::CloseWallet(name.clone()))?;
+        if let Reply::Success = reply {
+            if self.wallet.as_ref() == Some(&name) {
+                self.wallet = None;
+            }
+        }
+        Ok(reply)
+    }
+
+    pub fn wallet_delete(
+        &mut self,
+        name: impl ToString,
+    ) -> Result<Reply, Error> {
+        self.request(Request::DeleteWallet(name.to_string()))
+    }
+
     pub fn contract_list(&mut self) -> Result<Reply, Error> {
         self.request(Request::ListContracts)
     }
 
+    /// Creates single-signature contract. Public key chain may be given
+    /// either as [`wallet::hd::PubkeyChain`] or as a string, which may use
+    /// `<0;1>` multipath derivation step for the receive and change branches.
     pub fn single_sig_create(
         &mut self,
         name: impl ToString,
-        pubkey_chain: PubkeyChain,
+        pubkey_chain: impl ToString,
         category: ContentType,
     ) -> Result<Reply, Error> {
-        self.request(Request::CreateSingleSig(message::SingleSigInfo {
-            name: name.to_string(),
-            pubkey_chain,
-            category,
-        }))
+        self.request(Request::CreateSingleSigDescriptor(
+            message::SingleSigDescriptorInfo {
+                name: name.to_string(),
+                pubkey_chain: pubkey_chain.to_string(),
+                category,
+            },
+        ))
     }
 
     pub fn contract_operations(
@@ -105,6 +267,13 @@ impl Client {
         self.request(Request::ContractOperations(contract_id))
     }
 
+    pub fn contract_operations_query(
+        &mut self,
+        query: message::OperationsQuery,
+    ) -> Result<Reply, Error> {
+        self.request(Request::QueryOperations(query))
+    }
+
     pub fn contract_rename(
         &mut self,
         contract_id: ContractId,
@@ -123,6 +292,91 @@ impl Client {
         self.request(Request::DeleteContract(contract_id))
     }
 
+    /// Deletes (archives) the contract even if it still has funded outputs
+    pub fn contract_force_delete(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Reply, Error> {
+        self.request(Request::ForceDeleteContract(contract_id))
+    }
+
+    pub fn reorg_list(&mut self) -> Result<Reply, Error> {
+        self.request(Request::ListReorgs)
+    }
+
+    pub fn contract_list_archived(&mut self) -> Result<Reply, Error> {
+        self.request(Request::ListArchivedContracts)
+    }
+
+    pub fn contract_restore(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Reply, Error> {
+        self.request(Request::RestoreContract(contract_id))
+    }
+
+    pub fn label_list(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Reply, Error> {
+        self.request(Request::ListLabels(contract_id))
+    }
+
+    pub fn label_get(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+    ) -> Result<Reply, Error> {
+        self.request(Request::GetLabel(message::ContractLabelRef {
+            contract_id,
+            reference,
+        }))
+    }
+
+    pub fn label_set(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<impl ToString>,
+    ) -> Result<Reply, Error> {
+        self.request(Request::SetLabel(message::SetLabelRequest {
+            contract_id,
+            reference,
+            label: label.map(|label| label.to_string()),
+        }))
+    }
+
+    /// Exports labels into the BIP-329 JSON lines file at the client side
+    pub fn labels_export(
+        &mut self,
+        path: impl AsRef<Path>,
+        contract_id: Option<ContractId>,
+    ) -> Result<Reply, Error> {
+        match self.request(Request::ExportLabels(
+            message::ExportLabelsRequest { contract_id },
+        ))? {
+            Reply::LabelsExport(jsonl) => {
+                fs::write(path, jsonl)?;
+                Ok(Reply::Success)
+            }
+            Reply::Failure(failure) => Err(failure.into()),
+            _ => Err(Error::UnexpectedApi),
+        }
+    }
+
+    /// Imports labels from the BIP-329 JSON lines file at the client side
+    pub fn labels_import(
+        &mut self,
+        path: impl AsRef<Path>,
+        contract_id: Option<ContractId>,
+    ) -> Result<Reply, Error> {
+        let jsonl = fs::read_to_string(path)?;
+        self.request(Request::ImportLabels(message::ImportLabelsRequest {
+            jsonl,
+            contract_id,
+        }))
+    }
+
     pub fn contract_balance(
         &mut self,
         contract_id: ContractId,
@@ -139,6 +393,31 @@ impl Client {
         }
     }
 
+    /// Derivation branches of the contract unspent outputs, which are not
+    /// reported together with the outputs
+    pub fn contract_unspent_branches(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Reply, Error> {
+        self.request(Request::ContractUnspentBranches(contract_id))
+    }
+
+    /// Synchronizes the contract using its stored gap limit. If `gap_limit`
+    /// is provided, it replaces the stored one for this and the following
+    /// synchronizations.
+    pub fn contract_sync_gap(
+        &mut self,
+        contract_id: ContractId,
+        gap_limit: Option<u8>,
+    ) -> Result<Reply, Error> {
+        self.request(Request::SyncContractGap(
+            message::SyncContractGapRequest {
+                contract_id,
+                gap_limit,
+            },
+        ))
+    }
+
     pub fn address_list(
         &mut self,
         contract_id: ContractId,
@@ -365,6 +644,42 @@ impl Client {
         }
     }
 
+    pub fn identity_list(&mut self) -> Result<Reply, Error> {
+        self.request(Request::ListIdentities)
+    }
+
+    pub fn identity_add(
+        &mut self,
+        identity: message::IdentityInfo,
+    ) -> Result<Reply, Error> {
+        self.request(Request::AddIdentity(identity))
+    }
+
+    pub fn identity_remove(
+        &mut self,
+        key: descriptors::SingleSig,
+    ) -> Result<Reply, Error> {
+        self.request(Request::RemoveIdentity(key))
+    }
+
+    pub fn signer_list(&mut self) -> Result<Reply, Error> {
+        self.request(Request::ListSigners)
+    }
+
+    pub fn signer_add(
+        &mut self,
+        account: message::SignerAccountInfo,
+    ) -> Result<Reply, Error> {
+        self.request(Request::AddSigner(account))
+    }
+
+    pub fn signer_remove(
+        &mut self,
+        key: descriptors::SingleSig,
+    ) -> Result<Reply, Error> {
+        self.request(Request::RemoveSigner(key))
+    }
+
     pub fn asset_list(&mut self) -> Result<Reply, Error> {
         self.request(Request::ListAssets)
     }
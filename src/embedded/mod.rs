@@ -31,7 +31,7 @@ pub fn run_embedded(mut config: runtime::Config) -> Result<Client, Error> {
     let data_dir_str = data_dir.to_string_lossy().to_string();
     let network = config.chain.clone();
     let verbose = config.verbose;
-    let electrum_server = config.electrum_server.clone();
+    let electrum_server = config.electrum_server();
     let rgb20_endpoint = config.rgb20_endpoint.clone();
     let stash_endpoint = ZmqSocketAddr::Inproc(s!("stash.rpc"));
     let stash = format!("{data_dir}/stash/", data_dir = data_dir_str);
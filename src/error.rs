@@ -20,6 +20,8 @@ use internet2::{presentation, transport};
 #[cfg(any(feature = "runtime", feature = "client"))]
 use microservices::rpc;
 
+#[cfg(feature = "runtime")]
+use crate::{backup, chainapi};
 use crate::{cache, storage};
 
 #[derive(Clone, Debug, Display, From, Error)]
@@ -53,9 +55,10 @@ pub enum Error {
     #[from(rgb_node::i9n::Error)]
     RgbNode(String),
 
-    /// electrum server error
-    #[from(electrum_client::Error)]
-    Electrum,
+    /// blockchain data backend failure - {0}
+    #[cfg(feature = "runtime")]
+    #[from]
+    ChainApi(chainapi::Error),
 
     /// storage failure - {0}
     #[cfg(any(feature = "runtime", feature = "embedded"))]
@@ -67,6 +70,11 @@ pub enum Error {
     #[from]
     CacheDriver(cache::Error),
 
+    /// backup failure - {0}
+    #[cfg(feature = "runtime")]
+    #[from]
+    Backup(backup::Error),
+
     // TODO: split client- and server-side error types
     /// server-reported failure
     #[from]
@@ -95,9 +103,62 @@ pub enum Error {
     #[from(bech32::Error)]
     Bech32(lnpbp::bech32::Error),
 
+    /// data directory is already in use by another citadel runtime (process
+    /// {1} holding lock file {0})
+    #[cfg(feature = "runtime")]
+    DataDirLocked(String, u32),
+
+    /// data directory is already in use by another citadel runtime, which
+    /// has not identified itself in lock file {0}
+    #[cfg(feature = "runtime")]
+    DataDirLockUnreadable(String),
+
+    /// wallet name "{0}" is invalid; it must be non-empty, must not start
+    /// with a dot and may contain up to 64 ASCII letters, digits, dots,
+    /// dashes and underscores
+    #[cfg(feature = "runtime")]
+    InvalidWalletName(String),
+
+    /// wallet {0} already exists
+    #[cfg(feature = "runtime")]
+    WalletExists(String),
+
+    /// wallet {0} is not found
+    #[cfg(feature = "runtime")]
+    WalletNotFound(String),
+
+    /// wallet {0} is open; close it first
+    #[cfg(feature = "runtime")]
+    WalletOpen(String),
+
+    /// wallet {0} is not open; open it first
+    #[cfg(feature = "runtime")]
+    WalletClosed(String),
+
+    /// default wallet can't be closed or deleted
+    #[cfg(feature = "runtime")]
+    DefaultWallet,
+
+    /// request {0} can't be addressed to a wallet
+    #[cfg(feature = "runtime")]
+    WalletIndependentRequest(String),
+
+    /// contract {0} still has unspent outputs with non-zero balance; spend
+    /// them first or use forced deletion to archive the contract anyway
+    #[cfg(feature = "runtime")]
+    ContractNotEmpty(crate::model::ContractId),
+
+    /// invalid public key chain "{0}": {1}
+    InvalidPubkeyChain(String, String),
+
     /// embedded node initialization failure
     EmbeddedNodeInitError,
 
+    /// embedded RGB node can't connect to the Electrum server through proxy
+    /// {0}; run RGB node as a separate process or disable the proxy
+    #[cfg(feature = "runtime")]
+    EmbeddedNodeProxy(String),
+
     /// unexpected RPC API message; please check that the client version
     /// matches server
     UnexpectedApi,
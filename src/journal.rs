@@ -0,0 +1,476 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Crash-safe file persistence used by file-based storage and cache drivers.
+//!
+//! New data are never written into the existing file. Instead they are saved
+//! into a temporary file, which is flushed to the disk and then renamed over
+//! the original one, keeping the previous generation as a backup copy. Before
+//! the rename a record with generation number, data length and checksum is
+//! appended to the journal file, so on the next start we can verify the data,
+//! replay a write interrupted before the rename and restore the last good
+//! generation if the main file got corrupted or truncated.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bitcoin::hashes::{sha256, Hash};
+
+const TMP_EXT: &str = "tmp";
+const BACKUP_EXT: &str = "bak";
+const JOURNAL_EXT: &str = "journal";
+const CORRUPTED_EXT: &str = "corrupted";
+
+/// Number of records after which journal is compacted
+const JOURNAL_MAX_RECORDS: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Display)]
+#[display("{generation} {len} {checksum}")]
+struct Record {
+    generation: u64,
+    len: u64,
+    checksum: sha256::Hash,
+}
+
+impl Record {
+    fn with(generation: u64, data: &[u8]) -> Record {
+        Record {
+            generation,
+            len: data.len() as u64,
+            checksum: sha256::Hash::hash(data),
+        }
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        self.len == data.len() as u64
+            && self.checksum == sha256::Hash::hash(data)
+    }
+}
+
+impl FromStr for Record {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            io::Error::new(io::ErrorKind::InvalidData, "broken journal record")
+        };
+        let mut split = s.split(' ');
+        match (split.next(), split.next(), split.next(), split.next()) {
+            (Some(generation), Some(len), Some(checksum), None) => Ok(Record {
+                generation: generation.parse().map_err(|_| err())?,
+                len: len.parse().map_err(|_| err())?,
+                checksum: checksum.parse().map_err(|_| err())?,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// File with atomic writes and write-ahead journal
+#[derive(Debug)]
+pub(crate) struct JournaledFile {
+    path: PathBuf,
+    records: Vec<Record>,
+}
+
+impl JournaledFile {
+    pub fn with(path: PathBuf) -> Result<Self, io::Error> {
+        let mut me = JournaledFile {
+            path,
+            records: vec![],
+        };
+        me.records = me.read_journal()?;
+        Ok(me)
+    }
+
+    /// Path to the main data file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Detects whether there is any data (including data from unfinished
+    /// writes and backups) which may be recovered by [`JournaledFile::read`]
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+            || self.sibling(TMP_EXT).exists()
+            || self.sibling(BACKUP_EXT).exists()
+    }
+
+    /// Reads the last good generation of the data, replaying unfinished
+    /// writes and restoring data from the backup when necessary
+    pub fn read(&mut self) -> Result<Vec<u8>, io::Error> {
+        let main = fs::read(&self.path).ok();
+        let tmp_path = self.sibling(TMP_EXT);
+        let backup_path = self.sibling(BACKUP_EXT);
+
+        let last = match self.records.last() {
+            None => {
+                // Files written before the journal was introduced can't be
+                // verified, so we take them as they are
+                if tmp_path.exists() {
+                    fs::remove_file(&tmp_path)?;
+                }
+                return main.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("data file {:?} is absent", self.path),
+                    )
+                });
+            }
+            Some(last) => *last,
+        };
+
+        if let Some(data) = main.as_ref().filter(|data| last.matches(data)) {
+            if tmp_path.exists() {
+                fs::remove_file(&tmp_path)?;
+            }
+            return Ok(data.clone());
+        }
+
+        if let Some(data) =
+            fs::read(&tmp_path).ok().filter(|data| last.matches(data))
+        {
+            warn!(
+                "Replaying interrupted write of generation #{} to {:?}",
+                last.generation, self.path
+            );
+            if main.is_some() {
+                fs::rename(&self.path, &backup_path)?;
+            }
+            fs::rename(&tmp_path, &self.path)?;
+            self.sync_dir()?;
+            return Ok(data);
+        }
+
+        if let Some((record, data)) = main.as_ref().and_then(|data| {
+            self.find_record(data).map(|record| (record, data.clone()))
+        }) {
+            warn!(
+                "Last write to {:?} was not completed; continuing with \
+                 generation #{}",
+                self.path, record.generation
+            );
+            return Ok(data);
+        }
+
+        if let Some((record, data)) =
+            fs::read(&backup_path).ok().and_then(|data| {
+                self.find_record(&data).map(|record| (record, data))
+            })
+        {
+            error!(
+                "Data file {:?} is corrupted; restoring last good generation \
+                 #{} from the backup",
+                self.path, record.generation
+            );
+            if main.is_some() {
+                // Keeping damaged data for the investigation
+                fs::rename(&self.path, self.sibling(CORRUPTED_EXT))?;
+            }
+            self.write(&data)?;
+            return Ok(data);
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "data file {:?} is corrupted and no good generation can be \
+                 restored from the journal",
+                self.path
+            ),
+        ))
+    }
+
+    /// Atomically replaces file content with the new data
+    pub fn write(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let generation = self
+            .records
+            .last()
+            .map(|record| record.generation + 1)
+            .unwrap_or_default();
+        let record = Record::with(generation, data);
+        trace!("Writing generation {} to {:?}", record, self.path);
+
+        let tmp_path = self.sibling(TMP_EXT);
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(data)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        self.append_journal(record)?;
+
+        if self.path.exists() {
+            fs::rename(&self.path, self.sibling(BACKUP_EXT))?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.sync_dir()?;
+
+        if self.records.len() > JOURNAL_MAX_RECORDS {
+            self.compact_journal()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces file content with the new data, leaving no previous
+    /// generations of the data on the disk. Used when the old data must not
+    /// be readable anymore, like after the change of the encryption key.
+    pub fn rewrite(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        // Second write replaces the backup generation with the same data
+        self.write(data)?;
+        self.write(data)?;
+        let corrupted_path = self.sibling(CORRUPTED_EXT);
+        if corrupted_path.exists() {
+            fs::remove_file(&corrupted_path)?;
+        }
+        self.sync_dir()
+    }
+
+    fn sibling(&self, ext: &str) -> PathBuf {
+        let mut name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        name.push(".");
+        name.push(ext);
+        self.path.with_file_name(name)
+    }
+
+    fn find_record(&self, data: &[u8]) -> Option<Record> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.matches(data))
+            .copied()
+    }
+
+    fn read_journal(&self) -> Result<Vec<Record>, io::Error> {
+        let journal_path = self.sibling(JOURNAL_EXT);
+        if !journal_path.exists() {
+            return Ok(vec![]);
+        }
+        let mut records = vec![];
+        for line in io::BufReader::new(fs::File::open(&journal_path)?).lines() {
+            match line?.parse() {
+                Ok(record) => records.push(record),
+                // Last record may be torn by a crash during the append
+                Err(err) => warn!(
+                    "Skipping broken record in journal {:?}: {}",
+                    journal_path, err
+                ),
+            }
+        }
+        Ok(records)
+    }
+
+    fn append_journal(&mut self, record: Record) -> Result<(), io::Error> {
+        let mut journal = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(self.sibling(JOURNAL_EXT))?;
+        let mut content = vec![];
+        journal.read_to_end(&mut content)?;
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            // Record torn by a crash during the previous append must not be
+            // glued with the new one, so we cut it off
+            let len = content
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map(|pos| pos + 1)
+                .unwrap_or_default();
+            warn!(
+                "Cutting off torn record from journal {:?}",
+                self.sibling(JOURNAL_EXT)
+            );
+            journal.set_len(len as u64)?;
+        }
+        journal.seek(SeekFrom::End(0))?;
+        writeln!(journal, "{}", record)?;
+        journal.sync_all()?;
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn compact_journal(&mut self) -> Result<(), io::Error> {
+        // We need to keep the record for the backup generation as well
+        let keep = self.records.split_off(self.records.len() - 2);
+        let journal_path = self.sibling(JOURNAL_EXT);
+        let mut tmp_name = journal_path.as_os_str().to_owned();
+        tmp_name.push(".");
+        tmp_name.push(TMP_EXT);
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut tmp = fs::File::create(&tmp_path)?;
+        for record in &keep {
+            writeln!(tmp, "{}", record)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &journal_path)?;
+        self.records = keep;
+        self.sync_dir()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self) -> Result<(), io::Error> {
+        match self.path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Ok(()),
+            Some(dir) => fs::File::open(dir)?.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn journaled(name: &str) -> JournaledFile {
+        let dir = temp_dir(&format!("journal-{}", name));
+        JournaledFile::with(dir.join("data")).unwrap()
+    }
+
+    fn cleanup(file: &JournaledFile) {
+        fs::remove_dir_all(file.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut file = journaled("round-trip");
+        assert!(!file.exists());
+        file.write(b"first").unwrap();
+        file.write(b"second").unwrap();
+        assert!(file.exists());
+        assert_eq!(file.read().unwrap(), b"second");
+        assert_eq!(fs::read(file.sibling(BACKUP_EXT)).unwrap(), b"first");
+
+        let mut reopened = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(reopened.records, file.records);
+        assert_eq!(reopened.read().unwrap(), b"second");
+        cleanup(&file);
+    }
+
+    #[test]
+    fn torn_record() {
+        let mut file = journaled("torn");
+        file.write(b"first").unwrap();
+        // Crash during the journal append
+        let journal_path = file.sibling(JOURNAL_EXT);
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(&journal_path)
+            .unwrap();
+        journal.write_all(b"1 6 0123").unwrap();
+        drop(journal);
+
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(file.records.len(), 1);
+        assert_eq!(file.read().unwrap(), b"first");
+
+        file.write(b"second").unwrap();
+        let content = fs::read_to_string(&journal_path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(file.records.len(), 2);
+        assert_eq!(file.read().unwrap(), b"second");
+        cleanup(&file);
+    }
+
+    #[test]
+    fn tmp_replay() {
+        let mut file = journaled("replay");
+        file.write(b"first").unwrap();
+        // Crash after the journal append and before the rename
+        let record = Record::with(1, b"second");
+        fs::write(file.sibling(TMP_EXT), b"second").unwrap();
+        file.append_journal(record).unwrap();
+
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(file.read().unwrap(), b"second");
+        assert!(!file.sibling(TMP_EXT).exists());
+        assert_eq!(fs::read(file.path()).unwrap(), b"second");
+        assert_eq!(fs::read(file.sibling(BACKUP_EXT)).unwrap(), b"first");
+
+        // Incomplete temporary file is dropped
+        fs::write(file.sibling(TMP_EXT), b"thi").unwrap();
+        file.append_journal(Record::with(2, b"third")).unwrap();
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(file.read().unwrap(), b"second");
+        cleanup(&file);
+    }
+
+    #[test]
+    fn backup_restore() {
+        let mut file = journaled("restore");
+        file.write(b"first").unwrap();
+        file.write(b"second").unwrap();
+        // Main file damaged after the write
+        fs::write(file.path(), b"sec").unwrap();
+
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(file.read().unwrap(), b"first");
+        assert_eq!(fs::read(file.path()).unwrap(), b"first");
+        assert_eq!(fs::read(file.sibling(CORRUPTED_EXT)).unwrap(), b"sec");
+
+        // Nothing to restore from
+        fs::write(file.path(), b"fir").unwrap();
+        fs::write(file.sibling(BACKUP_EXT), b"sec").unwrap();
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        assert_eq!(
+            file.read().map_err(|err| err.kind()),
+            Err(io::ErrorKind::InvalidData)
+        );
+        cleanup(&file);
+    }
+
+    #[test]
+    fn compaction() {
+        let mut file = journaled("compaction");
+        for no in 0..=JOURNAL_MAX_RECORDS + 1 {
+            file.write(format!("generation {}", no).as_bytes()).unwrap();
+        }
+        let content = fs::read_to_string(file.sibling(JOURNAL_EXT)).unwrap();
+        assert!(content.lines().count() <= JOURNAL_MAX_RECORDS);
+
+        // Both main and backup generations are still verified
+        fs::write(file.path(), b"damaged").unwrap();
+        let mut file = JournaledFile::with(file.path().to_owned()).unwrap();
+        let expected = format!("generation {}", JOURNAL_MAX_RECORDS);
+        assert_eq!(file.read().unwrap(), expected.as_bytes());
+        assert_eq!(
+            file.records.last().unwrap().generation,
+            JOURNAL_MAX_RECORDS as u64 + 2
+        );
+        cleanup(&file);
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut file = journaled("rewrite");
+        file.write(b"first").unwrap();
+        fs::write(file.sibling(CORRUPTED_EXT), b"fir").unwrap();
+        file.rewrite(b"second").unwrap();
+        assert_eq!(fs::read(file.path()).unwrap(), b"second");
+        assert_eq!(fs::read(file.sibling(BACKUP_EXT)).unwrap(), b"second");
+        assert!(!file.sibling(CORRUPTED_EXT).exists());
+        cleanup(&file);
+    }
+}
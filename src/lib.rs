@@ -41,9 +41,13 @@ extern crate log;
 extern crate serde_with;
 
 mod error;
+mod journal;
+mod migration;
 pub mod model;
 #[cfg(any(feature = "client", feature = "runtime"))]
 pub mod rpc;
+#[cfg(test)]
+mod test_utils;
 
 #[cfg(feature = "client")]
 pub mod client;
@@ -54,6 +58,8 @@ pub use embedded::run_embedded;
 #[cfg(feature = "runtime")]
 pub mod runtime;
 
+#[cfg(feature = "runtime")]
+pub mod backup;
 pub mod cache;
 pub mod storage;
 
@@ -0,0 +1,569 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Format versioning for persisted storage and cache data.
+//!
+//! Each data file starts with the format version: strict-encoded files have
+//! a binary header, while YAML, TOML and JSON files have a top-level
+//! `version` field. Files written before versioning was introduced have no
+//! version and are treated as version 0. On load, data of older versions are
+//! upgraded to the current layout by the migrations registered in the data
+//! [`Schema`], one version at a time.
+
+use std::path::{Path, PathBuf};
+
+use microservices::FileFormat;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+/// Magic bytes starting the version header of strict-encoded data
+const VERSION_MAGIC: [u8; 4] = *b"CTDV";
+const VERSION_HEADER_LEN: usize = VERSION_MAGIC.len() + 2;
+const VERSION_KEY: &str = "version";
+const BACKUP_EXT: &str = "backup";
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// {0} data have format version {1}, which is newer than version {2}
+    /// supported by this software; please upgrade it
+    UnsupportedVersion(&'static str, u16, u16),
+
+    /// no migration of {0} data from format version {1} is known
+    NoMigration(&'static str, u16),
+
+    /// migration of {0} data to format version {1} has failed: {2}
+    Failed(&'static str, u16, String),
+
+    /// unable to parse {0} data: {1}
+    Encoding(&'static str, String),
+
+    /// unable to create backup copy before migrating {0} data: {1}
+    Backup(&'static str, String),
+
+    /// {0} data can't be kept in {1} file format
+    UnsupportedFormat(&'static str, String),
+}
+
+/// Persisted data which are not converted into the model types yet
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum Document {
+    /// Strict-encoded data without version header
+    Strict(Vec<u8>),
+
+    /// Data from one of structured text formats (YAML, TOML or JSON)
+    /// without `version` field
+    Structured(serde_yaml::Value),
+}
+
+/// Step upgrading data from the previous format version
+pub(crate) struct Migration {
+    /// Format version of the data produced by the migration
+    pub version: u16,
+
+    /// Description of the changes made by the migration
+    pub description: &'static str,
+
+    pub upgrade: fn(Document) -> Result<Document, String>,
+}
+
+/// Data loaded from the persisted representation
+pub(crate) struct Loaded<T> {
+    pub data: T,
+
+    /// Original format version, if the data were upgraded by migrations
+    pub migrated_from: Option<u16>,
+
+    /// Descriptions of the applied migrations
+    pub changes: Vec<String>,
+}
+
+/// Format versions of a persisted data type and migrations between them
+pub(crate) struct Schema {
+    /// Name of the data used in logs and errors
+    pub name: &'static str,
+
+    /// Current format version
+    pub version: u16,
+
+    /// Migrations, ordered by the version they produce
+    pub migrations: &'static [Migration],
+}
+
+impl Schema {
+    /// Loads data of any supported format version, upgrading them to the
+    /// current one. Data without version information are assumed to have
+    /// `legacy_version`.
+    pub fn load<T>(
+        &self,
+        data: &[u8],
+        format: FileFormat,
+        legacy_version: u16,
+    ) -> Result<Loaded<T>, Error>
+    where
+        T: DeserializeOwned + StrictDecode,
+    {
+        let (version, document) = self.decode(data, format, legacy_version)?;
+        let (document, changes) = self.upgrade(version, document)?;
+        let data = match document {
+            Document::Strict(data) => T::strict_deserialize(data)
+                .map_err(|err| Error::Encoding(self.name, err.to_string()))?,
+            Document::Structured(value) => serde_yaml::from_value(value)
+                .map_err(|err| Error::Encoding(self.name, err.to_string()))?,
+        };
+        Ok(Loaded {
+            data,
+            migrated_from: if version < self.version {
+                Some(version)
+            } else {
+                None
+            },
+            changes,
+        })
+    }
+
+    /// Serializes data with the current format version
+    pub fn store<T>(
+        &self,
+        data: &T,
+        format: FileFormat,
+    ) -> Result<Vec<u8>, Error>
+    where
+        T: Serialize + StrictEncode,
+    {
+        let err = |err: &dyn std::fmt::Display| {
+            Error::Encoding(self.name, err.to_string())
+        };
+        if let FileFormat::StrictEncode = format {
+            let mut buf = Vec::from(&VERSION_MAGIC[..]);
+            buf.extend_from_slice(&self.version.to_le_bytes());
+            data.strict_encode(&mut buf).map_err(|e| err(&e))?;
+            return Ok(buf);
+        }
+
+        let mut versioned = serde_yaml::Mapping::new();
+        versioned.insert(
+            VERSION_KEY.into(),
+            serde_yaml::Value::Number(u64::from(self.version).into()),
+        );
+        match serde_yaml::to_value(data).map_err(|e| err(&e))? {
+            serde_yaml::Value::Mapping(map) => {
+                for (key, value) in map {
+                    versioned.insert(key, value);
+                }
+            }
+            _ => unreachable!("persisted data are always structures"),
+        }
+        match format {
+            FileFormat::Yaml => {
+                serde_yaml::to_vec(&versioned).map_err(|e| err(&e))
+            }
+            FileFormat::Toml => toml::to_vec(&versioned).map_err(|e| err(&e)),
+            FileFormat::Json => {
+                serde_json::to_vec(&versioned).map_err(|e| err(&e))
+            }
+            _ => Err(Error::UnsupportedFormat(
+                self.name,
+                format!("{:?}", format),
+            )),
+        }
+    }
+
+    /// Creates a copy of the data file before it gets overwritten with the
+    /// migrated data
+    pub fn backup(&self, path: &Path, version: u16) -> Result<PathBuf, Error> {
+        backup(self.name, path, version)
+    }
+
+    /// Lists backup copies of the data file created before the migrations
+    pub fn backups(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        backups(self.name, path)
+    }
+
+    /// Logs the migrations applied to the data
+    pub fn report(&self, from: u16, changes: &[String], backup: Option<&Path>) {
+        report(self.name, from, self.version, changes, backup)
+    }
+
+    fn decode(
+        &self,
+        data: &[u8],
+        format: FileFormat,
+        legacy_version: u16,
+    ) -> Result<(u16, Document), Error> {
+        if let FileFormat::StrictEncode = format {
+            if data.len() >= VERSION_HEADER_LEN
+                && data[..VERSION_MAGIC.len()] == VERSION_MAGIC
+            {
+                let version = u16::from_le_bytes([
+                    data[VERSION_MAGIC.len()],
+                    data[VERSION_MAGIC.len() + 1],
+                ]);
+                return Ok((
+                    version,
+                    Document::Strict(data[VERSION_HEADER_LEN..].to_vec()),
+                ));
+            }
+            return Ok((legacy_version, Document::Strict(data.to_vec())));
+        }
+
+        let err = |err: &dyn std::fmt::Display| {
+            Error::Encoding(self.name, err.to_string())
+        };
+        let mut value: serde_yaml::Value = match format {
+            FileFormat::Yaml => {
+                serde_yaml::from_slice(data).map_err(|e| err(&e))?
+            }
+            FileFormat::Toml => toml::from_slice(data).map_err(|e| err(&e))?,
+            FileFormat::Json => {
+                serde_json::from_slice(data).map_err(|e| err(&e))?
+            }
+            _ => {
+                return Err(Error::UnsupportedFormat(
+                    self.name,
+                    format!("{:?}", format),
+                ))
+            }
+        };
+        let version = match value
+            .as_mapping_mut()
+            .and_then(|map| map.remove(&VERSION_KEY.into()))
+        {
+            None => legacy_version,
+            Some(version) => version
+                .as_u64()
+                .filter(|version| *version <= u16::MAX as u64)
+                .ok_or_else(|| {
+                    Error::Encoding(self.name, s!("invalid format version"))
+                })? as u16,
+        };
+        Ok((version, Document::Structured(value)))
+    }
+
+    fn upgrade(
+        &self,
+        mut version: u16,
+        mut document: Document,
+    ) -> Result<(Document, Vec<String>), Error> {
+        if version > self.version {
+            return Err(Error::UnsupportedVersion(
+                self.name,
+                version,
+                self.version,
+            ));
+        }
+        let mut changes = vec![];
+        while version < self.version {
+            let migration = self
+                .migrations
+                .iter()
+                .find(|migration| migration.version == version + 1)
+                .ok_or(Error::NoMigration(self.name, version))?;
+            debug!(
+                "Migrating {} data to format version {}",
+                self.name, migration.version
+            );
+            document = (migration.upgrade)(document).map_err(|details| {
+                Error::Failed(self.name, migration.version, details)
+            })?;
+            changes.push(format!(
+                "v{}: {}",
+                migration.version, migration.description
+            ));
+            version = migration.version;
+        }
+        Ok((document, changes))
+    }
+}
+
+/// Step upgrading SQLite database from the previous schema version
+#[cfg(feature = "sqlite")]
+pub(crate) struct SqlMigration {
+    /// Description of the changes made by the migration
+    pub description: &'static str,
+
+    /// SQL statements performing the migration
+    pub sql: &'static str,
+
+    /// Upgrade of the data which can't be done with SQL statements, like
+    /// re-encoding of the stored blobs. It runs after the SQL statements
+    /// within the same transaction.
+    pub upgrade: Option<fn(&rusqlite::Transaction) -> Result<(), String>>,
+}
+
+/// Versions of SQLite database schema, kept in the `user_version` pragma of
+/// the database. Schema version is equal to the number of migrations applied
+/// to the database.
+#[cfg(feature = "sqlite")]
+pub(crate) struct SqlSchema {
+    /// Name of the data used in logs and errors
+    pub name: &'static str,
+
+    /// Migrations from the empty database to the current schema version
+    pub migrations: &'static [SqlMigration],
+}
+
+#[cfg(feature = "sqlite")]
+impl SqlSchema {
+    pub fn version(&self) -> u16 {
+        self.migrations.len() as u16
+    }
+
+    /// Brings database schema to the current version. Existing databases of
+    /// older versions are copied to a backup file first.
+    pub fn apply(
+        &self,
+        db: &mut rusqlite::Connection,
+        path: &Path,
+    ) -> Result<(), Error> {
+        let sql_err =
+            |err: rusqlite::Error| Error::Encoding(self.name, err.to_string());
+        let version: u16 = db
+            .query_row("PRAGMA user_version", rusqlite::params![], |row| {
+                row.get(0)
+            })
+            .map_err(sql_err)?;
+        if version > self.version() {
+            return Err(Error::UnsupportedVersion(
+                self.name,
+                version,
+                self.version(),
+            ));
+        }
+        if version == self.version() {
+            return Ok(());
+        }
+
+        let tables: u32 = db
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE type = 'table'",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .map_err(sql_err)?;
+        let backup = if tables > 0 {
+            Some(backup(self.name, path, version)?)
+        } else {
+            None
+        };
+
+        let mut changes = vec![];
+        for (no, migration) in
+            self.migrations.iter().enumerate().skip(version as usize)
+        {
+            let target = no as u16 + 1;
+            let failed = |err: rusqlite::Error| {
+                Error::Failed(self.name, target, err.to_string())
+            };
+            let tx = db.transaction().map_err(failed)?;
+            tx.execute_batch(migration.sql).map_err(failed)?;
+            if let Some(upgrade) = migration.upgrade {
+                upgrade(&tx).map_err(|details| {
+                    Error::Failed(self.name, target, details)
+                })?;
+            }
+            tx.execute_batch(&format!("PRAGMA user_version = {}", target))
+                .map_err(failed)?;
+            tx.commit().map_err(failed)?;
+            changes.push(format!("v{}: {}", target, migration.description));
+        }
+
+        if backup.is_some() {
+            report(
+                self.name,
+                version,
+                self.version(),
+                &changes,
+                backup.as_deref(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn backup(
+    name: &'static str,
+    path: &Path,
+    version: u16,
+) -> Result<PathBuf, Error> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.{}", version, BACKUP_EXT));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)
+        .map_err(|err| Error::Backup(name, err.to_string()))?;
+    Ok(backup)
+}
+
+fn backups(name: &'static str, path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let err = |err: std::io::Error| Error::Backup(name, err.to_string());
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().to_string(),
+        None => return Ok(vec![]),
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(vec![]),
+    };
+    let prefix = format!("{}.v", file_name);
+    let suffix = format!(".{}", BACKUP_EXT);
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir).map_err(err)? {
+        let entry = entry.map_err(err)?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_backup = name.len() > prefix.len() + suffix.len()
+            && name.starts_with(&prefix)
+            && name.ends_with(&suffix)
+            && name[prefix.len()..name.len() - suffix.len()]
+                .parse::<u16>()
+                .is_ok();
+        if is_backup {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+fn report(
+    name: &'static str,
+    from: u16,
+    to: u16,
+    changes: &[String],
+    backup: Option<&Path>,
+) {
+    warn!(
+        "The {} data were migrated from format version {} to {}",
+        name, from, to
+    );
+    for change in changes {
+        info!("- {}", change);
+    }
+    if let Some(backup) = backup {
+        info!("A copy of the original data is kept in {:?}", backup);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use microservices::FileFormat;
+    use serde::{Deserialize, Serialize};
+    use strict_encoding::StrictEncode;
+
+    use super::{Document, Error, Migration, Schema};
+
+    #[derive(
+        Serialize,
+        Deserialize,
+        Clone,
+        PartialEq,
+        Debug,
+        StrictEncode,
+        StrictDecode,
+    )]
+    struct Data {
+        name: String,
+        value: u64,
+    }
+
+    /// Adds `value` field, which is missing in the version 1 data
+    fn add_value(document: Document) -> Result<Document, String> {
+        match document {
+            Document::Strict(mut data) => {
+                data.extend_from_slice(&7u64.to_le_bytes());
+                Ok(Document::Strict(data))
+            }
+            Document::Structured(mut value) => {
+                value
+                    .as_mapping_mut()
+                    .ok_or_else(|| s!("data are not a mapping"))?
+                    .insert(
+                        "value".into(),
+                        serde_yaml::Value::Number(7u64.into()),
+                    );
+                Ok(Document::Structured(value))
+            }
+        }
+    }
+
+    static SCHEMA: Schema = Schema {
+        name: "test",
+        version: 2,
+        migrations: &[Migration {
+            version: 2,
+            description: "add value",
+            upgrade: add_value,
+        }],
+    };
+
+    fn data() -> Data {
+        Data {
+            name: s!("wallet"),
+            value: 7,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in &[
+            FileFormat::Yaml,
+            FileFormat::Toml,
+            FileFormat::Json,
+            FileFormat::StrictEncode,
+        ] {
+            let stored = SCHEMA.store(&data(), *format).unwrap();
+            let loaded = SCHEMA.load::<Data>(&stored, *format, 1).unwrap();
+            assert_eq!(loaded.data, data());
+            assert_eq!(loaded.migrated_from, None);
+            assert!(loaded.changes.is_empty());
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_data() {
+        let loaded = SCHEMA
+            .load::<Data>(b"name: wallet\n", FileFormat::Yaml, 1)
+            .unwrap();
+        assert_eq!(loaded.data, data());
+        assert_eq!(loaded.migrated_from, Some(1));
+        assert_eq!(loaded.changes, vec![s!("v2: add value")]);
+
+        let legacy = s!("wallet").strict_serialize().unwrap();
+        let loaded = SCHEMA
+            .load::<Data>(&legacy, FileFormat::StrictEncode, 1)
+            .unwrap();
+        assert_eq!(loaded.data, data());
+        assert_eq!(loaded.migrated_from, Some(1));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert_eq!(
+            SCHEMA
+                .load::<Data>(
+                    b"version: 3\nname: wallet\n",
+                    FileFormat::Yaml,
+                    1
+                )
+                .err(),
+            Some(Error::UnsupportedVersion("test", 3, 2))
+        );
+        assert_eq!(
+            SCHEMA
+                .load::<Data>(b"name: wallet\n", FileFormat::Yaml, 0)
+                .err(),
+            Some(Error::NoMigration("test", 0))
+        );
+    }
+}
@@ -14,8 +14,11 @@
 use serde_with::DisplayFromStr;
 use std::collections::BTreeMap;
 
-use crate::model::{Contract, ContractId};
-use crate::rpc::message::IdentityInfo;
+use wallet::descriptors;
+
+use crate::model::{ArchivedContract, Contract, ContractId};
+use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
+use crate::storage;
 
 #[serde_as]
 #[derive(
@@ -32,9 +35,152 @@ pub struct Citadel {
     #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
     pub contracts: BTreeMap<ContractId, Contract>,
 
-    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
-    pub identities: BTreeMap<rgb::ContractId, IdentityInfo>,
+    /// Identities indexed by their key representation (see
+    /// [`IdentityInfo::id`])
+    pub identities: BTreeMap<String, IdentityInfo>,
 
     #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
     pub assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+
+    /// Signer accounts indexed by their key representation (see
+    /// [`SignerAccountInfo::id`])
+    #[serde(default)]
+    pub signers: BTreeMap<String, SignerAccountInfo>,
+
+    /// Contracts removed by the user, which can be restored
+    #[serde(default)]
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    pub archived: BTreeMap<ContractId, ArchivedContract>,
+}
+
+impl Citadel {
+    pub(crate) fn contract_ref(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<&Contract, storage::Error> {
+        self.contracts
+            .get(&contract_id)
+            .ok_or(storage::Error::ContractNotFound(contract_id))
+    }
+
+    pub(crate) fn contract_mut(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<&mut Contract, storage::Error> {
+        self.contracts
+            .get_mut(&contract_id)
+            .ok_or(storage::Error::ContractNotFound(contract_id))
+    }
+
+    /// Adds new contract, unless the contract with the same id is archived.
+    /// For an already known contract only its name is updated, keeping all
+    /// of the accumulated contract data.
+    pub(crate) fn add_contract(
+        &mut self,
+        contract: Contract,
+    ) -> Result<Contract, storage::Error> {
+        let id = *contract.id();
+        if self.archived.contains_key(&id) {
+            return Err(storage::Error::ContractArchived(id));
+        }
+        let contract = match self.contracts.get_mut(&id) {
+            Some(existing) => {
+                existing.name = contract.name;
+                existing.clone()
+            }
+            None => {
+                self.contracts.insert(id, contract.clone());
+                contract
+            }
+        };
+        Ok(contract)
+    }
+
+    /// Moves contract with all its data into the archive
+    pub(crate) fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<&ArchivedContract, storage::Error> {
+        let contract = self
+            .contracts
+            .remove(&contract_id)
+            .ok_or(storage::Error::ContractNotFound(contract_id))?;
+        self.archived
+            .insert(contract_id, ArchivedContract::with(contract));
+        Ok(&self.archived[&contract_id])
+    }
+
+    /// Moves contract from the archive back to the list of active contracts
+    pub(crate) fn restore_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<&Contract, storage::Error> {
+        let contract = self
+            .archived
+            .remove(&contract_id)
+            .ok_or(storage::Error::ArchivedContractNotFound(contract_id))?
+            .into_contract();
+        self.contracts.insert(contract_id, contract);
+        Ok(&self.contracts[&contract_id])
+    }
+
+    pub(crate) fn add_signer(
+        &mut self,
+        account: SignerAccountInfo,
+    ) -> Result<SignerAccountInfo, storage::Error> {
+        let id = account.id();
+        let account = match self.signers.get(&id) {
+            Some(known) if known.title != account.title => {
+                return Err(storage::Error::SignerExists(id))
+            }
+            Some(known) => {
+                let mut known = known.clone();
+                known.merge_used(&account);
+                known
+            }
+            None => account,
+        };
+        self.signers.insert(id, account.clone());
+        Ok(account)
+    }
+
+    pub(crate) fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, storage::Error> {
+        let id = key.to_string();
+        self.signers
+            .remove(&id)
+            .ok_or(storage::Error::SignerNotFound(id))
+    }
+
+    pub(crate) fn add_identity(
+        &mut self,
+        identity: IdentityInfo,
+    ) -> Result<IdentityInfo, storage::Error> {
+        let id = identity.id();
+        let identity = match self.identities.get(&id) {
+            Some(known) if known.name != identity.name => {
+                return Err(storage::Error::IdentityExists(id))
+            }
+            Some(known) => {
+                let mut known = known.clone();
+                known.merge_known(&identity);
+                known
+            }
+            None => identity,
+        };
+        self.identities.insert(id, identity.clone());
+        Ok(identity)
+    }
+
+    pub(crate) fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<IdentityInfo, storage::Error> {
+        let id = key.to_string();
+        self.identities
+            .remove(&id)
+            .ok_or(storage::Error::IdentityNotFound(id))
+    }
 }
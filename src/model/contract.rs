@@ -18,7 +18,7 @@ use std::io;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use amplify::Slice32;
-use bitcoin::{OutPoint, PublicKey, Script};
+use bitcoin::{OutPoint, PublicKey, Script, Txid};
 use bp::seals::{OutpointHash, OutpointReveal};
 use commit_verify::{CommitConceal, CommitEncode, ConsensusCommit};
 use invoice::Invoice;
@@ -27,7 +27,9 @@ use miniscript::ForEachKey;
 use strict_encoding::StrictEncode;
 use wallet::hd::{PubkeyChain, UnhardenedIndex};
 
-use super::{ContractId, Operation, Policy, PolicyType, State};
+use super::{
+    Branch, ContractId, LabelRef, Operation, Policy, PolicyType, State,
+};
 use crate::model::AddressDerivation;
 
 #[serde_as]
@@ -114,6 +116,20 @@ impl From<Contract> for ContractMeta {
     }
 }
 
+impl From<ContractMeta> for Contract {
+    /// Reconstructs contract without any accumulated contract data
+    fn from(meta: ContractMeta) -> Self {
+        Contract {
+            id: meta.id,
+            name: meta.name,
+            chain: meta.chain,
+            policy: meta.policy,
+            created_at: meta.created_at,
+            data: ContractData::default(),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(
     Serialize,
@@ -143,6 +159,89 @@ pub struct ContractData {
 
     #[serde_as(as = "Vec<_>")]
     operations: Vec<Operation>,
+
+    /// User-defined labels for the contract and related transactions,
+    /// addresses and outputs
+    #[serde(default)]
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    labels: BTreeMap<LabelRef, String>,
+}
+
+/// Contract removed by the user. It is kept with all of its data, including
+/// blinding factors and pay-to-contract tweaks required to spend RGB-tweaked
+/// outputs, so it can be restored later.
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Getters,
+    Clone,
+    PartialEq,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedContract {
+    contract: Contract,
+
+    #[serde_as(as = "chrono::DateTime<chrono::Utc>")]
+    archived_at: NaiveDateTime,
+}
+
+impl ArchivedContract {
+    pub fn with(contract: Contract) -> Self {
+        ArchivedContract {
+            contract,
+            archived_at: now(),
+        }
+    }
+
+    pub(crate) fn with_time(
+        contract: Contract,
+        archived_at: NaiveDateTime,
+    ) -> Self {
+        ArchivedContract {
+            contract,
+            archived_at,
+        }
+    }
+
+    pub fn into_contract(self) -> Contract {
+        self.contract
+    }
+}
+
+/// Information about archived contract provided via RPC
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Getters,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedContractMeta {
+    #[serde(flatten)]
+    contract: ContractMeta,
+
+    #[serde_as(as = "chrono::DateTime<chrono::Utc>")]
+    archived_at: NaiveDateTime,
+}
+
+impl From<ArchivedContract> for ArchivedContractMeta {
+    fn from(archived: ArchivedContract) -> Self {
+        ArchivedContractMeta {
+            contract: ContractMeta::from(archived.contract),
+            archived_at: archived.archived_at,
+        }
+    }
 }
 
 impl ConsensusCommit for Contract {
@@ -180,10 +279,12 @@ pub trait SpendingPolicy {
 
     fn derive_address(
         &self,
+        branch: Branch,
         index: UnhardenedIndex,
         legacy: bool,
     ) -> Option<AddressDerivation> {
-        self.policy().derive_address(index, self.chain(), legacy)
+        self.policy()
+            .derive_address(branch, index, self.chain(), legacy)
     }
 }
 
@@ -206,18 +307,12 @@ impl SpendingPolicy for ContractMeta {
 
 impl Contract {
     pub fn with(policy: Policy, name: String, chain: Chain) -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Failed time service");
         Contract {
             id: policy.id(),
             name,
             chain,
             policy,
-            created_at: NaiveDateTime::from_timestamp(
-                timestamp.as_secs() as i64,
-                0,
-            ),
+            created_at: now(),
             data: ContractData::default(),
         }
     }
@@ -239,10 +334,62 @@ impl Contract {
         self.data.operations.push(operation);
     }
 
+    /// Sets heights of the operations to the heights of the blocks mining
+    /// their transactions (zero for unconfirmed ones), returning the updated
+    /// operations
+    // TODO: This must be private and must be used by storage driver only
+    pub(crate) fn mine_operations(
+        &mut self,
+        heights: &BTreeMap<Txid, u32>,
+    ) -> Vec<Operation> {
+        self.data
+            .operations
+            .iter_mut()
+            .filter_map(|operation| {
+                let height = *heights.get(&operation.txid)? as i64;
+                if operation.height == height {
+                    return None;
+                }
+                operation.height = height;
+                Some(operation.clone())
+            })
+            .collect()
+    }
+
+    /// Marks operations mined at or above the given block height as not
+    /// mined, returning the updated operations
+    // TODO: This must be private and must be used by storage driver only
+    pub(crate) fn unmine_operations(&mut self, height: u32) -> Vec<Operation> {
+        self.data
+            .operations
+            .iter_mut()
+            .filter(|operation| operation.height >= height as i64)
+            .filter(|operation| operation.height > 0)
+            .map(|operation| {
+                operation.height = 0;
+                operation.clone()
+            })
+            .collect()
+    }
+
     // TODO: This must be private and must be used by storage driver only
     //       also it should return iterator
     pub(crate) fn history(&self) -> Vec<Operation> {
-        self.data.operations.iter().cloned().collect()
+        self.data.operations.clone()
+    }
+
+    /// Assigns new label to the object or, if `label` is `None`, removes
+    /// the existing one. Returns previous label.
+    // TODO: This must be private and must be used by storage driver only
+    pub(crate) fn set_label(
+        &mut self,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Option<String> {
+        match label {
+            Some(label) => self.data.labels.insert(reference, label),
+            None => self.data.labels.remove(&reference),
+        }
     }
 
     // TODO: This must be private and must be used by storage driver only
@@ -258,6 +405,38 @@ impl Contract {
             .blinding_factors
             .insert(outpoint_reveal.commit_conceal(), outpoint_reveal);
     }
+
+    /// Combines contract data with the data of the same contract coming from
+    /// a different source (like a wallet backup), skipping already known
+    /// items
+    pub(crate) fn merge_data(&mut self, other: &Contract) {
+        for reveal in other.data.blinding_factors.values() {
+            self.add_blinding(reveal.clone());
+        }
+        for invoice in &other.data.sent_invoices {
+            self.add_invoice(invoice.clone());
+        }
+        for (invoice, created_at) in &other.data.unpaid_invoices {
+            self.data
+                .unpaid_invoices
+                .entry(invoice.clone())
+                .or_insert(*created_at);
+        }
+        self.data
+            .p2c_tweaks
+            .extend(other.data.p2c_tweaks.iter().cloned());
+        for operation in &other.data.operations {
+            if !self.data.operations.contains(operation) {
+                self.data.operations.push(operation.clone());
+            }
+        }
+        for (reference, label) in &other.data.labels {
+            self.data
+                .labels
+                .entry(reference.clone())
+                .or_insert_with(|| label.clone());
+        }
+    }
 }
 
 #[derive(
@@ -281,3 +460,10 @@ pub struct TweakedOutput {
     pub pubkey: PublicKey,
     pub derivation_index: UnhardenedIndex,
 }
+
+fn now() -> NaiveDateTime {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed time service");
+    NaiveDateTime::from_timestamp(timestamp.as_secs() as i64, 0)
+}
@@ -0,0 +1,164 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! User-defined labels for contracts and their transactions, addresses and
+//! outputs, which can be exchanged with other wallets using the BIP-329
+//! format.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use bitcoin::{Address, OutPoint, Txid};
+use serde_with::DisplayFromStr;
+
+use super::ContractId;
+
+/// Errors in label data
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum LabelError {
+    /// unknown label type `{0}`
+    UnknownType(String),
+
+    /// invalid reference `{1}` for the {0} label
+    InvalidRef(&'static str, String),
+}
+
+/// Object which label is assigned to
+#[derive(
+    Clone,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+pub enum LabelRef {
+    /// Contract itself
+    Contract,
+
+    /// Transaction spending from or paying to the contract
+    Tx(Txid),
+
+    /// Address of the contract
+    Addr(Address),
+
+    /// Transaction output belonging to the contract
+    Output(OutPoint),
+}
+
+impl LabelRef {
+    /// Label type name, matching BIP-329 type names where applicable
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LabelRef::Contract => "contract",
+            LabelRef::Tx(_) => "tx",
+            LabelRef::Addr(_) => "addr",
+            LabelRef::Output(_) => "output",
+        }
+    }
+
+    /// Constructs label reference from BIP-329 type and reference strings
+    pub fn with(type_name: &str, reference: &str) -> Result<Self, LabelError> {
+        let err = |name| LabelError::InvalidRef(name, reference.to_owned());
+        Ok(match type_name {
+            "contract" if reference.is_empty() => LabelRef::Contract,
+            "contract" => return Err(err("contract")),
+            "tx" => {
+                LabelRef::Tx(Txid::from_str(reference).map_err(|_| err("tx"))?)
+            }
+            "addr" => LabelRef::Addr(
+                Address::from_str(reference).map_err(|_| err("addr"))?,
+            ),
+            "output" => LabelRef::Output(
+                OutPoint::from_str(reference).map_err(|_| err("output"))?,
+            ),
+            unknown => return Err(LabelError::UnknownType(unknown.to_owned())),
+        })
+    }
+
+    /// Reference string in BIP-329 format; empty for contract labels
+    pub fn reference(&self) -> String {
+        match self {
+            LabelRef::Contract => s!(""),
+            LabelRef::Tx(txid) => txid.to_string(),
+            LabelRef::Addr(address) => address.to_string(),
+            LabelRef::Output(outpoint) => outpoint.to_string(),
+        }
+    }
+}
+
+impl Display for LabelRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelRef::Contract => f.write_str(self.type_name()),
+            _ => write!(f, "{}:{}", self.type_name(), self.reference()),
+        }
+    }
+}
+
+impl FromStr for LabelRef {
+    type Err = LabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.splitn(2, ':');
+        let type_name = split.next().unwrap_or_default();
+        LabelRef::with(type_name, split.next().unwrap_or_default())
+    }
+}
+
+/// Label assigned to a contract-related object
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{contract_id}.{reference}: {label}")]
+pub struct Label {
+    #[serde_as(as = "DisplayFromStr")]
+    pub contract_id: ContractId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub reference: LabelRef,
+    pub label: String,
+}
+
+/// Label record in BIP-329 JSON lines format
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct Bip329Record {
+    #[serde(rename = "type")]
+    pub type_name: String,
+
+    #[serde(rename = "ref")]
+    pub reference: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    /// Descriptor of the wallet the label belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
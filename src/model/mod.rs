@@ -15,6 +15,7 @@ mod address;
 mod citadel;
 mod contract;
 mod ids;
+mod label;
 mod operation;
 mod policy;
 mod state;
@@ -23,10 +24,15 @@ mod utxo;
 pub use address::AddressDerivation;
 pub use citadel::Citadel;
 pub use contract::{
-    Contract, ContractData, ContractMeta, SpendingPolicy, TweakedOutput,
+    ArchivedContract, ArchivedContractMeta, Contract, ContractData,
+    ContractMeta, SpendingPolicy, TweakedOutput,
 };
 pub use ids::ContractId;
+pub use label::{Bip329Record, Label, LabelError, LabelRef};
 pub use operation::{Operation, PaymentDirecton, PsbtWrapper};
-pub use policy::{ChannelDescriptor, Policy, PolicyType};
+pub use policy::{
+    parse_multipath_chain, Branch, BranchPair, ChannelDescriptor,
+    MultipathDescriptor, MultipathError, Policy, PolicyType,
+};
 pub use state::State;
 pub use utxo::{Allocations, Utxo};
@@ -24,6 +24,8 @@ use rgb::Disclosure;
 use wallet::hd::UnhardenedIndex;
 use wallet::psbt::Psbt;
 
+use super::Branch;
+
 #[serde_as]
 #[derive(
     Serialize,
@@ -50,8 +52,10 @@ pub enum PaymentDirecton {
         change_outputs: HashSet<u16>,
         giveaway: Option<u64>,
         paid_bitcoin_fee: u64,
+        /// Derivation branches and indexes of the outputs paying to the
+        /// contract
         #[serde_as(as = "HashSet<_>")]
-        output_derivation_indexes: HashSet<UnhardenedIndex>,
+        output_derivation_indexes: HashSet<(Branch, UnhardenedIndex)>,
         #[serde_as(as = "DisplayFromStr")]
         invoice: Invoice,
     },
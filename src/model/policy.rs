@@ -16,9 +16,10 @@ use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::io;
 use std::ops::Range;
+use std::str::FromStr;
 
 use bitcoin::util::bip32::KeySource;
-use bitcoin::Script;
+use bitcoin::{PublicKey, Script};
 use commit_verify::{CommitEncode, ConsensusCommit};
 use internet2::RemoteNodeAddr;
 use lnp::ChannelId;
@@ -28,7 +29,7 @@ use miniscript::{
     descriptor, Descriptor, DescriptorTrait, ForEach, ForEachKey, TranslatePk2,
 };
 use strict_encoding::{self, StrictDecode, StrictEncode};
-use wallet::descriptors::ContractDescriptor;
+use wallet::descriptors::{ContentType, ContractDescriptor};
 use wallet::hd::{ChildIndex, PubkeyChain, TerminalStep, UnhardenedIndex};
 
 use super::ContractId;
@@ -95,6 +96,156 @@ pub enum PolicyType {
     Computing,
 }
 
+/// Branch of the contract key derivation. Contracts created with the
+/// multipath derivation step (like `/<0;1>/*`) have distinct branches for the
+/// receive and change addresses (see [`BranchPair`]); other contracts use a
+/// single branch for both.
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Branch {
+    /// Addresses given to payers
+    #[display("external")]
+    External,
+
+    /// Change addresses
+    #[display("internal")]
+    Internal,
+}
+
+impl Default for Branch {
+    fn default() -> Self {
+        Branch::External
+    }
+}
+
+/// Derivation indexes of the receive and change branches of the contract
+/// keys, given with the multipath derivation step `/<{receive};{change}>/*`
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("<{receive};{change}>")]
+pub struct BranchPair {
+    pub receive: UnhardenedIndex,
+    pub change: UnhardenedIndex,
+}
+
+impl BranchPair {
+    /// Index used for the branch derivation step
+    pub fn index(self, branch: Branch) -> UnhardenedIndex {
+        match branch {
+            Branch::External => self.receive,
+            Branch::Internal => self.change,
+        }
+    }
+}
+
+/// Errors in the public key chain with the multipath derivation step
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum MultipathError {
+    /// multipath derivation step must directly precede the final wildcard
+    /// step, like in `/<0;1>/*`
+    Position,
+
+    /// multipath derivation step `<{0}>` is not supported; it must list two
+    /// different unhardened indexes for the receive and change branches
+    UnsupportedTuple(String),
+
+    /// {0}
+    PubkeyChain(String),
+}
+
+/// Parses public key chain, which may use the multipath derivation step
+/// `/<{receive};{change}>/*` giving the indexes of the receive and change
+/// branches. The returned chain uses the receive branch index in place of the
+/// multipath step.
+pub fn parse_multipath_chain(
+    s: &str,
+) -> Result<(PubkeyChain, Option<BranchPair>), MultipathError> {
+    let s = s.trim();
+    let parse = |s: &str| {
+        PubkeyChain::from_str(s)
+            .map_err(|err| MultipathError::PubkeyChain(err.to_string()))
+    };
+    let start = match s.find('<') {
+        None => return parse(s).map(|chain| (chain, None)),
+        Some(start) => start,
+    };
+    let end = s[start..]
+        .find('>')
+        .map(|len| start + len)
+        .ok_or(MultipathError::Position)?;
+    let (prefix, suffix) = (&s[..start], &s[end + 1..]);
+    if !prefix.ends_with('/') || suffix != "/*" {
+        return Err(MultipathError::Position);
+    }
+    let tuple = &s[start + 1..end];
+    let indexes = tuple
+        .split(';')
+        .map(|index| index.trim().parse::<UnhardenedIndex>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| MultipathError::UnsupportedTuple(tuple.to_owned()))?;
+    let branches = match indexes[..] {
+        [receive, change] if receive != change => {
+            BranchPair { receive, change }
+        }
+        _ => return Err(MultipathError::UnsupportedTuple(tuple.to_owned())),
+    };
+    let chain = parse(&format!("{}{}/*", prefix, branches.receive))?;
+    Ok((chain, Some(branches)))
+}
+
+/// Contract descriptor which keys have separate receive and change
+/// derivation branches. The keys use the receive branch index in the branch
+/// derivation step.
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{descriptor} {branches}")]
+pub struct MultipathDescriptor {
+    #[serde_as(as = "DisplayFromStr")]
+    pub descriptor: ContractDescriptor<PubkeyChain>,
+    pub branches: BranchPair,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -118,6 +269,9 @@ pub enum Policy {
     Instant(ChannelDescriptor),
 
     Saving(#[serde_as(as = "DisplayFromStr")] ContractDescriptor<PubkeyChain>),
+
+    /// Current contract with separate receive and change derivation branches
+    CurrentMultipath(MultipathDescriptor),
 }
 
 impl ConsensusCommit for Policy {
@@ -136,9 +290,27 @@ impl Policy {
         self.clone().consensus_commit()
     }
 
+    /// Single-signature current contract policy; keys of contracts with
+    /// `branches` have separate receive and change derivation branches
+    pub fn single_sig(
+        category: ContentType,
+        pk: PubkeyChain,
+        branches: Option<BranchPair>,
+    ) -> Policy {
+        let descriptor = ContractDescriptor::SingleSig { category, pk };
+        match branches {
+            None => Policy::Current(descriptor),
+            Some(branches) => Policy::CurrentMultipath(MultipathDescriptor {
+                descriptor,
+                branches,
+            }),
+        }
+    }
+
     pub fn policy_type(&self) -> PolicyType {
         match self {
             Policy::Current { .. } => PolicyType::Current,
+            Policy::CurrentMultipath { .. } => PolicyType::Current,
             Policy::Instant { .. } => PolicyType::Instant,
             Policy::Saving { .. } => PolicyType::Saving,
         }
@@ -147,6 +319,10 @@ impl Policy {
     pub fn is_scripted(&self) -> bool {
         match self {
             Policy::Current(ContractDescriptor::SingleSig { .. }) => false,
+            Policy::CurrentMultipath(MultipathDescriptor {
+                descriptor: ContractDescriptor::SingleSig { .. },
+                ..
+            }) => false,
             _ => true,
         }
     }
@@ -174,6 +350,82 @@ impl Policy {
             Policy::Current(descriptor) => descriptor.to_descriptor(false),
             Policy::Instant(channel) => channel.to_descriptor(),
             Policy::Saving(descriptor) => descriptor.to_descriptor(false),
+            Policy::CurrentMultipath(multipath) => {
+                multipath.descriptor.to_descriptor(false)
+            }
+        }
+    }
+
+    /// Derivation indexes of the receive and change branches for contracts
+    /// created with separate branches
+    pub fn branch_pair(&self) -> Option<BranchPair> {
+        match self {
+            Policy::CurrentMultipath(multipath) => Some(multipath.branches),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the contract keys have separate receive and change
+    /// derivation branches
+    pub fn has_change_branch(&self) -> bool {
+        self.branch_pair().is_some()
+    }
+
+    /// Derivation branches used by the contract
+    pub fn branches(&self) -> Vec<Branch> {
+        if self.has_change_branch() {
+            vec![Branch::External, Branch::Internal]
+        } else {
+            vec![Branch::External]
+        }
+    }
+
+    /// Branch used for the change outputs; for contracts without separate
+    /// change branch this is the receive branch
+    pub fn change_branch(&self) -> Branch {
+        if self.has_change_branch() {
+            Branch::Internal
+        } else {
+            Branch::External
+        }
+    }
+
+    /// Detects the branch of the key derived at `index`
+    pub fn key_branch(
+        &self,
+        index: UnhardenedIndex,
+        pubkey: PublicKey,
+    ) -> Option<Branch> {
+        self.branches()
+            .into_iter()
+            .find(|branch| self.first_public_key(*branch, index) == pubkey)
+    }
+
+    fn branch_chain(
+        chain: &PubkeyChain,
+        branches: BranchPair,
+        branch: Branch,
+    ) -> PubkeyChain {
+        let mut chain = chain.clone();
+        let len = chain.terminal_path.len();
+        if len >= 2 {
+            chain.terminal_path[len - 2] =
+                TerminalStep::Index(branches.index(branch).into());
+        }
+        chain
+    }
+
+    /// Contract descriptor with the keys of the given derivation branch
+    pub fn to_branch_descriptor(
+        &self,
+        branch: Branch,
+    ) -> Descriptor<PubkeyChain> {
+        let d = self.to_descriptor();
+        match self.branch_pair() {
+            None => d,
+            Some(branches) => d.translate_pk2_infallible(|chain| {
+                Self::branch_chain(chain, branches, branch)
+            }),
         }
     }
 
@@ -203,11 +455,23 @@ impl Policy {
         collected
     }
 
+    pub fn branch_pubkey_chains(&self, branch: Branch) -> Vec<PubkeyChain> {
+        match self.branch_pair() {
+            None => self.pubkey_chains(),
+            Some(branches) => self
+                .pubkey_chains()
+                .iter()
+                .map(|chain| Self::branch_chain(chain, branches, branch))
+                .collect(),
+        }
+    }
+
     pub fn bip32_derivations(
         &self,
+        branch: Branch,
         index: UnhardenedIndex,
     ) -> BTreeMap<bitcoin::PublicKey, KeySource> {
-        self.pubkey_chains()
+        self.branch_pubkey_chains(branch)
             .into_iter()
             .map(|pubkey_chain| {
                 pubkey_chain.bip32_derivation(&*SECP256K1, Some(index))
@@ -217,9 +481,10 @@ impl Policy {
 
     pub fn first_public_key(
         &self,
+        branch: Branch,
         index: UnhardenedIndex,
     ) -> bitcoin::PublicKey {
-        self.pubkey_chains()
+        self.branch_pubkey_chains(branch)
             .first()
             .expect("Descriptor must contain at least one signing key")
             .derive_pubkey(&*SECP256K1, Some(index))
@@ -227,10 +492,11 @@ impl Policy {
 
     pub fn derive_scripts(
         &self,
+        branch: Branch,
         range: Range<UnhardenedIndex>,
     ) -> BTreeMap<UnhardenedIndex, Script> {
         let mut script_map = bmap![];
-        let d = self.to_descriptor();
+        let d = self.to_branch_descriptor(branch);
         let mut index = range.start;
         while index < range.end {
             script_map
@@ -244,10 +510,11 @@ impl Policy {
 
     pub fn derive_descriptor(
         &self,
+        branch: Branch,
         index: UnhardenedIndex,
         legacy: bool,
     ) -> Option<Descriptor<bitcoin::PublicKey>> {
-        let mut d = self.to_descriptor();
+        let mut d = self.to_branch_descriptor(branch);
         // TODO: Propose a PR to rust-miniscript with `to_nested()` method
         if legacy {
             d = match d {
@@ -270,11 +537,12 @@ impl Policy {
 
     pub fn derive_address(
         &self,
+        branch: Branch,
         index: UnhardenedIndex,
         chain: &Chain,
         legacy: bool,
     ) -> Option<AddressDerivation> {
-        self.derive_descriptor(index, legacy)
+        self.derive_descriptor(branch, index, legacy)
             .and_then(|d| chain.try_into().ok().map(|network| (d, network)))
             .and_then(|(d, network)| d.address(network).ok())
             .map(|address| AddressDerivation::with(address, vec![index]))
@@ -312,3 +580,75 @@ impl ChannelDescriptor {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use lnpbp::chain::Chain;
+    use wallet::descriptors::ContentType;
+    use wallet::hd::UnhardenedIndex;
+
+    use super::{parse_multipath_chain, Branch, BranchPair, Policy};
+    use crate::test_utils;
+
+    fn chain_with(terminal: &str) -> String {
+        test_utils::pubkey_chain(1).replace("/<0;1>/*", terminal)
+    }
+
+    fn pair(receive: u8, change: u8) -> BranchPair {
+        BranchPair {
+            receive: UnhardenedIndex::from(receive),
+            change: UnhardenedIndex::from(change),
+        }
+    }
+
+    #[test]
+    fn multipath_step() {
+        let (chain, branches) =
+            parse_multipath_chain(&chain_with("/<0;1>/*")).unwrap();
+        assert_eq!(branches, Some(pair(0, 1)));
+        assert_eq!(chain.to_string(), chain_with("/0/*"));
+
+        let (chain, branches) =
+            parse_multipath_chain(&chain_with("/< 1 ; 0 >/*")).unwrap();
+        assert_eq!(branches, Some(pair(1, 0)));
+        assert_eq!(chain.to_string(), chain_with("/1/*"));
+    }
+
+    #[test]
+    fn unsupported_multipath_step() {
+        for terminal in &[
+            "/<0;1;2>/*",
+            "/<0;0>/*",
+            "/<0>/*",
+            "/<0';1'>/*",
+            "/<0;x>/*",
+            "/<0;1>/0/*",
+            "/<0;1>",
+            "/<0;1/*",
+        ] {
+            assert!(parse_multipath_chain(&chain_with(terminal)).is_err());
+        }
+    }
+
+    #[test]
+    fn change_branch_is_explicit() {
+        let (pk, branches) =
+            parse_multipath_chain(&chain_with("/0/*")).unwrap();
+        assert_eq!(branches, None);
+        let policy = Policy::single_sig(ContentType::SegWit, pk, branches);
+        assert!(!policy.has_change_branch());
+        assert_eq!(policy.branches(), vec![Branch::External]);
+
+        let (pk, branches) =
+            parse_multipath_chain(&chain_with("/<0;1>/*")).unwrap();
+        let policy = Policy::single_sig(ContentType::SegWit, pk, branches);
+        assert!(policy.has_change_branch());
+        assert_eq!(policy.change_branch(), Branch::Internal);
+        let address = |branch| {
+            policy
+                .derive_address(branch, 0u8.into(), &Chain::Testnet3, false)
+                .map(|derivation| derivation.address)
+        };
+        assert_ne!(address(Branch::External), address(Branch::Internal));
+    }
+}
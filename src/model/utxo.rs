@@ -13,15 +13,20 @@
 
 use serde_with::DisplayFromStr;
 use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
 use std::str::FromStr;
 
 use amplify::Slice32;
 use bitcoin::{OutPoint, PublicKey, Txid};
 use rgb::AtomicValue;
+use strict_encoding::{StrictDecode, StrictEncode};
 use wallet::address::AddressCompat;
 use wallet::blockchain::ParseError;
 use wallet::hd::UnhardenedIndex;
 
+use super::Branch;
+
 pub type Allocations =
     BTreeMap<OutPoint, BTreeMap<rgb::ContractId, AtomicValue>>;
 
@@ -37,11 +42,7 @@ pub type Allocations =
     PartialEq,
     Hash,
     Debug,
-    Display,
-    StrictEncode,
-    StrictDecode,
 )]
-#[display("{value}@{height}>{offset}>{txid}:{vout}%{derivation_index}")]
 #[repr(C)]
 pub struct Utxo {
     /// Amount (in native atomic asset amount) of unspent asset
@@ -60,6 +61,10 @@ pub struct Utxo {
     /// Transaction output containing asset
     pub vout: u16,
 
+    /// Derivation branch of the key controlling the output
+    #[serde(default)]
+    pub branch: Branch,
+
     /// Index used by the description in deriving script from the transaction
     /// output
     pub derivation_index: UnhardenedIndex,
@@ -83,9 +88,65 @@ impl Utxo {
     }
 }
 
+/// Outputs from the receive branch keep the format of the older versions,
+/// `{value}@{height}>{offset}>{txid}:{vout}%{index}`; for the outputs from
+/// the change branch the index is prefixed with the branch: `%1/{index}`
+impl Display for Utxo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}>{}>{}:{}%",
+            self.value, self.height, self.offset, self.txid, self.vout
+        )?;
+        if self.branch == Branch::Internal {
+            f.write_str("1/")?;
+        }
+        Display::fmt(&self.derivation_index, f)
+    }
+}
+
+/// Strict encoding keeps the layout of the older versions, since it is used
+/// by RPC replies. Derivation branch is not encoded and is decoded as the
+/// receive branch; the cache keeps branches with its own layout of the data.
+impl StrictEncode for Utxo {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        Ok(self.value.strict_encode(&mut e)?
+            + self.height.strict_encode(&mut e)?
+            + self.offset.strict_encode(&mut e)?
+            + self.txid.strict_encode(&mut e)?
+            + self.vout.strict_encode(&mut e)?
+            + self.derivation_index.strict_encode(&mut e)?
+            + self.tweak.strict_encode(&mut e)?
+            + self.address.strict_encode(&mut e)?)
+    }
+}
+
+impl StrictDecode for Utxo {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        Ok(Utxo {
+            value: StrictDecode::strict_decode(&mut d)?,
+            height: StrictDecode::strict_decode(&mut d)?,
+            offset: StrictDecode::strict_decode(&mut d)?,
+            txid: StrictDecode::strict_decode(&mut d)?,
+            vout: StrictDecode::strict_decode(&mut d)?,
+            branch: Branch::External,
+            derivation_index: StrictDecode::strict_decode(&mut d)?,
+            tweak: StrictDecode::strict_decode(&mut d)?,
+            address: StrictDecode::strict_decode(&mut d)?,
+        })
+    }
+}
+
 impl FromStr for Utxo {
     type Err = ParseError;
 
+    /// Parses UTXO string representation; strings without derivation branch
+    /// written by the older versions refer to the receive branch
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut split = s.split(&['@', '>', '%', '=', ':'][..]);
         match (
@@ -107,21 +168,97 @@ impl FromStr for Utxo {
                 Some(index),
                 address,
                 None,
-            ) => Ok(Utxo {
-                value: value.parse()?,
-                height: height.parse()?,
-                offset: offset.parse()?,
-                vout: vout.parse()?,
-                txid: txid.parse()?,
-                derivation_index: index.parse().map_err(|_| ParseError)?,
-                tweak: None,
-                address: address
-                    .map(AddressCompat::from_str)
-                    .transpose()
-                    .ok()
-                    .flatten(),
-            }),
+            ) => {
+                let mut derivation = index.rsplitn(2, '/');
+                let index = derivation.next().unwrap_or_default();
+                let branch = match derivation.next() {
+                    None | Some("0") => Branch::External,
+                    Some("1") => Branch::Internal,
+                    Some(_) => return Err(ParseError),
+                };
+                Ok(Utxo {
+                    value: value.parse()?,
+                    height: height.parse()?,
+                    offset: offset.parse()?,
+                    vout: vout.parse()?,
+                    txid: txid.parse()?,
+                    branch,
+                    derivation_index: index.parse().map_err(|_| ParseError)?,
+                    tweak: None,
+                    address: address
+                        .map(AddressCompat::from_str)
+                        .transpose()
+                        .ok()
+                        .flatten(),
+                })
+            }
             _ => Err(ParseError),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    use strict_encoding::{StrictDecode, StrictEncode};
+    use wallet::hd::UnhardenedIndex;
+
+    use super::{Branch, Utxo};
+
+    fn utxo(branch: Branch) -> Utxo {
+        Utxo {
+            value: 1000,
+            height: 10,
+            offset: 2,
+            txid: Txid::hash(b"tx"),
+            vout: 1,
+            branch,
+            derivation_index: UnhardenedIndex::from(5u8),
+            tweak: None,
+            address: None,
+        }
+    }
+
+    #[test]
+    fn string_round_trip() {
+        for branch in &[Branch::External, Branch::Internal] {
+            let utxo = utxo(*branch);
+            assert_eq!(Utxo::from_str(&utxo.to_string()).ok(), Some(utxo));
+        }
+    }
+
+    #[test]
+    fn legacy_string() {
+        let legacy = format!("1000@10>2>{}:1%5", Txid::hash(b"tx"));
+        assert_eq!(Utxo::from_str(&legacy).ok(), Some(utxo(Branch::External)));
+        assert_eq!(utxo(Branch::External).to_string(), legacy);
+        let unknown = format!("1000@10>2>{}:1%2/5", Txid::hash(b"tx"));
+        assert!(Utxo::from_str(&unknown).is_err());
+    }
+
+    #[test]
+    fn legacy_encoding() {
+        let utxo = utxo(Branch::Internal);
+        let data = utxo.strict_serialize().unwrap();
+        let mut legacy = vec![];
+        utxo.value.strict_encode(&mut legacy).unwrap();
+        utxo.height.strict_encode(&mut legacy).unwrap();
+        utxo.offset.strict_encode(&mut legacy).unwrap();
+        utxo.txid.strict_encode(&mut legacy).unwrap();
+        utxo.vout.strict_encode(&mut legacy).unwrap();
+        utxo.derivation_index.strict_encode(&mut legacy).unwrap();
+        utxo.tweak.strict_encode(&mut legacy).unwrap();
+        utxo.address.strict_encode(&mut legacy).unwrap();
+        assert_eq!(data, legacy);
+        assert_eq!(
+            Utxo::strict_deserialize(&data).unwrap(),
+            Utxo {
+                branch: Branch::External,
+                ..utxo
+            }
+        );
+    }
+}
@@ -13,16 +13,18 @@
 
 use serde_with::DisplayFromStr;
 use std::collections::BTreeMap;
-use std::io;
 use std::ops::RangeInclusive;
+use std::{fmt, io};
 
-use bitcoin::Address;
+use bitcoin::{Address, Txid};
 use bp::seals::{OutpointHash, OutpointReveal};
+use chrono::NaiveDateTime;
 use invoice::Invoice;
 use rgb::Consignment;
 use strict_encoding::{self, StrictDecode, StrictEncode};
 use wallet::hd::{PubkeyChain, UnhardenedIndex};
 use wallet::{descriptors, psbt::Psbt};
+use zeroize::Zeroizing;
 
 use crate::model;
 
@@ -50,6 +52,32 @@ pub struct SingleSigInfo {
     pub category: descriptors::ContentType,
 }
 
+/// Single-signature contract with the public key chain in the text form,
+/// which may use `<0;1>` multipath derivation step for the receive and change
+/// branches
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("create_single_sig({category}({pubkey_chain}), \"{name}\")")]
+pub struct SingleSigDescriptorInfo {
+    pub name: String,
+    pub pubkey_chain: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub category: descriptors::ContentType,
+}
+
 #[derive(
     Serialize,
     Deserialize,
@@ -67,9 +95,36 @@ pub struct SingleSigInfo {
 #[display("sync_contract({contract_id}, depth: {lookup_depth})")]
 pub struct SyncContractRequest {
     pub contract_id: model::ContractId,
+
+    /// Gap limit for the address lookup used by this synchronization only;
+    /// the gap limit stored for the contract is left unchanged
     pub lookup_depth: u8,
 }
 
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("sync_contract_gap({contract_id}, ...)")]
+pub struct SyncContractGapRequest {
+    pub contract_id: model::ContractId,
+
+    /// Gap limit for the address lookup. If provided, it is also remembered
+    /// for the following synchronizations of the contract; otherwise the
+    /// gap limit stored for the contract is used.
+    pub gap_limit: Option<u8>,
+}
+
 #[serde_as]
 #[derive(
     Serialize,
@@ -261,6 +316,29 @@ pub struct PreparedTransfer {
     pub consignment: Option<Consignment>,
 }
 
+/// Merges two lists of inclusive index ranges into a sorted list of
+/// non-overlapping ranges, joining adjacent ones
+fn merge_ranges(
+    ranges: &mut Vec<RangeInclusive<u32>>,
+    other: impl IntoIterator<Item = RangeInclusive<u32>>,
+) {
+    ranges.extend(other);
+    ranges.sort_by_key(|range| (*range.start(), *range.end()));
+    let mut merged: Vec<RangeInclusive<u32>> = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                if range.end() > last.end() {
+                    let start = *last.start();
+                    *last = start..=*range.end();
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
 #[serde_as]
 #[derive(
     Serialize, Deserialize, Clone, Eq, PartialEq, Hash, Debug, Display,
@@ -273,6 +351,19 @@ pub struct SignerAccountInfo {
     pub used: Vec<RangeInclusive<u32>>,
 }
 
+impl SignerAccountInfo {
+    /// Returns string representation of the account key, which is used as a
+    /// unique account identifier by the storage
+    pub fn id(&self) -> String {
+        self.key.to_string()
+    }
+
+    /// Adds ranges of used indexes from the other account information
+    pub fn merge_used(&mut self, other: &SignerAccountInfo) {
+        merge_ranges(&mut self.used, other.used.iter().cloned())
+    }
+}
+
 impl StrictEncode for SignerAccountInfo {
     fn strict_encode<E: io::Write>(
         &self,
@@ -315,6 +406,19 @@ pub struct IdentityInfo {
     pub known: Vec<RangeInclusive<u32>>,
 }
 
+impl IdentityInfo {
+    /// Returns string representation of the identity key, which is used as a
+    /// unique identity identifier by the storage
+    pub fn id(&self) -> String {
+        self.key.to_string()
+    }
+
+    /// Adds ranges of known indexes from the other identity information
+    pub fn merge_known(&mut self, other: &IdentityInfo) {
+        merge_ranges(&mut self.known, other.known.iter().cloned())
+    }
+}
+
 impl StrictEncode for IdentityInfo {
     fn strict_encode<E: io::Write>(
         &self,
@@ -344,3 +448,473 @@ impl StrictDecode for IdentityInfo {
         })
     }
 }
+
+/// Passphrase used for storage encryption. Does not reveal its value in
+/// debug output and logs and wipes it from the memory when dropped.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Default for Passphrase {
+    fn default() -> Self {
+        Passphrase::from(String::new())
+    }
+}
+
+impl Passphrase {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Passphrase(Zeroizing::new(passphrase))
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+impl StrictEncode for Passphrase {
+    fn strict_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.0.strict_encode(e)
+    }
+}
+
+impl StrictDecode for Passphrase {
+    fn strict_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        String::strict_decode(d).map(Passphrase::from)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("change_passphrase(...)")]
+pub struct ChangePassphraseRequest {
+    pub old_passphrase: Passphrase,
+    pub new_passphrase: Passphrase,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("export_backup(include_stash: {include_stash})")]
+pub struct ExportBackupRequest {
+    /// Passphrase for encrypting the archive; if absent the archive is
+    /// created unencrypted
+    pub passphrase: Option<Passphrase>,
+    /// Whether to include RGB stash data into the archive
+    pub include_stash: bool,
+}
+
+/// How to treat contracts from the backup which already exist in the wallet
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+pub enum RestoreMode {
+    /// Refuse to restore the backup if it has contracts already present in
+    /// the wallet
+    #[display("abort")]
+    Abort,
+
+    /// Combine data from the backup and the wallet for the existing
+    /// contracts
+    #[display("merge")]
+    Merge,
+
+    /// Replace data of the existing contracts with the data from the backup
+    #[display("replace")]
+    Replace,
+}
+
+impl Default for RestoreMode {
+    fn default() -> Self {
+        RestoreMode::Abort
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("import_backup({mode}, restore_stash: {restore_stash})")]
+pub struct ImportBackupRequest {
+    /// Backup archive data
+    pub archive: Vec<u8>,
+    /// Passphrase for decrypting encrypted archive
+    pub passphrase: Option<Passphrase>,
+    pub mode: RestoreMode,
+    /// Whether to restore RGB stash data if they are present in the archive
+    pub restore_stash: bool,
+}
+
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Default,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(Debug)]
+pub struct BackupReport {
+    /// Contracts added to the wallet
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub added: Vec<model::ContractId>,
+    /// Existing contracts which data were combined with the backup
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub merged: Vec<model::ContractId>,
+    /// Existing contracts which data were replaced with the backup
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub replaced: Vec<model::ContractId>,
+    /// Number of restored RGB stash files
+    pub stash_files: u32,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{name}")]
+pub struct WalletInfo {
+    /// Wallet name
+    pub name: String,
+    /// Whether the wallet is opened by the runtime
+    pub open: bool,
+    /// Whether the wallet is used by wallet-specific requests not addressed
+    /// to a particular wallet
+    pub active: bool,
+    /// Whether the wallet is open, but its storage is locked
+    pub locked: bool,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("in_wallet({wallet}, ...)")]
+pub struct WalletRequest {
+    /// Name of the open wallet the request is addressed to
+    pub wallet: String,
+    /// Serialized wallet-specific request
+    pub request: Vec<u8>,
+}
+
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{contract_id}.{reference}")]
+pub struct ContractLabelRef {
+    #[serde_as(as = "DisplayFromStr")]
+    pub contract_id: model::ContractId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub reference: model::LabelRef,
+}
+
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("set_label({contract_id}.{reference}, ...)")]
+pub struct SetLabelRequest {
+    #[serde_as(as = "DisplayFromStr")]
+    pub contract_id: model::ContractId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub reference: model::LabelRef,
+    /// New label; `None` removes the existing label
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("export_labels(...)")]
+pub struct ExportLabelsRequest {
+    /// Contract to export labels of; all contracts if `None`
+    pub contract_id: Option<model::ContractId>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, StrictEncode, StrictDecode)]
+#[display("import_labels(...)")]
+pub struct ImportLabelsRequest {
+    /// Labels in BIP-329 JSON lines format
+    pub jsonl: String,
+    /// Contract to assign all imported labels to. If `None`, labels are
+    /// assigned to the contracts matching label origin or owning labelled
+    /// transaction, address or output.
+    pub contract_id: Option<model::ContractId>,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Default,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{imported} labels imported, {skipped} skipped")]
+pub struct LabelsReport {
+    /// Number of imported labels
+    pub imported: u32,
+    /// Number of records with unsupported types or without matching
+    /// contract
+    pub skipped: u32,
+}
+
+/// Order of the operations returned by the history query
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+pub enum SortOrder {
+    /// Oldest operations first
+    #[display("asc")]
+    Ascending,
+
+    /// Most recent operations first
+    #[display("desc")]
+    Descending,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Descending
+    }
+}
+
+/// Direction of the payment performed by the operation
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+pub enum OperationDirection {
+    #[display("incoming")]
+    Incoming,
+
+    #[display("outcoming")]
+    Outcoming,
+}
+
+/// Position of the operation in the query results, used to request the next
+/// page. Operations are ordered by their creation time and, within the same
+/// time, by their position in the contract history.
+#[derive(
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("{timestamp}:{position}")]
+pub struct OperationsCursor {
+    /// Operation creation time as a UNIX timestamp
+    pub timestamp: i64,
+    /// Index of the operation in the contract history
+    pub position: u32,
+}
+
+/// Filtered and paginated request for the contract operations. All filters
+/// are optional and are combined with logical AND.
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("query_operations({contract_id}, ...)")]
+pub struct OperationsQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    pub contract_id: model::ContractId,
+
+    /// Operations created at or after the given time
+    #[serde_as(as = "Option<chrono::DateTime<chrono::Utc>>")]
+    pub since: Option<NaiveDateTime>,
+
+    /// Operations created before the given time
+    #[serde_as(as = "Option<chrono::DateTime<chrono::Utc>>")]
+    pub until: Option<NaiveDateTime>,
+
+    /// Operations mined at or above the given block height
+    pub min_height: Option<i64>,
+
+    /// Operations mined at or below the given block height
+    pub max_height: Option<i64>,
+
+    pub direction: Option<OperationDirection>,
+
+    /// Operations with the given asset; bitcoin operations are selected with
+    /// the default (zero) asset id
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub asset_id: Option<rgb::ContractId>,
+
+    pub txid: Option<Txid>,
+
+    /// Operations which label or notes contain the given text, ignoring
+    /// case
+    pub label: Option<String>,
+
+    pub order: SortOrder,
+
+    /// Cursor of the last operation from the previous page
+    pub cursor: Option<OperationsCursor>,
+
+    /// Maximal number of operations in the page; default page size is used
+    /// if zero
+    pub limit: u16,
+}
+
+/// Operation from the contract history together with the label of its
+/// transaction
+#[derive(
+    Serialize, Deserialize, Clone, PartialEq, Debug, StrictEncode, StrictDecode,
+)]
+pub struct OperationInfo {
+    #[serde(flatten)]
+    pub operation: model::Operation,
+
+    /// Label of the operation transaction
+    pub label: Option<String>,
+}
+
+/// Page of the operations matching the query
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    PartialEq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display("operations_page({total} total)")]
+pub struct OperationsPage {
+    pub operations: Vec<OperationInfo>,
+
+    /// Cursor for requesting the next page; `None` for the last page
+    pub next_cursor: Option<OperationsCursor>,
+
+    /// Total number of operations matching the query filters
+    pub total: u32,
+}
+
+/// Chain reorganization detected during the wallet synchronization
+#[serde_as]
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Eq,
+    PartialEq,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(
+    "chain reorganization in wallet {wallet} starting from block \
+     {fork_height}: {unmined_operations} operation(s) are not mined anymore"
+)]
+pub struct ReorgInfo {
+    /// Name of the wallet which cache was rolled back
+    pub wallet: String,
+
+    #[serde_as(as = "chrono::DateTime<chrono::Utc>")]
+    pub detected_at: NaiveDateTime,
+
+    /// Height of the first replaced block
+    pub fork_height: u32,
+
+    /// Transactions mined in the replaced blocks. If they are mined in the
+    /// new chain, they will be found by the next synchronization.
+    pub orphaned_txids: Vec<Txid>,
+
+    /// Number of the contract operations which were marked as not mined
+    pub unmined_operations: u32,
+}
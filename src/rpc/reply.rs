@@ -14,15 +14,21 @@
 use serde_with::{As, DisplayFromStr};
 use std::collections::BTreeMap;
 
-use bitcoin::Address;
+use bitcoin::{Address, OutPoint};
 use bp::seals::OutpointReveal;
 use internet2::presentation;
 use invoice::Invoice;
 use microservices::{rpc, rpc_connection};
 use wallet::hd::UnhardenedIndex;
 
-use crate::model::{AddressDerivation, ContractMeta, Operation, Utxo};
-use crate::rpc::message::{IdentityInfo, PreparedTransfer};
+use crate::model::{
+    AddressDerivation, ArchivedContractMeta, Branch, ContractMeta, Label,
+    Operation, Utxo,
+};
+use crate::rpc::message::{
+    BackupReport, IdentityInfo, LabelsReport, OperationsPage, PreparedTransfer,
+    ReorgInfo, SignerAccountInfo, WalletInfo,
+};
 use crate::Error;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Display, Api)]
@@ -37,6 +43,23 @@ pub enum Reply {
     #[display("failure({0})")]
     Failure(microservices::rpc::Failure),
 
+    #[api(type = 0x0110)]
+    #[display("backup_imported({0})")]
+    BackupImported(BackupReport),
+
+    /// Backup archive data
+    #[api(type = 0x0112)]
+    #[display("backup(...)")]
+    Backup(Vec<u8>),
+
+    #[api(type = 0x0111)]
+    #[display("labels_imported({0})")]
+    LabelsImported(LabelsReport),
+
+    #[api(type = 0x0120)]
+    #[display("wallets(...)")]
+    Wallets(Vec<WalletInfo>),
+
     #[api(type = 0x0200)]
     #[display("contracts(...)")]
     Contracts(Vec<ContractMeta>),
@@ -50,10 +73,42 @@ pub enum Reply {
     #[display("contract_unspent(...)")]
     ContractUnspent(BTreeMap<rgb::ContractId, Vec<Utxo>>),
 
+    /// Derivation branches of the unspent outputs, which are not a part of
+    /// the strict encoding of [`Utxo`]
+    #[serde(with = "As::<BTreeMap<DisplayFromStr, _>>")]
+    #[api(type = 0x0204)]
+    #[display("unspent_branches(...)")]
+    UnspentBranches(BTreeMap<OutPoint, Branch>),
+
+    #[api(type = 0x0203)]
+    #[display("archived_contracts(...)")]
+    ArchivedContracts(Vec<ArchivedContractMeta>),
+
     #[api(type = 0x0210)]
     #[display("operations(...)")]
     Operations(Vec<Operation>),
 
+    #[api(type = 0x0211)]
+    #[display(inner)]
+    OperationsPage(OperationsPage),
+
+    #[api(type = 0x0212)]
+    #[display("reorgs(...)")]
+    Reorgs(Vec<ReorgInfo>),
+
+    #[api(type = 0x0220)]
+    #[display("labels(...)")]
+    Labels(Vec<Label>),
+
+    #[api(type = 0x0221)]
+    #[display("label({0})")]
+    Label(Label),
+
+    /// Labels in BIP-329 JSON lines format
+    #[api(type = 0x0222)]
+    #[display("labels_export(...)")]
+    LabelsExport(String),
+
     #[api(type = 0x0310)]
     #[display("addresses(...)")]
     Addresses(BTreeMap<Address, UnhardenedIndex>),
@@ -92,6 +147,18 @@ pub enum Reply {
     #[api(type = 0x0500)]
     #[display("identities(...)")]
     Identities(Vec<IdentityInfo>),
+
+    #[api(type = 0x0501)]
+    #[display("identity({0})")]
+    Identity(IdentityInfo),
+
+    #[api(type = 0x0600)]
+    #[display("signers(...)")]
+    Signers(Vec<SignerAccountInfo>),
+
+    #[api(type = 0x0601)]
+    #[display("signer({0})")]
+    Signer(SignerAccountInfo),
 }
 
 impl rpc_connection::Reply for Reply {}
@@ -132,10 +199,21 @@ impl Reply {
             Reply::Failure(err) => {
                 Ok(format!(r#"{{"error": "{}"}}"#, err.to_string()))
             }
+            Reply::BackupImported(data) => serde_json::to_string(data),
+            Reply::Backup(data) => serde_json::to_string(data),
+            Reply::LabelsImported(data) => serde_json::to_string(data),
+            Reply::Wallets(data) => serde_json::to_string(data),
             Reply::Contracts(data) => serde_json::to_string(data),
             Reply::Contract(data) => serde_json::to_string(data),
             Reply::ContractUnspent(data) => serde_json::to_string(data),
+            Reply::UnspentBranches(data) => serde_json::to_string(data),
+            Reply::ArchivedContracts(data) => serde_json::to_string(data),
             Reply::Operations(data) => serde_json::to_string(data),
+            Reply::OperationsPage(data) => serde_json::to_string(data),
+            Reply::Reorgs(data) => serde_json::to_string(data),
+            Reply::Labels(data) => serde_json::to_string(data),
+            Reply::Label(data) => serde_json::to_string(data),
+            Reply::LabelsExport(data) => serde_json::to_string(data),
             Reply::Addresses(data) => serde_json::to_string(data),
             Reply::AddressDerivation(data) => serde_json::to_string(data),
             Reply::BlindUtxo(data) => serde_json::to_string(data),
@@ -145,6 +223,9 @@ impl Reply {
             Reply::Asset(data) => serde_json::to_string(data),
             Reply::Assets(data) => serde_json::to_string(data),
             Reply::Identities(data) => serde_json::to_string(data),
+            Reply::Identity(data) => serde_json::to_string(data),
+            Reply::Signers(data) => serde_json::to_string(data),
+            Reply::Signer(data) => serde_json::to_string(data),
         }
     }
 }
@@ -12,12 +12,17 @@
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 use rgb::{Consignment, Genesis};
+use wallet::descriptors;
 use wallet::psbt::Psbt;
 
 use super::message::{
-    AddInvoiceRequest, ComposeTransferRequest, ContractAddressTuple,
-    IdentityInfo, NextAddressRequest, RenameContractRequest, SignerAccountInfo,
-    SingleSigInfo, SyncContractRequest,
+    AddInvoiceRequest, ChangePassphraseRequest, ComposeTransferRequest,
+    ContractAddressTuple, ContractLabelRef, ExportBackupRequest,
+    ExportLabelsRequest, IdentityInfo, ImportBackupRequest,
+    ImportLabelsRequest, NextAddressRequest, OperationsQuery, Passphrase,
+    RenameContractRequest, SetLabelRequest, SignerAccountInfo,
+    SingleSigDescriptorInfo, SingleSigInfo, SyncContractGapRequest,
+    SyncContractRequest, WalletRequest,
 };
 use crate::model::ContractId;
 
@@ -25,6 +30,54 @@ use crate::model::ContractId;
 #[api(encoding = "strict")]
 #[non_exhaustive]
 pub enum Request {
+    #[api(type = 0x0010)]
+    #[display("unlock_storage(...)")]
+    UnlockStorage(Passphrase),
+
+    #[api(type = 0x0011)]
+    #[display("lock_storage()")]
+    LockStorage,
+
+    #[api(type = 0x0012)]
+    #[display(inner)]
+    ChangePassphrase(ChangePassphraseRequest),
+
+    #[api(type = 0x0013)]
+    #[display("init_storage(...)")]
+    InitStorage(Passphrase),
+
+    #[api(type = 0x0020)]
+    #[display(inner)]
+    ExportBackup(ExportBackupRequest),
+
+    #[api(type = 0x0021)]
+    #[display(inner)]
+    ImportBackup(ImportBackupRequest),
+
+    #[api(type = 0x0030)]
+    #[display("list_wallets()")]
+    ListWallets,
+
+    #[api(type = 0x0031)]
+    #[display("create_wallet({0})")]
+    CreateWallet(String),
+
+    #[api(type = 0x0032)]
+    #[display("open_wallet({0})")]
+    OpenWallet(String),
+
+    #[api(type = 0x0033)]
+    #[display("close_wallet({0})")]
+    CloseWallet(String),
+
+    #[api(type = 0x0034)]
+    #[display("delete_wallet({0})")]
+    DeleteWallet(String),
+
+    #[api(type = 0x0035)]
+    #[display(inner)]
+    InWallet(WalletRequest),
+
     #[api(type = 0x0100)]
     #[display("list_contracts()")]
     ListContracts,
@@ -41,10 +94,30 @@ pub enum Request {
     #[display("contract_unspent({0})")]
     ContractUnspent(ContractId),
 
+    #[api(type = 0x0104)]
+    #[display(inner)]
+    QueryOperations(OperationsQuery),
+
+    #[api(type = 0x0105)]
+    #[display("list_reorgs()")]
+    ListReorgs,
+
+    #[api(type = 0x0106)]
+    #[display(inner)]
+    SyncContractGap(SyncContractGapRequest),
+
+    #[api(type = 0x0107)]
+    #[display("contract_unspent_branches({0})")]
+    ContractUnspentBranches(ContractId),
+
     #[api(type = 0x0110)]
     #[display(inner)]
     CreateSingleSig(SingleSigInfo),
 
+    #[api(type = 0x0111)]
+    #[display(inner)]
+    CreateSingleSigDescriptor(SingleSigDescriptorInfo),
+
     #[api(type = 0x0120)]
     #[display(inner)]
     RenameContract(RenameContractRequest),
@@ -53,6 +126,38 @@ pub enum Request {
     #[display("delete_contract({0})")]
     DeleteContract(ContractId),
 
+    #[api(type = 0x0141)]
+    #[display("list_archived_contracts()")]
+    ListArchivedContracts,
+
+    #[api(type = 0x0142)]
+    #[display("restore_contract({0})")]
+    RestoreContract(ContractId),
+
+    #[api(type = 0x0143)]
+    #[display("force_delete_contract({0})")]
+    ForceDeleteContract(ContractId),
+
+    #[api(type = 0x0160)]
+    #[display("list_labels({0})")]
+    ListLabels(ContractId),
+
+    #[api(type = 0x0161)]
+    #[display("get_label({0})")]
+    GetLabel(ContractLabelRef),
+
+    #[api(type = 0x0162)]
+    #[display(inner)]
+    SetLabel(SetLabelRequest),
+
+    #[api(type = 0x0163)]
+    #[display(inner)]
+    ExportLabels(ExportLabelsRequest),
+
+    #[api(type = 0x0164)]
+    #[display(inner)]
+    ImportLabels(ImportLabelsRequest),
+
     #[api(type = 0x0310)]
     #[display("used_addresses({0})")]
     UsedAddresses(ContractId),
@@ -93,13 +198,25 @@ pub enum Request {
     #[display("list_identities()")]
     ListIdentities,
 
+    #[api(type = 0x0510)]
+    #[display("add_identity({0})")]
+    AddIdentity(IdentityInfo),
+
+    #[api(type = 0x0520)]
+    #[display("remove_identity({0})")]
+    RemoveIdentity(descriptors::SingleSig),
+
+    #[api(type = 0x0600)]
+    #[display("list_signers()")]
+    ListSigners,
+
     #[api(type = 0x0610)]
     #[display("add_signing({0})")]
     AddSigner(SignerAccountInfo),
 
-    #[api(type = 0x0510)]
-    #[display("add_identity({0})")]
-    AddIdentity(IdentityInfo),
+    #[api(type = 0x0620)]
+    #[display("remove_signer({0})")]
+    RemoveSigner(descriptors::SingleSig),
 
     #[api(type = 0x0700)]
     #[display("list_assets()")]
@@ -109,3 +226,41 @@ pub enum Request {
     #[display("import_asset({0})")]
     ImportAsset(Genesis),
 }
+
+impl Request {
+    /// Detects whether the request can be processed while the wallet storage
+    /// is locked
+    pub fn is_storage_independent(&self) -> bool {
+        matches!(
+            self,
+            Request::UnlockStorage(_)
+                | Request::LockStorage
+                | Request::ChangePassphrase(_)
+                | Request::InitStorage(_)
+                | Request::ListWallets
+                | Request::CreateWallet(_)
+                | Request::OpenWallet(_)
+                | Request::CloseWallet(_)
+                | Request::DeleteWallet(_)
+                | Request::InWallet(_)
+                | Request::ListReorgs
+                | Request::ListAssets
+                | Request::ImportAsset(_)
+        )
+    }
+
+    /// Detects whether the request manages wallets or is addressed to some
+    /// wallet itself, and thus can't be addressed to a wallet with
+    /// [`Request::InWallet`]
+    pub fn is_wallet_independent(&self) -> bool {
+        matches!(
+            self,
+            Request::ListWallets
+                | Request::CreateWallet(_)
+                | Request::OpenWallet(_)
+                | Request::CloseWallet(_)
+                | Request::DeleteWallet(_)
+                | Request::InWallet(_)
+        )
+    }
+}
@@ -18,10 +18,80 @@ use internet2::zmqsocket::ZmqSocketAddr;
 use lnpbp::chain::Chain;
 use microservices::FileFormat;
 
-use crate::{cache, storage};
+use crate::{cache, chainapi, storage};
 
 const STORAGE_FORMAT: FileFormat = FileFormat::Yaml;
 const CACHE_FORMAT: FileFormat = FileFormat::Yaml;
+#[cfg(feature = "sqlite")]
+const STORAGE_DB_FILENAME: &str = "data.sqlite";
+#[cfg(feature = "sqlite")]
+const CACHE_DB_FILENAME: &str = "cache.sqlite";
+const WALLETS_DIR: &str = "wallets";
+#[cfg(feature = "compact_filters")]
+const FILTERS_FILE: &str = "filters.dat";
+
+/// Name of the wallet opened on runtime start. Its data are kept right in the
+/// data directory, while other wallets use subdirectories of
+/// `{data_dir}/wallets`.
+pub const DEFAULT_WALLET: &str = "default";
+
+/// Type of the driver used for storing wallet data
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum StorageType {
+    /// Plain file storage
+    #[display("file")]
+    File,
+
+    /// File storage encrypted with a passphrase-derived key. Wallet data are
+    /// not accessible until the storage is unlocked via RPC.
+    #[display("encrypted")]
+    Encrypted,
+
+    /// Embedded SQLite database, used both for wallet data and cache
+    #[cfg(feature = "sqlite")]
+    #[display("sqlite")]
+    Sqlite,
+
+    /// Wallet data and cache are kept in memory only and are lost once the
+    /// runtime stops
+    #[display("memory")]
+    Memory,
+}
+
+impl Default for StorageType {
+    fn default() -> Self {
+        StorageType::File
+    }
+}
+
+/// Type of the source of blockchain data
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum ChainApiType {
+    /// Electrum server
+    #[display("electrum")]
+    Electrum,
+
+    /// Esplora (or electrs) HTTP REST API server
+    #[cfg(feature = "esplora")]
+    #[display("esplora")]
+    Esplora,
+
+    /// Bitcoin Core node JSON-RPC API
+    #[cfg(feature = "bitcoind")]
+    #[display("bitcoind")]
+    Bitcoind,
+
+    /// Bitcoin P2P peer serving BIP157/158 compact block filters
+    #[cfg(feature = "compact_filters")]
+    #[display("bip157")]
+    CompactFilters,
+}
+
+impl Default for ChainApiType {
+    fn default() -> Self {
+        ChainApiType::Electrum
+    }
+}
 
 /// Final configuration resulting from data contained in config file environment
 /// variables and command-line options. For security reasons node key is kept
@@ -44,27 +114,157 @@ pub struct Config {
     /// Data location
     pub data_dir: PathBuf,
 
+    /// Type of the wallet data storage
+    pub storage_type: StorageType,
+
     /// Verbosity level
     pub verbose: u8,
 
-    /// Electrum server connection string
-    pub electrum_server: String,
+    /// Electrum server connection strings in the order of their priority;
+    /// the first server is also used by the embedded RGB node
+    pub electrum_servers: Vec<String>,
+
+    /// Whether unspent outputs and transaction positions within blocks must
+    /// be confirmed by two different Electrum servers
+    pub electrum_paranoid: bool,
+
+    /// Timeout for Electrum server requests, in seconds
+    pub electrum_timeout: Option<u8>,
+
+    /// Type of the source of blockchain data
+    pub chain_api: ChainApiType,
+
+    /// SOCKS5 proxy address in `host:port` form, like `127.0.0.1:9050` for
+    /// Tor, used for all connections to the sources of blockchain data.
+    /// Embedded RGB node connects to Electrum server directly, so it can't
+    /// be used together with a proxy.
+    pub proxy: Option<String>,
+
+    /// Whether to run chainwatch service, which synchronizes contracts on
+    /// new transactions without client requests
+    pub chainwatch: bool,
+
+    /// Base URL of Esplora HTTP REST API, used with the Esplora source of
+    /// blockchain data
+    pub esplora_server: String,
+
+    /// URL of bitcoind JSON-RPC API, used with the bitcoind source of
+    /// blockchain data
+    pub bitcoind_server: String,
+
+    /// Bitcoind RPC credentials in `user:password` form, or path to the
+    /// bitcoind cookie file
+    pub bitcoind_auth: Option<String>,
+
+    /// Address of the bitcoin P2P peer serving compact block filters in
+    /// `host:port` form, used with the BIP157 source of blockchain data
+    pub filters_peer: String,
+
+    /// Height of the block from which compact block filters are matched
+    /// against wallet scripts; block headers are downloaded starting from
+    /// the highest known checkpoint below it
+    pub filters_start_height: u32,
 }
 
 impl Config {
-    pub fn storage_conf(&self) -> storage::FileConfig {
+    /// Directory containing subdirectories of all non-default wallets
+    pub fn wallets_dir(&self) -> PathBuf {
+        self.data_dir.join(WALLETS_DIR)
+    }
+
+    /// Directory keeping storage and cache data of the named wallet
+    pub fn wallet_dir(&self, wallet: &str) -> PathBuf {
+        if wallet == DEFAULT_WALLET {
+            self.data_dir.clone()
+        } else {
+            self.wallets_dir().join(wallet)
+        }
+    }
+
+    pub fn storage_conf(&self, wallet: &str) -> storage::FileConfig {
         storage::FileConfig {
-            location: self.data_dir.to_string_lossy().to_string(),
+            location: self.wallet_dir(wallet).to_string_lossy().to_string(),
             format: STORAGE_FORMAT,
         }
     }
 
-    pub fn cache_conf(&self) -> cache::FileConfig {
+    /// Electrum server with the highest priority, which is used by the
+    /// embedded RGB node
+    pub fn electrum_server(&self) -> String {
+        self.electrum_servers.first().cloned().unwrap_or_default()
+    }
+
+    /// Proxy configuration for the connections made by the wallet. The
+    /// connections are isolated from the connections of other wallets, so
+    /// Tor uses different circuits for them.
+    pub fn proxy_conf(&self, wallet: &str) -> Option<chainapi::ProxyConfig> {
+        self.proxy.as_ref().map(|addr| chainapi::ProxyConfig {
+            addr: addr.clone(),
+            isolation: Some(wallet.to_owned()),
+        })
+    }
+
+    pub fn electrum_conf(&self, wallet: &str) -> chainapi::ElectrumPoolConfig {
+        chainapi::ElectrumPoolConfig {
+            servers: self
+                .electrum_servers
+                .iter()
+                .map(|server| chainapi::ElectrumConfig {
+                    server: server.clone(),
+                    timeout: self.electrum_timeout,
+                    proxy: self.proxy_conf(wallet),
+                })
+                .collect(),
+            paranoid: self.electrum_paranoid,
+        }
+    }
+
+    #[cfg(feature = "esplora")]
+    pub fn esplora_conf(&self, wallet: &str) -> chainapi::EsploraConfig {
+        chainapi::EsploraConfig {
+            server: self.esplora_server.clone(),
+            proxy: self.proxy_conf(wallet),
+        }
+    }
+
+    #[cfg(feature = "bitcoind")]
+    pub fn bitcoind_conf(&self, wallet: &str) -> chainapi::BitcoindConfig {
+        chainapi::BitcoindConfig {
+            server: self.bitcoind_server.clone(),
+            auth: self.bitcoind_auth.clone(),
+            proxy: self.proxy_conf(wallet),
+        }
+    }
+
+    /// Compact filter data are kept in the wallet directory, since wallets
+    /// use separate light clients when a proxy is configured
+    #[cfg(feature = "compact_filters")]
+    pub fn filters_conf(&self, wallet: &str) -> chainapi::CompactFilterConfig {
+        chainapi::CompactFilterConfig {
+            peer: self.filters_peer.clone(),
+            chain: self.chain.clone(),
+            start_height: self.filters_start_height,
+            cache: self.wallet_dir(wallet).join(FILTERS_FILE),
+            proxy: self.proxy_conf(wallet),
+        }
+    }
+
+    pub fn cache_conf(&self, wallet: &str) -> cache::FileConfig {
         cache::FileConfig {
-            location: self.data_dir.to_string_lossy().to_string(),
+            location: self.wallet_dir(wallet).to_string_lossy().to_string(),
             format: CACHE_FORMAT,
         }
     }
+
+    #[cfg(feature = "sqlite")]
+    pub fn storage_db(&self, wallet: &str) -> PathBuf {
+        self.wallet_dir(wallet).join(STORAGE_DB_FILENAME)
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn cache_db(&self, wallet: &str) -> PathBuf {
+        self.wallet_dir(wallet).join(CACHE_DB_FILENAME)
+    }
 }
 
 impl Config {
@@ -79,8 +279,11 @@ impl Config {
         self.process_dir(&mut data_dir);
         self.data_dir = PathBuf::from(data_dir);
 
-        fs::create_dir_all(&self.data_dir)
-            .expect("Unable to access data directory");
+        // In-memory runtime does not need to persist any data
+        if self.storage_type != StorageType::Memory {
+            fs::create_dir_all(&self.data_dir)
+                .expect("Unable to access data directory");
+        }
 
         for dir in vec![&mut self.rpc_endpoint, &mut self.rgb20_endpoint] {
             match dir {
@@ -95,7 +298,7 @@ impl Config {
     pub fn process_dir(&self, path: &mut String) {
         *path = path.replace("{data_dir}", &self.data_dir.to_string_lossy());
         *path = path.replace("{network}", &self.chain.to_string());
-        *path = path.replace("{id}", "default");
+        *path = path.replace("{id}", DEFAULT_WALLET);
         *path = shellexpand::tilde(path).to_string();
     }
 }
@@ -0,0 +1,270 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Exclusive use of the data directory by a single runtime process.
+//!
+//! On unix and Windows systems the exclusivity is provided by a lock which
+//! the runtime holds over the lock file inside the data directory for its
+//! whole lifetime (`flock` on unix and `LockFileEx` on Windows). The lock is
+//! released by the operating system whenever the process exits, so locks of
+//! crashed processes never become stale. The file contains the identifier of
+//! the owning process, which is used only for diagnostic messages.
+//!
+//! Other platforms fall back to the atomic creation of the lock file, which
+//! is removed when the runtime is dropped.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+#[cfg(not(any(unix, windows)))]
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use crate::Error;
+
+const LOCK_FILE_NAME: &str = "citadel.lock";
+
+/// Number of attempts to read the owner of the lock, which may be just
+/// taken by another process and not have the owner written yet
+const OWNER_READ_ATTEMPTS: usize = 5;
+const OWNER_READ_DELAY: Duration = Duration::from_millis(100);
+
+/// Lock held over the data directory for the lifetime of the runtime
+#[derive(Debug)]
+pub(super) struct DataDirLock {
+    /// Open lock file; the lock is released when it gets closed
+    #[cfg(any(unix, windows))]
+    file: fs::File,
+    #[cfg(not(any(unix, windows)))]
+    path: PathBuf,
+}
+
+impl DataDirLock {
+    /// Takes the lock over the data directory
+    #[cfg(any(unix, windows))]
+    pub fn acquire(data_dir: &Path) -> Result<Self, Error> {
+        use std::io::{Seek, SeekFrom};
+
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        if !try_lock(&file)? {
+            return Err(Self::locked_error(&path));
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        debug!("Data directory lock {:?} is taken", path);
+        Ok(Self { file })
+    }
+
+    /// Takes the lock over the data directory, removing the lock file left by
+    /// a previous run of a process with the same identifier, which may happen
+    /// when the process identifiers are reused (like in containers)
+    #[cfg(not(any(unix, windows)))]
+    pub fn acquire(data_dir: &Path) -> Result<Self, Error> {
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let mut own_removed = false;
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    writeln!(file, "{}", std::process::id())?;
+                    file.sync_all()?;
+                    debug!("Data directory lock {:?} is taken", path);
+                    return Ok(Self { path });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            if own_removed || Self::owner(&path)? != Some(std::process::id()) {
+                return Err(Self::locked_error(&path));
+            }
+            warn!(
+                "Removing data directory lock {:?} left by a previous run of \
+                 this process",
+                path
+            );
+            fs::remove_file(&path)?;
+            own_removed = true;
+        }
+    }
+
+    fn locked_error(path: &Path) -> Error {
+        let path_str = path.to_string_lossy().to_string();
+        match Self::owner(path) {
+            Ok(Some(owner)) => Error::DataDirLocked(path_str, owner),
+            _ => Error::DataDirLockUnreadable(path_str),
+        }
+    }
+
+    /// Reads identifier of the process owning the lock, waiting for the
+    /// process to write it if the lock was just taken
+    fn owner(path: &Path) -> io::Result<Option<u32>> {
+        for attempt in 1..=OWNER_READ_ATTEMPTS {
+            let mut content = String::new();
+            match fs::File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut content))
+            {
+                Ok(_) => {
+                    if let Ok(pid) = content.trim().parse() {
+                        return Ok(Some(pid));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            }
+            if attempt < OWNER_READ_ATTEMPTS {
+                thread::sleep(OWNER_READ_DELAY);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Takes advisory lock over the file unless it is held by another process
+#[cfg(unix)]
+fn try_lock(file: &fs::File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let res =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if res == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Takes lock over the file unless it is held by another process. Locked
+/// bytes can't be read by other processes on Windows, so the locked byte lies
+/// far beyond the identifier of the owning process written to the file.
+#[cfg(windows)]
+fn try_lock(file: &fs::File) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{
+        LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED,
+    };
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    unsafe { overlapped.u.s_mut().OffsetHigh = 1 };
+    let res = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+            0,
+            1,
+            0,
+            &mut overlapped,
+        )
+    };
+    if res != 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+impl Drop for DataDirLock {
+    /// The lock file itself is kept: removing it would let a process which
+    /// has already opened it and a process creating a new one to hold the
+    /// lock at the same time. The lock is released once the file is closed.
+    #[cfg(any(unix, windows))]
+    fn drop(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            warn!("Unable to clear data directory lock: {}", err);
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(
+                "Unable to remove data directory lock {:?}: {}",
+                self.path, err
+            );
+        }
+    }
+}
+
+#[cfg(all(test, any(unix, windows)))]
+mod test {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn data_dir(name: &str) -> std::path::PathBuf {
+        temp_dir(&format!("lock-{}", name))
+    }
+
+    #[test]
+    fn exclusive_while_held() {
+        let dir = data_dir("exclusive");
+        let lock = DataDirLock::acquire(&dir).unwrap();
+        match DataDirLock::acquire(&dir) {
+            Err(Error::DataDirLocked(_, owner)) => {
+                assert_eq!(owner, std::process::id())
+            }
+            other => panic!("lock is not exclusive: {:?}", other),
+        }
+        drop(lock);
+        DataDirLock::acquire(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lock_file_left_by_same_pid() {
+        // Lock file left by a crashed run of the process which had the same
+        // identifier as the current one, like PID 1 in a restarted container
+        let dir = data_dir("same-pid");
+        fs::write(
+            dir.join(LOCK_FILE_NAME),
+            format!("{}\n", std::process::id()),
+        )
+        .unwrap();
+        DataDirLock::acquire(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lock_file_left_by_other_pid() {
+        // Lock file left by a crashed process with a different identifier
+        let dir = data_dir("other-pid");
+        let stale = std::process::id().wrapping_add(1);
+        fs::write(dir.join(LOCK_FILE_NAME), format!("{}\n", stale)).unwrap();
+        DataDirLock::acquire(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,9 +14,11 @@
 //! Main RPC server runtime (operating as daemon or thread)
 
 mod config;
+mod lock;
 mod processor;
 mod rpc_server;
 mod service;
+mod wallet;
 
-pub use config::Config;
+pub use config::{ChainApiType, Config, StorageType, DEFAULT_WALLET};
 pub use service::{run, Runtime};
@@ -0,0 +1,49 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use crate::cache::Driver as CacheDriver;
+use crate::model::ContractId;
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+
+impl Runtime {
+    /// Moves contract into the archive, refusing to do that for contracts
+    /// having funds known to the cache unless `force` is set
+    pub(in crate::runtime) fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+        force: bool,
+    ) -> Result<(), Error> {
+        self.storage.contract_ref(contract_id)?;
+        let funded = self
+            .cache
+            .unspent(contract_id)?
+            .values()
+            .flatten()
+            .filter(|utxo| utxo.value > 0)
+            .count();
+        if funded > 0 {
+            if !force {
+                return Err(Error::ContractNotEmpty(contract_id));
+            }
+            warn!(
+                "Archiving contract {} which still has {} funded outputs",
+                contract_id, funded
+            );
+        }
+        self.storage.archive_contract(contract_id)?;
+        info!("Contract {} is moved into the archive", contract_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::backup::{self, Backup};
+use crate::cache::{self, Driver as CacheDriver};
+use crate::model::Branch;
+use crate::rpc::message::{
+    BackupReport, ExportBackupRequest, ImportBackupRequest, RestoreMode,
+};
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+
+/// Directories inside the data directory containing RGB stash data
+const STASH_DIRS: [&str; 2] = ["stash", "index"];
+
+impl Runtime {
+    pub(in crate::runtime) fn export_backup(
+        &self,
+        request: ExportBackupRequest,
+    ) -> Result<Vec<u8>, Error> {
+        debug!("Exporting wallet backup");
+        let citadel = self.storage.dump()?;
+        let mut address_derivations = BTreeMap::new();
+        let mut change_derivations = BTreeMap::new();
+        for contract_id in citadel.contracts.keys() {
+            address_derivations.insert(
+                *contract_id,
+                self.cache.used_address_derivations(*contract_id)?,
+            );
+            change_derivations.insert(
+                *contract_id,
+                self.cache.used_change_derivations(*contract_id)?,
+            );
+        }
+        let stash = if request.include_stash {
+            self.read_stash()?
+        } else {
+            none!()
+        };
+        let archive = Backup {
+            citadel,
+            address_derivations,
+            change_derivations,
+            stash,
+        }
+        .to_archive(request.passphrase.as_ref().map(|p| p.as_str()))?;
+        info!("Wallet backup exported");
+        Ok(archive)
+    }
+
+    pub(in crate::runtime) fn import_backup(
+        &mut self,
+        request: ImportBackupRequest,
+    ) -> Result<BackupReport, Error> {
+        debug!("Importing wallet backup");
+        let backup = Backup::from_archive(
+            &request.archive,
+            request.passphrase.as_ref().map(|p| p.as_str()),
+        )?;
+        let mut data = self.storage.dump()?;
+        let mut report = BackupReport::default();
+
+        let conflicts = backup
+            .citadel
+            .contracts
+            .keys()
+            .filter(|id| data.contracts.contains_key(id))
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        if request.mode == RestoreMode::Abort && !conflicts.is_empty() {
+            return Err(
+                backup::Error::ContractConflicts(conflicts.join(", ")).into()
+            );
+        }
+
+        for (id, mut contract) in backup.citadel.contracts {
+            // Contracts archived in the wallet are restored, since the
+            // backup has them as active ones
+            if let Some(archived) = data.archived.remove(&id) {
+                contract.merge_data(archived.contract());
+                data.contracts.insert(id, contract);
+                report.merged.push(id);
+                continue;
+            }
+            match (data.contracts.get_mut(&id), request.mode) {
+                (None, _) => {
+                    data.contracts.insert(id, contract);
+                    report.added.push(id);
+                }
+                (Some(existing), RestoreMode::Merge) => {
+                    existing.merge_data(&contract);
+                    report.merged.push(id);
+                }
+                (Some(existing), _) => {
+                    *existing = contract;
+                    report.replaced.push(id);
+                }
+            }
+        }
+        for (id, account) in backup.citadel.signers {
+            if request.mode == RestoreMode::Replace {
+                data.signers.insert(id, account);
+            } else if let Err(err) = data.add_signer(account) {
+                warn!("Signer account from the backup is skipped: {}", err);
+            }
+        }
+        for (id, identity) in backup.citadel.identities {
+            if request.mode == RestoreMode::Replace {
+                data.identities.insert(id, identity);
+            } else if let Err(err) = data.add_identity(identity) {
+                warn!("Identity from the backup is skipped: {}", err);
+            }
+        }
+        for (id, archived) in backup.citadel.archived {
+            if !data.contracts.contains_key(&id) {
+                data.archived.entry(id).or_insert(archived);
+            }
+        }
+        for (id, asset) in backup.citadel.assets {
+            data.assets.entry(id).or_insert(asset);
+        }
+        self.storage.restore(data)?;
+
+        let branches = backup
+            .address_derivations
+            .into_iter()
+            .map(|derivations| (Branch::External, derivations))
+            .chain(
+                backup
+                    .change_derivations
+                    .into_iter()
+                    .map(|derivations| (Branch::Internal, derivations)),
+            );
+        for (branch, (contract_id, derivations)) in branches {
+            for (address, index) in derivations {
+                match self.cache.use_address_derivation(
+                    contract_id,
+                    branch,
+                    address.clone(),
+                    index,
+                ) {
+                    Err(cache::Error::WrongDerivation)
+                        if request.mode == RestoreMode::Replace =>
+                    {
+                        self.cache.forget_address(contract_id, &address)?;
+                        self.cache.use_address_derivation(
+                            contract_id,
+                            branch,
+                            address,
+                            index,
+                        )?;
+                    }
+                    Err(cache::Error::WrongDerivation) => warn!(
+                        "Address {} from the backup is already used with a \
+                         different derivation; skipping",
+                        address
+                    ),
+                    result => {
+                        result?;
+                    }
+                }
+            }
+        }
+
+        if request.restore_stash && !backup.stash.is_empty() {
+            report.stash_files =
+                self.write_stash(backup.stash, request.mode)?;
+            warn!(
+                "RGB stash data were restored from the backup; RGB node must \
+                 be restarted to use them"
+            );
+        }
+
+        info!("Wallet backup imported: {}", report);
+        Ok(report)
+    }
+
+    fn read_stash(&self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        let mut stash = bmap! {};
+        let mut dirs = STASH_DIRS
+            .iter()
+            .map(|dir| self.config.data_dir.join(dir))
+            .collect::<Vec<_>>();
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(&self.config.data_dir)
+                    .expect("stash files are inside data directory")
+                    .to_string_lossy()
+                    .to_string();
+                stash.insert(name, fs::read(&path)?);
+            }
+        }
+        Ok(stash)
+    }
+
+    fn write_stash(
+        &self,
+        stash: BTreeMap<String, Vec<u8>>,
+        mode: RestoreMode,
+    ) -> Result<u32, Error> {
+        let mut count = 0u32;
+        for (name, data) in stash {
+            let path = match self.stash_path(&name) {
+                Some(path) => path,
+                None => {
+                    warn!("Skipping stash file with invalid name {}", name);
+                    continue;
+                }
+            };
+            if path.exists() && mode != RestoreMode::Replace {
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&path, data)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Resolves stash file name from the backup into the path inside one of
+    /// the stash directories, refusing names which may point outside of them
+    fn stash_path(&self, name: &str) -> Option<PathBuf> {
+        let relative = Path::new(name);
+        let mut components = relative.components();
+        match components.next() {
+            Some(Component::Normal(dir))
+                if STASH_DIRS.iter().any(|stash| dir == *stash) => {}
+            _ => return None,
+        }
+        if !components.all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        Some(self.config.data_dir.join(relative))
+    }
+}
@@ -14,34 +14,56 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 
-use bitcoin::{OutPoint, Script, Txid};
-use electrum_client::{Client as ElectrumClient, ElectrumApi};
+use bitcoin::{OutPoint, Script, Transaction, Txid};
 use wallet::address::AddressCompat;
 use wallet::hd::{ChildIndex, UnhardenedIndex};
 
 use crate::cache::Driver as CacheDriver;
-use crate::model::{ContractId, TweakedOutput, Utxo};
+use crate::chainapi::{self, Driver as ChainDriver};
+use crate::chainwatch::ScriptStatuses;
+use crate::model::{Branch, ContractId, TweakedOutput, Utxo};
 use crate::runtime::Runtime;
 use crate::storage::Driver as StorageDriver;
 use crate::Error;
 
+/// Number of unused addresses after the last used one which are
+/// checked before the synchronization stops, unless the contract has its own
+/// gap limit
+pub const DEFAULT_GAP_LIMIT: u8 = 20;
+
 impl Runtime {
+    /// Synchronizes contract with the blockchain. The lookup depth, if
+    /// provided, is used as the gap limit of this synchronization only;
+    /// otherwise the gap limit stored for the contract is used.
     pub(in crate::runtime) fn chain_sync(
         &mut self,
         contract_id: ContractId,
-        lookup_depth: u8,
+        lookup_depth: Option<u8>,
     ) -> Result<BTreeMap<rgb::ContractId, Vec<Utxo>>, Error> {
-        debug!("Synchronizing contract data with electrum server");
+        debug!("Synchronizing contract data with the blockchain");
 
-        debug!(
-            "Connecting electrum server at {} ...",
-            self.config.electrum_server
-        );
-        debug!("Electrum server successfully connected");
-        let electrum =
-            ElectrumClient::new(&self.config.electrum_server.to_string())?;
+        self.check_reorg()?;
 
-        let lookup_depth = UnhardenedIndex::from(lookup_depth);
+        let gap_limit = lookup_depth
+            .or_else(|| self.cache.gap_limit(contract_id))
+            .unwrap_or(DEFAULT_GAP_LIMIT);
+        debug!("Using gap limit of {} addresses", gap_limit);
+        let gap_limit = UnhardenedIndex::from(gap_limit.max(1));
+
+        // Block positions of the transactions which were already resolved by
+        // the previous synchronizations
+        let updated_height =
+            self.cache.updated_height(contract_id).unwrap_or_default();
+        let known_offsets = self
+            .cache
+            .unspent(contract_id)
+            .unwrap_or_default()
+            .remove(&rgb::ContractId::default())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|utxo| utxo.height > 0 && utxo.height <= updated_height)
+            .map(|utxo| ((utxo.height, utxo.txid), utxo.offset))
+            .collect::<BTreeMap<_, _>>();
 
         let contract = self.storage.contract_ref(contract_id)?;
         let policy = self.storage.policy(contract_id)?;
@@ -49,149 +71,247 @@ impl Runtime {
         let mut unspent: Vec<Utxo> = vec![];
         let mut outpoints: BTreeSet<OutPoint> = bset![];
         let mut mine_info: BTreeMap<(u32, u16), Txid> = bmap! {};
+        let mut tx_heights: BTreeMap<Txid, u32> = bmap! {};
+        let mut highest_indexes: BTreeMap<Branch, UnhardenedIndex> = bmap! {};
+        let mut statuses: ScriptStatuses = bmap! {};
 
-        let mut index_offset = UnhardenedIndex::zero();
-        let last_used_index = self
-            .cache
-            .last_used_derivation(contract_id)
-            .unwrap_or_default();
-
-        let mut scripts: Vec<(UnhardenedIndex, Script, Option<TweakedOutput>)> =
-            contract
-                .data()
-                .p2c_tweaks()
-                .into_iter()
-                .map(|tweak| {
-                    (
-                        tweak.derivation_index,
-                        tweak.script.clone(),
-                        Some(tweak.clone()),
-                    )
-                })
-                .collect();
+        let tweaks: Vec<(
+            Branch,
+            UnhardenedIndex,
+            Script,
+            Option<TweakedOutput>,
+        )> = contract
+            .data()
+            .p2c_tweaks()
+            .into_iter()
+            .map(|tweak| {
+                (
+                    policy
+                        .key_branch(tweak.derivation_index, tweak.pubkey)
+                        .unwrap_or_default(),
+                    tweak.derivation_index,
+                    tweak.script.clone(),
+                    Some(tweak.clone()),
+                )
+            })
+            .collect();
         debug!(
             "Requesting unspent information for {} known tweaked scripts",
-            scripts.len()
+            tweaks.len()
         );
 
-        loop {
-            let mut count = 0usize;
-            trace!("{:#?}", scripts);
+        for branch in policy.branches() {
+            // Addresses up to the highest one known to be used are always
+            // checked with the first batch; the following batches look
+            // beyond them until the gap limit of unused addresses is reached
+            let known_end = self.known_end(contract_id, branch);
+            debug!(
+                "Scanning {} derivation branch; {} addresses are known",
+                branch, known_end
+            );
+            let mut index_offset = known_end;
+
+            // Tweaked scripts are requested together with the first branch
+            let mut scripts = if branch == Branch::External {
+                tweaks.clone()
+            } else {
+                vec![]
+            };
+            scripts.extend(
+                policy
+                    .derive_scripts(branch, UnhardenedIndex::zero()..known_end)
+                    .into_iter()
+                    .map(|(derivation_index, script)| {
+                        (branch, derivation_index, script, None)
+                    }),
+            );
+            let mut first_batch = true;
 
-            let txid_map = electrum
-                .batch_script_list_unspent(
-                    &scripts
+            loop {
+                trace!("{:#?}", scripts);
+
+                let batch = scripts
+                    .iter()
+                    .map(|(_, _, script, _)| script.clone())
+                    .collect::<Vec<_>>();
+                // Script statuses are recorded as the chainwatch baseline.
+                // Subscribed statuses are taken before the history, so the
+                // transactions received in between trigger a new
+                // synchronization.
+                if self.chain.subscribes_scripts() {
+                    statuses.extend(
+                        batch
+                            .iter()
+                            .cloned()
+                            .zip(self.chain.scripts_status(&batch)?),
+                    );
+                }
+                // Addresses are considered used if they have ever received
+                // funds, even if all of them are spent already, so the gap
+                // limit is checked against the script history
+                let history = self.chain.scripts_history(&batch)?;
+                // Heights of all the transactions touching the contract
+                // scripts, including the spending ones, are used to update
+                // heights of the contract operations
+                tx_heights.extend(
+                    history
                         .iter()
-                        .map(|(_, script, _)| script.clone())
-                        .collect::<Vec<_>>(),
-                )
-                .map_err(|_| Error::Electrum)?
-                .into_iter()
-                .zip(scripts)
-                .fold(
+                        .flatten()
+                        .map(|item| (item.txid, item.height)),
+                );
+                if !self.chain.subscribes_scripts() {
+                    statuses.extend(
+                        batch.into_iter().zip(
+                            history.iter().map(|history| {
+                                chainapi::script_status(history)
+                            }),
+                        ),
+                    );
+                }
+                let used = scripts
+                    .into_iter()
+                    .zip(history)
+                    .filter(|(_, history)| !history.is_empty())
+                    .map(|(item, _)| item)
+                    .collect::<Vec<_>>();
+                let count = used.len();
+                debug!("Found {} used scripts in the batch", count);
+                for (branch, derivation_index, _, _) in &used {
+                    let highest = highest_indexes
+                        .entry(*branch)
+                        .or_insert(*derivation_index);
+                    if *highest < *derivation_index {
+                        *highest = *derivation_index;
+                    }
+                }
+
+                // Only used scripts may have unspent outputs
+                let unspent_lists = if used.is_empty() {
+                    vec![]
+                } else {
+                    self.chain.scripts_unspent(
+                        &used
+                            .iter()
+                            .map(|(_, _, script, _)| script.clone())
+                            .collect::<Vec<_>>(),
+                    )?
+                };
+                let txid_map = unspent_lists.into_iter().zip(used).fold(
                     BTreeMap::<
                         (u32, Txid),
                         Vec<(
                             u16,
                             u64,
+                            Branch,
                             UnhardenedIndex,
                             Script,
                             Option<TweakedOutput>,
                         )>,
                     >::new(),
-                    |mut map, (found, (derivation_index, script, tweak))| {
+                    |mut map, (found, (branch, derivation_index, script, tweak))| {
                         for item in found {
-                            map.entry((item.height as u32, item.tx_hash))
+                            map.entry((item.height, item.txid))
                                 .or_insert(Vec::new())
                                 .push((
-                                    item.tx_pos as u16,
+                                    item.vout as u16,
                                     item.value,
+                                    branch,
                                     derivation_index,
                                     script.clone(),
                                     tweak.clone(),
                                 ));
-                            count += 1;
                         }
                         map
                     },
                 );
-            debug!("Found {} unspent outputs in the batch", count);
-            trace!("{:#?}", txid_map);
+                trace!("{:#?}", txid_map);
 
-            trace!(
-                "Resolving block transaction position for {} transactions",
-                txid_map.len()
-            );
-            for ((height, txid), outs) in txid_map {
-                match electrum.transaction_get_merkle(&txid, height as usize) {
-                    Ok(res) => {
-                        mine_info.insert((height, res.pos as u16), txid);
-                        for (vout, value, derivation_index, script, tweak) in
-                            outs
-                        {
-                            if !outpoints
-                                .insert(OutPoint::new(txid, vout as u32))
-                            {
-                                continue;
-                            }
-                            let address = contract
-                                .chain()
-                                .try_into()
-                                .ok()
-                                .and_then(|network| {
-                                    AddressCompat::from_script(&script, network)
-                                });
-                            unspent.push(Utxo {
-                                value,
-                                height,
-                                offset: res.pos as u16,
-                                txid,
+                trace!(
+                    "Resolving block transaction position for {} transactions",
+                    txid_map.len()
+                );
+                for ((height, txid), outs) in txid_map {
+                    let offset = match known_offsets.get(&(height, txid)) {
+                        Some(offset) => Ok(*offset),
+                        None => self.chain.merkle_position(&txid, height),
+                    };
+                    match offset {
+                        Ok(offset) => {
+                            mine_info.insert((height, offset), txid);
+                            for (
                                 vout,
+                                value,
+                                branch,
                                 derivation_index,
-                                tweak: tweak
-                                    .map(|tweak| (tweak.tweak, tweak.pubkey)),
-                                address,
-                            });
+                                script,
+                                tweak,
+                            ) in outs
+                            {
+                                if !outpoints
+                                    .insert(OutPoint::new(txid, vout as u32))
+                                {
+                                    continue;
+                                }
+                                let address =
+                                    contract.chain().try_into().ok().and_then(
+                                        |network| {
+                                            AddressCompat::from_script(
+                                                &script, network,
+                                            )
+                                        },
+                                    );
+                                unspent.push(Utxo {
+                                    value,
+                                    height,
+                                    offset,
+                                    txid,
+                                    vout,
+                                    branch,
+                                    derivation_index,
+                                    tweak: tweak.map(|tweak| {
+                                        (tweak.tweak, tweak.pubkey)
+                                    }),
+                                    address,
+                                });
+                            }
                         }
+                        Err(err) => warn!(
+                            "Unable to get tx block position for {} at height \
+                             {}: {}",
+                            txid, height, err
+                        ),
                     }
-                    Err(err) => warn!(
-                        "Unable to get tx block position for {} at height {}: \
-                        electrum server error {:?}",
-                        txid, height, err
-                    ),
                 }
-            }
 
-            if count == 0 && index_offset > last_used_index {
-                debug!(
-                    "No unspent outputs are found in the batch and we \
-                            are behind the last used derivation; stopping search"
-                );
-                break;
-            }
+                if count == 0 && !first_batch {
+                    debug!(
+                        "No used addresses are found within the gap limit \
+                         behind the known addresses; stopping search"
+                    );
+                    break;
+                }
+                first_batch = false;
 
-            if index_offset == UnhardenedIndex::largest() {
-                debug!("Reached last possible index number, breaking");
-                break;
+                if index_offset == UnhardenedIndex::largest() {
+                    debug!("Reached last possible index number, breaking");
+                    break;
+                }
+                let from = index_offset;
+                index_offset = index_offset
+                    .checked_add(gap_limit)
+                    .unwrap_or(UnhardenedIndex::largest());
+                scripts = policy
+                    .derive_scripts(branch, from..index_offset)
+                    .into_iter()
+                    .map(|(derivation_index, script)| {
+                        (branch, derivation_index, script, None)
+                    })
+                    .collect();
+                debug!("Generating next spending script batch");
             }
-            let from = index_offset;
-            index_offset = index_offset
-                .checked_add(lookup_depth)
-                .unwrap_or(UnhardenedIndex::largest());
-            scripts = policy
-                .derive_scripts(from..index_offset)
-                .into_iter()
-                .map(|(derivation_index, script)| {
-                    (derivation_index, script, None)
-                })
-                .collect();
-            debug!("Generating next spending script batch");
         }
 
-        while let Ok(Some(info)) = electrum.block_headers_pop() {
-            debug!("Updating known blockchain height: {}", info.height);
-            self.known_height = info.height as u32;
-        }
+        self.update_known_height();
 
         let mut assets =
             bmap! { rgb::ContractId::default() => unspent.clone() };
@@ -211,6 +331,18 @@ impl Runtime {
             }
         }
 
+        let txids = outpoints.iter().map(|outpoint| outpoint.txid);
+        fetch_transactions(&mut *self.cache, &*self.chain, txids)?;
+        let heights = mine_info.keys().map(|(height, _)| *height).collect();
+        self.record_block_hashes(heights)?;
+        let mined = self.storage.mine_operations(contract_id, tx_heights)?;
+        if mined > 0 {
+            debug!("Heights of {} contract operations are updated", mined);
+        }
+
+        self.cache
+            .record_highest_indexes(contract_id, highest_indexes)?;
+
         trace!("Transaction mining info: {:#?}", mine_info);
         self.cache.update(
             contract_id,
@@ -219,7 +351,68 @@ impl Runtime {
             outpoints,
             assets.clone(),
         )?;
+        let evicted = self.cache.evict_transactions()?;
+        if evicted > 0 {
+            debug!("{} spent transactions are removed from cache", evicted);
+        }
+        self.watch_contract(contract_id);
+        self.watchlist.set_statuses(contract_id, &statuses);
 
         Ok(assets)
     }
+
+    /// Index following the highest address index of the branch which is
+    /// known to be used
+    pub(super) fn known_end(
+        &self,
+        contract_id: ContractId,
+        branch: Branch,
+    ) -> UnhardenedIndex {
+        self.cache
+            .highest_index(contract_id, branch)
+            .into_iter()
+            .chain(self.cache.last_used_derivation(contract_id, branch))
+            .max()
+            .map(|index| {
+                index
+                    .checked_add(UnhardenedIndex::from(1u8))
+                    .unwrap_or(UnhardenedIndex::largest())
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Returns transactions with the given ids, taking them from the cache when
+/// possible. Transactions missing in the cache are requested from the
+/// blockchain data source and added to the cache; the ones which can't be
+/// retrieved are skipped.
+pub(super) fn fetch_transactions(
+    cache: &mut dyn CacheDriver,
+    chain: &dyn ChainDriver,
+    txids: impl IntoIterator<Item = Txid>,
+) -> Result<BTreeMap<Txid, Transaction>, Error> {
+    let mut transactions = bmap! {};
+    let mut missing = vec![];
+    for txid in txids {
+        match cache.transaction(&txid) {
+            Some(tx) => {
+                transactions.insert(txid, tx);
+            }
+            None if !missing.contains(&txid) => missing.push(txid),
+            None => {}
+        }
+    }
+    if missing.is_empty() {
+        return Ok(transactions);
+    }
+
+    trace!("Requesting {} transactions", missing.len());
+    match chain.transactions(&missing) {
+        Ok(fetched) => {
+            cache.cache_transactions(fetched.clone())?;
+            transactions.extend(fetched.into_iter().map(|tx| (tx.txid(), tx)));
+        }
+        Err(err) => warn!("Unable to get transactions: {}", err),
+    }
+    Ok(transactions)
 }
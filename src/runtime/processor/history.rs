@@ -0,0 +1,134 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use crate::model::{LabelRef, PaymentDirecton};
+use crate::rpc::message::{
+    OperationDirection, OperationInfo, OperationsCursor, OperationsPage,
+    OperationsQuery, SortOrder,
+};
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+
+/// Number of operations returned when the query does not specify the limit
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+impl Runtime {
+    pub(in crate::runtime) fn query_operations(
+        &self,
+        query: OperationsQuery,
+    ) -> Result<OperationsPage, Error> {
+        let contract = self.storage.contract_ref(query.contract_id)?;
+        let labels = contract.data().labels();
+        let label = query.label.as_ref().map(|label| label.to_lowercase());
+        let mut matching = contract
+            .history()
+            .into_iter()
+            .map(|operation| OperationInfo {
+                label: labels.get(&LabelRef::Tx(operation.txid)).cloned(),
+                operation,
+            })
+            .enumerate()
+            .filter(|(_, info)| matches(&query, &label, info))
+            .map(|(position, info)| {
+                let cursor = OperationsCursor {
+                    timestamp: info.operation.created_at.timestamp(),
+                    position: position as u32,
+                };
+                (cursor, info)
+            })
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|(cursor, _)| *cursor);
+        if query.order == SortOrder::Descending {
+            matching.reverse();
+        }
+
+        let total = matching.len() as u32;
+        let start = match query.cursor {
+            None => 0,
+            Some(last) => matching
+                .iter()
+                .position(|(cursor, _)| match query.order {
+                    SortOrder::Ascending => *cursor > last,
+                    SortOrder::Descending => *cursor < last,
+                })
+                .unwrap_or(matching.len()),
+        };
+        let limit = match query.limit {
+            0 => DEFAULT_PAGE_SIZE,
+            limit => limit as usize,
+        };
+        let page = matching
+            .into_iter()
+            .skip(start)
+            .take(limit)
+            .collect::<Vec<_>>();
+        let next_cursor = if start + page.len() < total as usize {
+            page.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+
+        Ok(OperationsPage {
+            operations: page.into_iter().map(|(_, info)| info).collect(),
+            next_cursor,
+            total,
+        })
+    }
+}
+
+fn matches(
+    query: &OperationsQuery,
+    label: &Option<String>,
+    info: &OperationInfo,
+) -> bool {
+    let operation = &info.operation;
+    let direction = match operation.direction {
+        PaymentDirecton::Incoming { .. } => OperationDirection::Incoming,
+        PaymentDirecton::Outcoming { .. } => OperationDirection::Outcoming,
+    };
+    query
+        .since
+        .map(|since| operation.created_at >= since)
+        .unwrap_or(true)
+        && query
+            .until
+            .map(|until| operation.created_at < until)
+            .unwrap_or(true)
+        && query
+            .min_height
+            .map(|height| operation.height >= height)
+            .unwrap_or(true)
+        && query
+            .max_height
+            .map(|height| operation.height <= height)
+            .unwrap_or(true)
+        && query.direction.map(|d| d == direction).unwrap_or(true)
+        && query
+            .asset_id
+            .map(|asset_id| operation.asset_id.unwrap_or_default() == asset_id)
+            .unwrap_or(true)
+        && query
+            .txid
+            .map(|txid| operation.txid == txid)
+            .unwrap_or(true)
+        && label
+            .as_ref()
+            .map(|label| {
+                [&info.label, &operation.notes]
+                    .iter()
+                    .filter_map(|text| text.as_ref())
+                    .any(|text| text.to_lowercase().contains(label))
+            })
+            .unwrap_or(true)
+}
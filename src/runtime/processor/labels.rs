@@ -0,0 +1,174 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Label import and export in BIP-329 JSON lines format.
+//!
+//! Contract descriptors are used as the label origin. Contract labels have no
+//! BIP-329 counterpart and are not exported.
+
+use crate::cache::Driver as CacheDriver;
+use crate::model::{Bip329Record, Contract, ContractId, LabelRef};
+use crate::rpc::message::{
+    ExportLabelsRequest, ImportLabelsRequest, LabelsReport,
+};
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+
+impl Runtime {
+    pub(in crate::runtime) fn export_labels(
+        &self,
+        request: ExportLabelsRequest,
+    ) -> Result<String, Error> {
+        debug!("Exporting labels");
+        let contracts = match request.contract_id {
+            Some(contract_id) => {
+                vec![self.storage.contract_ref(contract_id)?.clone()]
+            }
+            None => self.storage.contracts()?,
+        };
+        let mut jsonl = String::new();
+        for contract in contracts {
+            let origin = origin(&contract);
+            for (reference, label) in contract.data().labels() {
+                if *reference == LabelRef::Contract {
+                    continue;
+                }
+                let record = Bip329Record {
+                    type_name: reference.type_name().to_owned(),
+                    reference: reference.reference(),
+                    label: Some(label.clone()),
+                    origin: Some(origin.clone()),
+                    spendable: None,
+                };
+                jsonl += &serde_json::to_string(&record)
+                    .expect("label records are always serializable");
+                jsonl.push('\n');
+            }
+        }
+        info!("Labels exported");
+        Ok(jsonl)
+    }
+
+    pub(in crate::runtime) fn import_labels(
+        &mut self,
+        request: ImportLabelsRequest,
+    ) -> Result<LabelsReport, Error> {
+        debug!("Importing labels");
+        let jsonl = request.jsonl;
+        let contracts = self.storage.contracts()?;
+        let mut report = LabelsReport::default();
+
+        for (no, line) in jsonl.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = match serde_json::from_str::<Bip329Record>(line) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Skipping broken label record #{}: {}", no + 1, err);
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            let label = match record.label {
+                Some(ref label) if !label.is_empty() => label.clone(),
+                _ => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            let reference =
+                match LabelRef::with(&record.type_name, &record.reference) {
+                    Ok(reference) => reference,
+                    Err(err) => {
+                        debug!("Skipping label record #{}: {}", no + 1, err);
+                        report.skipped += 1;
+                        continue;
+                    }
+                };
+            let contract_id = match request.contract_id {
+                Some(contract_id) => Some(contract_id),
+                None => self.label_owner(&contracts, &record, &reference)?,
+            };
+            match contract_id {
+                Some(contract_id) => {
+                    self.storage.set_label(
+                        contract_id,
+                        reference,
+                        Some(label),
+                    )?;
+                    report.imported += 1;
+                }
+                None => {
+                    debug!(
+                        "Skipping label for {}: no matching contract",
+                        reference
+                    );
+                    report.skipped += 1;
+                }
+            }
+        }
+
+        info!("Labels imported: {}", report);
+        Ok(report)
+    }
+
+    /// Detects contract which the label belongs to, either by the label
+    /// origin or by the labelled transaction, address or output
+    fn label_owner(
+        &self,
+        contracts: &[Contract],
+        record: &Bip329Record,
+        reference: &LabelRef,
+    ) -> Result<Option<ContractId>, Error> {
+        if let Some(ref record_origin) = record.origin {
+            if let Some(contract) = contracts
+                .iter()
+                .find(|contract| origin(contract) == *record_origin)
+            {
+                return Ok(Some(*contract.id()));
+            }
+        }
+        for contract in contracts {
+            let contract_id = *contract.id();
+            let has_tx = |txid| {
+                contract
+                    .data()
+                    .operations()
+                    .iter()
+                    .any(|operation| operation.txid == txid)
+            };
+            let owns = match reference {
+                LabelRef::Contract => false,
+                LabelRef::Tx(txid) => has_tx(*txid),
+                LabelRef::Addr(address) => {
+                    self.cache.used_addresses(contract_id)?.contains(address)
+                }
+                LabelRef::Output(outpoint) => {
+                    has_tx(outpoint.txid)
+                        || self.cache.utxo(contract_id)?.contains(outpoint)
+                }
+            };
+            if owns {
+                return Ok(Some(contract_id));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Label origin for the contract, which is its descriptor
+fn origin(contract: &Contract) -> String {
+    contract.policy().to_descriptor().to_string()
+}
@@ -11,5 +11,12 @@
 // along with this software.
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+mod archive;
+mod backup;
 mod chain_sync;
+mod history;
+mod labels;
+mod reorg;
 mod transfer;
+mod wallets;
+mod watch;
@@ -0,0 +1,130 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeSet;
+
+use crate::cache::Driver as CacheDriver;
+use crate::chainapi::Driver as ChainDriver;
+use crate::rpc::message::ReorgInfo;
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+use bitcoin::BlockHeader;
+
+/// Maximal number of chain reorganization events kept for the clients
+const MAX_REORGS: usize = 64;
+
+/// Number of the blocks below the chain tip which are checked for
+/// reorganizations; hashes of the deeper blocks are not kept in the cache
+const REORG_CHECK_DEPTH: u32 = 100;
+
+impl Runtime {
+    /// Checks hashes of the recent blocks known to the cache against the
+    /// current chain and, if some of them were replaced, rolls back cached
+    /// mining information, UTXOs and heights of the contract operations
+    /// starting from the first replaced block. Hashes of the blocks deeper
+    /// than [`REORG_CHECK_DEPTH`] are removed from the cache.
+    pub(in crate::runtime) fn check_reorg(
+        &mut self,
+    ) -> Result<Option<ReorgInfo>, Error> {
+        let block_hashes = self.cache.block_hashes();
+        if block_hashes.is_empty() {
+            return Ok(None);
+        }
+
+        // Subscription is not renewed, since it would drop block headers
+        // which are not yet received by the runtime or chainwatch service
+        let tip = self.chain.tip_height()?;
+        let depth = tip.saturating_sub(REORG_CHECK_DEPTH);
+        self.cache.prune_block_hashes(depth)?;
+        let heights = block_hashes
+            .range(depth..=tip)
+            .map(|(height, _)| *height)
+            .collect::<Vec<_>>();
+        trace!("Checking hashes of {} known blocks", heights.len());
+        let headers = self.chain.block_headers(&heights)?;
+        let fork_height = heights
+            .iter()
+            .zip(headers)
+            .find(|(height, header)| {
+                block_hashes.get(height) != Some(&header.block_hash())
+            })
+            .map(|(height, _)| *height)
+            // Blocks above the current tip are orphaned as well
+            .or_else(|| block_hashes.keys().copied().find(|h| *h > tip));
+        let fork_height = match fork_height {
+            None => return Ok(None),
+            Some(height) => height,
+        };
+
+        warn!(
+            "Chain reorganization detected starting from block {}; rolling \
+             back cached data",
+            fork_height
+        );
+        let orphaned = self.cache.rollback(fork_height)?;
+        let mut unmined_operations = 0usize;
+        for contract in self.storage.contracts()? {
+            unmined_operations += self
+                .storage
+                .unmine_operations(*contract.id(), fork_height)?;
+        }
+        self.known_height = self.known_height.min(fork_height - 1);
+
+        let reorg = ReorgInfo {
+            wallet: self.wallet.clone(),
+            detected_at: chrono::Utc::now().naive_utc(),
+            fork_height,
+            orphaned_txids: orphaned.into_iter().collect(),
+            unmined_operations: unmined_operations as u32,
+        };
+        info!("{}", reorg);
+        if self.reorgs.len() >= MAX_REORGS {
+            self.reorgs.remove(0);
+        }
+        self.reorgs.push(reorg.clone());
+        Ok(Some(reorg))
+    }
+
+    /// Records hashes of the blocks at the given heights, so they can be
+    /// checked for reorganizations during the following synchronizations;
+    /// blocks deeper than [`REORG_CHECK_DEPTH`] are skipped
+    pub(super) fn record_block_hashes(
+        &mut self,
+        heights: BTreeSet<u32>,
+    ) -> Result<(), Error> {
+        let known = self.cache.block_hashes();
+        let depth = self.known_height.saturating_sub(REORG_CHECK_DEPTH);
+        let heights = heights
+            .into_iter()
+            .filter(|height| {
+                *height > 0 && *height >= depth && !known.contains_key(height)
+            })
+            .collect::<Vec<_>>();
+        if heights.is_empty() {
+            return Ok(());
+        }
+
+        trace!("Recording hashes of {} blocks", heights.len());
+        match self.chain.block_headers(&heights) {
+            Ok(headers) => self.cache.record_block_hashes(
+                heights
+                    .into_iter()
+                    .zip(headers.iter().map(BlockHeader::block_hash))
+                    .collect(),
+            )?,
+            Err(err) => warn!("Unable to get block headers: {}", err),
+        }
+        Ok(())
+    }
+}
@@ -18,7 +18,6 @@ use bitcoin::secp256k1::rand::RngCore;
 use bitcoin::{OutPoint, PublicKey, Transaction, TxIn, TxOut};
 use bp::seals::OutpointReveal;
 use chrono::{NaiveDateTime, Utc};
-use electrum_client::{Client as ElectrumClient, ElectrumApi};
 use microservices::rpc::Failure;
 use miniscript::{Descriptor, DescriptorTrait};
 use rgb::{SealDefinition, SealEndpoint};
@@ -209,9 +208,11 @@ impl Runtime {
             > bitcoin_value + bitcoin_fee
         {
             let change = bitcoin_input_amount - bitcoin_value - bitcoin_fee;
-            let change_index = self.cache.next_unused_derivation(pay_from)?;
+            let change_branch = policy.change_branch();
+            let change_index =
+                self.cache.next_unused_derivation(pay_from, change_branch)?;
             let change_address = contract
-                .derive_address(change_index, false)
+                .derive_address(change_branch, change_index, false)
                 .ok_or(Error::ServerFailure(Failure {
                     code: 0,
                     info: s!("Unable to derive change address"),
@@ -219,6 +220,7 @@ impl Runtime {
                 .address;
             self.cache.use_address_derivation(
                 pay_from,
+                change_branch,
                 change_address.clone(),
                 change_index,
             )?;
@@ -231,9 +233,9 @@ impl Runtime {
                     value: change,
                     script_pubkey: change_address.script_pubkey(),
                 },
-                Some(change_index),
+                Some((change_branch, change_index)),
             ));
-            output_derivation_indexes.insert(change_index);
+            output_derivation_indexes.insert((change_branch, change_index));
             (change, Some(tx_outputs.len() as u32 - 1))
         } else {
             (0, None)
@@ -273,13 +275,11 @@ impl Runtime {
         }
         trace!("RGB change: {:?}", rgb_change);
 
-        debug!(
-            "Connecting electrum server at {} ...",
-            self.config.electrum_server
-        );
-        debug!("Electrum server successfully connected");
-        let electrum =
-            ElectrumClient::new(&self.config.electrum_server.to_string())?;
+        let prev_txs = super::chain_sync::fetch_transactions(
+            &mut *self.cache,
+            &*self.chain,
+            tx_inputs.iter().map(|txin| txin.previous_output.txid),
+        )?;
 
         // Constructing bitcoin payment PSBT (for bitcoin payments) or
         // RGB witness PSBT prototype for the commitment (for RGB
@@ -289,13 +289,17 @@ impl Runtime {
             .zip(&selected_utxos)
             .map(|(txin, utxo)| {
                 let mut input = psbt::Input::default();
-                // TODO: cache transactions
+                // Several inputs may spend outputs of the same transaction
                 input.non_witness_utxo =
-                    electrum.transaction_get(&txin.previous_output.txid).ok();
-                input.bip32_derivation =
-                    policy.bip32_derivations(utxo.derivation_index);
+                    prev_txs.get(&txin.previous_output.txid).cloned();
+                input.bip32_derivation = policy
+                    .bip32_derivations(utxo.branch, utxo.derivation_index);
                 let script = policy
-                    .derive_descriptor(utxo.derivation_index, false)
+                    .derive_descriptor(
+                        utxo.branch,
+                        utxo.derivation_index,
+                        false,
+                    )
                     .as_ref()
                     .map(Descriptor::explicit_script);
                 if policy.is_scripted() {
@@ -313,16 +317,16 @@ impl Runtime {
             .collect();
         let psbt_outputs = tx_outputs
             .iter()
-            .map(|(txout, index)| {
+            .map(|(txout, derivation)| {
                 let mut output = psbt::Output::default();
-                if let Some(index) = index {
+                if let Some((branch, index)) = derivation {
                     output.proprietary.insert(
                         ProprietaryKey {
                             prefix: rgb::PSBT_PREFIX.to_vec(),
                             subtype: rgb::PSBT_OUT_PUBKEY,
                             key: vec![],
                         },
-                        policy.first_public_key(*index).to_bytes(),
+                        policy.first_public_key(*branch, *index).to_bytes(),
                     );
                 }
                 output
@@ -391,7 +395,8 @@ impl Runtime {
                     .transpose()
                     .ok()
                     .flatten();
-                let derivation_index = tx_outputs[vout].1;
+                let derivation_index =
+                    tx_outputs[vout].1.map(|(_, index)| index);
                 if let (Some(pubkey), Some(tweak), Some(derivation_index)) =
                     (pubkey, tweak, derivation_index)
                 {
@@ -0,0 +1,253 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::mem;
+use std::sync::Arc;
+
+use internet2::Unmarshall;
+
+use crate::chainapi;
+use crate::rpc::message::{WalletInfo, WalletRequest};
+use crate::rpc::{Reply, Request};
+use crate::runtime::wallet::{self, Wallet};
+use crate::runtime::{Runtime, StorageType, DEFAULT_WALLET};
+use crate::Error;
+
+impl Runtime {
+    pub(in crate::runtime) fn list_wallets(
+        &self,
+    ) -> Result<Vec<WalletInfo>, Error> {
+        let mut names = self.wallets.keys().cloned().collect::<BTreeSet<_>>();
+        names.insert(self.wallet.clone());
+        names.insert(s!(DEFAULT_WALLET));
+        let wallets_dir = self.config.wallets_dir();
+        if self.config.storage_type != StorageType::Memory
+            && wallets_dir.is_dir()
+        {
+            for entry in fs::read_dir(wallets_dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().to_string();
+                if wallet::check_name(&name).is_ok() {
+                    names.insert(name);
+                }
+            }
+        }
+
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let active = name == self.wallet;
+                let locked = if active {
+                    self.storage.is_locked()
+                } else {
+                    self.wallets
+                        .get(&name)
+                        .map(|wallet| wallet.storage.is_locked())
+                        .unwrap_or_default()
+                };
+                WalletInfo {
+                    open: active || self.wallets.contains_key(&name),
+                    active,
+                    locked,
+                    name,
+                }
+            })
+            .collect())
+    }
+
+    /// Creates new wallet and makes it active
+    pub(in crate::runtime) fn create_wallet(
+        &mut self,
+        name: String,
+    ) -> Result<(), Error> {
+        wallet::check_name(&name)?;
+        if self.wallet_exists(&name) {
+            return Err(Error::WalletExists(name));
+        }
+        debug!("Creating wallet {}", name);
+        if self.config.storage_type != StorageType::Memory {
+            fs::create_dir_all(self.config.wallet_dir(&name))?;
+        }
+        let wallet =
+            Wallet::open(&self.config, &name, self.wallet_chain(&name)?)?;
+        info!("Wallet {} is created", name);
+        self.activate_wallet(name, wallet);
+        Ok(())
+    }
+
+    /// Opens the wallet, if it is not opened yet, and makes it active
+    pub(in crate::runtime) fn open_wallet(
+        &mut self,
+        name: String,
+    ) -> Result<(), Error> {
+        wallet::check_name(&name)?;
+        if name == self.wallet {
+            return Ok(());
+        }
+        let wallet = match self.wallets.remove(&name) {
+            Some(wallet) => wallet,
+            None if self.wallet_exists(&name) => {
+                debug!("Opening wallet {}", name);
+                Wallet::open(&self.config, &name, self.wallet_chain(&name)?)?
+            }
+            None => return Err(Error::WalletNotFound(name)),
+        };
+        self.activate_wallet(name, wallet);
+        Ok(())
+    }
+
+    /// Closes the wallet. If the wallet was active, the default wallet
+    /// becomes active.
+    pub(in crate::runtime) fn close_wallet(
+        &mut self,
+        name: String,
+    ) -> Result<(), Error> {
+        wallet::check_name(&name)?;
+        if name == DEFAULT_WALLET {
+            return Err(Error::DefaultWallet);
+        }
+        if name == self.wallet {
+            let default = self
+                .wallets
+                .remove(DEFAULT_WALLET)
+                .expect("default wallet is always open");
+            self.activate_wallet(s!(DEFAULT_WALLET), default);
+        }
+        match self.wallets.remove(&name) {
+            Some(_) if self.config.storage_type == StorageType::Memory => {
+                warn!("In-memory wallet {} is closed; its data are lost", name)
+            }
+            Some(_) => info!("Wallet {} is closed", name),
+            None if self.wallet_exists(&name) => {}
+            None => return Err(Error::WalletNotFound(name)),
+        }
+        Ok(())
+    }
+
+    /// Removes all data of a closed wallet
+    pub(in crate::runtime) fn delete_wallet(
+        &mut self,
+        name: String,
+    ) -> Result<(), Error> {
+        wallet::check_name(&name)?;
+        if name == DEFAULT_WALLET {
+            return Err(Error::DefaultWallet);
+        }
+        if name == self.wallet || self.wallets.contains_key(&name) {
+            return Err(Error::WalletOpen(name));
+        }
+        if !self.wallet_exists(&name) {
+            return Err(Error::WalletNotFound(name));
+        }
+        fs::remove_dir_all(self.config.wallet_dir(&name))?;
+        info!("Wallet {} is deleted", name);
+        Ok(())
+    }
+
+    fn wallet_exists(&self, name: &str) -> bool {
+        name == DEFAULT_WALLET
+            || name == self.wallet
+            || self.wallets.contains_key(name)
+            || (self.config.storage_type != StorageType::Memory
+                && self.config.wallet_dir(name).is_dir())
+    }
+
+    /// Source of blockchain data for the wallet. With a proxy each wallet
+    /// gets its own connections for stream isolation, otherwise the source is
+    /// shared by all wallets.
+    fn wallet_chain(
+        &self,
+        name: &str,
+    ) -> Result<Arc<dyn chainapi::Driver>, Error> {
+        if self.config.proxy.is_some() {
+            wallet::open_chain(&self.config, name)
+        } else {
+            Ok(self.chain.clone())
+        }
+    }
+
+    /// Processes wallet-specific request with the named open wallet, keeping
+    /// the active wallet unchanged. Chainwatch service keeps watching the
+    /// active wallet only.
+    pub(in crate::runtime) fn process_in_wallet(
+        &mut self,
+        request: WalletRequest,
+    ) -> Result<Reply, Reply> {
+        let WalletRequest {
+            wallet: name,
+            request,
+        } = request;
+        wallet::check_name(&name)?;
+        let message: Request =
+            (&*self.unmarshaller.unmarshall(&request)?).clone();
+        if message.is_wallet_independent() {
+            return Err(
+                Error::WalletIndependentRequest(message.to_string()).into()
+            );
+        }
+        if name == self.wallet {
+            return self.process_request(message);
+        }
+        let wallet = match self.wallets.remove(&name) {
+            Some(wallet) => wallet,
+            None if self.wallet_exists(&name) => {
+                return Err(Error::WalletClosed(name).into())
+            }
+            None => return Err(Error::WalletNotFound(name).into()),
+        };
+        debug!("Processing {} with wallet {}", message, name);
+        let (active, active_wallet) = self.swap_wallet(name, wallet);
+        let watchlist = mem::take(&mut self.watchlist);
+        let reply = self.process_request(message);
+        self.watchlist = watchlist;
+        let (name, wallet) = self.swap_wallet(active, active_wallet);
+        self.wallets.insert(name, wallet);
+        reply
+    }
+
+    /// Makes the provided wallet active, keeping previously active wallet
+    /// open
+    fn activate_wallet(&mut self, name: String, wallet: Wallet) {
+        let (previous, wallet) = self.swap_wallet(name, wallet);
+        debug!("Wallet {} is active", self.wallet);
+        self.wallets.insert(previous, wallet);
+        self.watchlist.set_chain(self.chain.clone());
+        self.watch_contracts();
+    }
+
+    /// Replaces the data of the active wallet with the provided wallet,
+    /// returning name and data of the replaced wallet
+    fn swap_wallet(
+        &mut self,
+        name: String,
+        wallet: Wallet,
+    ) -> (String, Wallet) {
+        let storage = mem::replace(&mut self.storage, wallet.storage);
+        let cache = mem::replace(&mut self.cache, wallet.cache);
+        let chain = mem::replace(&mut self.chain, wallet.chain);
+        let previous = mem::replace(&mut self.wallet, name);
+        (
+            previous,
+            Wallet {
+                storage,
+                cache,
+                chain,
+            },
+        )
+    }
+}
@@ -0,0 +1,124 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeSet;
+
+use bitcoin::Script;
+use wallet::hd::UnhardenedIndex;
+
+use super::chain_sync::DEFAULT_GAP_LIMIT;
+use crate::cache::Driver as CacheDriver;
+use crate::chainapi::Driver as ChainDriver;
+use crate::model::ContractId;
+use crate::runtime::Runtime;
+use crate::storage::Driver as StorageDriver;
+use crate::Error;
+
+impl Runtime {
+    /// Updates known blockchain height with the block headers received since
+    /// the last update. If chainwatch service is running, it is the only
+    /// consumer of the block header notifications and the height reported by
+    /// it is used.
+    pub(in crate::runtime) fn update_known_height(&mut self) {
+        if self.config.chainwatch {
+            let watched_height = self.watchlist.height();
+            if watched_height > self.known_height {
+                debug!("Updating known blockchain height: {}", watched_height);
+                self.known_height = watched_height;
+            }
+            return;
+        }
+        if self.known_height == 0 {
+            // The height is unknown if the source of blockchain data was not
+            // reachable on the runtime start
+            match self.chain.subscribe_headers() {
+                Ok(info) => self.known_height = info.height,
+                Err(err) => warn!("Blockchain height is unknown: {}", err),
+            }
+        }
+        while let Ok(Some(info)) = self.chain.pop_header() {
+            debug!("Updating known blockchain height: {}", info.height);
+            self.known_height = info.height;
+        }
+    }
+
+    /// Replaces contracts watched by chainwatch service with the contracts
+    /// of the active wallet
+    pub(in crate::runtime) fn watch_contracts(&mut self) {
+        self.watchlist.clear();
+        if self.storage.is_locked() {
+            debug!("Wallet storage is locked; its contracts are not watched");
+            return;
+        }
+        match self.storage.contracts() {
+            Ok(contracts) => {
+                for contract in contracts {
+                    self.watch_contract(*contract.id());
+                }
+            }
+            Err(err) => warn!("Unable to watch wallet contracts: {}", err),
+        }
+    }
+
+    /// Updates scripts watched for the contract by chainwatch service
+    pub(in crate::runtime) fn watch_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) {
+        match self.contract_scripts(contract_id) {
+            Ok(scripts) => {
+                trace!(
+                    "Watching {} scripts of contract {}",
+                    scripts.len(),
+                    contract_id
+                );
+                self.watchlist.watch(contract_id, scripts);
+            }
+            Err(err) => {
+                warn!("Unable to watch contract {}: {}", contract_id, err)
+            }
+        }
+    }
+
+    /// Scripts which may receive funds for the contract: tweaked scripts and
+    /// scripts of the known addresses followed by the gap limit of unused
+    /// ones
+    fn contract_scripts(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<BTreeSet<Script>, Error> {
+        let contract = self.storage.contract_ref(contract_id)?;
+        let policy = self.storage.policy(contract_id)?;
+        let gap_limit = UnhardenedIndex::from(
+            self.cache
+                .gap_limit(contract_id)
+                .unwrap_or(DEFAULT_GAP_LIMIT)
+                .max(1),
+        );
+        let mut scripts =
+            contract.tweaked_script_iter().collect::<BTreeSet<_>>();
+        for branch in policy.branches() {
+            let end = self
+                .known_end(contract_id, branch)
+                .checked_add(gap_limit)
+                .unwrap_or(UnhardenedIndex::largest());
+            scripts.extend(
+                policy
+                    .derive_scripts(branch, UnhardenedIndex::zero()..end)
+                    .into_iter()
+                    .map(|(_, script)| script),
+            );
+        }
+        Ok(scripts)
+    }
+}
@@ -12,7 +12,6 @@
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 use bp::seals::OutpointReveal;
-use electrum_client::{Client as ElectrumClient, ElectrumApi};
 use internet2::{TypedEnum, Unmarshall};
 use microservices::rpc::Failure;
 use microservices::FileFormat;
@@ -21,13 +20,16 @@ use rgb20::Asset;
 use rgb_node::rpc::reply::SyncFormat;
 use rgb_node::util::ToBech32Data;
 use strict_encoding::StrictDecode;
-use wallet::descriptors::ContractDescriptor;
 
 use super::Runtime;
 use crate::cache::Driver as CacheDriver;
-use crate::model::{Contract, ContractMeta, Policy, SpendingPolicy};
+use crate::chainapi::Driver as ChainDriver;
+use crate::model::{
+    self, ArchivedContractMeta, Branch, BranchPair, Contract, ContractMeta,
+    Label, Policy, SpendingPolicy,
+};
 use crate::rpc::{message, Reply, Request};
-use crate::storage::Driver as StorageDriver;
+use crate::storage::{self, Driver as StorageDriver};
 use crate::Error;
 use crate::SECP256K1;
 
@@ -44,21 +46,103 @@ impl Runtime {
             message.get_type(),
             message
         );
+        self.process_request(message)
+    }
+
+    pub(super) fn process_request(
+        &mut self,
+        message: Request,
+    ) -> Result<Reply, Reply> {
+        if self.storage.is_locked() && !message.is_storage_independent() {
+            return Err(Error::from(storage::Error::Locked).into());
+        }
         match message {
+            Request::UnlockStorage(passphrase) => {
+                self.storage.unlock(passphrase.as_str()).map_err(Error::from)?;
+                self.watch_contracts();
+                Ok(Reply::Success)
+            }
+
+            Request::InitStorage(passphrase) => {
+                self.storage.init(passphrase.as_str()).map_err(Error::from)?;
+                self.watch_contracts();
+                Ok(Reply::Success)
+            }
+
+            Request::LockStorage => {
+                self.storage.lock().map_err(Error::from)?;
+                self.watch_contracts();
+                Ok(Reply::Success)
+            }
+
+            Request::ChangePassphrase(message::ChangePassphraseRequest {
+                old_passphrase,
+                new_passphrase,
+            }) => self
+                .storage
+                .change_passphrase(
+                    old_passphrase.as_str(),
+                    new_passphrase.as_str(),
+                )
+                .map(|_| Reply::Success)
+                .map_err(Error::from),
+
+            Request::ExportBackup(request) => {
+                self.export_backup(request).map(Reply::Backup)
+            }
+
+            Request::ImportBackup(request) => {
+                let report = self.import_backup(request)?;
+                self.watch_contracts();
+                Ok(Reply::BackupImported(report))
+            }
+
+            Request::ListWallets => self.list_wallets().map(Reply::Wallets),
+
+            Request::CreateWallet(name) => {
+                self.create_wallet(name)?;
+                Ok(Reply::Success)
+            }
+
+            Request::OpenWallet(name) => {
+                self.open_wallet(name)?;
+                Ok(Reply::Success)
+            }
+
+            Request::CloseWallet(name) => {
+                self.close_wallet(name)?;
+                Ok(Reply::Success)
+            }
+
+            Request::DeleteWallet(name) => {
+                self.delete_wallet(name)?;
+                Ok(Reply::Success)
+            }
+
+            Request::InWallet(request) => self.process_in_wallet(request),
+
             Request::CreateSingleSig(req) => {
-                let contract = Contract::with(
-                    Policy::Current(ContractDescriptor::SingleSig {
+                Ok(self.create_single_sig(req, None)?)
+            }
+
+            Request::CreateSingleSigDescriptor(req) => {
+                let (pubkey_chain, branches) =
+                    model::parse_multipath_chain(&req.pubkey_chain).map_err(
+                        |err| {
+                            Error::InvalidPubkeyChain(
+                                req.pubkey_chain.clone(),
+                                err.to_string(),
+                            )
+                        },
+                    )?;
+                Ok(self.create_single_sig(
+                    message::SingleSigInfo {
+                        name: req.name,
+                        pubkey_chain,
                         category: req.category,
-                        pk: req.pubkey_chain,
-                    }),
-                    req.name,
-                    self.config.chain.clone(),
-                );
-                self.storage
-                    .add_contract(contract)
-                    .map(ContractMeta::from)
-                    .map(Reply::Contract)
-                    .map_err(Error::from)
+                    },
+                    branches,
+                )?)
             }
 
             Request::ContractOperations(contract_id) => self
@@ -68,6 +152,10 @@ impl Runtime {
                 .map(Reply::Operations)
                 .map_err(Error::from),
 
+            Request::QueryOperations(query) => {
+                self.query_operations(query).map(Reply::OperationsPage)
+            }
+
             Request::ListContracts => self
                 .storage
                 .contracts()
@@ -84,20 +172,113 @@ impl Runtime {
                 .map(|_| Reply::Success)
                 .map_err(Error::from),
 
-            Request::DeleteContract(contract_id) => self
+            Request::DeleteContract(contract_id) => {
+                self.archive_contract(contract_id, false)?;
+                self.watchlist.unwatch(contract_id);
+                Ok(Reply::Success)
+            }
+
+            Request::ForceDeleteContract(contract_id) => {
+                self.archive_contract(contract_id, true)?;
+                self.watchlist.unwatch(contract_id);
+                Ok(Reply::Success)
+            }
+
+            Request::ListReorgs => Ok(Reply::Reorgs(self.reorgs.clone())),
+
+            Request::ListArchivedContracts => self
                 .storage
-                .delete_contract(contract_id)
-                .map(|_| Reply::Success)
+                .archived_contracts()
+                .map(|vec| vec.into_iter().map(ArchivedContractMeta::from).collect::<Vec<_>>())
+                .map(Reply::ArchivedContracts)
                 .map_err(Error::from),
 
+            Request::RestoreContract(contract_id) => {
+                let contract = self
+                    .storage
+                    .restore_contract(contract_id)
+                    .map_err(Error::from)?;
+                self.watch_contract(contract_id);
+                Ok(Reply::Contract(ContractMeta::from(contract)))
+            }
+
             Request::SyncContract(message::SyncContractRequest {
                 contract_id,
                 lookup_depth,
             }) => {
-                let assets = self.chain_sync(contract_id, lookup_depth)?;
+                let assets =
+                    self.chain_sync(contract_id, Some(lookup_depth))?;
+                Ok(Reply::ContractUnspent(assets))
+            }
+
+            Request::SyncContractGap(message::SyncContractGapRequest {
+                contract_id,
+                gap_limit,
+            }) => {
+                if let Some(gap_limit) = gap_limit {
+                    self.cache
+                        .set_gap_limit(contract_id, gap_limit)
+                        .map_err(Error::from)?;
+                }
+                let assets = self.chain_sync(contract_id, gap_limit)?;
                 Ok(Reply::ContractUnspent(assets))
             }
 
+            Request::ListLabels(contract_id) => self
+                .storage
+                .contract_ref(contract_id)
+                .map(|contract| {
+                    contract
+                        .data()
+                        .labels()
+                        .iter()
+                        .map(|(reference, label)| Label {
+                            contract_id,
+                            reference: reference.clone(),
+                            label: label.clone(),
+                        })
+                        .collect()
+                })
+                .map(Reply::Labels)
+                .map_err(Error::from),
+
+            Request::GetLabel(message::ContractLabelRef {
+                contract_id,
+                reference,
+            }) => self
+                .storage
+                .contract_ref(contract_id)
+                .map_err(Error::from)?
+                .data()
+                .labels()
+                .get(&reference)
+                .map(|label| Reply::Label(Label {
+                    contract_id,
+                    reference: reference.clone(),
+                    label: label.clone(),
+                }))
+                .ok_or_else(|| storage::Error::LabelNotFound(
+                    format!("{}.{}", contract_id, reference)
+                ).into()),
+
+            Request::SetLabel(message::SetLabelRequest {
+                contract_id,
+                reference,
+                label,
+            }) => self
+                .storage
+                .set_label(contract_id, reference, label)
+                .map(|_| Reply::Success)
+                .map_err(Error::from),
+
+            Request::ExportLabels(request) => {
+                self.export_labels(request).map(Reply::LabelsExport)
+            }
+
+            Request::ImportLabels(request) => {
+                self.import_labels(request).map(Reply::LabelsImported)
+            }
+
             Request::UsedAddresses(contract_id) => self
                 .cache
                 .used_address_derivations(contract_id)
@@ -109,35 +290,44 @@ impl Runtime {
                 index,
                 legacy,
                 mark_used,
-            }) => self
-                .storage
-                .contract_ref(contract_id)
-                .map_err(Error::from)?
-                .derive_address(
-                    index.unwrap_or(
-                        self.cache
-                            .next_unused_derivation(contract_id)
-                            .map_err(Error::from)?,
-                    ),
-                    legacy,
-                )
-                .and_then(|address_derivation| {
-                    if mark_used {
-                        self.cache.use_address_derivation(
-                            contract_id,
-                            address_derivation.address.clone(),
-                            *address_derivation.derivation.last().expect(
-                                "derivation path must always have at least one element"
-                            ),
-                        ).ok()?;
-                    }
-                    Some(address_derivation)
-                })
-                .map(Reply::AddressDerivation)
-                .ok_or(Error::ServerFailure(Failure {
-                    code: 0,
-                    info: s!("Unable to derive address for the provided network/chain"),
-                })),
+            }) => {
+                let reply = self
+                    .storage
+                    .contract_ref(contract_id)
+                    .map_err(Error::from)?
+                    .derive_address(
+                        Branch::External,
+                        index.unwrap_or(
+                            self.cache
+                                .next_unused_derivation(contract_id, Branch::External)
+                                .map_err(Error::from)?,
+                        ),
+                        legacy,
+                    )
+                    .and_then(|address_derivation| {
+                        if mark_used {
+                            self.cache.use_address_derivation(
+                                contract_id,
+                                Branch::External,
+                                address_derivation.address.clone(),
+                                *address_derivation.derivation.last().expect(
+                                    "derivation path must always have at least one element"
+                                ),
+                            ).ok()?;
+                        }
+                        Some(address_derivation)
+                    })
+                    .map(Reply::AddressDerivation)
+                    .ok_or(Error::ServerFailure(Failure {
+                        code: 0,
+                        info: s!("Unable to derive address for the provided network/chain"),
+                    }));
+                if mark_used {
+                    // Addresses given away must be watched for payments
+                    self.watch_contract(contract_id);
+                }
+                reply
+            }
 
             Request::UnuseAddress(message::ContractAddressTuple {
                 contract_id,
@@ -195,21 +385,12 @@ impl Runtime {
                         // TODO: Update saved PSBT
                         trace!("Finalized PSBT: {:#?}", psbt);
 
-                        debug!(
-                            "Connecting electrum server at {} ...",
-                            self.config.electrum_server
-                        );
-                        debug!("Electrum server successfully connected");
-                        let electrum =
-                            ElectrumClient::new(&self.config.electrum_server.to_string()).map_err(Error::from)?;
-
-                        debug!("Publishing transaction to bitcoin network via Electrum server");
                         trace!("{:#?}", tx);
-                        electrum
-                            .transaction_broadcast(&tx)
+                        self.chain
+                            .broadcast(&tx)
                             .map(|_| Reply::Success)
                             .map_err(|err| {
-                                error!("Electrum server error: {:?}", err);
+                                error!("Transaction broadcast failure: {}", err);
                                 err
                             })
                             .map_err(Error::from)
@@ -247,6 +428,19 @@ impl Runtime {
                 Ok(Reply::Validation(status))
             }
 
+            Request::ContractUnspentBranches(id) => self
+                .cache
+                .unspent(id)
+                .map(|assets| {
+                    assets
+                        .values()
+                        .flatten()
+                        .map(|utxo| (utxo.outpoint(), utxo.branch))
+                        .collect()
+                })
+                .map(Reply::UnspentBranches)
+                .map_err(Error::from),
+
             Request::ContractUnspent(id) => self
                 .cache
                 .unspent(id)
@@ -260,15 +454,33 @@ impl Runtime {
                 .map(Reply::Identities)
                 .map_err(Error::from),
 
+            Request::AddIdentity(identity) => self
+                .storage
+                .add_identity(identity)
+                .map(Reply::Identity)
+                .map_err(Error::from),
+
+            Request::RemoveIdentity(key) => self
+                .storage
+                .remove_identity(&key)
+                .map(|_| Reply::Success)
+                .map_err(Error::from),
+
+            Request::ListSigners => self
+                .storage
+                .signers()
+                .map(Reply::Signers)
+                .map_err(Error::from),
+
             Request::AddSigner(account) => self
                 .storage
                 .add_signer(account)
-                .map(|_| Reply::Success)
+                .map(Reply::Signer)
                 .map_err(Error::from),
 
-            Request::AddIdentity(identity) => self
+            Request::RemoveSigner(key) => self
                 .storage
-                .add_identity(identity)
+                .remove_signer(&key)
                 .map(|_| Reply::Success)
                 .map_err(Error::from),
 
@@ -290,4 +502,19 @@ impl Runtime {
         }
         .map_err(Error::into)
     }
+
+    fn create_single_sig(
+        &mut self,
+        info: message::SingleSigInfo,
+        branches: Option<BranchPair>,
+    ) -> Result<Reply, Error> {
+        let contract = Contract::with(
+            Policy::single_sig(info.category, info.pubkey_chain, branches),
+            info.name,
+            self.config.chain.clone(),
+        );
+        let contract = self.storage.add_contract(contract)?;
+        self.watch_contract(*contract.id());
+        Ok(Reply::Contract(ContractMeta::from(contract)))
+    }
 }
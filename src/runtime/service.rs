@@ -11,15 +11,22 @@
 // along with this software.
 // If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use bitcoin::secp256k1::rand::rngs::ThreadRng;
-use electrum_client::{Client as ElectrumClient, ElectrumApi};
 use internet2::{
     session, zmqsocket, CreateUnmarshaller, PlainTranscoder, Session,
     TypedEnum, Unmarshaller, ZmqSocketAddr, ZmqType,
 };
 use microservices::node::TryService;
 
-use super::Config;
+use super::lock::DataDirLock;
+use super::wallet::{self, Wallet};
+use super::{Config, StorageType, DEFAULT_WALLET};
+use crate::chainapi;
+use crate::chainwatch::{self, WatchList};
+use crate::rpc::message::ReorgInfo;
 use crate::rpc::Request;
 use crate::{cache, storage, Error};
 
@@ -39,11 +46,25 @@ pub struct Runtime {
     pub(super) session_rpc:
         session::Raw<PlainTranscoder, zmqsocket::Connection>,
 
-    /// Wallet data storage
-    pub(super) storage: storage::FileDriver,
+    /// Name of the active wallet, which is used by wallet-specific requests
+    /// not addressed to some other open wallet
+    pub(super) wallet: String,
+
+    /// Data storage of the active wallet
+    pub(super) storage: Box<dyn storage::Driver>,
+
+    /// Data cache of the active wallet
+    pub(super) cache: Box<dyn cache::Driver>,
+
+    /// Source of the blockchain data of the active wallet, shared with
+    /// chainwatch service
+    pub(super) chain: Arc<dyn chainapi::Driver>,
+
+    /// Scripts of the active wallet watched by chainwatch service
+    pub(super) watchlist: WatchList,
 
-    /// Wallet data cache
-    pub(super) cache: cache::FileDriver,
+    /// Other opened wallets, indexed by their names
+    pub(super) wallets: BTreeMap<String, Wallet>,
 
     /// Unmarshaller instance used for parsing RPC request
     pub(super) unmarshaller: Unmarshaller<Request>,
@@ -56,16 +77,55 @@ pub struct Runtime {
 
     /// Known blockchain height by the last received block header
     pub(super) known_height: u32,
+
+    /// Chain reorganizations detected since the runtime start
+    pub(super) reorgs: Vec<ReorgInfo>,
+
+    /// Exclusive lock over the data directory, released on drop
+    pub(super) data_dir_lock: Option<DataDirLock>,
 }
 
 impl Runtime {
     pub fn init(config: Config) -> Result<Self, Error> {
-        debug!("Initializing wallet storage {:?}", config.storage_conf());
-        let storage = storage::FileDriver::with(config.storage_conf())?;
+        Self::check_proxy(&config)?;
 
-        debug!("Initializing wallet cache {:?}", config.cache_conf());
-        let cache = cache::FileDriver::with(config.cache_conf())?;
+        let data_dir_lock = match config.storage_type {
+            StorageType::Memory => None,
+            _ => Some(DataDirLock::acquire(&config.data_dir)?),
+        };
 
+        let chain = wallet::open_chain(&config, DEFAULT_WALLET)?;
+        let Wallet {
+            storage,
+            cache,
+            chain,
+        } = Wallet::open(&config, DEFAULT_WALLET, chain)?;
+
+        let mut runtime = Self::start(config, storage, cache, chain)?;
+        runtime.data_dir_lock = data_dir_lock;
+        Ok(runtime)
+    }
+
+    /// Initializes runtime with custom storage and cache drivers used for the
+    /// default wallet and a custom source of the blockchain data
+    pub fn with_drivers(
+        config: Config,
+        storage: Box<dyn storage::Driver>,
+        cache: Box<dyn cache::Driver>,
+        chain: Arc<dyn chainapi::Driver>,
+    ) -> Result<Self, Error> {
+        Self::check_proxy(&config)?;
+        Self::start(config, storage, cache, chain)
+    }
+
+    /// Starts runtime with the given drivers; the proxy configuration must be
+    /// checked by the caller
+    fn start(
+        config: Config,
+        storage: Box<dyn storage::Driver>,
+        cache: Box<dyn cache::Driver>,
+        chain: Arc<dyn chainapi::Driver>,
+    ) -> Result<Self, Error> {
         debug!("Initializing random number generator");
         let rng = bitcoin::secp256k1::rand::thread_rng();
 
@@ -77,20 +137,20 @@ impl Runtime {
             None,
         )?;
 
-        debug!(
-            "Connecting electrum server at {} ...",
-            config.electrum_server
-        );
-        debug!("Electrum server successfully connected");
-        let electrum =
-            ElectrumClient::new(&config.electrum_server.to_string())?;
         debug!("Subscribing to new block notifications");
-        let known_height = electrum.block_headers_subscribe()?.height as u32;
+        let known_height = match chain.subscribe_headers() {
+            Ok(info) => info.height,
+            Err(err) => {
+                // Subscription is made again on the first synchronization
+                warn!("Unable to get current blockchain height: {}", err);
+                0
+            }
+        };
 
         let rgb_config = rgb_node::i9n::Config {
             verbose: config.verbose,
             data_dir: config.data_dir.clone().to_string_lossy().to_string(),
-            electrum_server: config.electrum_server.clone(),
+            electrum_server: config.electrum_server(),
             stash_rpc_endpoint: ZmqSocketAddr::Inproc(s!("stash.rpc")),
             contract_endpoints: map! {
                 rgb_node::rgbd::ContractName::Fungible => config.rgb20_endpoint.clone()
@@ -108,16 +168,43 @@ impl Runtime {
 
         info!("Citadel runtime started successfully");
 
-        Ok(Self {
+        let mut runtime = Self {
             config,
             session_rpc,
+            wallet: s!(DEFAULT_WALLET),
             storage,
             cache,
+            chain,
+            watchlist: none!(),
+            wallets: none!(),
             rgb20_client,
             rng,
             unmarshaller: Request::create_unmarshaller(),
             known_height,
-        })
+            reorgs: none!(),
+            data_dir_lock: None,
+        };
+
+        runtime.watchlist.set_chain(runtime.chain.clone());
+        runtime.watch_contracts();
+        if runtime.config.chainwatch {
+            debug!("Starting chainwatch service");
+            chainwatch::spawn(
+                runtime.config.rpc_endpoint.clone(),
+                runtime.watchlist.clone(),
+            )?;
+        }
+
+        Ok(runtime)
+    }
+
+    /// Refuses configurations in which the embedded RGB node would bypass
+    /// the proxy used for the rest of the blockchain connections
+    fn check_proxy(config: &Config) -> Result<(), Error> {
+        match (config.rgb_embedded, &config.proxy) {
+            (true, Some(proxy)) => Err(Error::EmbeddedNodeProxy(proxy.clone())),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -141,6 +228,7 @@ impl Runtime {
     fn run(&mut self) -> Result<(), Error> {
         trace!("Awaiting for ZMQ RPC requests...");
         let raw = self.session_rpc.recv_raw_message()?;
+        self.update_known_height();
         let reply = self.rpc_process(raw).unwrap_or_else(|err| err);
         trace!("Preparing ZMQ RPC reply: {:?}", reply);
         let data = reply.serialize();
@@ -152,3 +240,272 @@ impl Runtime {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Address, Network, OutPoint, Script, Transaction, TxOut};
+    use internet2::{TypedEnum, ZmqSocketAddr};
+    use invoice::{Beneficiary, Invoice};
+    use lnpbp::chain::Chain;
+    use wallet::descriptors::{self, ContentType};
+    use wallet::hd::UnhardenedIndex;
+    use wallet::scripts::PubkeyScript;
+
+    use super::Runtime;
+    use crate::cache::Driver as CacheDriver;
+    use crate::chainapi::RegtestSimulator;
+    use crate::model::{Branch, ContractId, Utxo};
+    use crate::rpc::{message, Reply, Request};
+    use crate::runtime::{ChainApiType, Config, StorageType, DEFAULT_WALLET};
+    use crate::storage::Driver as StorageDriver;
+    use crate::test_utils::{pubkey_chain, temp_dir};
+    use crate::{cache, storage};
+
+    const FUNDING: u64 = 1_0000_0000;
+
+    fn regtest() -> Chain {
+        Chain::Regtest(genesis_block(Network::Regtest).block_hash())
+    }
+
+    // Embedded RGB node binds fixed in-process endpoints, so there may be
+    // only a single runtime per test process
+    fn runtime(chain: &RegtestSimulator) -> Runtime {
+        let data_dir = temp_dir("runtime");
+        let config = Config {
+            chain: regtest(),
+            rpc_endpoint: ZmqSocketAddr::Inproc(s!("citadel-test.rpc")),
+            rgb20_endpoint: ZmqSocketAddr::Inproc(s!("citadel-test.rgb20")),
+            rgb_embedded: true,
+            data_dir,
+            storage_type: StorageType::Memory,
+            verbose: 0,
+            electrum_servers: vec![],
+            electrum_paranoid: false,
+            electrum_timeout: None,
+            chain_api: ChainApiType::Electrum,
+            proxy: None,
+            chainwatch: false,
+            esplora_server: String::new(),
+            bitcoind_server: String::new(),
+            bitcoind_auth: None,
+            filters_peer: String::new(),
+            filters_start_height: 0,
+        };
+        Runtime::with_drivers(
+            config,
+            Box::new(storage::MemoryDriver::new()),
+            Box::new(cache::MemoryDriver::new()),
+            Arc::new(chain.clone()),
+        )
+        .expect("runtime with in-memory drivers")
+    }
+
+    fn request(runtime: &mut Runtime, request: Request) -> Reply {
+        let name = request.to_string();
+        runtime
+            .rpc_process(request.serialize())
+            .unwrap_or_else(|failure| panic!("{} failed: {}", name, failure))
+    }
+
+    fn sync(runtime: &mut Runtime, contract_id: ContractId) -> Vec<Utxo> {
+        let request = Request::SyncContract(message::SyncContractRequest {
+            contract_id,
+            lookup_depth: 5,
+        });
+        match self::request(runtime, request) {
+            Reply::ContractUnspent(mut assets) => assets
+                .remove(&rgb::ContractId::default())
+                .unwrap_or_default(),
+            reply => panic!("unexpected reply {}", reply),
+        }
+    }
+
+    #[test]
+    fn sync_reorg_and_transfer() {
+        let chain = RegtestSimulator::new();
+        chain.mine(1);
+        let mut runtime = runtime(&chain);
+
+        let request = Request::CreateSingleSigDescriptor(
+            message::SingleSigDescriptorInfo {
+                name: s!("test"),
+                pubkey_chain: pubkey_chain(7),
+                category: ContentType::SegWit,
+            },
+        );
+        let contract_id = match self::request(&mut runtime, request) {
+            Reply::Contract(contract) => *contract.id(),
+            reply => panic!("unexpected reply {}", reply),
+        };
+        let request = Request::NextAddress(message::NextAddressRequest {
+            contract_id,
+            index: None,
+            legacy: false,
+            mark_used: true,
+        });
+        let receive = match self::request(&mut runtime, request) {
+            Reply::AddressDerivation(derivation) => derivation.address,
+            reply => panic!("unexpected reply {}", reply),
+        };
+
+        // Payment mined at height 2 is found by the synchronization
+        let orphaned = chain.pay_to(receive.script_pubkey(), FUNDING);
+        chain.mine(1);
+        let unspent = sync(&mut runtime, contract_id);
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].txid, orphaned);
+        assert_eq!(unspent[0].value, FUNDING);
+        assert_eq!(unspent[0].height, 2);
+        assert_eq!(unspent[0].branch, Branch::External);
+        assert_eq!(unspent[0].derivation_index, UnhardenedIndex::zero());
+
+        // The block is replaced by a chain which does not include the payment
+        chain.reorg(1, &[orphaned]);
+        assert!(sync(&mut runtime, contract_id).is_empty());
+        let reorgs = match self::request(&mut runtime, Request::ListReorgs) {
+            Reply::Reorgs(reorgs) => reorgs,
+            reply => panic!("unexpected reply {}", reply),
+        };
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].fork_height, 2);
+        assert_eq!(reorgs[0].orphaned_txids, vec![orphaned]);
+        match self::request(&mut runtime, Request::ContractUnspent(contract_id))
+        {
+            Reply::ContractUnspent(assets) => {
+                assert!(assets.values().all(|unspent| unspent.is_empty()))
+            }
+            reply => panic!("unexpected reply {}", reply),
+        }
+
+        // Transfer spends the new payment, sending change to the change
+        // branch of the contract
+        let funding = chain.pay_to(receive.script_pubkey(), FUNDING);
+        chain.mine(1);
+        assert_eq!(sync(&mut runtime, contract_id).len(), 1);
+        let payee = Address::p2wsh(&Script::new(), Network::Regtest);
+        let descriptor = descriptors::Compact::try_from(PubkeyScript::from(
+            payee.script_pubkey(),
+        ))
+        .expect("segwit address");
+        let amount = 3000_0000;
+        let fee = 1000;
+        let request =
+            Request::ComposeTransfer(message::ComposeTransferRequest {
+                pay_from: contract_id,
+                bitcoin_fee: fee,
+                asset_value: amount,
+                transfer_info: message::TransferInfo::Bitcoin(descriptor),
+                invoice: Invoice::new(
+                    Beneficiary::Address(payee.clone()),
+                    Some(amount),
+                    Some(regtest().native_asset()),
+                ),
+            });
+        let transfer = match self::request(&mut runtime, request) {
+            Reply::PreparedPayment(transfer) => transfer,
+            reply => panic!("unexpected reply {}", reply),
+        };
+        assert!(transfer.consignment.is_none());
+        let psbt = transfer.psbt;
+        let tx = &psbt.global.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, OutPoint::new(funding, 0));
+        assert_eq!(
+            psbt.inputs[0]
+                .non_witness_utxo
+                .as_ref()
+                .map(Transaction::txid),
+            Some(funding)
+        );
+        assert!(!psbt.inputs[0].bip32_derivation.is_empty());
+
+        let change = runtime
+            .storage
+            .contract_ref(contract_id)
+            .unwrap()
+            .derive_address(Branch::Internal, UnhardenedIndex::zero(), false)
+            .expect("regtest address")
+            .address;
+        assert_eq!(
+            tx.output,
+            vec![
+                TxOut {
+                    value: amount,
+                    script_pubkey: payee.script_pubkey(),
+                },
+                TxOut {
+                    value: FUNDING - amount - fee,
+                    script_pubkey: change.script_pubkey(),
+                },
+            ]
+        );
+
+        // Block mined before the reorganization check of the funding block
+        // is still reported
+        assert!(!runtime.cache.block_hashes().is_empty());
+        chain.mine(1);
+        assert!(runtime.check_reorg().unwrap().is_none());
+        runtime.update_known_height();
+        assert_eq!(runtime.known_height, chain.height());
+
+        // Transfer operation gets height of the block mining its transaction,
+        // loses it when the block is orphaned and gets it back once the
+        // transaction is mined again
+        let transfer_txid = chain.add_transaction(tx.clone());
+        let operation_height = |runtime: &mut Runtime| {
+            let request = Request::ContractOperations(contract_id);
+            match self::request(runtime, request) {
+                Reply::Operations(operations) => operations
+                    .into_iter()
+                    .find(|operation| operation.txid == transfer_txid)
+                    .map(|operation| operation.height)
+                    .expect("transfer operation"),
+                reply => panic!("unexpected reply {}", reply),
+            }
+        };
+        assert_eq!(operation_height(&mut runtime), 0);
+        chain.mine(1);
+        sync(&mut runtime, contract_id);
+        assert_eq!(operation_height(&mut runtime), chain.height() as i64);
+        chain.reorg(1, &[transfer_txid]);
+        sync(&mut runtime, contract_id);
+        assert_eq!(operation_height(&mut runtime), 0);
+        chain.add_transaction(tx.clone());
+        chain.mine(1);
+        sync(&mut runtime, contract_id);
+        assert_eq!(operation_height(&mut runtime), chain.height() as i64);
+
+        // Requests addressed to an open wallet leave the active wallet
+        // unchanged
+        request(&mut runtime, Request::CreateWallet(s!("other")));
+        let in_default = |request: Request| {
+            Request::InWallet(message::WalletRequest {
+                wallet: s!(DEFAULT_WALLET),
+                request: request.serialize(),
+            })
+        };
+        match self::request(&mut runtime, in_default(Request::ListContracts)) {
+            Reply::Contracts(contracts) => assert_eq!(contracts.len(), 1),
+            reply => panic!("unexpected reply {}", reply),
+        }
+        match self::request(&mut runtime, Request::ListContracts) {
+            Reply::Contracts(contracts) => assert!(contracts.is_empty()),
+            reply => panic!("unexpected reply {}", reply),
+        }
+        assert_eq!(runtime.wallet, "other");
+        assert!(runtime
+            .rpc_process(
+                in_default(Request::OpenWallet(s!("other"))).serialize()
+            )
+            .is_err());
+        assert!(runtime
+            .rpc_process(Request::CloseWallet(s!("../other")).serialize())
+            .is_err());
+        request(&mut runtime, Request::CloseWallet(s!("other")));
+        assert_eq!(runtime.wallet, DEFAULT_WALLET);
+    }
+}
@@ -0,0 +1,123 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Named wallet profiles hosted by a single runtime.
+//!
+//! Each wallet has its own storage and cache, kept in a separate directory
+//! (see [`Config::wallet_dir`]). RGB stash and assets are shared by all
+//! wallets of the runtime.
+
+use std::sync::Arc;
+
+use super::{ChainApiType, Config, StorageType};
+use crate::{cache, chainapi, storage, Error};
+
+/// Maximal length of the wallet name
+const WALLET_NAME_MAX_LEN: usize = 64;
+
+/// Storage, cache and source of blockchain data of an opened wallet
+pub(super) struct Wallet {
+    pub storage: Box<dyn storage::Driver>,
+    pub cache: Box<dyn cache::Driver>,
+    pub chain: Arc<dyn chainapi::Driver>,
+}
+
+impl Wallet {
+    /// Opens wallet data using drivers of the configured storage type
+    pub fn open(
+        config: &Config,
+        name: &str,
+        chain: Arc<dyn chainapi::Driver>,
+    ) -> Result<Self, Error> {
+        debug!(
+            "Initializing {} storage for wallet {}",
+            config.storage_type, name
+        );
+        let storage: Box<dyn storage::Driver> = match config.storage_type {
+            StorageType::File => {
+                Box::new(storage::FileDriver::with(config.storage_conf(name))?)
+            }
+            StorageType::Encrypted => Box::new(storage::EncryptedDriver::with(
+                config.storage_conf(name),
+            )?),
+            #[cfg(feature = "sqlite")]
+            StorageType::Sqlite => {
+                Box::new(storage::SqliteDriver::with(config.storage_db(name))?)
+            }
+            StorageType::Memory => Box::new(storage::MemoryDriver::new()),
+        };
+
+        debug!("Initializing cache for wallet {}", name);
+        let cache: Box<dyn cache::Driver> = match config.storage_type {
+            StorageType::File | StorageType::Encrypted => {
+                Box::new(cache::FileDriver::with(config.cache_conf(name))?)
+            }
+            #[cfg(feature = "sqlite")]
+            StorageType::Sqlite => {
+                Box::new(cache::SqliteDriver::with(config.cache_db(name))?)
+            }
+            StorageType::Memory => Box::new(cache::MemoryDriver::new()),
+        };
+
+        Ok(Wallet {
+            storage,
+            cache,
+            chain,
+        })
+    }
+}
+
+/// Connects to the configured source of blockchain data on behalf of the
+/// wallet. Connections made via proxy are isolated from the connections of
+/// other wallets.
+pub(super) fn open_chain(
+    config: &Config,
+    name: &str,
+) -> Result<Arc<dyn chainapi::Driver>, Error> {
+    debug!(
+        "Initializing {} source of blockchain data for wallet {}",
+        config.chain_api, name
+    );
+    Ok(match config.chain_api {
+        ChainApiType::Electrum => {
+            Arc::new(chainapi::ElectrumPool::with(&config.electrum_conf(name))?)
+        }
+        #[cfg(feature = "esplora")]
+        ChainApiType::Esplora => {
+            Arc::new(chainapi::EsploraDriver::with(&config.esplora_conf(name))?)
+        }
+        #[cfg(feature = "bitcoind")]
+        ChainApiType::Bitcoind => Arc::new(chainapi::BitcoindDriver::with(
+            &config.bitcoind_conf(name),
+        )?),
+        #[cfg(feature = "compact_filters")]
+        ChainApiType::CompactFilters => Arc::new(
+            chainapi::CompactFilterDriver::with(&config.filters_conf(name))?,
+        ),
+    })
+}
+
+/// Checks that the wallet name can be used as a directory name on all
+/// supported platforms
+pub(super) fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || name.len() > WALLET_NAME_MAX_LEN
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(Error::InvalidWalletName(name.to_owned()));
+    }
+    Ok(())
+}
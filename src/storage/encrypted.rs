@@ -0,0 +1,759 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Encrypted file storage driver.
+//!
+//! Serialized [`Citadel`] data are encrypted with XChaCha20-Poly1305 using a
+//! key derived from the user passphrase with scrypt. The driver starts in the
+//! locked state and gets access to the data only after it is unlocked with the
+//! passphrase. New storage must be explicitly initialized with
+//! [`Driver::init`], so a mistyped passphrase on unlock never creates empty
+//! storage in place of the missing data file.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::Txid;
+use bp::seals::OutpointReveal;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use invoice::Invoice;
+use wallet::descriptors;
+use zeroize::Zeroize;
+
+use super::migrations::SCHEMA;
+use super::{Driver, Error, FileConfig};
+use crate::journal::JournaledFile;
+use crate::migration::Loaded;
+use crate::model::{
+    ArchivedContract, Citadel, Contract, ContractId, LabelRef, Operation,
+    Policy, TweakedOutput,
+};
+use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
+
+const ENCRYPTED_FILENAME: &str = "data";
+const ENCRYPTED_EXTENSION: &str = "enc";
+
+const MAGIC: [u8; 8] = *b"CITADEL\x00";
+const VERSION: u8 = 2;
+/// Version of encrypted storage with unversioned plaintext data, which
+/// always have the layout of wallet data format version 1
+const UNVERSIONED_PLAINTEXT_VERSION: u8 = 1;
+pub(crate) const SALT_LEN: usize = 16;
+pub(crate) const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+pub(crate) const KDF_PARAMS_LEN: usize = 9;
+const HEADER_LEN: usize = MAGIC.len() + 1 + KDF_PARAMS_LEN + SALT_LEN;
+
+/// Upper bounds for the key derivation parameters read from the data files,
+/// preventing a crafted file from exhausting memory or CPU
+const MAX_KDF_LOG_N: u8 = 20;
+const MAX_KDF_R: u32 = 32;
+const MAX_KDF_P: u32 = 16;
+/// Maximal memory used by scrypt, which is `128 * r * 2^log_n` bytes
+const MAX_KDF_MEMORY: u64 = 1 << 30;
+
+/// Parameters of the scrypt key derivation function
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        // Values recommended by scrypt authors for interactive logins
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    pub(crate) fn to_bytes(self) -> [u8; KDF_PARAMS_LEN] {
+        let mut bytes = [0u8; KDF_PARAMS_LEN];
+        bytes[0] = self.log_n;
+        bytes[1..5].copy_from_slice(&self.r.to_le_bytes());
+        bytes[5..].copy_from_slice(&self.p.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> KdfParams {
+        KdfParams {
+            log_n: bytes[0],
+            r: u32::from_le_bytes(
+                bytes[1..5].try_into().expect("fixed-size slice"),
+            ),
+            p: u32::from_le_bytes(
+                bytes[5..9].try_into().expect("fixed-size slice"),
+            ),
+        }
+    }
+
+    pub(crate) fn derive_key(
+        self,
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Result<[u8; KEY_LEN], Error> {
+        if self.log_n > MAX_KDF_LOG_N
+            || self.r > MAX_KDF_R
+            || self.p > MAX_KDF_P
+            || 128 * self.r as u64 * (1u64 << self.log_n) > MAX_KDF_MEMORY
+        {
+            return Err(Error::Encryption(format!(
+                "key derivation parameters {:?} exceed the allowed limits",
+                self
+            )));
+        }
+        let params =
+            scrypt::Params::new(self.log_n, self.r, self.p).map_err(|_| {
+                Error::Encryption(s!("invalid key derivation parameters"))
+            })?;
+        let mut key = [0u8; KEY_LEN];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+            .map_err(|_| Error::Encryption(s!("key derivation failure")))?;
+        Ok(key)
+    }
+}
+
+/// Key material and data available while the storage is unlocked
+struct Unlocked {
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+    kdf: KdfParams,
+    data: Citadel,
+}
+
+impl Drop for Unlocked {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Decrypted content of an encrypted file together with its key material
+struct Opened {
+    version: u8,
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+    kdf: KdfParams,
+    plaintext: Vec<u8>,
+}
+
+impl Drop for Opened {
+    fn drop(&mut self) {
+        self.key.zeroize();
+        self.plaintext.zeroize();
+    }
+}
+
+fn header(version: u8, kdf: KdfParams, salt: &[u8; SALT_LEN]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.push(version);
+    header.extend_from_slice(&kdf.to_bytes());
+    header.extend_from_slice(salt);
+    header
+}
+
+/// Decrypts content of an encrypted file without parsing the plaintext
+fn open(passphrase: &str, encrypted: &[u8]) -> Result<Opened, Error> {
+    if encrypted.len() < HEADER_LEN + NONCE_LEN
+        || encrypted[..MAGIC.len()] != MAGIC
+    {
+        return Err(Error::Encryption(s!(
+            "data file is not an encrypted citadel storage"
+        )));
+    }
+    let version = encrypted[MAGIC.len()];
+    if version != VERSION && version != UNVERSIONED_PLAINTEXT_VERSION {
+        return Err(Error::Encryption(format!(
+            "unsupported encrypted storage version {}",
+            version
+        )));
+    }
+    let kdf_start = MAGIC.len() + 1;
+    let salt_start = kdf_start + KDF_PARAMS_LEN;
+    let kdf = KdfParams::from_bytes(&encrypted[kdf_start..salt_start]);
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&encrypted[salt_start..HEADER_LEN]);
+    let (header, rest) = encrypted.split_at(HEADER_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let mut key = kdf.derive_key(passphrase, &salt)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let decrypted = cipher.decrypt(
+        XNonce::from_slice(nonce),
+        Payload {
+            msg: ciphertext,
+            aad: header,
+        },
+    );
+    match decrypted {
+        Ok(plaintext) => Ok(Opened {
+            version,
+            key,
+            salt,
+            kdf,
+            plaintext,
+        }),
+        Err(_) => {
+            key.zeroize();
+            Err(Error::WrongPassphrase)
+        }
+    }
+}
+
+/// Encrypts plaintext with the given key material
+fn seal(
+    version: u8,
+    key: &[u8; KEY_LEN],
+    salt: &[u8; SALT_LEN],
+    kdf: KdfParams,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let header = header(version, kdf, salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| Error::Encryption(s!("encryption failure")))?;
+
+    let mut encrypted =
+        Vec::with_capacity(HEADER_LEN + NONCE_LEN + ciphertext.len());
+    encrypted.extend(header);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend(ciphertext);
+    Ok(encrypted)
+}
+
+pub struct EncryptedDriver {
+    file: JournaledFile,
+    config: FileConfig,
+    state: Option<Unlocked>,
+}
+
+impl EncryptedDriver {
+    pub fn with(config: FileConfig) -> Result<Self, Error> {
+        info!(
+            "Initializing encrypted file driver for data in {}",
+            &config.location
+        );
+        fs::create_dir_all(&config.location)?;
+
+        let file = JournaledFile::with(Self::filename(&config))?;
+        if !file.exists() {
+            warn!(
+                "Encrypted data file `{:?}` does not exist: storage must be \
+                 initialized with a passphrase",
+                file.path()
+            );
+        }
+        Ok(EncryptedDriver {
+            file,
+            config,
+            state: None,
+        })
+    }
+
+    fn filename(config: &FileConfig) -> PathBuf {
+        let mut filename = PathBuf::from(config.location.clone());
+        filename.push(ENCRYPTED_FILENAME);
+        filename.set_extension(ENCRYPTED_EXTENSION);
+        filename
+    }
+
+    fn unlocked(&self) -> Result<&Unlocked, Error> {
+        self.state.as_ref().ok_or(Error::Locked)
+    }
+
+    fn data(&self) -> Result<&Citadel, Error> {
+        self.unlocked().map(|state| &state.data)
+    }
+
+    fn data_mut(&mut self) -> Result<&mut Citadel, Error> {
+        self.state
+            .as_mut()
+            .map(|state| &mut state.data)
+            .ok_or(Error::Locked)
+    }
+
+    fn decrypt(
+        &self,
+        passphrase: &str,
+        encrypted: &[u8],
+    ) -> Result<Loaded<Unlocked>, Error> {
+        let opened = open(passphrase, encrypted)?;
+        let loaded =
+            SCHEMA.load::<Citadel>(&opened.plaintext, self.config.format, 1)?;
+        Ok(Loaded {
+            data: Unlocked {
+                key: opened.key,
+                salt: opened.salt,
+                kdf: opened.kdf,
+                data: loaded.data,
+            },
+            migrated_from: loaded.migrated_from,
+            changes: loaded.changes,
+        })
+    }
+
+    fn encrypt(&self) -> Result<Vec<u8>, Error> {
+        let state = self.unlocked()?;
+        let mut plaintext = SCHEMA.store(&state.data, self.config.format)?;
+        let encrypted =
+            seal(VERSION, &state.key, &state.salt, state.kdf, &plaintext);
+        plaintext.zeroize();
+        encrypted
+    }
+
+    fn store(&mut self) -> Result<(), Error> {
+        debug!(
+            "Storing encrypted data to the file `{:?}`",
+            self.file.path()
+        );
+        let encrypted = self.encrypt()?;
+        self.file.write(&encrypted)?;
+        trace!("Encrypted citadel data stored");
+        Ok(())
+    }
+
+    /// Re-encrypts copies of the data made before the migrations with the
+    /// current key, keeping their format versions. Copies which can't be
+    /// decrypted with the old passphrase are encrypted with some even older
+    /// one and are removed.
+    fn reencrypt_backups(&self, old_passphrase: &str) -> Result<(), Error> {
+        let state = self.unlocked()?;
+        for path in SCHEMA.backups(self.file.path())? {
+            let opened = match open(old_passphrase, &fs::read(&path)?) {
+                Ok(opened) => opened,
+                Err(err) => {
+                    warn!(
+                        "Removing backup copy {:?} which can't be \
+                         re-encrypted: {}",
+                        path, err
+                    );
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+            };
+            let encrypted = seal(
+                opened.version,
+                &state.key,
+                &state.salt,
+                state.kdf,
+                &opened.plaintext,
+            )?;
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            let mut tmp = fs::File::create(&tmp_path)?;
+            tmp.write_all(&encrypted)?;
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+            debug!("Backup copy {:?} is re-encrypted", path);
+        }
+        Ok(())
+    }
+
+    fn new_state(passphrase: &str, data: Citadel) -> Result<Unlocked, Error> {
+        let kdf = KdfParams::default();
+        let mut salt = [0u8; SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        Ok(Unlocked {
+            key: kdf.derive_key(passphrase, &salt)?,
+            salt,
+            kdf,
+            data,
+        })
+    }
+}
+
+impl Driver for EncryptedDriver {
+    fn is_locked(&self) -> bool {
+        self.state.is_none()
+    }
+
+    fn init(&mut self, passphrase: &str) -> Result<(), Error> {
+        if self.file.exists() {
+            return Err(Error::AlreadyInitialized);
+        }
+        info!("Initializing new encrypted citadel storage");
+        self.state = Some(Self::new_state(passphrase, none!())?);
+        self.store()
+    }
+
+    fn unlock(&mut self, passphrase: &str) -> Result<(), Error> {
+        if self.state.is_some() {
+            return Ok(());
+        }
+        if !self.file.exists() {
+            return Err(Error::NotInitialized);
+        }
+        debug!("Unlocking encrypted data from `{:?}`", self.file.path());
+        let encrypted = self.file.read()?;
+        let loaded = self.decrypt(passphrase, &encrypted)?;
+        self.state = Some(loaded.data);
+        if let Some(version) = loaded.migrated_from {
+            let backup = SCHEMA.backup(self.file.path(), version)?;
+            self.store()?;
+            SCHEMA.report(version, &loaded.changes, Some(&backup));
+        }
+        info!("Encrypted storage unlocked");
+        Ok(())
+    }
+
+    fn lock(&mut self) -> Result<(), Error> {
+        // Dropping unlocked state wipes the key from the memory
+        self.state = None;
+        info!("Encrypted storage locked");
+        Ok(())
+    }
+
+    /// Besides the data file, re-encrypts all other copies of the data kept
+    /// on the disk, so none of them can be decrypted with the old passphrase
+    fn change_passphrase(
+        &mut self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), Error> {
+        let encrypted = self.file.read()?;
+        let current = self.decrypt(old_passphrase, &encrypted)?.data;
+        let was_locked = self.state.is_none();
+        let data = self
+            .state
+            .take()
+            .map(|state| state.data.clone())
+            .unwrap_or_else(|| current.data.clone());
+        self.state = Some(Self::new_state(new_passphrase, data)?);
+        let result = self.encrypt().and_then(|encrypted| {
+            // Previous generations of the data kept by the journal are
+            // encrypted with the old key
+            self.file.rewrite(&encrypted)?;
+            self.reencrypt_backups(old_passphrase)
+        });
+        if was_locked {
+            self.state = None;
+        }
+        result?;
+        info!("Passphrase for encrypted storage changed");
+        Ok(())
+    }
+
+    fn contracts(&self) -> Result<Vec<Contract>, Error> {
+        Ok(self.data()?.contracts.values().cloned().collect())
+    }
+
+    fn contract_ref(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<&Contract, Error> {
+        self.data()?.contract_ref(contract_id)
+    }
+
+    fn add_contract(&mut self, contract: Contract) -> Result<Contract, Error> {
+        let contract = self.data_mut()?.add_contract(contract)?;
+        self.store()?;
+        Ok(contract)
+    }
+
+    fn rename_contract(
+        &mut self,
+        contract_id: ContractId,
+        new_name: String,
+    ) -> Result<(), Error> {
+        self.data_mut()?.contract_mut(contract_id)?.name = new_name;
+        self.store()
+    }
+
+    fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<ArchivedContract, Error> {
+        let archived = self.data_mut()?.archive_contract(contract_id)?.clone();
+        self.store()?;
+        Ok(archived)
+    }
+
+    fn archived_contracts(&self) -> Result<Vec<ArchivedContract>, Error> {
+        Ok(self.data()?.archived.values().cloned().collect())
+    }
+
+    fn restore_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Contract, Error> {
+        let contract = self.data_mut()?.restore_contract(contract_id)?.clone();
+        self.store()?;
+        Ok(contract)
+    }
+
+    fn policy(&self, contract_id: ContractId) -> Result<&Policy, Error> {
+        self.data()?.contract_ref(contract_id).map(Contract::policy)
+    }
+
+    fn add_invoice(
+        &mut self,
+        contract_id: ContractId,
+        invoice: Invoice,
+        reveal_info: Vec<OutpointReveal>,
+    ) -> Result<(), Error> {
+        let contract = self.data_mut()?.contract_mut(contract_id)?;
+        contract.add_invoice(invoice);
+        for reveal in reveal_info {
+            contract.add_blinding(reveal);
+        }
+        self.store()
+    }
+
+    fn add_p2c_tweak(
+        &mut self,
+        contract_id: ContractId,
+        tweak: TweakedOutput,
+    ) -> Result<(), Error> {
+        self.data_mut()?
+            .contract_mut(contract_id)?
+            .add_p2c_tweak(tweak);
+        self.store()
+    }
+
+    fn register_operation(
+        &mut self,
+        contract_id: ContractId,
+        operation: Operation,
+    ) -> Result<(), Error> {
+        self.data_mut()?
+            .contract_mut(contract_id)?
+            .add_operation(operation);
+        self.store()
+    }
+
+    fn mine_operations(
+        &mut self,
+        contract_id: ContractId,
+        heights: BTreeMap<Txid, u32>,
+    ) -> Result<usize, Error> {
+        let count = self
+            .data_mut()?
+            .contract_mut(contract_id)?
+            .mine_operations(&heights)
+            .len();
+        if count > 0 {
+            self.store()?;
+        }
+        Ok(count)
+    }
+
+    fn unmine_operations(
+        &mut self,
+        contract_id: ContractId,
+        height: u32,
+    ) -> Result<usize, Error> {
+        let count = self
+            .data_mut()?
+            .contract_mut(contract_id)?
+            .unmine_operations(height)
+            .len();
+        if count > 0 {
+            self.store()?;
+        }
+        Ok(count)
+    }
+
+    fn set_label(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        let previous = self
+            .data_mut()?
+            .contract_mut(contract_id)?
+            .set_label(reference, label);
+        self.store()?;
+        Ok(previous)
+    }
+
+    fn history(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<Vec<Operation>, Error> {
+        self.data()?
+            .contract_ref(contract_id)
+            .map(Contract::history)
+    }
+
+    fn signers(&self) -> Result<Vec<SignerAccountInfo>, Error> {
+        Ok(self.data()?.signers.values().cloned().collect())
+    }
+
+    fn add_signer(
+        &mut self,
+        account: SignerAccountInfo,
+    ) -> Result<SignerAccountInfo, Error> {
+        let account = self.data_mut()?.add_signer(account)?;
+        self.store()?;
+        Ok(account)
+    }
+
+    fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, Error> {
+        let account = self.data_mut()?.remove_signer(key)?;
+        self.store()?;
+        Ok(account)
+    }
+
+    fn identities(&self) -> Result<Vec<IdentityInfo>, Error> {
+        Ok(self.data()?.identities.values().cloned().collect())
+    }
+
+    fn add_identity(
+        &mut self,
+        identity: IdentityInfo,
+    ) -> Result<IdentityInfo, Error> {
+        let identity = self.data_mut()?.add_identity(identity)?;
+        self.store()?;
+        Ok(identity)
+    }
+
+    fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<IdentityInfo, Error> {
+        let identity = self.data_mut()?.remove_identity(key)?;
+        self.store()?;
+        Ok(identity)
+    }
+
+    fn dump(&self) -> Result<Citadel, Error> {
+        self.data().map(Citadel::clone)
+    }
+
+    fn restore(&mut self, data: Citadel) -> Result<(), Error> {
+        *self.data_mut()? = data;
+        self.store()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use microservices::FileFormat;
+
+    use super::*;
+    use crate::test_utils::{contract, temp_dir};
+
+    fn config(name: &str) -> FileConfig {
+        FileConfig {
+            location: temp_dir(&format!("encrypted-{}", name))
+                .to_string_lossy()
+                .to_string(),
+            format: FileFormat::StrictEncode,
+        }
+    }
+
+    fn files(dir: &str) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let config = config("lock");
+        let mut storage = EncryptedDriver::with(config.clone()).unwrap();
+        assert!(storage.is_locked());
+        assert_eq!(storage.unlock("pass").err(), Some(Error::NotInitialized));
+
+        storage.init("pass").unwrap();
+        let contract = storage.add_contract(contract(1, "savings")).unwrap();
+        storage.lock().unwrap();
+        assert!(storage.is_locked());
+        assert_eq!(storage.contracts().err(), Some(Error::Locked));
+        assert_eq!(storage.unlock("wrong").err(), Some(Error::WrongPassphrase));
+        assert!(storage.is_locked());
+
+        // Data are read back from the disk by a new driver instance
+        let mut storage = EncryptedDriver::with(config.clone()).unwrap();
+        assert_eq!(storage.init("pass").err(), Some(Error::AlreadyInitialized));
+        storage.unlock("pass").unwrap();
+        assert_eq!(storage.contracts().unwrap(), vec![contract]);
+
+        // Data are never written to the disk in plaintext
+        for path in files(&config.location) {
+            let content = fs::read(&path).unwrap();
+            assert!(!content.windows(7).any(|window| window == b"savings"));
+        }
+        fs::remove_dir_all(&config.location).unwrap();
+    }
+
+    #[test]
+    fn change_passphrase() {
+        let config = config("rekey");
+        let mut storage = EncryptedDriver::with(config.clone()).unwrap();
+        storage.init("old").unwrap();
+        let contract = storage.add_contract(contract(1, "savings")).unwrap();
+        storage.lock().unwrap();
+
+        // Copies of the data left by the journal recovery and migrations
+        let path = EncryptedDriver::filename(&config);
+        let backup = Path::new(&config.location).join("data.enc.v1.backup");
+        fs::copy(
+            &path,
+            Path::new(&config.location).join("data.enc.corrupted"),
+        )
+        .unwrap();
+        fs::copy(&path, &backup).unwrap();
+        assert!(Path::new(&config.location).join("data.enc.bak").exists());
+
+        assert_eq!(
+            storage.change_passphrase("wrong", "new").err(),
+            Some(Error::WrongPassphrase)
+        );
+        storage.change_passphrase("old", "new").unwrap();
+        assert!(storage.is_locked());
+
+        for path in files(&config.location) {
+            let content = fs::read(&path).unwrap();
+            assert!(
+                open("old", &content).is_err(),
+                "{:?} is readable with the old passphrase",
+                path
+            );
+        }
+        assert!(open("new", &fs::read(&backup).unwrap()).is_ok());
+
+        let mut storage = EncryptedDriver::with(config.clone()).unwrap();
+        assert_eq!(storage.unlock("old").err(), Some(Error::WrongPassphrase));
+        storage.unlock("new").unwrap();
+        assert_eq!(storage.contracts().unwrap(), vec![contract]);
+        fs::remove_dir_all(&config.location).unwrap();
+    }
+}
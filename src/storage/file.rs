@@ -13,18 +13,22 @@
 
 //! File storage driver
 
-use std::io::{Read, Seek, Write};
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::PathBuf;
-use std::{fs, io};
 
+use bitcoin::Txid;
 use bp::seals::OutpointReveal;
 use invoice::Invoice;
 use microservices::FileFormat;
-use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::descriptors;
 
+use super::migrations::SCHEMA;
 use super::{Driver, Error};
+use crate::journal::JournaledFile;
 use crate::model::{
-    Citadel, Contract, ContractId, Operation, Policy, TweakedOutput,
+    ArchivedContract, Citadel, Contract, ContractId, LabelRef, Operation,
+    Policy, TweakedOutput,
 };
 use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
 
@@ -32,7 +36,7 @@ const STORAGE_FILENAME: &'static str = "data";
 
 #[derive(Debug)]
 pub struct FileDriver {
-    fd: fs::File,
+    file: JournaledFile,
     config: FileConfig,
     data: Citadel,
 }
@@ -70,14 +74,10 @@ impl FileDriver {
         fs::create_dir_all(&config.location)?;
 
         let filename = config.filename();
-        let exists = filename.exists();
-        let fd = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(!exists)
-            .open(&filename)?;
+        let file = JournaledFile::with(filename.clone())?;
+        let exists = file.exists();
         let mut me = Self {
-            fd,
+            file,
             config: config.clone(),
             data: Default::default(),
         };
@@ -95,19 +95,15 @@ impl FileDriver {
 
     fn load(&mut self) -> Result<(), Error> {
         debug!("Loading data from `{:?}`", self.config.filename());
-        self.fd.seek(io::SeekFrom::Start(0))?;
+        let data = self.file.read()?;
         trace!("Parsing data (expected format {})", self.config.format);
-        self.data = match self.config.format {
-            FileFormat::StrictEncode => Citadel::strict_decode(&mut self.fd)?,
-            FileFormat::Yaml => serde_yaml::from_reader(&mut self.fd)?,
-            FileFormat::Toml => {
-                let mut data: Vec<u8> = vec![];
-                self.fd.read_to_end(&mut data)?;
-                toml::from_slice(&data)?
-            }
-            FileFormat::Json => serde_json::from_reader(&mut self.fd)?,
-            _ => unimplemented!(),
-        };
+        let loaded = SCHEMA.load(&data, self.config.format, 0)?;
+        self.data = loaded.data;
+        if let Some(version) = loaded.migrated_from {
+            let backup = SCHEMA.backup(self.file.path(), version)?;
+            self.store()?;
+            SCHEMA.report(version, &loaded.changes, Some(&backup));
+        }
         trace!("Data loaded from storage");
         Ok(())
     }
@@ -118,24 +114,8 @@ impl FileDriver {
             self.config.filename(),
             self.config.format
         );
-        self.fd.seek(io::SeekFrom::Start(0))?;
-        self.fd.set_len(0)?;
-        match self.config.format {
-            FileFormat::StrictEncode => {
-                self.data.strict_encode(&mut self.fd)?;
-            }
-            FileFormat::Yaml => {
-                serde_yaml::to_writer(&mut self.fd, &self.data)?;
-            }
-            FileFormat::Toml => {
-                let data = toml::to_vec(&self.data)?;
-                self.fd.write_all(&data)?;
-            }
-            FileFormat::Json => {
-                serde_json::to_writer(&mut self.fd, &self.data)?;
-            }
-            _ => unimplemented!(),
-        };
+        let data = SCHEMA.store(&self.data, self.config.format)?;
+        self.file.write(&data)?;
         trace!("Citadel data stored");
         Ok(())
     }
@@ -150,14 +130,11 @@ impl Driver for FileDriver {
         &self,
         contract_id: ContractId,
     ) -> Result<&Contract, Error> {
-        self.data
-            .contracts
-            .get(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))
+        self.data.contract_ref(contract_id)
     }
 
     fn add_contract(&mut self, contract: Contract) -> Result<Contract, Error> {
-        self.data.contracts.insert(*contract.id(), contract.clone());
+        let contract = self.data.add_contract(contract)?;
         self.store()?;
         Ok(contract)
     }
@@ -167,33 +144,34 @@ impl Driver for FileDriver {
         contract_id: ContractId,
         new_name: String,
     ) -> Result<(), Error> {
-        self.data
-            .contracts
-            .get_mut(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?
-            .name = new_name;
+        self.data.contract_mut(contract_id)?.name = new_name;
+        self.store()
+    }
+
+    fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<ArchivedContract, Error> {
+        let archived = self.data.archive_contract(contract_id)?.clone();
         self.store()?;
-        Ok(())
+        Ok(archived)
+    }
+
+    fn archived_contracts(&self) -> Result<Vec<ArchivedContract>, Error> {
+        Ok(self.data.archived.values().cloned().collect())
     }
 
-    fn delete_contract(
+    fn restore_contract(
         &mut self,
         contract_id: ContractId,
-    ) -> Result<(), Error> {
-        self.data
-            .contracts
-            .remove(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?;
+    ) -> Result<Contract, Error> {
+        let contract = self.data.restore_contract(contract_id)?.clone();
         self.store()?;
-        Ok(())
+        Ok(contract)
     }
 
     fn policy(&self, contract_id: ContractId) -> Result<&Policy, Error> {
-        self.data
-            .contracts
-            .get(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))
-            .map(Contract::policy)
+        self.data.contract_ref(contract_id).map(Contract::policy)
     }
 
     fn add_invoice(
@@ -202,17 +180,12 @@ impl Driver for FileDriver {
         invoice: Invoice,
         reveal_info: Vec<OutpointReveal>,
     ) -> Result<(), Error> {
-        let contract = self
-            .data
-            .contracts
-            .get_mut(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?;
+        let contract = self.data.contract_mut(contract_id)?;
         contract.add_invoice(invoice);
         for reveal in reveal_info {
             contract.add_blinding(reveal);
         }
-        self.store()?;
-        Ok(())
+        self.store()
     }
 
     fn add_p2c_tweak(
@@ -220,14 +193,8 @@ impl Driver for FileDriver {
         contract_id: ContractId,
         tweak: TweakedOutput,
     ) -> Result<(), Error> {
-        let contract = self
-            .data
-            .contracts
-            .get_mut(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?;
-        contract.add_p2c_tweak(tweak);
-        self.store()?;
-        Ok(())
+        self.data.contract_mut(contract_id)?.add_p2c_tweak(tweak);
+        self.store()
     }
 
     fn register_operation(
@@ -235,47 +202,115 @@ impl Driver for FileDriver {
         contract_id: ContractId,
         operation: Operation,
     ) -> Result<(), Error> {
-        let contract = self
+        self.data
+            .contract_mut(contract_id)?
+            .add_operation(operation);
+        self.store()
+    }
+
+    fn mine_operations(
+        &mut self,
+        contract_id: ContractId,
+        heights: BTreeMap<Txid, u32>,
+    ) -> Result<usize, Error> {
+        let count = self
+            .data
+            .contract_mut(contract_id)?
+            .mine_operations(&heights)
+            .len();
+        if count > 0 {
+            self.store()?;
+        }
+        Ok(count)
+    }
+
+    fn unmine_operations(
+        &mut self,
+        contract_id: ContractId,
+        height: u32,
+    ) -> Result<usize, Error> {
+        let count = self
             .data
-            .contracts
-            .get_mut(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?;
-        contract.add_operation(operation);
+            .contract_mut(contract_id)?
+            .unmine_operations(height)
+            .len();
+        if count > 0 {
+            self.store()?;
+        }
+        Ok(count)
+    }
+
+    fn set_label(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        let previous = self
+            .data
+            .contract_mut(contract_id)?
+            .set_label(reference, label);
         self.store()?;
-        Ok(())
+        Ok(previous)
     }
 
     fn history(
         &self,
         contract_id: ContractId,
     ) -> Result<Vec<Operation>, Error> {
-        let contract = self
-            .data
-            .contracts
-            .get(&contract_id)
-            .ok_or(Error::ContractNotFound(contract_id))?;
-        Ok(contract.history())
+        self.data.contract_ref(contract_id).map(Contract::history)
     }
 
     fn signers(&self) -> Result<Vec<SignerAccountInfo>, Error> {
-        unimplemented!()
+        Ok(self.data.signers.values().cloned().collect())
     }
 
     fn add_signer(
         &mut self,
-        _account: SignerAccountInfo,
+        account: SignerAccountInfo,
     ) -> Result<SignerAccountInfo, Error> {
-        unimplemented!()
+        let account = self.data.add_signer(account)?;
+        self.store()?;
+        Ok(account)
+    }
+
+    fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, Error> {
+        let account = self.data.remove_signer(key)?;
+        self.store()?;
+        Ok(account)
     }
 
     fn identities(&self) -> Result<Vec<IdentityInfo>, Error> {
-        unimplemented!()
+        Ok(self.data.identities.values().cloned().collect())
     }
 
     fn add_identity(
         &mut self,
-        _identity: IdentityInfo,
+        identity: IdentityInfo,
+    ) -> Result<IdentityInfo, Error> {
+        let identity = self.data.add_identity(identity)?;
+        self.store()?;
+        Ok(identity)
+    }
+
+    fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
     ) -> Result<IdentityInfo, Error> {
-        unimplemented!()
+        let identity = self.data.remove_identity(key)?;
+        self.store()?;
+        Ok(identity)
+    }
+
+    fn dump(&self) -> Result<Citadel, Error> {
+        Ok(self.data.clone())
+    }
+
+    fn restore(&mut self, data: Citadel) -> Result<(), Error> {
+        self.data = data;
+        self.store()
     }
 }
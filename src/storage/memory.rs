@@ -0,0 +1,234 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! In-memory storage driver.
+//!
+//! Keeps wallet data only for the lifetime of the driver, which is useful
+//! for tests and short-lived embedded or watch-only sessions which must not
+//! touch the disk.
+
+use std::collections::BTreeMap;
+
+use bitcoin::Txid;
+use bp::seals::OutpointReveal;
+use invoice::Invoice;
+use wallet::descriptors;
+
+use super::{Driver, Error};
+use crate::model::{
+    ArchivedContract, Citadel, Contract, ContractId, LabelRef, Operation,
+    Policy, TweakedOutput,
+};
+use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MemoryDriver {
+    data: Citadel,
+}
+
+impl MemoryDriver {
+    pub fn new() -> Self {
+        info!("Initializing in-memory storage driver");
+        MemoryDriver::default()
+    }
+
+    /// Creates driver with some pre-defined wallet data
+    pub fn with(data: Citadel) -> Self {
+        MemoryDriver { data }
+    }
+}
+
+impl Driver for MemoryDriver {
+    fn contracts(&self) -> Result<Vec<Contract>, Error> {
+        Ok(self.data.contracts.values().cloned().collect())
+    }
+
+    fn contract_ref(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<&Contract, Error> {
+        self.data.contract_ref(contract_id)
+    }
+
+    fn add_contract(&mut self, contract: Contract) -> Result<Contract, Error> {
+        self.data.add_contract(contract)
+    }
+
+    fn rename_contract(
+        &mut self,
+        contract_id: ContractId,
+        new_name: String,
+    ) -> Result<(), Error> {
+        self.data.contract_mut(contract_id)?.name = new_name;
+        Ok(())
+    }
+
+    fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<ArchivedContract, Error> {
+        self.data.archive_contract(contract_id).map(Clone::clone)
+    }
+
+    fn archived_contracts(&self) -> Result<Vec<ArchivedContract>, Error> {
+        Ok(self.data.archived.values().cloned().collect())
+    }
+
+    fn restore_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Contract, Error> {
+        self.data.restore_contract(contract_id).map(Clone::clone)
+    }
+
+    fn policy(&self, contract_id: ContractId) -> Result<&Policy, Error> {
+        self.data.contract_ref(contract_id).map(Contract::policy)
+    }
+
+    fn add_invoice(
+        &mut self,
+        contract_id: ContractId,
+        invoice: Invoice,
+        reveal_info: Vec<OutpointReveal>,
+    ) -> Result<(), Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        contract.add_invoice(invoice);
+        for reveal in reveal_info {
+            contract.add_blinding(reveal);
+        }
+        Ok(())
+    }
+
+    fn add_p2c_tweak(
+        &mut self,
+        contract_id: ContractId,
+        tweak: TweakedOutput,
+    ) -> Result<(), Error> {
+        self.data.contract_mut(contract_id)?.add_p2c_tweak(tweak);
+        Ok(())
+    }
+
+    fn register_operation(
+        &mut self,
+        contract_id: ContractId,
+        operation: Operation,
+    ) -> Result<(), Error> {
+        self.data
+            .contract_mut(contract_id)?
+            .add_operation(operation);
+        Ok(())
+    }
+
+    fn mine_operations(
+        &mut self,
+        contract_id: ContractId,
+        heights: BTreeMap<Txid, u32>,
+    ) -> Result<usize, Error> {
+        Ok(self
+            .data
+            .contract_mut(contract_id)?
+            .mine_operations(&heights)
+            .len())
+    }
+
+    fn unmine_operations(
+        &mut self,
+        contract_id: ContractId,
+        height: u32,
+    ) -> Result<usize, Error> {
+        Ok(self
+            .data
+            .contract_mut(contract_id)?
+            .unmine_operations(height)
+            .len())
+    }
+
+    fn set_label(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        Ok(self
+            .data
+            .contract_mut(contract_id)?
+            .set_label(reference, label))
+    }
+
+    fn history(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<Vec<Operation>, Error> {
+        self.data.contract_ref(contract_id).map(Contract::history)
+    }
+
+    fn signers(&self) -> Result<Vec<SignerAccountInfo>, Error> {
+        Ok(self.data.signers.values().cloned().collect())
+    }
+
+    fn add_signer(
+        &mut self,
+        account: SignerAccountInfo,
+    ) -> Result<SignerAccountInfo, Error> {
+        self.data.add_signer(account)
+    }
+
+    fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, Error> {
+        self.data.remove_signer(key)
+    }
+
+    fn identities(&self) -> Result<Vec<IdentityInfo>, Error> {
+        Ok(self.data.identities.values().cloned().collect())
+    }
+
+    fn add_identity(
+        &mut self,
+        identity: IdentityInfo,
+    ) -> Result<IdentityInfo, Error> {
+        self.data.add_identity(identity)
+    }
+
+    fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<IdentityInfo, Error> {
+        self.data.remove_identity(key)
+    }
+
+    fn dump(&self) -> Result<Citadel, Error> {
+        Ok(self.data.clone())
+    }
+
+    fn restore(&mut self, data: Citadel) -> Result<(), Error> {
+        self.data = data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryDriver;
+    use crate::storage::{Driver, Error};
+
+    #[test]
+    fn not_encrypted() {
+        let mut storage = MemoryDriver::new();
+        assert!(!storage.is_locked());
+        assert_eq!(storage.init("secret"), Err(Error::NotEncrypted));
+        assert_eq!(storage.unlock("secret"), Err(Error::NotEncrypted));
+        assert_eq!(storage.lock(), Err(Error::NotEncrypted));
+    }
+}
@@ -0,0 +1,523 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Format versions of the wallet data (`Citadel`) and migrations between
+//! them.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use bitcoin::Txid;
+use bp::seals::{OutpointHash, OutpointReveal};
+use chrono::NaiveDateTime;
+use invoice::Invoice;
+use lnpbp::chain::Chain;
+use rgb::Disclosure;
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::hd::UnhardenedIndex;
+
+use crate::migration::{Document, Migration, Schema};
+use crate::model::{
+    Branch, ContractId, LabelRef, Operation, PaymentDirecton, Policy,
+    PsbtWrapper, State, TweakedOutput,
+};
+use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
+
+/// Wallet data format versions:
+/// - 0: unversioned data;
+/// - 1: identities are indexed by their keys, signer accounts are added;
+/// - 2: archive of removed contracts is added;
+/// - 3: labels are added to the contract data;
+/// - 4: output derivation indexes of the operations have derivation branch.
+pub(crate) static SCHEMA: Schema = Schema {
+    name: "wallet",
+    version: 4,
+    migrations: &[
+        Migration {
+            version: 1,
+            description: "identities are re-indexed by their keys; empty list \
+                          of signer accounts is added",
+            upgrade: v1_index_identities,
+        },
+        Migration {
+            version: 2,
+            description: "empty archive of removed contracts is added",
+            upgrade: v2_add_archive,
+        },
+        Migration {
+            version: 3,
+            description: "empty set of labels is added to each contract",
+            upgrade: v3_add_labels,
+        },
+        Migration {
+            version: 4,
+            description: "derivation branch is added to the output derivation \
+                          indexes of the operations",
+            upgrade: v4_add_output_branches,
+        },
+    ],
+};
+
+/// Layout of `PaymentDirecton` data before format version 4
+#[derive(Clone, StrictEncode, StrictDecode)]
+enum PaymentDirectonV3 {
+    Incoming {
+        giveaway: Option<u64>,
+        input_derivation_indexes: HashSet<UnhardenedIndex>,
+    },
+
+    Outcoming {
+        published: bool,
+        asset_change: u64,
+        bitcoin_change: u64,
+        change_outputs: HashSet<u16>,
+        giveaway: Option<u64>,
+        paid_bitcoin_fee: u64,
+        output_derivation_indexes: HashSet<UnhardenedIndex>,
+        invoice: Invoice,
+    },
+}
+
+/// Layout of `Operation` data before format version 4
+#[derive(Clone, StrictEncode, StrictDecode)]
+struct OperationV3 {
+    direction: PaymentDirectonV3,
+    created_at: NaiveDateTime,
+    height: i64,
+    asset_id: Option<rgb::ContractId>,
+    balance_before: u64,
+    bitcoin_volume: u64,
+    asset_volume: u64,
+    bitcoin_value: u64,
+    asset_value: u64,
+    tx_fee: u64,
+    txid: Txid,
+    psbt: PsbtWrapper,
+    disclosure: Option<Disclosure>,
+    notes: Option<String>,
+}
+
+impl From<OperationV3> for Operation {
+    fn from(old: OperationV3) -> Self {
+        let direction = match old.direction {
+            PaymentDirectonV3::Incoming {
+                giveaway,
+                input_derivation_indexes,
+            } => PaymentDirecton::Incoming {
+                giveaway,
+                input_derivation_indexes,
+            },
+            // Before separate change branches were introduced change outputs
+            // were derived from the receive branch
+            PaymentDirectonV3::Outcoming {
+                published,
+                asset_change,
+                bitcoin_change,
+                change_outputs,
+                giveaway,
+                paid_bitcoin_fee,
+                output_derivation_indexes,
+                invoice,
+            } => PaymentDirecton::Outcoming {
+                published,
+                asset_change,
+                bitcoin_change,
+                change_outputs,
+                giveaway,
+                paid_bitcoin_fee,
+                output_derivation_indexes: output_derivation_indexes
+                    .into_iter()
+                    .map(|index| (Branch::External, index))
+                    .collect(),
+                invoice,
+            },
+        };
+        Operation {
+            direction,
+            created_at: old.created_at,
+            height: old.height,
+            asset_id: old.asset_id,
+            balance_before: old.balance_before,
+            bitcoin_volume: old.bitcoin_volume,
+            asset_volume: old.asset_volume,
+            bitcoin_value: old.bitcoin_value,
+            asset_value: old.asset_value,
+            tx_fee: old.tx_fee,
+            txid: old.txid,
+            psbt: old.psbt,
+            disclosure: old.disclosure,
+            notes: old.notes,
+        }
+    }
+}
+
+/// Upgrades strict-encoded operation from the layout used before format
+/// version 4
+pub(crate) fn upgrade_operation(data: &[u8]) -> Result<Vec<u8>, String> {
+    let old =
+        OperationV3::strict_deserialize(data).map_err(|err| err.to_string())?;
+    Operation::from(old)
+        .strict_serialize()
+        .map_err(|err| err.to_string())
+}
+
+/// Layout of `Contract` data before format version 3
+#[derive(Clone, StrictEncode, StrictDecode)]
+struct ContractV2 {
+    id: ContractId,
+    name: String,
+    chain: Chain,
+    policy: Policy,
+    created_at: NaiveDateTime,
+    state: State,
+    blinding_factors: BTreeMap<OutpointHash, OutpointReveal>,
+    sent_invoices: Vec<Invoice>,
+    unpaid_invoices: BTreeMap<Invoice, NaiveDateTime>,
+    p2c_tweaks: BTreeSet<TweakedOutput>,
+    operations: Vec<OperationV3>,
+}
+
+/// Layout of `Contract` data in format version 3. Labels are the last field
+/// of the contract data, so they follow the data of version 2.
+#[derive(Clone, StrictEncode, StrictDecode)]
+struct ContractV3 {
+    contract: ContractV2,
+    labels: BTreeMap<LabelRef, String>,
+}
+
+/// `Contract` data upgraded to format version 4
+#[derive(Clone, StrictEncode)]
+struct ContractV4 {
+    id: ContractId,
+    name: String,
+    chain: Chain,
+    policy: Policy,
+    created_at: NaiveDateTime,
+    state: State,
+    blinding_factors: BTreeMap<OutpointHash, OutpointReveal>,
+    sent_invoices: Vec<Invoice>,
+    unpaid_invoices: BTreeMap<Invoice, NaiveDateTime>,
+    p2c_tweaks: BTreeSet<TweakedOutput>,
+    operations: Vec<Operation>,
+    labels: BTreeMap<LabelRef, String>,
+}
+
+impl From<ContractV3> for ContractV4 {
+    fn from(old: ContractV3) -> Self {
+        let contract = old.contract;
+        ContractV4 {
+            id: contract.id,
+            name: contract.name,
+            chain: contract.chain,
+            policy: contract.policy,
+            created_at: contract.created_at,
+            state: contract.state,
+            blinding_factors: contract.blinding_factors,
+            sent_invoices: contract.sent_invoices,
+            unpaid_invoices: contract.unpaid_invoices,
+            p2c_tweaks: contract.p2c_tweaks,
+            operations: contract
+                .operations
+                .into_iter()
+                .map(Operation::from)
+                .collect(),
+            labels: old.labels,
+        }
+    }
+}
+
+/// Layout of `ArchivedContract` data in format version 2
+#[derive(Clone, StrictDecode)]
+struct ArchivedContractV2 {
+    contract: ContractV2,
+    archived_at: NaiveDateTime,
+}
+
+/// Layout of `ArchivedContract` data in format version 3
+#[derive(Clone, StrictEncode, StrictDecode)]
+struct ArchivedContractV3 {
+    contract: ContractV3,
+    archived_at: NaiveDateTime,
+}
+
+/// `ArchivedContract` data upgraded to format version 4
+#[derive(Clone, StrictEncode)]
+struct ArchivedContractV4 {
+    contract: ContractV4,
+    archived_at: NaiveDateTime,
+}
+
+/// Layout of `Citadel` data before format version 1
+#[derive(StrictDecode)]
+struct CitadelV0 {
+    contracts: BTreeMap<ContractId, ContractV2>,
+    identities: BTreeMap<rgb::ContractId, IdentityInfo>,
+    assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+}
+
+/// Layout of `Citadel` data in format version 1
+#[derive(StrictEncode, StrictDecode)]
+struct CitadelV1 {
+    contracts: BTreeMap<ContractId, ContractV2>,
+    identities: BTreeMap<String, IdentityInfo>,
+    assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+    signers: BTreeMap<String, SignerAccountInfo>,
+}
+
+/// Layout of `Citadel` data in format version 2
+#[derive(StrictEncode, StrictDecode)]
+struct CitadelV2 {
+    contracts: BTreeMap<ContractId, ContractV2>,
+    identities: BTreeMap<String, IdentityInfo>,
+    assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+    signers: BTreeMap<String, SignerAccountInfo>,
+    archived: BTreeMap<ContractId, ArchivedContractV2>,
+}
+
+/// Layout of `Citadel` data in format version 3
+#[derive(StrictEncode, StrictDecode)]
+struct CitadelV3 {
+    contracts: BTreeMap<ContractId, ContractV3>,
+    identities: BTreeMap<String, IdentityInfo>,
+    assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+    signers: BTreeMap<String, SignerAccountInfo>,
+    archived: BTreeMap<ContractId, ArchivedContractV3>,
+}
+
+/// `Citadel` data upgraded to format version 4
+#[derive(StrictEncode)]
+struct CitadelV4 {
+    contracts: BTreeMap<ContractId, ContractV4>,
+    identities: BTreeMap<String, IdentityInfo>,
+    assets: BTreeMap<rgb::ContractId, rgb20::Asset>,
+    signers: BTreeMap<String, SignerAccountInfo>,
+    archived: BTreeMap<ContractId, ArchivedContractV4>,
+}
+
+fn v1_index_identities(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = CitadelV0::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let citadel = CitadelV1 {
+                contracts: old.contracts,
+                identities: old
+                    .identities
+                    .into_iter()
+                    .map(|(_, identity)| (identity.id(), identity))
+                    .collect(),
+                assets: old.assets,
+                signers: none!(),
+            };
+            citadel
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        Document::Structured(mut value) => {
+            let map = value
+                .as_mapping_mut()
+                .ok_or_else(|| s!("wallet data must be a structure"))?;
+            let identities_key = serde_yaml::Value::from("identities");
+            let identities = map
+                .get(&identities_key)
+                .and_then(serde_yaml::Value::as_mapping)
+                .cloned()
+                .unwrap_or_default();
+            let mut indexed = serde_yaml::Mapping::new();
+            for (_, identity) in identities {
+                let info: IdentityInfo =
+                    serde_yaml::from_value(identity.clone())
+                        .map_err(|err| err.to_string())?;
+                indexed.insert(info.id().into(), identity);
+            }
+            map.insert(identities_key, serde_yaml::Value::Mapping(indexed));
+            let signers_key = serde_yaml::Value::from("signers");
+            if !map.contains_key(&signers_key) {
+                map.insert(signers_key, serde_yaml::Value::Mapping(none!()));
+            }
+            Ok(Document::Structured(value))
+        }
+    }
+}
+
+fn v2_add_archive(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = CitadelV1::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let citadel = CitadelV2 {
+                contracts: old.contracts,
+                identities: old.identities,
+                assets: old.assets,
+                signers: old.signers,
+                archived: none!(),
+            };
+            citadel
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        Document::Structured(mut value) => {
+            let map = value
+                .as_mapping_mut()
+                .ok_or_else(|| s!("wallet data must be a structure"))?;
+            let archived_key = serde_yaml::Value::from("archived");
+            if !map.contains_key(&archived_key) {
+                map.insert(archived_key, serde_yaml::Value::Mapping(none!()));
+            }
+            Ok(Document::Structured(value))
+        }
+    }
+}
+
+fn v3_add_labels(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = CitadelV2::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let citadel = CitadelV3 {
+                contracts: old
+                    .contracts
+                    .into_iter()
+                    .map(|(id, contract)| {
+                        (
+                            id,
+                            ContractV3 {
+                                contract,
+                                labels: none!(),
+                            },
+                        )
+                    })
+                    .collect(),
+                identities: old.identities,
+                assets: old.assets,
+                signers: old.signers,
+                archived: old
+                    .archived
+                    .into_iter()
+                    .map(|(id, archived)| {
+                        (
+                            id,
+                            ArchivedContractV3 {
+                                contract: ContractV3 {
+                                    contract: archived.contract,
+                                    labels: none!(),
+                                },
+                                archived_at: archived.archived_at,
+                            },
+                        )
+                    })
+                    .collect(),
+            };
+            citadel
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        // Missing labels are defaulted on deserialization
+        document @ Document::Structured(_) => Ok(document),
+    }
+}
+
+fn v4_add_output_branches(document: Document) -> Result<Document, String> {
+    match document {
+        Document::Strict(data) => {
+            let old = CitadelV3::strict_deserialize(data)
+                .map_err(|err| err.to_string())?;
+            let citadel = CitadelV4 {
+                contracts: old
+                    .contracts
+                    .into_iter()
+                    .map(|(id, contract)| (id, ContractV4::from(contract)))
+                    .collect(),
+                identities: old.identities,
+                assets: old.assets,
+                signers: old.signers,
+                archived: old
+                    .archived
+                    .into_iter()
+                    .map(|(id, archived)| {
+                        (
+                            id,
+                            ArchivedContractV4 {
+                                contract: ContractV4::from(archived.contract),
+                                archived_at: archived.archived_at,
+                            },
+                        )
+                    })
+                    .collect(),
+            };
+            citadel
+                .strict_serialize()
+                .map(Document::Strict)
+                .map_err(|err| err.to_string())
+        }
+        Document::Structured(mut value) => {
+            let map = value
+                .as_mapping_mut()
+                .ok_or_else(|| s!("wallet data must be a structure"))?;
+            let contracts = map
+                .get_mut(&"contracts".into())
+                .and_then(serde_yaml::Value::as_mapping_mut)
+                .into_iter()
+                .flat_map(|contracts| contracts.iter_mut())
+                .map(|(_, contract)| contract);
+            for contract in contracts {
+                add_output_branches(contract);
+            }
+            let archived = map
+                .get_mut(&"archived".into())
+                .and_then(serde_yaml::Value::as_mapping_mut)
+                .into_iter()
+                .flat_map(|archived| archived.iter_mut())
+                .filter_map(|(_, archived)| {
+                    archived.as_mapping_mut()?.get_mut(&"contract".into())
+                });
+            for contract in archived {
+                add_output_branches(contract);
+            }
+            Ok(Document::Structured(value))
+        }
+    }
+}
+
+/// Replaces output derivation indexes of the outgoing operations of the
+/// structured contract data with (branch, index) pairs
+fn add_output_branches(contract: &mut serde_yaml::Value) {
+    let operations = contract
+        .as_mapping_mut()
+        .and_then(|contract| contract.get_mut(&"operations".into()))
+        .and_then(serde_yaml::Value::as_sequence_mut)
+        .into_iter()
+        .flatten();
+    for operation in operations {
+        let indexes = operation
+            .as_mapping_mut()
+            .and_then(|operation| operation.get_mut(&"direction".into()))
+            .and_then(serde_yaml::Value::as_mapping_mut)
+            .and_then(|direction| direction.get_mut(&"outcoming".into()))
+            .and_then(serde_yaml::Value::as_mapping_mut)
+            .and_then(|outcoming| {
+                outcoming.get_mut(&"output_derivation_indexes".into())
+            })
+            .and_then(serde_yaml::Value::as_sequence_mut);
+        if let Some(indexes) = indexes {
+            for index in indexes.iter_mut() {
+                *index = serde_yaml::Value::Sequence(vec![
+                    Branch::External.to_string().into(),
+                    index.clone(),
+                ]);
+            }
+        }
+    }
+}
@@ -13,32 +13,88 @@
 
 //! Storage drivers
 
+#[cfg(feature = "runtime")]
+pub mod encrypted;
 pub mod file;
+pub mod memory;
+pub(crate) mod migrations;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
+#[cfg(feature = "runtime")]
+pub use encrypted::EncryptedDriver;
 pub use file::{FileConfig, FileDriver};
+pub use memory::MemoryDriver;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteDriver;
 
 // -----------------------------------------------------------------------------
 
+use std::collections::BTreeMap;
+
+use bitcoin::Txid;
 use bp::seals::OutpointReveal;
 use invoice::Invoice;
+use wallet::descriptors;
 
+use crate::migration;
 use crate::model::{
-    self, Contract, ContractId, Operation, Policy, TweakedOutput,
+    self, ArchivedContract, Citadel, Contract, ContractId, LabelRef, Operation,
+    Policy, TweakedOutput,
 };
 use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
 
 pub trait Driver {
+    /// Detects whether the storage data are encrypted and are not accessible
+    /// until the storage gets unlocked with [`Driver::unlock`]
+    fn is_locked(&self) -> bool {
+        false
+    }
+
+    /// Creates new empty encrypted storage protected with the passphrase and
+    /// leaves it unlocked
+    fn init(&mut self, _passphrase: &str) -> Result<(), Error> {
+        Err(Error::NotEncrypted)
+    }
+
+    fn unlock(&mut self, _passphrase: &str) -> Result<(), Error> {
+        Err(Error::NotEncrypted)
+    }
+
+    fn lock(&mut self) -> Result<(), Error> {
+        Err(Error::NotEncrypted)
+    }
+
+    fn change_passphrase(
+        &mut self,
+        _old_passphrase: &str,
+        _new_passphrase: &str,
+    ) -> Result<(), Error> {
+        Err(Error::NotEncrypted)
+    }
+
     fn contracts(&self) -> Result<Vec<Contract>, Error>;
     fn contract_ref(&self, contract_id: ContractId)
         -> Result<&Contract, Error>;
+    /// Adds new contract or, if the contract is already known, updates its
+    /// name keeping all of the contract data. Returns resulting contract.
     fn add_contract(&mut self, contract: Contract) -> Result<Contract, Error>;
     fn rename_contract(
         &mut self,
         contract_id: ContractId,
         new_name: String,
     ) -> Result<(), Error>;
-    fn delete_contract(&mut self, contract_id: ContractId)
-        -> Result<(), Error>;
+    /// Moves contract with all of its data into the archive, from which it
+    /// can be restored with [`Driver::restore_contract`]
+    fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<ArchivedContract, Error>;
+    fn archived_contracts(&self) -> Result<Vec<ArchivedContract>, Error>;
+    fn restore_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Contract, Error>;
 
     fn policy(&self, contract_id: ContractId) -> Result<&Policy, Error>;
 
@@ -61,20 +117,66 @@ pub trait Driver {
         operation: Operation,
     ) -> Result<(), Error>;
 
+    /// Updates heights of the operations which transactions are mined (or
+    /// become unconfirmed) according to the given transaction heights.
+    /// Returns the number of updated operations.
+    fn mine_operations(
+        &mut self,
+        contract_id: ContractId,
+        heights: BTreeMap<Txid, u32>,
+    ) -> Result<usize, Error>;
+
+    /// Marks operations mined at or above the given block height as not
+    /// mined, which happens after a chain reorganization. Returns the number
+    /// of updated operations.
+    fn unmine_operations(
+        &mut self,
+        contract_id: ContractId,
+        height: u32,
+    ) -> Result<usize, Error>;
+
+    /// Assigns label to the contract-related object or, if `label` is
+    /// `None`, removes the existing label. Returns previous label.
+    fn set_label(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Result<Option<String>, Error>;
+
     fn history(&self, contract_id: ContractId)
         -> Result<Vec<Operation>, Error>;
 
     fn signers(&self) -> Result<Vec<SignerAccountInfo>, Error>;
+    /// Adds new signer account or, if the account with the same key and title
+    /// is already known, merges its used index ranges. Returns resulting
+    /// account information.
     fn add_signer(
         &mut self,
         account: SignerAccountInfo,
     ) -> Result<SignerAccountInfo, Error>;
+    fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, Error>;
 
     fn identities(&self) -> Result<Vec<IdentityInfo>, Error>;
+    /// Adds new identity or, if the identity with the same key and name is
+    /// already known, merges its known index ranges. Returns resulting
+    /// identity information.
     fn add_identity(
         &mut self,
         identity: IdentityInfo,
     ) -> Result<IdentityInfo, Error>;
+    fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<IdentityInfo, Error>;
+
+    /// Returns copy of all wallet data kept by the storage
+    fn dump(&self) -> Result<Citadel, Error>;
+    /// Replaces all wallet data kept by the storage with the provided data
+    fn restore(&mut self, data: Citadel) -> Result<(), Error>;
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
@@ -96,15 +198,59 @@ pub enum Error {
     /// Contract with the given id {0} is not found
     ContractNotFound(model::ContractId),
 
-    /// Identity with the provided id {0} already exists
-    #[from]
-    IdentityExists(rgb::ContractId),
+    /// Archived contract with the given id {0} is not found
+    ArchivedContractNotFound(model::ContractId),
+
+    /// Contract with the given id {0} is archived; restore it instead of
+    /// creating a new one
+    ContractArchived(model::ContractId),
+
+    /// Label for {0} is not found
+    LabelNotFound(String),
+
+    /// Identity with the key {0} is already registered under a different
+    /// name
+    IdentityExists(String),
+
+    /// Identity with the key {0} is not found
+    IdentityNotFound(String),
+
+    /// Signer account with the key {0} is already registered under a
+    /// different title
+    SignerExists(String),
+
+    /// Signer account with the key {0} is not found
+    SignerNotFound(String),
+
+    /// storage is locked; unlock it with the passphrase first
+    Locked,
+
+    /// the provided passphrase does not match the one used to encrypt the
+    /// storage
+    WrongPassphrase,
+
+    /// storage is not encrypted and does not support locking
+    NotEncrypted,
+
+    /// encrypted storage does not exist yet; initialize it with a passphrase
+    /// first
+    NotInitialized,
+
+    /// encrypted storage is already initialized
+    AlreadyInitialized,
+
+    /// storage encryption error: {0}
+    Encryption(String),
 
     /// Error in strict data encoding: {0}
     /// Make sure that the storage is not broken.
     #[from]
     StrictEncoding(strict_encoding::Error),
 
+    /// {0}
+    #[from]
+    Migration(migration::Error),
+
     /// error in YAML data encoding: {0}
     YamlEncoding(String),
 
@@ -116,6 +262,10 @@ pub enum Error {
     #[from(toml::de::Error)]
     #[from(toml::ser::Error)]
     TomlEncoding,
+
+    /// SQLite database error: {0}
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
 }
 
 impl From<serde_yaml::Error> for Error {
@@ -123,3 +273,176 @@ impl From<serde_yaml::Error> for Error {
         Error::YamlEncoding(err.to_string())
     }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    use microservices::FileFormat;
+
+    use super::*;
+    use crate::test_utils::{contract, operation, temp_dir};
+
+    fn contract_lifecycle(storage: &mut dyn Driver) {
+        let contract = storage.add_contract(contract(1, "savings")).unwrap();
+        let id = *contract.id();
+        assert_eq!(storage.contracts().unwrap(), vec![contract.clone()]);
+        assert_eq!(storage.policy(id).unwrap(), contract.policy());
+
+        storage.rename_contract(id, s!("spending")).unwrap();
+        assert_eq!(storage.contract_ref(id).unwrap().name, "spending");
+
+        let archived = storage.archive_contract(id).unwrap();
+        assert_eq!(archived.contract().name, "spending");
+        assert!(storage.contracts().unwrap().is_empty());
+        assert_eq!(
+            storage.contract_ref(id).err(),
+            Some(Error::ContractNotFound(id))
+        );
+        assert_eq!(storage.archived_contracts().unwrap(), vec![archived]);
+        assert_eq!(
+            storage.add_contract(contract(1, "savings")).err(),
+            Some(Error::ContractArchived(id))
+        );
+
+        let restored = storage.restore_contract(id).unwrap();
+        assert_eq!(restored.name, "spending");
+        assert_eq!(storage.contracts().unwrap(), vec![restored]);
+        assert!(storage.archived_contracts().unwrap().is_empty());
+        assert_eq!(
+            storage.restore_contract(id).err(),
+            Some(Error::ArchivedContractNotFound(id))
+        );
+    }
+
+    fn labels(storage: &mut dyn Driver) {
+        let id = *storage.add_contract(contract(2, "labeled")).unwrap().id();
+        let tx = LabelRef::Tx(Txid::hash(b"tx"));
+
+        assert_eq!(
+            storage.set_label(id, tx.clone(), Some(s!("rent"))).unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .set_label(id, tx.clone(), Some(s!("salary")))
+                .unwrap(),
+            Some(s!("rent"))
+        );
+        storage
+            .set_label(id, LabelRef::Contract, Some(s!("main")))
+            .unwrap();
+        assert_eq!(
+            storage.contract_ref(id).unwrap().data().labels(),
+            &bmap! {
+                LabelRef::Contract => s!("main"),
+                tx.clone() => s!("salary")
+            }
+        );
+
+        assert_eq!(
+            storage.set_label(id, tx.clone(), None).unwrap(),
+            Some(s!("salary"))
+        );
+        assert_eq!(storage.set_label(id, tx, None).unwrap(), None);
+        assert_eq!(storage.contract_ref(id).unwrap().data().labels().len(), 1);
+
+        let unknown = *contract(3, "unknown").id();
+        assert_eq!(
+            storage.set_label(unknown, LabelRef::Contract, None).err(),
+            Some(Error::ContractNotFound(unknown))
+        );
+    }
+
+    fn re_adding_keeps_data(storage: &mut dyn Driver) {
+        let id = *storage.add_contract(contract(5, "savings")).unwrap().id();
+        storage.register_operation(id, operation(1)).unwrap();
+        storage
+            .set_label(id, LabelRef::Contract, Some(s!("daily")))
+            .unwrap();
+        let data = storage.contract_ref(id).unwrap().data().clone();
+
+        let readded = storage.add_contract(contract(5, "renamed")).unwrap();
+        assert_eq!(readded.name, "renamed");
+        assert_eq!(readded.data(), &data);
+        assert_eq!(storage.contract_ref(id).unwrap(), &readded);
+        assert_eq!(storage.history(id).unwrap().len(), 1);
+        storage.archive_contract(id).unwrap();
+    }
+
+    fn dump_and_restore(storage: &mut dyn Driver, restored: &mut dyn Driver) {
+        let id = *storage.add_contract(contract(4, "old")).unwrap().id();
+        storage.archive_contract(id).unwrap();
+
+        let dump = storage.dump().unwrap();
+        restored.restore(dump.clone()).unwrap();
+        assert_eq!(restored.dump().unwrap(), dump);
+        assert_eq!(restored.contracts().unwrap().len(), 2);
+        assert_eq!(restored.archived_contracts().unwrap().len(), 2);
+    }
+
+    /// Runs the same driver contract checks against each storage driver;
+    /// `restored` must be an empty storage of the same type
+    fn check_driver(storage: &mut dyn Driver, restored: &mut dyn Driver) {
+        contract_lifecycle(storage);
+        labels(storage);
+        re_adding_keeps_data(storage);
+        dump_and_restore(storage, restored);
+    }
+
+    fn file_config(name: &str) -> FileConfig {
+        FileConfig {
+            location: temp_dir(name).to_string_lossy().to_string(),
+            format: FileFormat::StrictEncode,
+        }
+    }
+
+    #[test]
+    fn memory_driver() {
+        check_driver(&mut MemoryDriver::new(), &mut MemoryDriver::new());
+    }
+
+    #[test]
+    fn file_driver() {
+        let config = file_config("storage-file");
+        let restored = file_config("storage-file-restored");
+        check_driver(
+            &mut FileDriver::with(config.clone()).unwrap(),
+            &mut FileDriver::with(restored.clone()).unwrap(),
+        );
+        std::fs::remove_dir_all(config.location).unwrap();
+        std::fs::remove_dir_all(restored.location).unwrap();
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn encrypted_driver() {
+        let config = file_config("storage-encrypted");
+        let restored = file_config("storage-encrypted-restored");
+        let mut storage = EncryptedDriver::with(config.clone()).unwrap();
+        storage.init("pass").unwrap();
+        let mut empty = EncryptedDriver::with(restored.clone()).unwrap();
+        empty.init("pass").unwrap();
+        check_driver(&mut storage, &mut empty);
+        std::fs::remove_dir_all(config.location).unwrap();
+        std::fs::remove_dir_all(restored.location).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_driver() {
+        let dir = temp_dir("storage-sqlite");
+        check_driver(
+            &mut SqliteDriver::with(dir.join("wallet.db")).unwrap(),
+            &mut SqliteDriver::with(dir.join("restored.db")).unwrap(),
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,826 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! SQLite storage driver.
+//!
+//! Each piece of contract data is kept in its own table, so adding an
+//! operation, invoice or tweak results in a single row insert instead of
+//! rewriting all of the wallet data. The complete data set is mirrored in
+//! memory on load, since the driver API provides references to the contract
+//! data.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bitcoin::Txid;
+use bp::seals::OutpointReveal;
+use chrono::NaiveDateTime;
+use invoice::Invoice;
+use rusqlite::{params, Connection};
+use strict_encoding::{StrictDecode, StrictEncode};
+use wallet::descriptors;
+
+use super::{migrations, Driver, Error};
+use crate::migration::{SqlMigration, SqlSchema};
+use crate::model::{
+    ArchivedContract, Citadel, Contract, ContractId, ContractMeta, LabelRef,
+    Operation, Policy, TweakedOutput,
+};
+use crate::rpc::message::{IdentityInfo, SignerAccountInfo};
+
+const SCHEMA_V1: &str = "
+CREATE TABLE IF NOT EXISTS contracts (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    meta BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blinding_factors (
+    contract_id TEXT NOT NULL REFERENCES contracts(id) ON DELETE CASCADE,
+    reveal BLOB NOT NULL,
+    PRIMARY KEY (contract_id, reveal)
+);
+
+CREATE TABLE IF NOT EXISTS invoices (
+    contract_id TEXT NOT NULL REFERENCES contracts(id) ON DELETE CASCADE,
+    invoice TEXT NOT NULL,
+    PRIMARY KEY (contract_id, invoice)
+);
+
+CREATE TABLE IF NOT EXISTS p2c_tweaks (
+    contract_id TEXT NOT NULL REFERENCES contracts(id) ON DELETE CASCADE,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    tweak BLOB NOT NULL,
+    PRIMARY KEY (contract_id, txid, vout)
+);
+
+CREATE TABLE IF NOT EXISTS operations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    contract_id TEXT NOT NULL REFERENCES contracts(id) ON DELETE CASCADE,
+    txid TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    operation BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS operations_by_contract
+    ON operations (contract_id, created_at);
+
+CREATE TABLE IF NOT EXISTS signers (
+    id TEXT PRIMARY KEY NOT NULL,
+    account BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS identities (
+    id TEXT PRIMARY KEY NOT NULL,
+    identity BLOB NOT NULL
+);
+";
+
+const SCHEMA_V2: &str = "
+ALTER TABLE contracts ADD COLUMN archived_at INTEGER;
+";
+
+const SCHEMA_V3: &str = "
+CREATE TABLE IF NOT EXISTS labels (
+    contract_id TEXT NOT NULL REFERENCES contracts(id) ON DELETE CASCADE,
+    reference TEXT NOT NULL,
+    label TEXT NOT NULL,
+    PRIMARY KEY (contract_id, reference)
+);
+";
+
+const SCHEMA_V5: &str = "
+CREATE TABLE IF NOT EXISTS assets (
+    id TEXT PRIMARY KEY NOT NULL,
+    asset BLOB NOT NULL
+);
+";
+
+static SCHEMA: SqlSchema = SqlSchema {
+    name: "wallet",
+    migrations: &[
+        SqlMigration {
+            description: "tables for contracts, operations, invoices, p2c \
+                          tweaks, signers and identities",
+            sql: SCHEMA_V1,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "archival time of contracts removed by the user",
+            sql: SCHEMA_V2,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "labels for contracts, transactions, addresses and \
+                          outputs",
+            sql: SCHEMA_V3,
+            upgrade: None,
+        },
+        SqlMigration {
+            description: "derivation branch is added to the output derivation \
+                          indexes of the operations",
+            sql: "",
+            upgrade: Some(upgrade_operations),
+        },
+        SqlMigration {
+            description: "table for RGB assets",
+            sql: SCHEMA_V5,
+            upgrade: None,
+        },
+    ],
+};
+
+/// Re-encodes operations stored before the derivation branches were added
+/// to their output derivation indexes
+fn upgrade_operations(tx: &rusqlite::Transaction) -> Result<(), String> {
+    let mut stmt = tx
+        .prepare("SELECT id, operation FROM operations")
+        .map_err(|err| err.to_string())?;
+    let operations = stmt
+        .query_map(params![], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| err.to_string())?;
+    for (id, operation) in operations {
+        tx.execute(
+            "UPDATE operations SET operation = ?2 WHERE id = ?1",
+            params![id, migrations::upgrade_operation(&operation)?],
+        )
+        .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+pub struct SqliteDriver {
+    db: Connection,
+    filename: PathBuf,
+    data: Citadel,
+}
+
+impl SqliteDriver {
+    pub fn with(filename: PathBuf) -> Result<Self, Error> {
+        info!("Initializing SQLite driver for data in {:?}", filename);
+        if let Some(dir) = filename.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut db = Connection::open(&filename)?;
+        db.execute_batch("PRAGMA foreign_keys = ON;")?;
+        SCHEMA.apply(&mut db, &filename)?;
+        let mut me = SqliteDriver {
+            db,
+            filename,
+            data: none!(),
+        };
+        me.load()?;
+        Ok(me)
+    }
+
+    /// Stores updated contract operations, replacing the contract data kept
+    /// in memory once they are written. Returns the number of operations.
+    fn update_operations(
+        &mut self,
+        contract: Contract,
+        operations: Vec<Operation>,
+    ) -> Result<usize, Error> {
+        let contract_id = *contract.id();
+        let tx = self.db.transaction()?;
+        for operation in &operations {
+            tx.execute(
+                "UPDATE operations SET height = ?4, operation = ?5 \
+                 WHERE contract_id = ?1 AND txid = ?2 AND created_at = ?3",
+                params![
+                    contract_id.to_string(),
+                    operation.txid.to_string(),
+                    operation.created_at.timestamp(),
+                    operation.height,
+                    operation.strict_serialize()?
+                ],
+            )?;
+        }
+        tx.commit()?;
+        *self.data.contract_mut(contract_id)? = contract;
+        Ok(operations.len())
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        debug!("Loading data from `{:?}`", self.filename);
+        let mut data = Citadel::default();
+
+        // Archived contracts are kept in the same table and are moved into
+        // the archive once all of their data are loaded
+        let mut archived = vec![];
+        let mut stmt = self.db.prepare(
+            "SELECT name, meta, archived_at FROM contracts ORDER BY rowid",
+        )?;
+        let rows = stmt
+            .query_map(params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<(String, Vec<u8>, Option<i64>)>, _>>()?;
+        drop(stmt);
+        for (name, meta, archived_at) in rows {
+            let mut contract =
+                Contract::from(ContractMeta::strict_deserialize(meta)?);
+            contract.name = name;
+            if let Some(timestamp) = archived_at {
+                archived.push((
+                    *contract.id(),
+                    NaiveDateTime::from_timestamp(timestamp, 0),
+                ));
+            }
+            data.contracts.insert(*contract.id(), contract);
+        }
+
+        for (contract_id, invoice) in self.rows::<String, String>(
+            "SELECT contract_id, invoice FROM invoices ORDER BY rowid",
+        )? {
+            let invoice = Invoice::from_str(&invoice).map_err(|err| {
+                Error::Sqlite(format!("broken invoice data: {}", err))
+            })?;
+            data.contract_mut(parse_id(&contract_id)?)?
+                .add_invoice(invoice);
+        }
+
+        for (contract_id, reveal) in self.rows::<String, Vec<u8>>(
+            "SELECT contract_id, reveal FROM blinding_factors ORDER BY rowid",
+        )? {
+            data.contract_mut(parse_id(&contract_id)?)?
+                .add_blinding(OutpointReveal::strict_deserialize(reveal)?);
+        }
+
+        for (contract_id, tweak) in self.rows::<String, Vec<u8>>(
+            "SELECT contract_id, tweak FROM p2c_tweaks ORDER BY rowid",
+        )? {
+            data.contract_mut(parse_id(&contract_id)?)?
+                .add_p2c_tweak(TweakedOutput::strict_deserialize(tweak)?);
+        }
+
+        for (contract_id, operation) in self.rows::<String, Vec<u8>>(
+            "SELECT contract_id, operation FROM operations ORDER BY id",
+        )? {
+            data.contract_mut(parse_id(&contract_id)?)?
+                .add_operation(Operation::strict_deserialize(operation)?);
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT contract_id, reference, label FROM labels")?;
+        let rows = stmt
+            .query_map(params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<(String, String, String)>, _>>()?;
+        drop(stmt);
+        for (contract_id, reference, label) in rows {
+            let reference = LabelRef::from_str(&reference).map_err(|err| {
+                Error::Sqlite(format!("broken label data: {}", err))
+            })?;
+            data.contract_mut(parse_id(&contract_id)?)?
+                .set_label(reference, Some(label));
+        }
+
+        for (id, account) in
+            self.rows::<String, Vec<u8>>("SELECT id, account FROM signers")?
+        {
+            data.signers
+                .insert(id, SignerAccountInfo::strict_deserialize(account)?);
+        }
+
+        for (id, identity) in
+            self.rows::<String, Vec<u8>>("SELECT id, identity FROM identities")?
+        {
+            data.identities
+                .insert(id, IdentityInfo::strict_deserialize(identity)?);
+        }
+
+        for (id, asset) in
+            self.rows::<String, Vec<u8>>("SELECT id, asset FROM assets")?
+        {
+            let id = rgb::ContractId::from_str(&id).map_err(|err| {
+                Error::Sqlite(format!("broken asset id {}: {}", id, err))
+            })?;
+            data.assets
+                .insert(id, rgb20::Asset::strict_deserialize(asset)?);
+        }
+
+        for (contract_id, archived_at) in archived {
+            let contract = data
+                .contracts
+                .remove(&contract_id)
+                .expect("archived contract was just loaded");
+            data.archived.insert(
+                contract_id,
+                ArchivedContract::with_time(contract, archived_at),
+            );
+        }
+
+        self.data = data;
+        trace!("Data loaded from SQLite database");
+        Ok(())
+    }
+
+    fn rows<A, B>(&self, sql: &str) -> Result<Vec<(A, B)>, Error>
+    where
+        A: rusqlite::types::FromSql,
+        B: rusqlite::types::FromSql,
+    {
+        let mut stmt = self.db.prepare(sql)?;
+        let rows = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+}
+
+fn parse_id(contract_id: &str) -> Result<ContractId, Error> {
+    ContractId::from_str(contract_id).map_err(|err| {
+        Error::Sqlite(format!("broken contract id {}: {}", contract_id, err))
+    })
+}
+
+impl Driver for SqliteDriver {
+    fn contracts(&self) -> Result<Vec<Contract>, Error> {
+        Ok(self.data.contracts.values().cloned().collect())
+    }
+
+    fn contract_ref(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<&Contract, Error> {
+        self.data.contract_ref(contract_id)
+    }
+
+    fn add_contract(&mut self, contract: Contract) -> Result<Contract, Error> {
+        if self.data.archived.contains_key(contract.id()) {
+            return Err(Error::ContractArchived(*contract.id()));
+        }
+        let meta = ContractMeta::from(contract.clone()).strict_serialize()?;
+        // Replacing the row would cascade the removal to all of the contract
+        // data, so only the contract name and metadata are updated for the
+        // existing contract
+        self.db.execute(
+            "INSERT INTO contracts (id, name, meta) VALUES (?1, ?2, ?3) \
+             ON CONFLICT(id) DO UPDATE SET name = ?2, meta = ?3",
+            params![contract.id().to_string(), contract.name, meta],
+        )?;
+        self.data.add_contract(contract)
+    }
+
+    fn rename_contract(
+        &mut self,
+        contract_id: ContractId,
+        new_name: String,
+    ) -> Result<(), Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        self.db.execute(
+            "UPDATE contracts SET name = ?1 WHERE id = ?2",
+            params![new_name, contract_id.to_string()],
+        )?;
+        contract.name = new_name;
+        Ok(())
+    }
+
+    fn archive_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<ArchivedContract, Error> {
+        let archived = self.data.archive_contract(contract_id)?.clone();
+        self.db
+            .execute(
+                "UPDATE contracts SET archived_at = ?1 WHERE id = ?2",
+                params![
+                    archived.archived_at().timestamp(),
+                    contract_id.to_string()
+                ],
+            )
+            .map_err(|err| {
+                self.data.restore_contract(contract_id).ok();
+                err
+            })?;
+        Ok(archived)
+    }
+
+    fn archived_contracts(&self) -> Result<Vec<ArchivedContract>, Error> {
+        Ok(self.data.archived.values().cloned().collect())
+    }
+
+    fn restore_contract(
+        &mut self,
+        contract_id: ContractId,
+    ) -> Result<Contract, Error> {
+        if !self.data.archived.contains_key(&contract_id) {
+            return Err(Error::ArchivedContractNotFound(contract_id));
+        }
+        self.db.execute(
+            "UPDATE contracts SET archived_at = NULL WHERE id = ?1",
+            params![contract_id.to_string()],
+        )?;
+        Ok(self.data.restore_contract(contract_id)?.clone())
+    }
+
+    fn policy(&self, contract_id: ContractId) -> Result<&Policy, Error> {
+        self.data.contract_ref(contract_id).map(Contract::policy)
+    }
+
+    fn add_invoice(
+        &mut self,
+        contract_id: ContractId,
+        invoice: Invoice,
+        reveal_info: Vec<OutpointReveal>,
+    ) -> Result<(), Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        let tx = self.db.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO invoices (contract_id, invoice) \
+             VALUES (?1, ?2)",
+            params![contract_id.to_string(), invoice.to_string()],
+        )?;
+        for reveal in &reveal_info {
+            tx.execute(
+                "INSERT OR IGNORE INTO blinding_factors \
+                 (contract_id, reveal) VALUES (?1, ?2)",
+                params![contract_id.to_string(), reveal.strict_serialize()?],
+            )?;
+        }
+        tx.commit()?;
+        contract.add_invoice(invoice);
+        for reveal in reveal_info {
+            contract.add_blinding(reveal);
+        }
+        Ok(())
+    }
+
+    fn add_p2c_tweak(
+        &mut self,
+        contract_id: ContractId,
+        tweak: TweakedOutput,
+    ) -> Result<(), Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        self.db.execute(
+            "INSERT OR REPLACE INTO p2c_tweaks \
+             (contract_id, txid, vout, tweak) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                contract_id.to_string(),
+                tweak.outpoint.txid.to_string(),
+                tweak.outpoint.vout,
+                tweak.strict_serialize()?
+            ],
+        )?;
+        contract.add_p2c_tweak(tweak);
+        Ok(())
+    }
+
+    fn register_operation(
+        &mut self,
+        contract_id: ContractId,
+        operation: Operation,
+    ) -> Result<(), Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        self.db.execute(
+            "INSERT INTO operations \
+             (contract_id, txid, created_at, height, operation) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                contract_id.to_string(),
+                operation.txid.to_string(),
+                operation.created_at.timestamp(),
+                operation.height,
+                operation.strict_serialize()?
+            ],
+        )?;
+        contract.add_operation(operation);
+        Ok(())
+    }
+
+    fn mine_operations(
+        &mut self,
+        contract_id: ContractId,
+        heights: BTreeMap<Txid, u32>,
+    ) -> Result<usize, Error> {
+        let mut contract = self.data.contract_ref(contract_id)?.clone();
+        let operations = contract.mine_operations(&heights);
+        self.update_operations(contract, operations)
+    }
+
+    fn unmine_operations(
+        &mut self,
+        contract_id: ContractId,
+        height: u32,
+    ) -> Result<usize, Error> {
+        let mut contract = self.data.contract_ref(contract_id)?.clone();
+        let operations = contract.unmine_operations(height);
+        self.update_operations(contract, operations)
+    }
+
+    fn set_label(
+        &mut self,
+        contract_id: ContractId,
+        reference: LabelRef,
+        label: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        let contract = self.data.contract_mut(contract_id)?;
+        match label {
+            Some(ref label) => self.db.execute(
+                "INSERT OR REPLACE INTO labels (contract_id, reference, label) \
+                 VALUES (?1, ?2, ?3)",
+                params![contract_id.to_string(), reference.to_string(), label],
+            )?,
+            None => self.db.execute(
+                "DELETE FROM labels WHERE contract_id = ?1 AND reference = ?2",
+                params![contract_id.to_string(), reference.to_string()],
+            )?,
+        };
+        Ok(contract.set_label(reference, label))
+    }
+
+    fn history(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<Vec<Operation>, Error> {
+        self.data.contract_ref(contract_id).map(Contract::history)
+    }
+
+    fn signers(&self) -> Result<Vec<SignerAccountInfo>, Error> {
+        Ok(self.data.signers.values().cloned().collect())
+    }
+
+    fn add_signer(
+        &mut self,
+        account: SignerAccountInfo,
+    ) -> Result<SignerAccountInfo, Error> {
+        let account = self.data.add_signer(account)?;
+        if let Err(err) = self.db.execute(
+            "INSERT OR REPLACE INTO signers (id, account) VALUES (?1, ?2)",
+            params![account.id(), account.strict_serialize()?],
+        ) {
+            // Reverting in-memory changes
+            self.load()?;
+            return Err(err.into());
+        }
+        Ok(account)
+    }
+
+    fn remove_signer(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<SignerAccountInfo, Error> {
+        self.db.execute(
+            "DELETE FROM signers WHERE id = ?1",
+            params![key.to_string()],
+        )?;
+        self.data.remove_signer(key)
+    }
+
+    fn identities(&self) -> Result<Vec<IdentityInfo>, Error> {
+        Ok(self.data.identities.values().cloned().collect())
+    }
+
+    fn add_identity(
+        &mut self,
+        identity: IdentityInfo,
+    ) -> Result<IdentityInfo, Error> {
+        let identity = self.data.add_identity(identity)?;
+        if let Err(err) = self.db.execute(
+            "INSERT OR REPLACE INTO identities (id, identity) \
+             VALUES (?1, ?2)",
+            params![identity.id(), identity.strict_serialize()?],
+        ) {
+            // Reverting in-memory changes
+            self.load()?;
+            return Err(err.into());
+        }
+        Ok(identity)
+    }
+
+    fn remove_identity(
+        &mut self,
+        key: &descriptors::SingleSig,
+    ) -> Result<IdentityInfo, Error> {
+        self.db.execute(
+            "DELETE FROM identities WHERE id = ?1",
+            params![key.to_string()],
+        )?;
+        self.data.remove_identity(key)
+    }
+
+    fn dump(&self) -> Result<Citadel, Error> {
+        Ok(self.data.clone())
+    }
+
+    fn restore(&mut self, data: Citadel) -> Result<(), Error> {
+        let tx = self.db.transaction()?;
+        for table in &[
+            "labels",
+            "operations",
+            "p2c_tweaks",
+            "invoices",
+            "blinding_factors",
+            "contracts",
+            "signers",
+            "identities",
+            "assets",
+        ] {
+            tx.execute(&format!("DELETE FROM {}", table), params![])?;
+        }
+        let contracts = data
+            .contracts
+            .values()
+            .map(|contract| (contract, None))
+            .chain(data.archived.values().map(|archived| {
+                (
+                    archived.contract(),
+                    Some(archived.archived_at().timestamp()),
+                )
+            }));
+        for (contract, archived_at) in contracts {
+            let id = contract.id().to_string();
+            let meta =
+                ContractMeta::from(contract.clone()).strict_serialize()?;
+            tx.execute(
+                "INSERT INTO contracts (id, name, meta, archived_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![id, contract.name, meta, archived_at],
+            )?;
+            for invoice in contract.data().sent_invoices() {
+                tx.execute(
+                    "INSERT OR IGNORE INTO invoices (contract_id, invoice) \
+                     VALUES (?1, ?2)",
+                    params![id, invoice.to_string()],
+                )?;
+            }
+            for reveal in contract.data().blinding_factors().values() {
+                tx.execute(
+                    "INSERT OR IGNORE INTO blinding_factors \
+                     (contract_id, reveal) VALUES (?1, ?2)",
+                    params![id, reveal.strict_serialize()?],
+                )?;
+            }
+            for tweak in contract.data().p2c_tweaks() {
+                tx.execute(
+                    "INSERT OR REPLACE INTO p2c_tweaks \
+                     (contract_id, txid, vout, tweak) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        id,
+                        tweak.outpoint.txid.to_string(),
+                        tweak.outpoint.vout,
+                        tweak.strict_serialize()?
+                    ],
+                )?;
+            }
+            for operation in contract.data().operations() {
+                tx.execute(
+                    "INSERT INTO operations \
+                     (contract_id, txid, created_at, height, operation) \
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        id,
+                        operation.txid.to_string(),
+                        operation.created_at.timestamp(),
+                        operation.height,
+                        operation.strict_serialize()?
+                    ],
+                )?;
+            }
+            for (reference, label) in contract.data().labels() {
+                tx.execute(
+                    "INSERT INTO labels (contract_id, reference, label) \
+                     VALUES (?1, ?2, ?3)",
+                    params![id, reference.to_string(), label],
+                )?;
+            }
+        }
+        for (id, account) in &data.signers {
+            tx.execute(
+                "INSERT INTO signers (id, account) VALUES (?1, ?2)",
+                params![id, account.strict_serialize()?],
+            )?;
+        }
+        for (id, identity) in &data.identities {
+            tx.execute(
+                "INSERT INTO identities (id, identity) VALUES (?1, ?2)",
+                params![id, identity.strict_serialize()?],
+            )?;
+        }
+        for (id, asset) in &data.assets {
+            tx.execute(
+                "INSERT INTO assets (id, asset) VALUES (?1, ?2)",
+                params![id.to_string(), asset.strict_serialize()?],
+            )?;
+        }
+        tx.commit()?;
+        self.load()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    use super::*;
+    use crate::test_utils::{contract, temp_dir};
+
+    fn db_path(name: &str) -> PathBuf {
+        temp_dir(&format!("storage-sqlite-{}", name)).join("wallet.db")
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = db_path("round-trip");
+        let mut storage = SqliteDriver::with(path.clone()).unwrap();
+        let id = *storage.add_contract(contract(1, "savings")).unwrap().id();
+        let archived_id =
+            *storage.add_contract(contract(2, "old")).unwrap().id();
+        storage.rename_contract(id, s!("spending")).unwrap();
+        storage
+            .set_label(id, LabelRef::Contract, Some(s!("daily")))
+            .unwrap();
+        let txid = Txid::hash(b"tx");
+        storage
+            .set_label(id, LabelRef::Tx(txid), Some(s!("coffee")))
+            .unwrap();
+        storage.archive_contract(archived_id).unwrap();
+        // Re-added contract name is persisted
+        storage.add_contract(contract(1, "renamed")).unwrap();
+        let dump = storage.dump().unwrap();
+        assert_eq!(storage.contract_ref(id).unwrap().name, "renamed");
+        assert_eq!(
+            storage.add_contract(contract(2, "old")).err(),
+            Some(Error::ContractArchived(archived_id))
+        );
+        drop(storage);
+
+        let mut storage = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(storage.dump().unwrap(), dump);
+        assert_eq!(storage.contract_ref(id).unwrap().data().labels().len(), 2);
+        assert_eq!(
+            storage.set_label(id, LabelRef::Tx(txid), None).unwrap(),
+            Some(s!("coffee"))
+        );
+        storage.restore_contract(archived_id).unwrap();
+        assert_eq!(storage.contracts().unwrap().len(), 2);
+
+        // Restoring the dump replaces all of the data
+        storage.restore(dump.clone()).unwrap();
+        drop(storage);
+        let storage = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(storage.dump().unwrap(), dump);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrates_old_schema() {
+        let path = db_path("migration");
+        let contract = contract(1, "savings");
+        let db = Connection::open(&path).unwrap();
+        db.execute_batch(SCHEMA_V1).unwrap();
+        db.execute_batch("PRAGMA user_version = 1").unwrap();
+        db.execute(
+            "INSERT INTO contracts (id, name, meta) VALUES (?1, ?2, ?3)",
+            params![
+                contract.id().to_string(),
+                contract.name,
+                ContractMeta::from(contract.clone())
+                    .strict_serialize()
+                    .unwrap()
+            ],
+        )
+        .unwrap();
+        drop(db);
+
+        let mut storage = SqliteDriver::with(path.clone()).unwrap();
+        assert_eq!(storage.contracts().unwrap(), vec![contract.clone()]);
+        let version: u16 = storage
+            .db
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA.version());
+        assert!(path.with_file_name("wallet.db.v1.backup").exists());
+
+        // Tables and columns added by the migrations are usable
+        storage
+            .set_label(*contract.id(), LabelRef::Contract, Some(s!("label")))
+            .unwrap();
+        storage.archive_contract(*contract.id()).unwrap();
+        drop(storage);
+        let storage = SqliteDriver::with(path.clone()).unwrap();
+        let archived = storage.archived_contracts().unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].contract().data().labels().len(), 1);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
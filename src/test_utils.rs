@@ -0,0 +1,124 @@
+// Citadel: Bitcoin, LN & RGB wallet runtime
+// Written in 2021 by
+//     Dr. Maxim Orlovsky <orlovsky@mycitadel.io>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the AGPL License
+// along with this software.
+// If not, see <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Fixtures shared by unit tests
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bitcoin::blockdata::script::Builder;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::{Address, Network, Transaction, TxOut, Txid};
+use lnpbp::chain::Chain;
+use wallet::descriptors::ContentType;
+use wallet::hd::UnhardenedIndex;
+use wallet::psbt::Psbt;
+
+use crate::model::{
+    self, Branch, Contract, Operation, PaymentDirecton, Policy, PsbtWrapper,
+    Utxo,
+};
+use crate::SECP256K1;
+
+/// Creates empty directory unique for the test name and the test process,
+/// removing the data left by the previous runs
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "citadel-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("temp dir is writable");
+    dir
+}
+
+/// Testnet BIP-84 account public key chain with receive and change branches
+/// derived from the `seed`
+pub(crate) fn pubkey_chain(seed: u8) -> String {
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let xpriv = ExtendedPrivKey::new_master(Network::Testnet, &[seed; 32])
+        .and_then(|master| master.derive_priv(&*SECP256K1, &path))
+        .expect("valid derivation");
+    let xpub = ExtendedPubKey::from_private(&*SECP256K1, &xpriv);
+    format!("m/84'/1'/0'=[{}]/<0;1>/*", xpub)
+}
+
+/// Single-sig testnet contract with the key derived from the `seed`
+pub(crate) fn contract(seed: u8, name: &str) -> Contract {
+    let (pk, branches) = model::parse_multipath_chain(&pubkey_chain(seed))
+        .expect("valid public key chain");
+    Contract::with(
+        Policy::single_sig(ContentType::SegWit, pk, branches),
+        s!(name),
+        Chain::Testnet3,
+    )
+}
+
+pub(crate) fn address(no: i64) -> Address {
+    Address::p2wsh(&Builder::new().push_int(no).into_script(), Network::Regtest)
+}
+
+/// Transaction paying to [`address`] with the same number
+pub(crate) fn transaction(no: u32) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: no,
+        input: vec![],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: address(no as i64).script_pubkey(),
+        }],
+    }
+}
+
+pub(crate) fn utxo(txid: Txid, value: u64, height: u32) -> Utxo {
+    Utxo {
+        value,
+        height,
+        offset: 1,
+        txid,
+        vout: 0,
+        branch: Branch::External,
+        derivation_index: UnhardenedIndex::zero(),
+        tweak: None,
+        address: None,
+    }
+}
+
+/// Incoming payment history record for [`transaction`] with the same number
+pub(crate) fn operation(no: u32) -> Operation {
+    let tx = transaction(no);
+    Operation {
+        txid: tx.txid(),
+        direction: PaymentDirecton::Incoming {
+            giveaway: None,
+            input_derivation_indexes: none!(),
+        },
+        created_at: chrono::NaiveDateTime::from_timestamp(no as i64, 0),
+        height: no as i64,
+        asset_id: None,
+        balance_before: 0,
+        bitcoin_volume: 1000,
+        asset_volume: 0,
+        bitcoin_value: 1000,
+        asset_value: 0,
+        tx_fee: 0,
+        psbt: PsbtWrapper(
+            Psbt::from_unsigned_tx(tx).expect("transaction without inputs"),
+        ),
+        disclosure: None,
+        notes: None,
+    }
+}